@@ -0,0 +1,15 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // Compiled with protox, so building the gRPC server doesn't need protoc installed
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/git_history.proto");
+        let descriptors = protox::compile(["git_history.proto"], ["proto"])
+            .expect("proto/git_history.proto should compile");
+        tonic_build::configure()
+            .build_client(false)
+            .compile_fds(descriptors)
+            .expect("gRPC code generation should succeed");
+    }
+}
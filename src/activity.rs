@@ -0,0 +1,149 @@
+use crate::identities::Identities;
+use crate::options::IndexOptions;
+use crate::{for_each_commit, CustomError};
+use chrono::{Datelike, Days, Months, NaiveDate, TimeZone};
+use chrono_tz::Tz;
+use git2::Repository;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+/// Width of the time-series buckets.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Bucket {
+    Day,
+    /// Weeks start on Monday, like ISO 8601
+    #[default]
+    Week,
+    Month,
+}
+
+impl Bucket {
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "day" => Ok(Bucket::Day),
+            "week" => Ok(Bucket::Week),
+            "month" => Ok(Bucket::Month),
+            _ => Err(format!("expected day, week or month, got '{}'", raw)),
+        }
+    }
+
+    /// First day of the bucket containing `date`.
+    fn start(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Bucket::Day => date,
+            Bucket::Week => date - Days::new(date.weekday().num_days_from_monday() as u64),
+            Bucket::Month => date.with_day(1).expect("every month has a first day"),
+        }
+    }
+
+    fn next(self, start: NaiveDate) -> NaiveDate {
+        match self {
+            Bucket::Day => start + Days::new(1),
+            Bucket::Week => start + Days::new(7),
+            Bucket::Month => start + Months::new(1),
+        }
+    }
+}
+
+/// Accepts IANA names such as `Europe/Berlin`, and `UTC`.
+pub fn parse_timezone(raw: &str) -> Result<Tz, String> {
+    raw.parse().map_err(|_| {
+        format!(
+            "expected an IANA time zone like Europe/Berlin, got '{}'",
+            raw
+        )
+    })
+}
+
+#[derive(Serialize)]
+pub struct Activity {
+    /// Local midnight starting the bucket, with the zone's offset at that time
+    bucket_start: String,
+    commits: usize,
+    /// Distinct author emails, canonicalized through .mailmap and `--identity-map`
+    authors: usize,
+    insertions: usize,
+    deletions: usize,
+}
+
+#[derive(Default)]
+struct Tally {
+    commits: usize,
+    authors: HashSet<String>,
+    insertions: usize,
+    deletions: usize,
+}
+
+/// Commit counts, authors and line changes per bucket of committer date, in `timezone`.
+/// Every bucket from `--since` (or the first commit) to `--until` (or the last commit)
+/// is listed, including empty ones.
+pub fn activity(
+    repo_path: &Path,
+    options: &IndexOptions,
+    bucket: Bucket,
+    timezone: Tz,
+) -> Result<Vec<Activity>, CustomError> {
+    let repo = Repository::open(repo_path)?;
+    let identities = Identities::load(&repo, options)?;
+    let local_date = |seconds: i64| {
+        timezone
+            .timestamp_opt(seconds, 0)
+            .single()
+            .map(|time| time.date_naive())
+    };
+
+    let mut tallies: BTreeMap<NaiveDate, Tally> = BTreeMap::new();
+    for_each_commit(&repo, options, |commit, diff, _| {
+        let Some(date) = local_date(commit.time().seconds()) else {
+            return Ok(());
+        };
+        let author = identities.author(commit)?;
+        let stats = diff.stats()?;
+
+        let tally = tallies.entry(bucket.start(date)).or_default();
+        tally.commits += 1;
+        tally
+            .authors
+            .insert(String::from_utf8_lossy(author.email_bytes()).to_lowercase());
+        tally.insertions += stats.insertions();
+        tally.deletions += stats.deletions();
+        Ok(())
+    })?;
+
+    let first = options
+        .since
+        .and_then(local_date)
+        .or_else(|| tallies.keys().next().copied());
+    let last = options
+        .until
+        .and_then(local_date)
+        .or_else(|| tallies.keys().next_back().copied());
+    let (Some(first), Some(last)) = (first, last) else {
+        return Ok(Vec::new());
+    };
+
+    let mut series = Vec::new();
+    let mut start = bucket.start(first);
+    while start <= last {
+        let tally = tallies.remove(&start).unwrap_or_default();
+        let midnight = start
+            .and_hms_opt(0, 0, 0)
+            .expect("midnight is a valid time");
+        // Zones that skip midnight for daylight saving get the instant of UTC midnight
+        let local_start = timezone
+            .from_local_datetime(&midnight)
+            .earliest()
+            .unwrap_or_else(|| timezone.from_utc_datetime(&midnight));
+        series.push(Activity {
+            bucket_start: local_start.to_rfc3339(),
+            commits: tally.commits,
+            authors: tally.authors.len(),
+            insertions: tally.insertions,
+            deletions: tally.deletions,
+        });
+        start = bucket.next(start);
+    }
+
+    Ok(series)
+}
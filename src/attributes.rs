@@ -0,0 +1,171 @@
+use crate::CustomError;
+use git2::{ObjectType, Repository, Tree};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Decides which files GitHub would treat as generated: those marked
+/// `linguist-generated` or `-diff` by the `.gitattributes` files of one commit's tree.
+pub struct GeneratedFiles<'r> {
+    repo: &'r Repository,
+    tree: Tree<'r>,
+    /// Parsed `.gitattributes` per directory, empty for directories without one
+    rules: HashMap<PathBuf, Vec<Rule>>,
+}
+
+struct Rule {
+    pattern: Gitignore,
+    linguist_generated: Option<bool>,
+    diff: Option<bool>,
+}
+
+impl<'r> GeneratedFiles<'r> {
+    pub fn new(repo: &'r Repository, commit: &git2::Commit<'r>) -> Result<Self, CustomError> {
+        Ok(GeneratedFiles {
+            repo,
+            tree: commit.tree()?,
+            rules: HashMap::new(),
+        })
+    }
+
+    /// Attributes files apply to everything below their directory, with deeper files
+    /// and later lines overriding earlier ones, as in git.
+    pub fn is_generated(&mut self, path: &Path) -> Result<bool, CustomError> {
+        let mut linguist_generated = None;
+        let mut diff = None;
+
+        let mut dirs: Vec<&Path> = path.ancestors().skip(1).collect();
+        dirs.reverse();
+        for dir in dirs {
+            let relative = path.strip_prefix(dir).unwrap_or(path);
+            for rule in self.rules_for(dir)? {
+                if rule.pattern.matched(relative, false).is_ignore() {
+                    linguist_generated = rule.linguist_generated.or(linguist_generated);
+                    diff = rule.diff.or(diff);
+                }
+            }
+        }
+
+        Ok(linguist_generated == Some(true) || diff == Some(false))
+    }
+
+    fn rules_for(&mut self, dir: &Path) -> Result<&[Rule], CustomError> {
+        if !self.rules.contains_key(dir) {
+            let rules = match self.tree.get_path(&dir.join(".gitattributes")) {
+                Ok(entry) if entry.kind() == Some(ObjectType::Blob) => {
+                    let blob = self.repo.find_blob(entry.id())?;
+                    parse(&String::from_utf8_lossy(blob.content()))
+                }
+                _ => Vec::new(),
+            };
+            self.rules.insert(dir.to_path_buf(), rules);
+        }
+        Ok(&self.rules[dir])
+    }
+}
+
+/// Parses the lines of a `.gitattributes` file that mention `linguist-generated`,
+/// `diff` or the `binary` macro, which unsets `diff`.
+fn parse(contents: &str) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let Some(pattern) = fields.next() else {
+            continue;
+        };
+
+        let mut linguist_generated = None;
+        let mut diff = None;
+        for attribute in fields {
+            match attribute {
+                "linguist-generated" | "linguist-generated=true" => linguist_generated = Some(true),
+                "-linguist-generated" | "linguist-generated=false" => {
+                    linguist_generated = Some(false)
+                }
+                "-diff" | "binary" => diff = Some(false),
+                "diff" => diff = Some(true),
+                _ => {}
+            }
+        }
+        if linguist_generated.is_none() && diff.is_none() {
+            continue;
+        }
+
+        let mut builder = GitignoreBuilder::new("");
+        // Lines git itself would reject are skipped rather than failing the index
+        if builder.add_line(None, pattern).is_err() {
+            continue;
+        }
+        let Ok(pattern) = builder.build() else {
+            continue;
+        };
+        rules.push(Rule {
+            pattern,
+            linguist_generated,
+            diff,
+        });
+    }
+    rules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_repo::TestRepo;
+
+    #[test]
+    fn nested_attributes_files_apply_below_their_directory() {
+        let repo = TestRepo::new();
+        repo.write(
+            ".gitattributes",
+            "*.pb.go linguist-generated\ndocs/** -diff\n",
+        )
+        .write(
+            "sub/.gitattributes",
+            "*.gen.rs linguist-generated\nkeep.pb.go -linguist-generated\n",
+        )
+        .write("main.rs", "")
+        .commit("Add attributes");
+        let git_repo = repo.open();
+        let commit = git_repo.head().unwrap().peel_to_commit().unwrap();
+        let mut generated = GeneratedFiles::new(&git_repo, &commit).unwrap();
+
+        for (path, expected) in [
+            ("main.rs", false),
+            ("api.pb.go", true),
+            ("sub/api.pb.go", true),
+            ("sub/keep.pb.go", false),
+            ("sub/x.gen.rs", true),
+            ("sub/deep/y.gen.rs", true),
+            ("x.gen.rs", false),
+            ("docs/guide.md", true),
+            ("sub/docs/guide.md", false),
+        ] {
+            assert_eq!(
+                generated.is_generated(Path::new(path)).unwrap(),
+                expected,
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn parses_only_the_attributes_that_matter() {
+        let rules =
+            parse("# comment\n\n*.lock binary\n*.md text eol=lf\n*.js diff -linguist-generated\n");
+        assert_eq!(rules.len(), 2);
+        assert_eq!(
+            (rules[0].linguist_generated, rules[0].diff),
+            (None, Some(false))
+        );
+        assert_eq!(
+            (rules[1].linguist_generated, rules[1].diff),
+            (Some(false), Some(true))
+        );
+    }
+}
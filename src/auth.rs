@@ -0,0 +1,114 @@
+use hyper::HeaderMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+/// API keys accepted by the server. Keys given on the command line are fixed for
+/// the lifetime of the process; keys from `--api-key-file` are re-read on reload.
+pub struct ApiKeys {
+    static_keys: Vec<String>,
+    key_file: Option<PathBuf>,
+    keys: RwLock<Vec<String>>,
+}
+
+impl ApiKeys {
+    pub fn new(static_keys: Vec<String>, key_file: Option<PathBuf>) -> io::Result<Self> {
+        let api_keys = ApiKeys {
+            static_keys,
+            key_file,
+            keys: RwLock::new(Vec::new()),
+        };
+        api_keys.reload()?;
+        Ok(api_keys)
+    }
+
+    /// Authentication is enforced as soon as any key source is configured, even if
+    /// the key file is currently empty.
+    pub fn is_enabled(&self) -> bool {
+        !self.static_keys.is_empty() || self.key_file.is_some()
+    }
+
+    /// Re-reads the key file and returns the number of keys now accepted.
+    pub fn reload(&self) -> io::Result<usize> {
+        let mut keys = self.static_keys.clone();
+        if let Some(path) = &self.key_file {
+            keys.extend(read_key_file(path)?);
+        }
+        let count = keys.len();
+        *self.keys.write().unwrap() = keys;
+        Ok(count)
+    }
+
+    /// Checks the `Authorization: Bearer <key>` or `X-Api-Key` header of a request.
+    pub fn authorize(&self, headers: &HeaderMap) -> bool {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        self.authorize_values(header("Authorization"), header("X-Api-Key"))
+    }
+
+    /// Checks the values of the `Authorization` and `X-Api-Key` headers, for callers
+    /// that don't have a hyper `HeaderMap` such as gRPC metadata.
+    pub fn authorize_values(&self, authorization: Option<&str>, api_key: Option<&str>) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+
+        let bearer = authorization.and_then(|v| v.strip_prefix("Bearer "));
+        let keys = self.keys.read().unwrap();
+        [bearer, api_key]
+            .iter()
+            .flatten()
+            .any(|candidate| matches_any(&keys, candidate.trim()))
+    }
+}
+
+/// Reads one key per line, ignoring blank lines and `#` comments.
+fn read_key_file(path: &PathBuf) -> io::Result<Vec<String>> {
+    let contents = fs::read_to_string(path)?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+// Compares against every key so the time taken doesn't reveal which one matched
+fn matches_any(keys: &[String], candidate: &str) -> bool {
+    keys.iter().fold(false, |found, key| {
+        found | constant_time_eq(key.as_bytes(), candidate.as_bytes())
+    })
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Reloads the key file whenever the process receives SIGHUP.
+#[cfg(unix)]
+pub fn reload_on_sighup(keys: Arc<ApiKeys>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match keys.reload() {
+                Ok(count) => println!("Reloaded API keys ({} active)", count),
+                Err(e) => eprintln!("Failed to reload API keys, keeping previous set: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn reload_on_sighup(_keys: Arc<ApiKeys>) {}
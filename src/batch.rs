@@ -0,0 +1,302 @@
+use crate::options::IndexOptions;
+use crate::{
+    budget, clone, envelope, index_source, webhooks, CustomError, IndexedRepo, RepoSource,
+    ServerState,
+};
+use hyper::StatusCode;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Semaphore};
+
+/// One repository of a batch.
+pub struct BatchEntry {
+    /// `repo_url` or `repo_path`, as given; results are reported under it
+    pub key: String,
+    /// Name of the field `key` came from
+    pub field: &'static str,
+    /// Where to index from, or why it can't be indexed
+    pub source: Result<RepoSource, (StatusCode, String)>,
+    /// Revision to index instead of HEAD
+    pub git_ref: Option<String>,
+}
+
+/// The outcome for one entry: `{"meta", "commits"}` or `{"error": {"status",
+/// "message"}}`.
+pub struct BatchResult {
+    pub key: String,
+    pub field: &'static str,
+    pub git_ref: Option<String>,
+    pub body: serde_json::Value,
+}
+
+impl BatchResult {
+    /// The result as one NDJSON record, tagged with its repository and ref.
+    pub fn ndjson_line(mut self) -> String {
+        self.body[self.field] = self.key.into();
+        if let Some(git_ref) = self.git_ref {
+            self.body["ref"] = git_ref.into();
+        }
+        format!("{}\n", self.body)
+    }
+}
+
+/// What a result is reported under: the repository, followed by `@<ref>` if the
+/// entry has one, so one repository can be listed with several refs.
+fn result_key(key: &str, git_ref: Option<&str>) -> String {
+    match git_ref {
+        Some(git_ref) => format!("{}@{}", key, git_ref),
+        None => key.to_string(),
+    }
+}
+
+/// Reads the `repos` array of a `POST /git_history/batch` body. Every entry has a
+/// `repo_url` or a `repo_path` and an optional `ref`. Entries the server refuses, such
+/// as paths outside `--allow-local-paths`, become per-repository errors.
+pub fn parse_request(
+    state: &ServerState,
+    body: &serde_json::Value,
+) -> Result<Vec<BatchEntry>, String> {
+    let repos = body["repos"]
+        .as_array()
+        .filter(|repos| !repos.is_empty())
+        .ok_or("repos must be a non-empty array")?;
+    let mut entries = Vec::new();
+    for repo in repos {
+        let (key, field) = match (repo["repo_url"].as_str(), repo["repo_path"].as_str()) {
+            (Some(url), None) => (url, "repo_url"),
+            (None, Some(path)) => (path, "repo_path"),
+            _ => return Err("every entry of repos needs either repo_url or repo_path".to_string()),
+        };
+        entries.push(BatchEntry {
+            key: key.to_string(),
+            field,
+            source: state.repo_source(repo),
+            git_ref: repo["ref"].as_str().map(String::from),
+        });
+    }
+    Ok(dedupe(entries))
+}
+
+/// Reads a `--repos-file`: one repository per line, optionally followed by a ref.
+/// Directories are indexed in place, anything else is cloned like a server `repo_url`.
+/// Blank lines and `#` comments are skipped.
+pub fn parse_repos_file(contents: &str) -> Result<Vec<BatchEntry>, String> {
+    let mut entries = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let repo = words.next().expect("line is not blank");
+        let git_ref = words.next().map(String::from);
+        if words.next().is_some() {
+            return Err(format!("expected '<repo> [ref]', got '{}'", line));
+        }
+        let (field, source) = if Path::new(repo).is_dir() {
+            ("repo_path", RepoSource::Local(repo.into()))
+        } else {
+            ("repo_url", RepoSource::Url(repo.to_string()))
+        };
+        entries.push(BatchEntry {
+            key: repo.to_string(),
+            field,
+            source: Ok(source),
+            git_ref,
+        });
+    }
+    if entries.is_empty() {
+        return Err("no repositories listed".to_string());
+    }
+    Ok(dedupe(entries))
+}
+
+/// Drops repeated entries, so each history is indexed once. URLs that differ only in
+/// scheme, trailing slash or `.git` are the same repository; the same repository with
+/// another ref is another entry. The other options are shared by the whole batch, so
+/// the ref is all that tells two entries of one repository apart.
+fn dedupe(entries: Vec<BatchEntry>) -> Vec<BatchEntry> {
+    let mut seen = HashSet::new();
+    let mut unique = Vec::new();
+    for entry in entries {
+        let id = match entry.field {
+            "repo_url" => webhooks::cache_key(&entry.key),
+            _ => entry.key.clone(),
+        };
+        if seen.insert((id, entry.git_ref.clone())) {
+            unique.push(entry);
+        }
+    }
+    unique
+}
+
+/// Indexes every entry with `options`, as many at once as `jobs` has permits, and
+/// sends each result as soon as it is ready. Entries wait for a permit however long
+/// it takes, like webhook re-indexing, since a batch is expected to exceed the limit.
+/// Repositories are cloned with `settings`.
+pub fn run(
+    entries: Vec<BatchEntry>,
+    options: &IndexOptions,
+    jobs: Arc<Semaphore>,
+    settings: &clone::CloneSettings,
+) -> mpsc::UnboundedReceiver<BatchResult> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    for entry in entries {
+        let sender = sender.clone();
+        let jobs = jobs.clone();
+        let settings = settings.clone();
+        let mut options = options.clone();
+        options.rev = entry.git_ref.clone();
+        tokio::spawn(async move {
+            let result_key = result_key(&entry.key, entry.git_ref.as_deref());
+            let body = match entry.source {
+                Ok(source) => {
+                    let _permit = jobs.acquire_owned().await;
+                    let body = |indexed: &IndexedRepo| {
+                        if options.envelope {
+                            envelope::wrap(&result_key, indexed, &options)
+                        } else {
+                            history_body(indexed)
+                        }
+                    };
+                    let fitted = index_source(source, options.clone(), &settings)
+                        .await
+                        .and_then(|mut indexed| {
+                            // Each repository's part held to the server's limit as sent
+                            if options.max_output_bytes.is_some() {
+                                budget::fit(&mut indexed, options.max_output_bytes, |indexed| {
+                                    Ok(serde_json::to_string_pretty(&body(indexed))?)
+                                })?;
+                            }
+                            Ok(indexed)
+                        });
+                    match fitted {
+                        Ok(indexed) => body(&indexed),
+                        Err(e) => error_body(e.http_status(), &format!("Error: {}", e)),
+                    }
+                }
+                Err((status, message)) => error_body(status, &message),
+            };
+            // The receiver is gone when the client disconnected
+            let _ = sender.send(BatchResult {
+                key: entry.key,
+                field: entry.field,
+                git_ref: entry.git_ref,
+                body,
+            });
+        });
+    }
+    receiver
+}
+
+/// Collects every result into one object keyed by `result_key`.
+pub async fn collect(
+    mut results: mpsc::UnboundedReceiver<BatchResult>,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut map = serde_json::Map::new();
+    while let Some(result) = results.recv().await {
+        map.insert(
+            result_key(&result.key, result.git_ref.as_deref()),
+            result.body,
+        );
+    }
+    map
+}
+
+fn history_body(indexed: &IndexedRepo) -> serde_json::Value {
+    serde_json::json!({
+        "meta": {
+            "empty_repository": indexed.empty_repository,
+            "shallow": indexed.shallow,
+            "truncated": indexed.next_cursor.is_some(),
+            "next_cursor": indexed.next_cursor,
+        },
+        "commits": indexed.commits,
+    })
+}
+
+fn error_body(status: StatusCode, message: &str) -> serde_json::Value {
+    serde_json::json!({
+        "error": { "status": status.as_u16(), "message": message },
+    })
+}
+
+/// Indexes the repositories listed in `repos_file` concurrently, one job per CPU, and
+/// returns the results keyed by repository as JSON. A repository that fails is
+/// reported in the output and on stderr without stopping the others.
+pub async fn index_file(repos_file: &Path, options: &IndexOptions) -> Result<String, CustomError> {
+    let contents = std::fs::read_to_string(repos_file)?;
+    let entries = parse_repos_file(&contents)
+        .map_err(|e| CustomError::InvalidRequest(format!("{}: {}", repos_file.display(), e)))?;
+    let jobs = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+    let mut results = run(
+        entries,
+        options,
+        Arc::new(Semaphore::new(jobs)),
+        &clone::CloneSettings::default(),
+    );
+
+    let mut map = serde_json::Map::new();
+    while let Some(result) = results.recv().await {
+        let key = result_key(&result.key, result.git_ref.as_deref());
+        match result.body["error"]["message"].as_str() {
+            Some(message) => eprintln!("Failed to index {}: {}", key, message),
+            None => println!(
+                "Indexed {}: {} commits",
+                key,
+                result.body["commits"].as_array().map_or(0, Vec::len)
+            ),
+        }
+        map.insert(key, result.body);
+    }
+    Ok(serde_json::to_string_pretty(&map)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedupes_on_repository_and_ref() {
+        let entries = parse_repos_file(
+            "# nightly\n\
+             github.com/o/api\n\
+             https://github.com/o/api.git\n\
+             github.com/o/api/ main\n\
+             github.com/o/api v1.0\n\
+             github.com/o/api main\n\
+             \n\
+             github.com/o/web v1.0\n",
+        )
+        .unwrap();
+        let keys: Vec<_> = entries
+            .iter()
+            .map(|entry| result_key(&entry.key, entry.git_ref.as_deref()))
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                "github.com/o/api",
+                "github.com/o/api/@main",
+                "github.com/o/api@v1.0",
+                "github.com/o/web@v1.0"
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_repos_files() {
+        assert_eq!(
+            parse_repos_file("github.com/o/api main extra\n")
+                .err()
+                .unwrap(),
+            "expected '<repo> [ref]', got 'github.com/o/api main extra'"
+        );
+        assert_eq!(
+            parse_repos_file("# nothing\n\n").err().unwrap(),
+            "no repositories listed"
+        );
+    }
+}
@@ -0,0 +1,118 @@
+use crate::redact::Redactor;
+use crate::{format_git_time, CustomError};
+use git2::{BlameOptions, ObjectType, Repository};
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Serialize)]
+pub struct BlameHunk {
+    start_line: usize,
+    line_count: usize,
+    commit_id: String,
+    author: String,
+    author_email: String,
+    commit_date: String,
+    /// Path of the lines in `commit_id`, which differs from the requested path when the
+    /// file was renamed since.
+    original_path: String,
+}
+
+impl BlameHunk {
+    pub fn redact(&mut self, redactor: &Redactor) {
+        redactor.name(&mut self.author);
+        redactor.email(&mut self.author_email);
+    }
+}
+
+/// Line-level attribution of `path` as of `rev`.
+pub fn blame_file(repo_path: &Path, path: &str, rev: &str) -> Result<Vec<BlameHunk>, CustomError> {
+    let repo = Repository::open(repo_path)?;
+    let commit = repo.revparse_single(rev)?.peel_to_commit()?;
+
+    let entry = commit
+        .tree()?
+        .get_path(Path::new(path))
+        .map_err(|_| CustomError::PathNotFound(format!("{} does not exist at {}", path, rev)))?;
+    if entry.kind() != Some(ObjectType::Blob) {
+        return Err(CustomError::PathNotFound(format!(
+            "{} is not a file at {}",
+            path, rev
+        )));
+    }
+    if repo.find_blob(entry.id())?.is_binary() {
+        return Err(CustomError::BinaryFile(format!(
+            "{} is a binary file",
+            path
+        )));
+    }
+
+    let mut options = BlameOptions::new();
+    options.newest_commit(commit.id());
+    let blame = repo.blame_file(Path::new(path), Some(&mut options))?;
+
+    Ok(blame
+        .iter()
+        .map(|hunk| {
+            let signature = hunk.final_signature();
+            BlameHunk {
+                start_line: hunk.final_start_line(),
+                line_count: hunk.lines_in_hunk(),
+                commit_id: hunk.final_commit_id().to_string(),
+                author: String::from_utf8_lossy(signature.name_bytes()).to_string(),
+                author_email: signature.email().unwrap_or("").to_string(),
+                commit_date: format_git_time(signature.when()),
+                original_path: hunk
+                    .path()
+                    .map(|p| p.to_string_lossy().to_string())
+                    .unwrap_or_else(|| path.to_string()),
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_repo::TestRepo;
+
+    #[test]
+    fn attributes_lines_across_a_rename() {
+        let repo = TestRepo::new();
+        let first = repo.write("old.txt", "one\ntwo\n").commit("Add two lines");
+        repo.rename("old.txt", "new.txt").commit("Rename the file");
+        let second = repo.write("new.txt", "one\ntwo\nthree\n").commit_as(
+            "Bob Builder",
+            "bob@example.com",
+            "Add a third line",
+        );
+
+        let hunks = blame_file(repo.path(), "new.txt", "HEAD").unwrap();
+        assert_eq!(hunks.len(), 2);
+        assert_eq!((hunks[0].start_line, hunks[0].line_count), (1, 2));
+        assert_eq!(hunks[0].commit_id, first);
+        assert_eq!(hunks[0].original_path, "old.txt");
+        assert_eq!((hunks[1].start_line, hunks[1].line_count), (3, 1));
+        assert_eq!(hunks[1].commit_id, second);
+        assert_eq!(hunks[1].author, "Bob Builder");
+        assert_eq!(hunks[1].author_email, "bob@example.com");
+        assert_eq!(hunks[1].original_path, "new.txt");
+
+        let before = blame_file(repo.path(), "old.txt", &first).unwrap();
+        assert_eq!(before.len(), 1);
+    }
+
+    #[test]
+    fn rejects_binary_files_and_missing_paths() {
+        let repo = TestRepo::new();
+        repo.write("bin.dat", b"\x00\x01binary")
+            .write("dir/file.txt", "text\n")
+            .commit("Add files");
+
+        let binary = blame_file(repo.path(), "bin.dat", "HEAD");
+        assert!(matches!(binary, Err(CustomError::BinaryFile(_))));
+        let missing = blame_file(repo.path(), "nope.txt", "HEAD");
+        assert!(matches!(missing, Err(CustomError::PathNotFound(_))));
+        let directory = blame_file(repo.path(), "dir", "HEAD");
+        assert!(matches!(directory, Err(CustomError::PathNotFound(_))));
+    }
+}
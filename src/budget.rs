@@ -0,0 +1,135 @@
+use crate::{CommitHistory, CustomError, IndexedRepo};
+use serde::Serialize;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Default for `--max-response-bytes`: 256 MiB.
+pub const DEFAULT_MAX_RESPONSE_BYTES: u64 = 256 * 1024 * 1024;
+
+/// The running size of the commits an index run has produced, pretty-printed as the
+/// server sends them, checked against the server's `--max-response-bytes` as each
+/// commit is added rather than once the whole history sits in memory. The response is
+/// held to the limit exactly by `fit`, once it is enriched and wrapped.
+pub struct Budget {
+    limit: Option<u64>,
+    used: u64,
+}
+
+impl Budget {
+    pub fn new(limit: Option<u64>) -> Self {
+        Budget { limit, used: 0 }
+    }
+
+    /// Counts `entry` and returns true if it still fits. The first commit always
+    /// does, so paging through a history with `next_cursor` makes progress.
+    pub fn admit(&mut self, entry: &CommitHistory) -> Result<bool, serde_json::Error> {
+        let size = pretty_size(entry)?;
+        let fits = self
+            .limit
+            .is_none_or(|limit| self.used == 0 || self.used + size <= limit);
+        if fits {
+            self.used += size;
+        }
+        Ok(fits)
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+}
+
+/// Renders `indexed` with `render` and drops commits from its end until the result
+/// takes at most `limit` bytes, the first commit dropped becoming its `next_cursor`.
+/// The walk's `Budget` can't see `--enrich` data or the envelope, so this is what holds
+/// a response to `--max-response-bytes`. As with `Budget::admit`, one commit is always
+/// kept. Sets `output_bytes` to the size of the rendered response.
+pub fn fit<F>(
+    indexed: &mut IndexedRepo,
+    limit: Option<u64>,
+    render: F,
+) -> Result<String, CustomError>
+where
+    F: Fn(&IndexedRepo) -> Result<String, CustomError>,
+{
+    loop {
+        let body = render(indexed)?;
+        let excess = limit.map_or(0, |limit| (body.len() as u64).saturating_sub(limit));
+        if excess == 0 || indexed.commits.len() <= 1 {
+            indexed.output_bytes = body.len() as u64;
+            return Ok(body);
+        }
+        // Commits take a little more than this nested in the response, so another
+        // round drops the rest when it falls short
+        let mut freed = 0;
+        while freed < excess && indexed.commits.len() > 1 {
+            let commit = indexed.commits.pop().expect("more than one commit");
+            freed += pretty_size(&commit)?;
+            indexed.next_cursor = Some(commit.commit_id);
+        }
+    }
+}
+
+/// The size of `value` as pretty-printed JSON.
+fn pretty_size(value: &impl Serialize) -> Result<u64, serde_json::Error> {
+    let mut size = ByteCount(0);
+    serde_json::to_writer_pretty(&mut size, value)?;
+    Ok(size.0)
+}
+
+/// Counts what is written instead of keeping it.
+struct ByteCount(u64);
+
+impl io::Write for ByteCount {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How large the server's index responses got, reported by `/health` to help tune
+/// `--max-response-bytes`.
+#[derive(Default)]
+pub struct ResponseSizes {
+    largest: AtomicU64,
+    last: AtomicU64,
+    truncated: AtomicU64,
+}
+
+impl ResponseSizes {
+    /// Records one response of `bytes` of commits, cut short or not.
+    pub fn record(&self, bytes: u64, truncated: bool) {
+        self.largest.fetch_max(bytes, Ordering::Relaxed);
+        self.last.store(bytes, Ordering::Relaxed);
+        if truncated {
+            self.truncated.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// `{"max_bytes", "largest_bytes", "last_bytes", "truncated"}` for `/health`.
+    pub fn to_json(&self, max_bytes: u64) -> serde_json::Value {
+        serde_json::json!({
+            "max_bytes": max_bytes,
+            "largest_bytes": self.largest(),
+            "last_bytes": self.last(),
+            "truncated": self.truncated(),
+        })
+    }
+
+    /// The largest response so far.
+    pub fn largest(&self) -> u64 {
+        self.largest.load(Ordering::Relaxed)
+    }
+
+    pub fn last(&self) -> u64 {
+        self.last.load(Ordering::Relaxed)
+    }
+
+    /// How many responses were cut short by the budget.
+    pub fn truncated(&self) -> u64 {
+        self.truncated.load(Ordering::Relaxed)
+    }
+}
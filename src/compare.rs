@@ -0,0 +1,294 @@
+use crate::options::{IndexOptions, PatchFormat};
+use crate::redact::Redactor;
+use crate::{
+    commit_entry, commit_summary, diff_entries, find_renames, resolve_commit, CommitDiff,
+    CommitHistory, CommitStats, CustomError,
+};
+use git2::{DiffOptions, Oid, Patch, Repository};
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Serialize)]
+pub struct Comparison {
+    base: String,
+    head: String,
+    merge_base: Option<String>,
+    ahead_by: usize,
+    behind_by: usize,
+    /// Commits reachable from head but not from base (`base..head`), newest first
+    commits: Vec<CommitHistory>,
+    /// Diff of the base tree against the head tree
+    files: Vec<CommitDiff>,
+    file_stats: Vec<FileStat>,
+}
+
+#[derive(Serialize)]
+pub struct FileStat {
+    file: String,
+    insertions: usize,
+    deletions: usize,
+}
+
+/// The net change of a range as one entry, for release notes: what `git diff base head`
+/// shows, with the range's commits listed but not diffed.
+#[derive(Serialize)]
+pub struct SquashedRange {
+    base: String,
+    head: String,
+    merge_base: Option<String>,
+    /// Commits reachable from head but not from base, newest first, with empty
+    /// `git_diff`s
+    commits: Vec<CommitHistory>,
+    /// Diff of the base tree against the head tree, with renames detected across the
+    /// whole range. Files changed and changed back within it don't appear
+    files: Vec<CommitDiff>,
+    stats: CommitStats,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    rename_detection_skipped: bool,
+}
+
+/// Equivalent of a GitHub compare view between two refs.
+pub fn compare(repo_path: &Path, base: &str, head: &str) -> Result<Comparison, CustomError> {
+    let repo = Repository::open(repo_path)?;
+    let base_commit = resolve_commit(&repo, base)?;
+    let head_commit = resolve_commit(&repo, head)?;
+
+    let merge_base = repo.merge_base(base_commit.id(), head_commit.id()).ok();
+    let (ahead_by, behind_by) = repo.graph_ahead_behind(head_commit.id(), base_commit.id())?;
+
+    let commits = range_commits(&repo, base_commit.id(), head_commit.id(), |commit| {
+        commit_entry(&repo, commit)
+    })?;
+
+    let diff = repo.diff_tree_to_tree(
+        Some(&base_commit.tree()?),
+        Some(&head_commit.tree()?),
+        Some(&mut DiffOptions::new()),
+    )?;
+    let mut file_stats = Vec::new();
+    for idx in 0..diff.deltas().len() {
+        let Some(patch) = Patch::from_diff(&diff, idx)? else {
+            continue;
+        };
+        let delta = patch.delta();
+        let (_, insertions, deletions) = patch.line_stats()?;
+        file_stats.push(FileStat {
+            file: delta
+                .new_file()
+                .path()
+                .or(delta.old_file().path())
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            insertions,
+            deletions,
+        });
+    }
+
+    Ok(Comparison {
+        base: base_commit.id().to_string(),
+        head: head_commit.id().to_string(),
+        merge_base: merge_base.map(|oid| oid.to_string()),
+        ahead_by,
+        behind_by,
+        commits,
+        files: diff_entries(&diff, PatchFormat::default())?,
+        file_stats,
+    })
+}
+
+/// `compare --squash`: the range `base..head` collapsed into a single diff.
+pub fn squash(repo_path: &Path, base: &str, head: &str) -> Result<SquashedRange, CustomError> {
+    let repo = Repository::open(repo_path)?;
+    let base_commit = resolve_commit(&repo, base)?;
+    let head_commit = resolve_commit(&repo, head)?;
+    let merge_base = repo.merge_base(base_commit.id(), head_commit.id()).ok();
+    let commits = range_commits(&repo, base_commit.id(), head_commit.id(), |commit| {
+        commit_summary(&repo, commit, None)
+    })?;
+
+    let mut diff = repo.diff_tree_to_tree(
+        Some(&base_commit.tree()?),
+        Some(&head_commit.tree()?),
+        Some(&mut DiffOptions::new()),
+    )?;
+    // Renames at git's defaults, so a file moved by one commit and edited by another
+    // shows up as one rename
+    let rename_detection_skipped = !find_renames(&mut diff, &IndexOptions::default())?;
+    let files = diff_entries(&diff, PatchFormat::default())?;
+    let stats = CommitStats {
+        files_changed: files.len(),
+        insertions: files.iter().filter_map(|file| file.additions).sum(),
+        deletions: files.iter().filter_map(|file| file.deletions).sum(),
+    };
+
+    Ok(SquashedRange {
+        base: base_commit.id().to_string(),
+        head: head_commit.id().to_string(),
+        merge_base: merge_base.map(|oid| oid.to_string()),
+        commits,
+        files,
+        stats,
+        rename_detection_skipped,
+    })
+}
+
+impl Comparison {
+    pub fn redact(&mut self, redactor: &Redactor) {
+        self.commits
+            .iter_mut()
+            .for_each(|commit| redactor.commit(commit));
+        self.files.iter_mut().for_each(|file| redactor.diff(file));
+    }
+}
+
+impl SquashedRange {
+    pub fn redact(&mut self, redactor: &Redactor) {
+        self.commits
+            .iter_mut()
+            .for_each(|commit| redactor.commit(commit));
+        self.files.iter_mut().for_each(|file| redactor.diff(file));
+    }
+}
+
+/// The commits of `base..head`, newest first, each turned into an entry by `entry`.
+fn range_commits<F>(
+    repo: &Repository,
+    base: Oid,
+    head: Oid,
+    mut entry: F,
+) -> Result<Vec<CommitHistory>, CustomError>
+where
+    F: FnMut(&git2::Commit) -> Result<CommitHistory, CustomError>,
+{
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(head)?;
+    revwalk.hide(base)?;
+    let mut commits = Vec::new();
+    for commit_id in revwalk {
+        commits.push(entry(&repo.find_commit(commit_id?)?)?);
+    }
+    Ok(commits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_repo::TestRepo;
+
+    /// `main` and `feature`, each one commit past their merge base.
+    fn diverged() -> (TestRepo, String) {
+        let repo = TestRepo::new();
+        let fork = repo.write("a.txt", "a\n").commit("Add a");
+        repo.git(["checkout", "-q", "-b", "feature"]);
+        repo.write("b.txt", "b\n").commit("Add b");
+        repo.git(["checkout", "-q", "main"]);
+        repo.write("a.txt", "a\nmore a\n").commit("Extend a");
+        (repo, fork)
+    }
+
+    #[test]
+    fn compares_diverged_branches() {
+        let (repo, fork) = diverged();
+        let comparison = compare(repo.path(), "main", "feature").unwrap();
+        assert_eq!(comparison.merge_base, Some(fork));
+        assert_eq!((comparison.ahead_by, comparison.behind_by), (1, 1));
+        assert_eq!(comparison.commits.len(), 1);
+        assert_eq!(comparison.commits[0].commit_message, "Add b\n");
+        // The trees differ in both files, whichever side changed them
+        let stats: Vec<_> = comparison
+            .file_stats
+            .iter()
+            .map(|stat| (stat.file.as_str(), stat.insertions, stat.deletions))
+            .collect();
+        assert_eq!(stats, [("a.txt", 0, 1), ("b.txt", 1, 0)]);
+    }
+
+    #[test]
+    fn identical_refs_compare_empty() {
+        let (repo, _) = diverged();
+        let comparison = compare(repo.path(), "main", "main").unwrap();
+        assert_eq!((comparison.ahead_by, comparison.behind_by), (0, 0));
+        assert!(comparison.commits.is_empty());
+        assert!(comparison.files.is_empty());
+        assert!(comparison.file_stats.is_empty());
+    }
+
+    #[test]
+    fn unresolvable_refs_are_reported_as_such() {
+        let (repo, _) = diverged();
+        let err = compare(repo.path(), "v9.9", "main").err().unwrap();
+        assert!(matches!(err, CustomError::RefNotFound(_)));
+        assert_eq!(err.http_status(), hyper::StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    /// `v1.2` and `v1.3` with an edit, an addition, a deletion, a rename spread over two
+    /// commits and a file changed and changed back in between.
+    fn release_range() -> TestRepo {
+        let repo = TestRepo::new();
+        repo.write("app.rs", "fn main() {\n    run();\n}\n")
+            .write("notes.txt", "one\ntwo\nthree\nfour\nfive\nsix\n")
+            .write("old.txt", "gone soon\n")
+            .write("config.toml", "debug = false\n")
+            .commit("Release 1.2");
+        repo.git(["tag", "v1.2"]);
+        repo.write("app.rs", "fn main() {\n    setup();\n    run();\n}\n")
+            .write("config.toml", "debug = true\n")
+            .commit("Set up first, debug on");
+        repo.rename("notes.txt", "docs.txt")
+            .commit("Rename the notes");
+        repo.write("docs.txt", "one\ntwo\nthree\nfour\nfive\nsix\nseven\n")
+            .write("new.txt", "new\nfile\n")
+            .remove("old.txt")
+            .commit("Extend the docs, add new, drop old");
+        repo.write("config.toml", "debug = false\n")
+            .commit("Debug off again");
+        repo.git(["tag", "v1.3"]);
+        repo
+    }
+
+    #[test]
+    fn squashed_totals_match_git_diff_stat() {
+        let repo = release_range();
+        let squashed = squash(repo.path(), "v1.2", "v1.3").unwrap();
+        assert_eq!(squashed.commits.len(), 4);
+
+        let mut files: Vec<_> = squashed
+            .files
+            .iter()
+            .map(|file| {
+                let path = match &file.old_file {
+                    Some(old_file) => format!("{} => {}", old_file, file.file),
+                    None => file.file.clone(),
+                };
+                format!(
+                    "{}\t{}\t{}",
+                    file.additions.unwrap(),
+                    file.deletions.unwrap(),
+                    path
+                )
+            })
+            .collect();
+        files.sort();
+        let mut numstat: Vec<_> = repo
+            .git(["diff", "--numstat", "-M", "v1.2", "v1.3"])
+            .lines()
+            .map(str::to_string)
+            .collect();
+        numstat.sort();
+        assert_eq!(files, numstat);
+        // The file changed and changed back within the range isn't listed at all
+        assert!(!files.iter().any(|line| line.ends_with("config.toml")));
+
+        // ` 4 files changed, 4 insertions(+), 1 deletion(-)`
+        let shortstat: Vec<usize> = repo
+            .git(["diff", "--shortstat", "-M", "v1.2", "v1.3"])
+            .split(',')
+            .map(|part| part.trim().split(' ').next().unwrap().parse().unwrap())
+            .collect();
+        let stats = &squashed.stats;
+        assert_eq!(
+            shortstat,
+            [stats.files_changed, stats.insertions, stats.deletions]
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::HeaderValue;
+use hyper::{Body, HeaderMap, Response, StatusCode};
+use std::io::Write;
+
+// Bodies smaller than this gain nothing from compression
+const MIN_COMPRESS_BYTES: u64 = 1024;
+// Input is fed to the encoder in slices of this size so a large body is never held
+// twice in memory (once plain, once compressed)
+const SLICE_BYTES: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContentEncoding {
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    fn name(self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+        }
+    }
+
+    /// Picks the preferred encoding from an `Accept-Encoding` header. A coding listed
+    /// by name takes precedence over `*`, and a quality of 0 refuses it; gzip wins
+    /// ties.
+    pub fn negotiate(headers: &HeaderMap) -> Option<ContentEncoding> {
+        let accept = headers.get("Accept-Encoding")?.to_str().ok()?;
+        let entries: Vec<(&str, f32)> = accept
+            .split(',')
+            .map(|entry| {
+                let mut parts = entry.split(';').map(str::trim);
+                let coding = parts.next().unwrap_or("");
+                let quality = parts
+                    .find_map(|p| p.strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (coding, quality)
+            })
+            .collect();
+        let quality = |name: &str| {
+            let named = entries
+                .iter()
+                .find(|(coding, _)| coding.eq_ignore_ascii_case(name));
+            named
+                .or_else(|| entries.iter().find(|(coding, _)| *coding == "*"))
+                .map_or(0.0, |(_, quality)| *quality)
+        };
+
+        let (gzip, deflate) = (quality("gzip"), quality("deflate"));
+        if gzip > 0.0 && gzip >= deflate {
+            Some(ContentEncoding::Gzip)
+        } else if deflate > 0.0 {
+            Some(ContentEncoding::Deflate)
+        } else {
+            None
+        }
+    }
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: ContentEncoding) -> Self {
+        match encoding {
+            ContentEncoding::Gzip => {
+                Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::default()))
+            }
+            ContentEncoding::Deflate => {
+                Encoder::Deflate(ZlibEncoder::new(Vec::new(), Compression::default()))
+            }
+        }
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::Gzip(e) => e,
+            Encoder::Deflate(e) => e,
+        }
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        match self {
+            Encoder::Gzip(e) => std::mem::take(e.get_mut()),
+            Encoder::Deflate(e) => std::mem::take(e.get_mut()),
+        }
+    }
+
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Deflate(e) => e.finish(),
+        }
+    }
+}
+
+/// Compresses the response body in place when the client accepts it and the body is
+/// worth compressing.
+pub fn compress_response(encoding: Option<ContentEncoding>, response: &mut Response<Body>) {
+    let Some(encoding) = encoding else { return };
+    let status = response.status();
+    if status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || response.headers().contains_key("Content-Encoding")
+    {
+        return;
+    }
+    if let Some(len) = response.body().size_hint().exact() {
+        if len < MIN_COMPRESS_BYTES {
+            return;
+        }
+    }
+
+    let body = std::mem::take(response.body_mut());
+    *response.body_mut() = compress_body(body, encoding);
+
+    let headers = response.headers_mut();
+    headers.remove("Content-Length");
+    headers.insert(
+        "Content-Encoding",
+        HeaderValue::from_static(encoding.name()),
+    );
+    headers.append("Vary", HeaderValue::from_static("Accept-Encoding"));
+}
+
+/// Streams `body` through the encoder. Every incoming chunk ends with a sync flush, so
+/// a streamed body (one record per chunk) still reaches the client incrementally.
+fn compress_body(mut body: Body, encoding: ContentEncoding) -> Body {
+    let (mut sender, compressed) = Body::channel();
+
+    tokio::spawn(async move {
+        let mut encoder = Encoder::new(encoding);
+
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(_) => return sender.abort(),
+            };
+            for slice in chunk.chunks(SLICE_BYTES) {
+                if encoder.writer().write_all(slice).is_err() {
+                    return sender.abort();
+                }
+                let output = encoder.take_output();
+                if !output.is_empty() && sender.send_data(Bytes::from(output)).await.is_err() {
+                    return;
+                }
+            }
+            if encoder.writer().flush().is_err() {
+                return sender.abort();
+            }
+            let output = encoder.take_output();
+            if !output.is_empty() && sender.send_data(Bytes::from(output)).await.is_err() {
+                return;
+            }
+        }
+
+        match encoder.finish() {
+            Ok(trailer) => {
+                let _ = sender.send_data(Bytes::from(trailer)).await;
+            }
+            Err(_) => sender.abort(),
+        }
+    });
+
+    compressed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzDecoder;
+    use std::time::Duration;
+
+    fn negotiate(accept: &str) -> Option<ContentEncoding> {
+        let mut headers = HeaderMap::new();
+        headers.insert("Accept-Encoding", HeaderValue::from_str(accept).unwrap());
+        ContentEncoding::negotiate(&headers)
+    }
+
+    #[test]
+    fn negotiates_listed_codings() {
+        assert_eq!(negotiate("gzip"), Some(ContentEncoding::Gzip));
+        assert_eq!(negotiate("deflate"), Some(ContentEncoding::Deflate));
+        assert_eq!(negotiate("deflate, gzip"), Some(ContentEncoding::Gzip));
+        assert_eq!(
+            negotiate("gzip;q=0.5, deflate"),
+            Some(ContentEncoding::Deflate)
+        );
+        assert_eq!(negotiate("br"), None);
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(ContentEncoding::negotiate(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn zero_quality_refuses_a_coding() {
+        assert_eq!(negotiate("gzip;q=0"), None);
+        assert_eq!(
+            negotiate("gzip;q=0, deflate"),
+            Some(ContentEncoding::Deflate)
+        );
+        assert_eq!(negotiate("gzip; q=0.0, deflate;q=0"), None);
+    }
+
+    #[test]
+    fn named_coding_takes_precedence_over_wildcard() {
+        assert_eq!(negotiate("*"), Some(ContentEncoding::Gzip));
+        assert_eq!(negotiate("gzip;q=0, *"), Some(ContentEncoding::Deflate));
+        assert_eq!(negotiate("*, gzip;q=0, deflate;q=0"), None);
+        assert_eq!(negotiate("*;q=0, deflate"), Some(ContentEncoding::Deflate));
+        assert_eq!(negotiate("*;q=0"), None);
+    }
+
+    fn gunzip(compressed: &[u8]) -> String {
+        let mut decoder = GzDecoder::new(Vec::new());
+        decoder.write_all(compressed).unwrap();
+        String::from_utf8(decoder.finish().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn compresses_large_bodies_only() {
+        let large = "x".repeat(MIN_COMPRESS_BYTES as usize * 4);
+        let mut response = Response::new(Body::from(large.clone()));
+        compress_response(Some(ContentEncoding::Gzip), &mut response);
+        assert_eq!(response.headers()["Content-Encoding"], "gzip");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(gunzip(&body), large);
+
+        let mut response = Response::new(Body::from("small"));
+        compress_response(Some(ContentEncoding::Gzip), &mut response);
+        assert!(!response.headers().contains_key("Content-Encoding"));
+    }
+
+    #[tokio::test]
+    async fn streamed_records_are_flushed_one_by_one() {
+        let (mut sender, body) = Body::channel();
+        let mut response = Response::new(body);
+        compress_response(Some(ContentEncoding::Gzip), &mut response);
+        let mut compressed = response.into_body();
+
+        // Each record must be readable before the next one is even sent
+        let mut received = Vec::new();
+        for record in ["{\"commit\":1}\n", "{\"commit\":2}\n"] {
+            sender.send_data(Bytes::from(record)).await.unwrap();
+            loop {
+                let chunk = tokio::time::timeout(Duration::from_secs(5), compressed.data())
+                    .await
+                    .expect("the record should be flushed")
+                    .unwrap()
+                    .unwrap();
+                received.extend(chunk);
+                let mut decoder = GzDecoder::new(Vec::new());
+                decoder.write_all(&received).unwrap();
+                decoder.flush().unwrap();
+                if String::from_utf8_lossy(decoder.get_ref()).ends_with(record) {
+                    break;
+                }
+            }
+        }
+        drop(sender);
+        while let Some(chunk) = compressed.data().await {
+            received.extend(chunk.unwrap());
+        }
+        assert_eq!(gunzip(&received), "{\"commit\":1}\n{\"commit\":2}\n");
+    }
+}
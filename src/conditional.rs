@@ -0,0 +1,148 @@
+use crate::options::IndexOptions;
+use crate::{clone, walk_starts, RepoSource, FORMAT_VERSION};
+use chrono::{DateTime, Utc};
+use git2::Repository;
+use hyper::{Body, HeaderMap, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Histories whose `Last-Modified` the server keeps track of. Past it, they are all
+/// forgotten and answered as new.
+const MAX_TRACKED: usize = 10_000;
+
+/// The commit a history would be indexed from.
+#[derive(Clone)]
+pub struct Tip {
+    pub id: String,
+}
+
+/// When the server first answered each request with its current history, which is
+/// what `Last-Modified` and `If-Modified-Since` go by. Commit dates can't be used:
+/// they go backwards when a branch is reset or force-pushed to an older commit.
+#[derive(Default)]
+pub struct LastModified(Mutex<HashMap<String, (String, i64)>>);
+
+impl LastModified {
+    /// The `Last-Modified` time of `etag`, the validator of the history `body` asks
+    /// for: when it became current, which is now if the last one seen differs. A
+    /// change within the second of the previous one still moves the time forward.
+    pub fn observe(&self, body: &serde_json::Value, etag: &str) -> i64 {
+        // Hashed, as bodies can carry tokens
+        let key = format!("{:x}", Sha256::digest(body.to_string().as_bytes()));
+        let now = Utc::now().timestamp();
+        let mut seen = self.0.lock().unwrap();
+        match seen.get(&key) {
+            Some((current, since)) if current == etag => *since,
+            previous => {
+                let since = previous.map_or(now, |(_, since)| now.max(since + 1));
+                if seen.len() >= MAX_TRACKED {
+                    seen.clear();
+                }
+                seen.insert(key, (etag.to_string(), since));
+                since
+            }
+        }
+    }
+}
+
+/// Resolves the tip the history of `source` would be indexed from with `options`,
+/// without cloning it: `git ls-remote` for URLs, `walk_tip` for repositories on disk.
+/// `None` when it can't be resolved, e.g. for empty repositories, and for URLs with
+/// `all`, `range` or `rev`, whose commits `ls-remote` can't tell; such requests are
+/// simply indexed.
+pub async fn resolve_tip(
+    source: &RepoSource,
+    options: &IndexOptions,
+    settings: &clone::CloneSettings,
+) -> Option<Tip> {
+    match source {
+        RepoSource::Url(repo_url) => {
+            if options.all || options.range.is_some() || options.rev.is_some() {
+                return None;
+            }
+            let id = clone::remote_head(repo_url, settings).await?;
+            Some(Tip { id })
+        }
+        RepoSource::Local(repo_path) => {
+            let repo_path = repo_path.clone();
+            let options = options.clone();
+            tokio::task::spawn_blocking(move || {
+                walk_tip(&Repository::open(repo_path).ok()?, &options)
+            })
+            .await
+            .ok()
+            .flatten()
+        }
+    }
+}
+
+/// The tip of a walk with `options`: the commit it starts from or, for `all` and
+/// `range`, every ref and range end it takes in, so moving any of them changes the
+/// ETag. `None` when there is nothing to walk from.
+pub fn walk_tip(repo: &Repository, options: &IndexOptions) -> Option<Tip> {
+    let (push, hide) = walk_starts(repo, options).ok()?;
+    if push.is_empty() {
+        return None;
+    }
+    let ids = push
+        .iter()
+        .map(|id| id.to_string())
+        .chain(hide.iter().map(|id| format!("^{}", id)));
+    Some(Tip {
+        id: ids.collect::<Vec<_>>().join(" "),
+    })
+}
+
+/// A weak ETag for the history of `tip` indexed with the options in `body`: the same
+/// history is sent gzipped, deflated or as is under it, which are different bytes. The
+/// output format version is part of it, so upgrades don't serve stale validators.
+pub fn etag(tip: &str, body: &serde_json::Value) -> String {
+    let mut hasher = Sha256::new();
+    // serde_json keeps object keys sorted, so equal options hash the same
+    for part in [FORMAT_VERSION, tip, &body.to_string()] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    let digest = hasher.finalize();
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("W/\"{}\"", hex)
+}
+
+/// Whether the client's copy is current: `If-None-Match` lists `etag` (or `*`), by
+/// weak comparison, or, without `If-None-Match`, the history has been current since
+/// `If-Modified-Since` at least, `time` being its `LastModified`.
+pub fn is_not_modified(headers: &HeaderMap, etag: &str, time: i64) -> bool {
+    if let Some(value) = headers.get("If-None-Match") {
+        let value = value.to_str().unwrap_or_default();
+        let weak = |tag: &str| tag.strip_prefix("W/").unwrap_or(tag).to_string();
+        return value
+            .split(',')
+            .map(str::trim)
+            .any(|candidate| candidate == "*" || weak(candidate) == weak(etag));
+    }
+    let since = headers
+        .get("If-Modified-Since")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok());
+    since.is_some_and(|since| time <= since.timestamp())
+}
+
+/// `304` carrying the validators again, as HTTP requires.
+pub fn not_modified(etag: &str, time: i64) -> Response<Body> {
+    let mut response = Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .body(Body::empty())
+        .unwrap();
+    add_validators(&mut response, etag, time);
+    response
+}
+
+pub fn add_validators(response: &mut Response<Body>, etag: &str, time: i64) {
+    let headers = response.headers_mut();
+    headers.insert("ETag", etag.parse().unwrap());
+    if let Some(date) = DateTime::from_timestamp(time, 0) {
+        let date = date.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        headers.insert("Last-Modified", date.parse().unwrap());
+    }
+}
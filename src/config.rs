@@ -0,0 +1,697 @@
+use crate::lint::LintOptions;
+use crate::options::{BoundaryDiff, IndexOptions, OutputFormat, PatchFormat};
+use crate::{clone, OverloadPolicy, ServerConfig};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Config file picked up from the working directory when `--config` isn't given.
+pub const DEFAULT_PATH: &str = "git-history.toml";
+
+/// Prefix of the environment variables that stand for options, e.g.
+/// `GIT_HISTORY_MAX_CONCURRENT_JOBS=4` means `--max-concurrent-jobs 4`.
+const ENV_PREFIX: &str = "GIT_HISTORY_";
+
+/// Options that may be given several times. Their environment variables take a
+/// comma-separated list.
+const REPEATABLE: &[&str] = &[
+    "--path",
+    "--exclude",
+    "--api-key",
+    "--cors-origin",
+    "--allow-local-paths",
+    "--git-config",
+    "--allowed-host",
+];
+
+/// Keys and variables named differently from their flag: `ref` reads better than
+/// `rev` in a file, and `allowed_hosts` and `cache_dir` are what deployments expect.
+const RENAMED_KEYS: &[(&str, &str)] = &[
+    ("ref", "--rev"),
+    ("allowed_hosts", "--allowed-host"),
+    ("cache_dir", "--work-dir"),
+];
+
+/// Flags of `index` itself rather than of every history subcommand. Their `[index]`
+/// settings and variables only reach `index`, since `graph` and `hotspots` have a
+/// `--format` of their own.
+const INDEX_COMMAND_FLAGS: &[&str] = &["--format", "--range"];
+
+/// Settings from `git-history.toml` and `GIT_HISTORY_*` environment variables, stored
+/// as the command-line flags they stand for. Keys are flag names with underscores,
+/// e.g. `max_concurrent_jobs = 4` under `[server]` means `--max-concurrent-jobs 4`.
+#[derive(Debug, Default)]
+pub struct Config {
+    /// Where the settings came from, if a file was found
+    pub path: Option<PathBuf>,
+    index: Vec<String>,
+    server: Vec<String>,
+    lint: Vec<String>,
+    env_index: Vec<String>,
+    env_server: Vec<String>,
+    env_lint: Vec<String>,
+}
+
+type Section = (&'static str, fn(&str, &[String]) -> Result<bool, String>);
+
+const INDEX: Section = ("index", |flag, values| {
+    apply_all(values, |value| match flag {
+        "--format" => OutputFormat::parse(&value()?).map(|_| true),
+        "--range" => value().map(|_| true),
+        _ => IndexOptions::default().apply_flag(flag, value),
+    })
+});
+const SERVER: Section = ("server", |flag, values| {
+    apply_all(values, |value| {
+        ServerConfig::default().apply_flag(flag, value)
+    })
+});
+const LINT: Section = ("lint", |flag, values| {
+    apply_all(values, |value| {
+        LintOptions::default().apply_flag(flag, value)
+    })
+});
+
+impl Config {
+    /// Loads `explicit`, or `git-history.toml` in the working directory if it exists,
+    /// and the environment. Unknown keys and variables are reported on stderr and
+    /// skipped; bad values are errors.
+    pub fn load(explicit: Option<&Path>) -> Result<Config, String> {
+        let mut config = match explicit {
+            Some(path) => Config::from_file(path)?,
+            None if Path::new(DEFAULT_PATH).is_file() => {
+                Config::from_file(Path::new(DEFAULT_PATH))?
+            }
+            None => Config::default(),
+        };
+        [config.env_index, config.env_server, config.env_lint] = env_args()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Config, String> {
+        let path = path.to_path_buf();
+        let raw = fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let mut table: toml::Table = raw
+            .parse()
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+
+        let mut config = Config {
+            index: section_args(&path, &mut table, INDEX)?,
+            server: section_args(&path, &mut table, SERVER)?,
+            lint: section_args(&path, &mut table, LINT)?,
+            ..Config::default()
+        };
+        for key in table.keys() {
+            eprintln!(
+                "Warning: {}: unknown section or key '{}', ignoring it",
+                path.display(),
+                key
+            );
+        }
+        config.path = Some(path);
+        Ok(config)
+    }
+
+    /// `args` for a history subcommand, preceded by the `[index]` settings they don't
+    /// override. The environment overrides the file.
+    pub fn index_args(&self, args: &[String]) -> Vec<String> {
+        let settings = merge(&self.index, &self.env_index);
+        merge(&without(&settings, INDEX_COMMAND_FLAGS), args)
+    }
+
+    /// Like `index_args`, for `index` itself, which also takes `format` and `range`.
+    pub fn index_command_args(&self, args: &[String]) -> Vec<String> {
+        merge(&merge(&self.index, &self.env_index), args)
+    }
+
+    /// `args` for `server`, preceded by the `[server]` settings they don't override.
+    /// The environment overrides the file.
+    pub fn server_args(&self, args: &[String]) -> Vec<String> {
+        merge(&merge(&self.server, &self.env_server), args)
+    }
+
+    /// `args` for `lint-messages`, preceded by the `[lint]` settings they don't
+    /// override. The environment overrides the file.
+    pub fn lint_args(&self, args: &[String]) -> Vec<String> {
+        merge(&merge(&self.lint, &self.env_lint), args)
+    }
+}
+
+/// Removes `--config <path>` from the arguments, wherever it appears.
+pub fn take_config_flag(args: &mut Vec<String>) -> Result<Option<PathBuf>, String> {
+    let Some(idx) = args.iter().position(|arg| arg == "--config") else {
+        return Ok(None);
+    };
+    if idx + 1 >= args.len() {
+        return Err("Missing value for --config".to_string());
+    }
+    let path = args.remove(idx + 1);
+    args.remove(idx);
+    Ok(Some(PathBuf::from(path)))
+}
+
+/// Turns one `[section]` table into flags, checking each key against the section's
+/// parser.
+fn section_args(
+    path: &Path,
+    table: &mut toml::Table,
+    (name, check): Section,
+) -> Result<Vec<String>, String> {
+    let Some(section) = table.remove(name) else {
+        return Ok(Vec::new());
+    };
+    let toml::Value::Table(section) = section else {
+        return Err(format!("{}: [{}] must be a table", path.display(), name));
+    };
+
+    let mut args = Vec::new();
+    for (key, value) in section {
+        let flag = key_flag(&key);
+        let invalid =
+            |reason: String| format!("{}: [{}] {}: {}", path.display(), name, key, reason);
+
+        let values = match value {
+            toml::Value::Array(items) => items
+                .into_iter()
+                .map(scalar)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(invalid)?,
+            value => vec![scalar(value).map_err(invalid)?],
+        };
+        for value in values {
+            let tokens: Vec<String> = match &value {
+                Setting::Value(value) => vec![value.clone()],
+                Setting::On | Setting::Off => Vec::new(),
+            };
+            // `false` is still checked, so a misspelled key is reported either way
+            if !check(&flag, &tokens).map_err(invalid)? {
+                eprintln!(
+                    "Warning: {}: unknown key '{}' in [{}], ignoring it",
+                    path.display(),
+                    key,
+                    name
+                );
+                break;
+            }
+            if !matches!(value, Setting::Off) {
+                args.push(flag.clone());
+                args.extend(tokens);
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// The flag a config key or lowercased variable name stands for.
+fn key_flag(key: &str) -> String {
+    match RENAMED_KEYS.iter().find(|(renamed, _)| *renamed == key) {
+        Some((_, flag)) => flag.to_string(),
+        None => format!("--{}", key.replace('_', "-")),
+    }
+}
+
+/// Flags for the `GIT_HISTORY_*` variables, split into index, server and lint options.
+fn env_args() -> Result<[Vec<String>; 3], String> {
+    let mut vars: Vec<(String, String)> = std::env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect();
+    vars.sort();
+
+    let mut sections = [INDEX, SERVER, LINT].map(|section| (section, Vec::new()));
+    for (name, raw) in vars {
+        let flag = key_flag(&name[ENV_PREFIX.len()..].to_lowercase());
+        // Parsers report unknown flags before asking for a value, so a dry run without
+        // one tells switches (accepted), options (missing value) and unknown flags apart
+        let found = sections.iter_mut().find_map(|((_, check), args)| {
+            let probe = check(&flag, &[]);
+            (probe != Ok(false)).then_some((*check, probe, args))
+        });
+        let Some((check, probe, args)) = found else {
+            eprintln!(
+                "Warning: unknown environment variable {}, ignoring it",
+                name
+            );
+            continue;
+        };
+
+        if probe.is_ok() {
+            let on = match raw.to_lowercase().as_str() {
+                "true" | "1" | "yes" => true,
+                "false" | "0" | "no" | "" => false,
+                _ => return Err(format!("{}: expected true or false, got '{}'", name, raw)),
+            };
+            if on {
+                args.push(flag);
+            }
+            continue;
+        }
+
+        let values: Vec<String> = if REPEATABLE.contains(&flag.as_str()) {
+            raw.split(',')
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(String::from)
+                .collect()
+        } else {
+            vec![raw]
+        };
+        for value in values {
+            check(&flag, std::slice::from_ref(&value)).map_err(|e| format!("{}: {}", name, e))?;
+            args.push(flag.clone());
+            args.push(value);
+        }
+    }
+    Ok(sections.map(|(_, args)| args))
+}
+
+/// What a TOML value means for its flag.
+enum Setting {
+    /// `true`: pass the flag on its own
+    On,
+    /// `false`: leave the flag out, which is every boolean flag's default
+    Off,
+    /// Pass the flag with this value
+    Value(String),
+}
+
+fn scalar(value: toml::Value) -> Result<Setting, String> {
+    match value {
+        toml::Value::String(value) => Ok(Setting::Value(value)),
+        toml::Value::Integer(value) => Ok(Setting::Value(value.to_string())),
+        toml::Value::Float(value) => Ok(Setting::Value(value.to_string())),
+        toml::Value::Datetime(value) => Ok(Setting::Value(value.to_string())),
+        toml::Value::Boolean(true) => Ok(Setting::On),
+        toml::Value::Boolean(false) => Ok(Setting::Off),
+        _ => Err("expected a string, number, date or boolean".to_string()),
+    }
+}
+
+/// Runs `apply` with `values` as the flag's value and checks that it used exactly
+/// those, so a boolean isn't given where a value is needed or the other way round.
+fn apply_all<F>(values: &[String], apply: F) -> Result<bool, String>
+where
+    F: FnOnce(&mut dyn FnMut() -> Result<String, String>) -> Result<bool, String>,
+{
+    let mut remaining = values.iter();
+    let mut value = || {
+        remaining
+            .next()
+            .cloned()
+            .ok_or_else(|| "expected a value, not a boolean".to_string())
+    };
+    let known = apply(&mut value)?;
+    if known && remaining.next().is_some() {
+        return Err("expected true or false".to_string());
+    }
+    Ok(known)
+}
+
+/// Settings from a lower layer come first and are dropped for every flag the higher
+/// layer sets itself, so repeatable flags are replaced rather than combined.
+fn merge(defaults: &[String], args: &[String]) -> Vec<String> {
+    let mut merged = Vec::new();
+    let mut defaults = defaults.iter().peekable();
+    while let Some(flag) = defaults.next() {
+        let mut group = vec![flag.clone()];
+        while let Some(value) = defaults.next_if(|arg| !arg.starts_with("--")) {
+            group.push(value.clone());
+        }
+        if !args.contains(flag) {
+            merged.extend(group);
+        }
+    }
+    merged.extend(args.iter().cloned());
+    merged
+}
+
+/// `args` without `flags` and their values.
+fn without(args: &[String], flags: &[&str]) -> Vec<String> {
+    let mut kept = Vec::new();
+    let mut skipping = false;
+    for arg in args {
+        if arg.starts_with("--") {
+            skipping = flags.contains(&arg.as_str());
+        }
+        if !skipping {
+            kept.push(arg.clone());
+        }
+    }
+    kept
+}
+
+/// The settings in effect once defaults, the config file, the environment and `args`
+/// are combined, as a config file. API keys, tokens and secrets are redacted.
+pub fn effective_toml(
+    index: &IndexOptions,
+    format: OutputFormat,
+    server: &ServerConfig,
+    lint: &LintOptions,
+) -> String {
+    let mut table = toml::Table::new();
+    let mut index = index_table(index);
+    index.insert("format".into(), format.as_str().into());
+    table.insert("index".into(), index.into());
+    table.insert("server".into(), server_table(server).into());
+    table.insert("lint".into(), lint_table(lint).into());
+    toml::to_string(&table).expect("config always serializes")
+}
+
+/// The server settings on one line, for the startup log. API keys and secrets are
+/// redacted.
+pub fn server_summary(server: &ServerConfig) -> String {
+    server_table(server)
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn index_table(index: &IndexOptions) -> toml::Table {
+    let mut index_table = toml::Table::new();
+    let date = |timestamp: i64| {
+        chrono::DateTime::from_timestamp(timestamp, 0)
+            .map(|date| date.to_rfc3339())
+            .unwrap_or_default()
+    };
+    if let Some(since) = index.since {
+        index_table.insert("since".into(), date(since).into());
+    }
+    if let Some(until) = index.until {
+        index_table.insert("until".into(), date(until).into());
+    }
+    index_table.insert("path".into(), index.paths.clone().into());
+    if let Some(author) = &index.author {
+        // An author filter names someone, which redaction promises to hide
+        let author = match index.redact {
+            Some(_) => "<redacted>".to_string(),
+            None => author.clone(),
+        };
+        index_table.insert("author".into(), author.into());
+    }
+    index_table.insert("all".into(), index.all.into());
+    if let Some(rev) = &index.rev {
+        index_table.insert("ref".into(), rev.as_str().into());
+    }
+    if let Some(range) = &index.range {
+        index_table.insert("range".into(), range.as_str().into());
+    }
+    index_table.insert("exclude".into(), index.exclude.clone().into());
+    index_table.insert("recurse_submodules".into(), index.recurse_submodules.into());
+    index_table.insert("include_generated".into(), index.include_generated.into());
+    if let Some(lines) = index.context_lines {
+        index_table.insert("context_lines".into(), i64::from(lines).into());
+    }
+    index_table.insert("ignore_whitespace".into(), index.ignore_whitespace.into());
+    index_table.insert(
+        "ignore_whitespace_change".into(),
+        index.ignore_whitespace_change.into(),
+    );
+    let format = match index.diff_format {
+        PatchFormat::Legacy => "legacy",
+        PatchFormat::Unified => "unified",
+    };
+    index_table.insert("diff_format".into(), format.into());
+    index_table.insert("no_diff".into(), index.no_diff.into());
+    index_table.insert("stats_only".into(), index.stats_only.into());
+    if let Some(bytes) = index.flag_large_additions {
+        index_table.insert("flag_large_additions".into(), (bytes as i64).into());
+    }
+    let boundary_diff = match index.boundary_diff {
+        BoundaryDiff::Full => "full",
+        BoundaryDiff::Empty => "empty",
+        BoundaryDiff::Omit => "omit",
+    };
+    index_table.insert("boundary_diff".into(), boundary_diff.into());
+    if let Some(percent) = index.rename_threshold {
+        index_table.insert("rename_threshold".into(), i64::from(percent).into());
+    }
+    if let Some(percent) = index.copy_threshold {
+        index_table.insert("copy_threshold".into(), i64::from(percent).into());
+    }
+    if index.finds_renames() {
+        index_table.insert("rename_limit".into(), (index.rename_limit() as i64).into());
+    }
+    if let Some(length) = index.sha_length {
+        index_table.insert("sha_length".into(), (length as i64).into());
+    }
+    if let Some(path) = &index.identity_map {
+        index_table.insert("identity_map".into(), path.display().to_string().into());
+    }
+    if let Some(mode) = index.redact {
+        index_table.insert("redact".into(), mode.as_str().into());
+    }
+    if index.redact_salt.is_some() {
+        index_table.insert("redact_salt".into(), "<redacted>".into());
+    }
+    if let Some(path) = &index.redaction_map {
+        index_table.insert("redaction_map".into(), path.display().to_string().into());
+    }
+    index_table.insert("envelope".into(), index.envelope.into());
+    index_table.insert("word_diff".into(), index.word_diff.into());
+    index_table.insert(
+        "word_diff_max_bytes".into(),
+        (index.word_diff_max_bytes() as i64).into(),
+    );
+    if let Some(bytes) = index.max_diff_bytes {
+        index_table.insert("max_diff_bytes".into(), (bytes as i64).into());
+    }
+    if let Some(provider) = &index.enrich {
+        index_table.insert("enrich".into(), provider.as_str().into());
+    }
+    if index.github_token.is_some() {
+        index_table.insert("github_token".into(), "<redacted>".into());
+    }
+    if let Some(url) = &index.gitlab_url {
+        index_table.insert("gitlab_url".into(), url.as_str().into());
+    }
+    if index.gitlab_token.is_some() {
+        index_table.insert("gitlab_token".into(), "<redacted>".into());
+    }
+    index_table
+}
+
+fn server_table(server: &ServerConfig) -> toml::Table {
+    let mut server_table = toml::Table::new();
+    let path = |path: &Path| path.display().to_string();
+    server_table.insert("bind".into(), server.bind.to_string().into());
+    server_table.insert(
+        "max_concurrent_jobs".into(),
+        (server.max_concurrent_jobs as i64).into(),
+    );
+    let policy = match server.overload_policy {
+        OverloadPolicy::Queue => "queue",
+        OverloadPolicy::Reject => "reject",
+    };
+    server_table.insert("overload_policy".into(), policy.into());
+    server_table.insert(
+        "queue_timeout".into(),
+        (server.queue_timeout.as_secs() as i64).into(),
+    );
+    let redacted: Vec<&str> = server.api_keys.iter().map(|_| "<redacted>").collect();
+    server_table.insert("api_key".into(), redacted.into());
+    if let Some(file) = &server.api_key_file {
+        server_table.insert("api_key_file".into(), path(file).into());
+    }
+    server_table.insert("cors_origin".into(), server.cors_origins.clone().into());
+    server_table.insert("no_compression".into(), (!server.compression).into());
+    if let Some(cert) = &server.tls_cert {
+        server_table.insert("tls_cert".into(), path(cert).into());
+    }
+    if let Some(key) = &server.tls_key {
+        server_table.insert("tls_key".into(), path(key).into());
+    }
+    let roots: Vec<String> = server.allow_local_paths.iter().map(|p| path(p)).collect();
+    server_table.insert("allow_local_paths".into(), roots.into());
+    server_table.insert("print_config".into(), server.print_config.into());
+    if server.webhook_secret.is_some() {
+        server_table.insert("webhook_secret".into(), "<redacted>".into());
+    }
+    server_table.insert(
+        "clone_retries".into(),
+        i64::from(server.clone.retries).into(),
+    );
+    server_table.insert(
+        "clone_timeout".into(),
+        (server.clone.timeout.as_secs() as i64).into(),
+    );
+    server_table.insert(
+        "allowed_host".into(),
+        server.clone.allowed_hosts.clone().into(),
+    );
+    if let Some(proxy) = &server.clone.http_proxy {
+        server_table.insert("http_proxy".into(), clone::sanitize_url(proxy).into());
+    }
+    let git_config: Vec<String> = server
+        .clone
+        .git_config
+        .iter()
+        .map(|entry| clone::sanitize_config_entry(entry))
+        .collect();
+    server_table.insert("git_config".into(), git_config.into());
+    let work_dir = &server.clone.work_dir;
+    server_table.insert("work_dir".into(), path(&work_dir.root()).into());
+    server_table.insert(
+        "work_dir_ttl".into(),
+        (work_dir.ttl.as_secs() as i64).into(),
+    );
+    if let Some(bytes) = work_dir.min_free_bytes {
+        server_table.insert("min_free_bytes".into(), (bytes as i64).into());
+    }
+    if let Some(bytes) = work_dir.max_clone_bytes {
+        server_table.insert("max_clone_bytes".into(), (bytes as i64).into());
+    }
+    server_table.insert(
+        "max_response_bytes".into(),
+        (server.max_response_bytes as i64).into(),
+    );
+    if let Some(addr) = server.grpc_bind {
+        server_table.insert("grpc_bind".into(), addr.to_string().into());
+    }
+    server_table
+}
+
+fn lint_table(lint: &LintOptions) -> toml::Table {
+    let mut lint_table = toml::Table::new();
+    for (rule, severity) in lint.rules() {
+        lint_table.insert(rule.name.replace('-', "_"), severity.name().into());
+    }
+    lint_table.insert(
+        "max_subject_length".into(),
+        (lint.max_subject_length as i64).into(),
+    );
+    if let Some(score) = lint.fail_under {
+        lint_table.insert("fail_under".into(), score.into());
+    }
+    lint_table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(contents: &str) -> Result<Config, String> {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join(DEFAULT_PATH);
+        fs::write(&path, contents).unwrap();
+        Config::from_file(&path)
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn index_section_takes_ref_format_and_range() {
+        let config = load(
+            "[index]\nref = \"release\"\nformat = \"ndjson\"\nrange = \"v1.0..HEAD\"\nall = true\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.index_command_args(&[]),
+            args(&[
+                "--all",
+                "--format",
+                "ndjson",
+                "--range",
+                "v1.0..HEAD",
+                "--rev",
+                "release"
+            ])
+        );
+        // Other history commands only get the settings they share with `index`
+        assert_eq!(config.index_args(&[]), args(&["--all", "--rev", "release"]));
+    }
+
+    #[test]
+    fn format_setting_leaves_other_commands_formats_alone() {
+        let config = load("[index]\nformat = \"ndjson\"\n").unwrap();
+        assert_eq!(
+            config.index_args(&args(&["repo", "--format", "table"])),
+            args(&["repo", "--format", "table"])
+        );
+        assert_eq!(
+            config.index_command_args(&args(&["repo", "--format", "json"])),
+            args(&["repo", "--format", "json"])
+        );
+    }
+
+    #[test]
+    fn index_settings_are_checked() {
+        let error = load("[index]\nformat = \"xml\"\n").unwrap_err();
+        assert!(error.ends_with("[index] format: expected json or ndjson, got 'xml'"));
+        let error = load("[index]\nref = true\n").unwrap_err();
+        assert!(error.ends_with("[index] ref: expected a value, not a boolean"));
+        // Unknown keys are warned about and dropped
+        let config = load("[index]\nbranch = \"main\"\nall = true\n").unwrap();
+        assert_eq!(config.index_command_args(&[]), args(&["--all"]));
+    }
+
+    #[test]
+    fn effective_config_shows_the_walked_refs() {
+        let mut index = IndexOptions::default();
+        index
+            .apply_flag("--rev", &mut || Ok("release".into()))
+            .unwrap();
+        index.range = Some("v1.0..HEAD".into());
+        let server = ServerConfig::default();
+        let printed = effective_toml(
+            &index,
+            OutputFormat::Ndjson,
+            &server,
+            &LintOptions::default(),
+        );
+        let table: toml::Table = printed.parse().unwrap();
+        assert_eq!(table["index"]["ref"].as_str(), Some("release"));
+        assert_eq!(table["index"]["range"].as_str(), Some("v1.0..HEAD"));
+        assert_eq!(table["index"]["format"].as_str(), Some("ndjson"));
+    }
+
+    #[test]
+    fn deployment_names_map_to_their_options() {
+        let config = load(
+            "[server]\ncache_dir = \"/var/cache/git-history\"\nallowed_hosts = [\"github.com\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.server_args(&[]),
+            args(&[
+                "--allowed-host",
+                "github.com",
+                "--work-dir",
+                "/var/cache/git-history"
+            ])
+        );
+
+        // The only test touching GIT_HISTORY_* variables, so none of them leak elsewhere
+        std::env::set_var("GIT_HISTORY_ALLOWED_HOSTS", "github.com, gitlab.com");
+        std::env::set_var("GIT_HISTORY_CACHE_DIR", "/tmp/clones");
+        std::env::set_var("GIT_HISTORY_MAX_DIFF_BYTES", "4096");
+        let [index, server, _] = env_args().unwrap();
+        std::env::set_var("GIT_HISTORY_MAX_DIFF_BYTES", "lots");
+        let error = env_args().unwrap_err();
+        for name in [
+            "GIT_HISTORY_ALLOWED_HOSTS",
+            "GIT_HISTORY_CACHE_DIR",
+            "GIT_HISTORY_MAX_DIFF_BYTES",
+        ] {
+            std::env::remove_var(name);
+        }
+        assert_eq!(index, args(&["--max-diff-bytes", "4096"]));
+        assert_eq!(
+            server,
+            args(&[
+                "--allowed-host",
+                "github.com",
+                "--allowed-host",
+                "gitlab.com",
+                "--work-dir",
+                "/tmp/clones"
+            ])
+        );
+        assert_eq!(
+            error,
+            "GIT_HISTORY_MAX_DIFF_BYTES: expected a non-negative integer, got 'lots'"
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use crate::identities::Identities;
+use crate::options::IndexOptions;
+use crate::{for_each_commit, format_git_time, CustomError};
+use git2::Repository;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+#[derive(Serialize)]
+pub struct Contributor {
+    name: String,
+    email: String,
+    commits: usize,
+    insertions: usize,
+    deletions: usize,
+    files_touched: usize,
+    first_commit_date: String,
+    last_commit_date: String,
+    /// Fraction of all matching commits authored by this contributor
+    commit_share: f64,
+}
+
+#[derive(Default)]
+struct Tally {
+    commits: usize,
+    insertions: usize,
+    deletions: usize,
+    files: HashSet<String>,
+    first: Option<git2::Time>,
+    last: Option<git2::Time>,
+}
+
+/// Per-author statistics over the filtered history, sorted by commit count. Authors are
+/// canonicalized through .mailmap and `--identity-map`. Only diff stats are computed,
+/// never patch text.
+pub fn contributors(
+    repo_path: &Path,
+    options: &IndexOptions,
+    top: Option<usize>,
+) -> Result<Vec<Contributor>, CustomError> {
+    let repo = Repository::open(repo_path)?;
+    let identities = Identities::load(&repo, options)?;
+
+    let mut tallies: HashMap<(String, String), Tally> = HashMap::new();
+    let mut total_commits = 0;
+
+    for_each_commit(&repo, options, |commit, diff, _| {
+        let author = identities.author(commit)?;
+        let key = (
+            String::from_utf8_lossy(author.name_bytes()).to_string(),
+            author.email().unwrap_or("").to_string(),
+        );
+        let stats = diff.stats()?;
+        let when = author.when();
+
+        let tally = tallies.entry(key).or_default();
+        tally.commits += 1;
+        tally.insertions += stats.insertions();
+        tally.deletions += stats.deletions();
+        tally.files.extend(
+            diff.deltas()
+                .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()))
+                .map(|path| path.to_string_lossy().to_string()),
+        );
+        if tally
+            .first
+            .is_none_or(|first| when.seconds() < first.seconds())
+        {
+            tally.first = Some(when);
+        }
+        if tally
+            .last
+            .is_none_or(|last| when.seconds() > last.seconds())
+        {
+            tally.last = Some(when);
+        }
+        total_commits += 1;
+        Ok(())
+    })?;
+
+    let mut contributors: Vec<Contributor> = tallies
+        .into_iter()
+        .map(|((name, email), tally)| Contributor {
+            name,
+            email,
+            commits: tally.commits,
+            insertions: tally.insertions,
+            deletions: tally.deletions,
+            files_touched: tally.files.len(),
+            first_commit_date: tally.first.map(format_git_time).unwrap_or_default(),
+            last_commit_date: tally.last.map(format_git_time).unwrap_or_default(),
+            commit_share: tally.commits as f64 / total_commits as f64,
+        })
+        .collect();
+
+    contributors.sort_by(|a, b| {
+        b.commits
+            .cmp(&a.commits)
+            .then_with(|| a.name.cmp(&b.name))
+            .then_with(|| a.email.cmp(&b.email))
+    });
+    if let Some(top) = top {
+        contributors.truncate(top);
+    }
+
+    Ok(contributors)
+}
@@ -0,0 +1,76 @@
+use hyper::header::HeaderValue;
+use hyper::{Body, Response, StatusCode};
+
+const ALLOWED_METHODS: &str = "GET, POST, OPTIONS";
+const ALLOWED_HEADERS: &str =
+    "Authorization, Content-Type, If-Modified-Since, If-None-Match, X-Api-Key";
+/// Response headers scripts may read besides the CORS-safelisted ones: the validators
+/// they send back for conditional requests
+const EXPOSED_HEADERS: &str = "ETag, Last-Modified";
+// Browsers clamp this anyway (Chromium to 2 hours), so there is no point going higher
+const MAX_AGE_SECS: u32 = 7200;
+
+/// Cross-origin settings for browser clients. Disabled when no origins are configured.
+///
+/// Credentials (`Access-Control-Allow-Credentials`) are never allowed: API keys travel
+/// in explicit headers, so browsers have no cookies to forward.
+pub struct Cors {
+    origins: Vec<String>,
+}
+
+impl Cors {
+    pub fn new(origins: Vec<String>) -> Self {
+        Cors { origins }
+    }
+
+    fn allows_any(&self) -> bool {
+        self.origins.iter().any(|o| o == "*")
+    }
+
+    fn allowed_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        if self.allows_any() {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let origin_str = origin.to_str().ok()?;
+        self.origins
+            .iter()
+            .any(|o| o == origin_str)
+            .then(|| origin.clone())
+    }
+
+    /// Answers an `OPTIONS` preflight request. The origin header itself is added by
+    /// [`Cors::apply`] like on any other response.
+    pub fn preflight(&self) -> Response<Body> {
+        let mut builder = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header("Allow", ALLOWED_METHODS);
+        if !self.origins.is_empty() {
+            builder = builder
+                .header("Access-Control-Allow-Methods", ALLOWED_METHODS)
+                .header("Access-Control-Allow-Headers", ALLOWED_HEADERS)
+                .header("Access-Control-Max-Age", MAX_AGE_SECS.to_string());
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    /// Adds `Access-Control-Allow-Origin` and `Access-Control-Expose-Headers` when the
+    /// request's origin is allowed.
+    pub fn apply(&self, origin: Option<&HeaderValue>, response: &mut Response<Body>) {
+        if self.origins.is_empty() {
+            return;
+        }
+
+        let headers = response.headers_mut();
+        if !self.allows_any() {
+            // The response differs per origin, so caches must key on it
+            headers.append("Vary", HeaderValue::from_static("Origin"));
+        }
+        if let Some(allowed) = origin.and_then(|o| self.allowed_origin(o)) {
+            headers.insert("Access-Control-Allow-Origin", allowed);
+            headers.insert(
+                "Access-Control-Expose-Headers",
+                HeaderValue::from_static(EXPOSED_HEADERS),
+            );
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use crate::github::GitHub;
+use crate::gitlab::GitLab;
+use crate::options::IndexOptions;
+use crate::redact::Redactor;
+use crate::CommitHistory;
+use reqwest::header::{HeaderMap, HeaderValue, USER_AGENT};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Attempts per lookup before giving up on server errors and rate limits
+const MAX_ATTEMPTS: u32 = 4;
+
+/// Longest rate-limit wait worth sitting through; beyond it the run goes on without
+/// enrichment
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+
+/// Pull request (or GitLab merge request) metadata attached to a commit.
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct PullRequest {
+    pub number: u64,
+    pub title: String,
+    pub body: Option<String>,
+    /// Login of the user who opened it
+    pub author: Option<String>,
+    pub labels: Vec<String>,
+    pub merged_at: Option<String>,
+    pub url: String,
+}
+
+/// Issue metadata attached to a commit.
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct Issue {
+    pub number: u64,
+    pub title: String,
+    pub body: Option<String>,
+    /// Login of the user who opened it
+    pub author: Option<String>,
+    pub labels: Vec<String>,
+    pub closed_at: Option<String>,
+    pub url: String,
+}
+
+/// Something a commit message points at on the hosting service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Reference {
+    PullRequest(u64),
+    Issue(u64),
+}
+
+#[derive(Clone, Debug)]
+pub enum Linked {
+    PullRequest(PullRequest),
+    Issue(Issue),
+}
+
+/// A hosting service that can describe the pull requests and issues a commit
+/// references. Providers only build requests and read responses; caching, retries
+/// and attaching the results to commits are shared.
+pub trait IssueProvider: Send + Sync {
+    /// Service name for warnings
+    fn name(&self) -> &'static str;
+
+    /// The references in `commit` worth looking up, at most one of each kind.
+    fn references(&self, commit: &CommitHistory) -> Vec<Reference>;
+
+    fn request(&self, reference: Reference) -> RequestBuilder;
+
+    /// Reads a successful response for `reference`.
+    fn parse(
+        &self,
+        reference: Reference,
+        body: serde_json::Value,
+    ) -> Result<Linked, serde_json::Error>;
+
+    /// How long a rate-limited response asks us to wait. `None` means the response was
+    /// not about rate limits.
+    fn rate_limit_wait(&self, headers: &HeaderMap) -> Option<Duration>;
+}
+
+/// Validates the `--enrich` value.
+pub fn parse_provider(raw: &str) -> Result<String, String> {
+    match raw {
+        "github" | "gitlab" => Ok(raw.to_string()),
+        _ => Err(format!("expected github or gitlab, got '{}'", raw)),
+    }
+}
+
+/// Fills in `pull_request` and `issue` for every commit that references one, when
+/// enrichment is enabled. Failures never fail the index: the affected commits keep
+/// null fields and a warning is logged.
+pub async fn enrich(
+    commits: &mut [CommitHistory],
+    remote_url: Option<&str>,
+    options: &IndexOptions,
+) {
+    let provider = match options.enrich.as_deref() {
+        None => return,
+        Some("gitlab") => GitLab::new(remote_url, options).map(|p| Box::new(p) as Box<_>),
+        Some(_) => GitHub::new(remote_url, options).map(|p| Box::new(p) as Box<_>),
+    };
+    let provider: Box<dyn IssueProvider> = match provider {
+        Ok(provider) => provider,
+        Err(e) => {
+            eprintln!("Warning: skipping enrichment: {}", e);
+            return;
+        }
+    };
+
+    let mut enricher = Enricher {
+        provider,
+        cache: HashMap::new(),
+        unreachable: false,
+    };
+    let redactor = Redactor::new(options);
+    for commit in commits.iter_mut() {
+        for reference in enricher.provider.references(commit) {
+            match enricher.lookup(reference).await {
+                Some(Linked::PullRequest(mut pull_request)) => {
+                    redactor.pull_request(&mut pull_request);
+                    commit.pull_request = Some(pull_request);
+                }
+                Some(Linked::Issue(mut issue)) => {
+                    redactor.issue(&mut issue);
+                    commit.issue = Some(issue);
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+/// Client with the headers every provider sends, plus `auth`.
+pub fn client(auth: Option<(&'static str, String)>) -> Result<Client, String> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        USER_AGENT,
+        HeaderValue::from_static(concat!("git-history/", env!("CARGO_PKG_VERSION"))),
+    );
+    if let Some((name, value)) = auth {
+        let value = HeaderValue::from_str(&value)
+            .map_err(|_| "the token contains invalid characters".to_string())?;
+        headers.insert(name, value);
+    }
+    Client::builder()
+        .default_headers(headers)
+        .timeout(Duration::from_secs(10))
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+/// Reads a header holding a non-negative integer.
+pub fn numeric_header(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+/// Time until a Unix timestamp reset, plus a second for clock skew.
+pub fn until_reset(reset: u64) -> Duration {
+    let now = chrono::Utc::now().timestamp().max(0) as u64;
+    Duration::from_secs(reset.saturating_sub(now) + 1)
+}
+
+struct Enricher {
+    provider: Box<dyn IssueProvider>,
+    /// Responses for this run, including misses
+    cache: HashMap<Reference, Option<Linked>>,
+    /// Set after a network failure, so the rest of the run doesn't wait on timeouts
+    unreachable: bool,
+}
+
+impl Enricher {
+    async fn lookup(&mut self, reference: Reference) -> Option<Linked> {
+        if let Some(cached) = self.cache.get(&reference) {
+            return cached.clone();
+        }
+        if self.unreachable {
+            return None;
+        }
+
+        let linked = match self.fetch(reference).await {
+            Ok(linked) => linked,
+            Err(e) => {
+                eprintln!("Warning: could not fetch {}: {}", describe(reference), e);
+                None
+            }
+        };
+        self.cache.insert(reference, linked.clone());
+        linked
+    }
+
+    async fn fetch(&mut self, reference: Reference) -> Result<Option<Linked>, String> {
+        let name = self.provider.name();
+
+        for attempt in 0..MAX_ATTEMPTS {
+            let response = match self.provider.request(reference).send().await {
+                Ok(response) => response,
+                Err(e) => {
+                    self.unreachable = true;
+                    return Err(format!(
+                        "{} API unreachable, skipping the rest: {}",
+                        name, e
+                    ));
+                }
+            };
+
+            let status = response.status();
+            if status.is_success() {
+                return self.read(reference, response).await.map(Some);
+            }
+            match status {
+                // A number GitHub treats as an issue, or a reference to another project
+                StatusCode::NOT_FOUND => return Ok(None),
+                StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS => {
+                    let Some(wait) = self.provider.rate_limit_wait(response.headers()) else {
+                        return Err(format!("{} API answered {}", name, status));
+                    };
+                    if wait > MAX_RATE_LIMIT_WAIT {
+                        self.unreachable = true;
+                        return Err(format!(
+                            "rate limited for another {}s, skipping the rest",
+                            wait.as_secs()
+                        ));
+                    }
+                    tokio::time::sleep(wait).await;
+                }
+                status if status.is_server_error() => {
+                    tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+                }
+                status => return Err(format!("{} API answered {}", name, status)),
+            }
+        }
+        Err(format!("gave up after {} attempts", MAX_ATTEMPTS))
+    }
+
+    async fn read(&self, reference: Reference, response: Response) -> Result<Linked, String> {
+        let body = response.json().await.map_err(|e| e.to_string())?;
+        self.provider
+            .parse(reference, body)
+            .map_err(|e| format!("unexpected response: {}", e))
+    }
+}
+
+fn describe(reference: Reference) -> String {
+    match reference {
+        Reference::PullRequest(number) => format!("pull request {}", number),
+        Reference::Issue(number) => format!("issue {}", number),
+    }
+}
@@ -0,0 +1,88 @@
+use crate::options::IndexOptions;
+use crate::range::RevRange;
+use crate::sink::RunStart;
+use crate::{config, IndexedRepo, FORMAT_VERSION};
+use serde::Serialize;
+
+/// What an index run covered and how, written as the `meta` of `--envelope` output so
+/// it doesn't have to be kept in file names.
+#[derive(Serialize)]
+pub struct Meta<'a> {
+    /// The repository as given: a path for `index`, `repo_url` or `repo_path` for the
+    /// server
+    pub repo: &'a str,
+    /// The ref the history was walked from, e.g. `refs/heads/main`; `HEAD` when
+    /// detached, null for `--range`
+    pub git_ref: Option<&'a str>,
+    /// The commit that ref pointed at; null for an empty repository
+    pub tip: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<&'a RevRange>,
+    pub tool_version: &'static str,
+    pub format_version: &'static str,
+    /// When the output was produced, as RFC 3339
+    pub generated_at: String,
+    /// The effective filters and diff options, as in `config --print-effective`
+    pub options: toml::Table,
+    /// Unknown when the meta is written before the commits, as NDJSON's first line
+    #[serde(flatten)]
+    pub totals: Option<Totals<'a>>,
+    pub empty_repository: bool,
+    pub shallow: bool,
+}
+
+#[derive(Serialize)]
+pub struct Totals<'a> {
+    pub commit_count: usize,
+    /// Whether commits were left out to respect a limit
+    pub truncated: bool,
+    /// Where the rest of a truncated history starts: send it back as `cursor`
+    pub next_cursor: Option<&'a str>,
+}
+
+impl<'a> Meta<'a> {
+    pub fn new(repo: &'a str, indexed: &'a IndexedRepo, options: &IndexOptions) -> Self {
+        Meta {
+            repo,
+            git_ref: indexed.git_ref.as_deref(),
+            tip: indexed.tip.as_ref().map(|tip| tip.id.as_str()),
+            range: indexed.range.as_ref(),
+            tool_version: env!("CARGO_PKG_VERSION"),
+            format_version: FORMAT_VERSION,
+            generated_at: chrono::Utc::now().to_rfc3339(),
+            options: config::index_table(options),
+            totals: Some(Totals {
+                commit_count: indexed.commits.len(),
+                truncated: indexed.next_cursor.is_some(),
+                next_cursor: indexed.next_cursor.as_deref(),
+            }),
+            empty_repository: indexed.empty_repository,
+            shallow: indexed.shallow,
+        }
+    }
+
+    /// The meta of a run that hasn't produced any commit yet.
+    pub fn at_start(repo: &'a str, run: &'a RunStart, options: &IndexOptions) -> Self {
+        Meta {
+            repo,
+            git_ref: run.git_ref.as_deref(),
+            tip: run.tip.as_deref(),
+            range: run.range.as_ref(),
+            tool_version: env!("CARGO_PKG_VERSION"),
+            format_version: FORMAT_VERSION,
+            generated_at: chrono::Utc::now().to_rfc3339(),
+            options: config::index_table(options),
+            totals: None,
+            empty_repository: run.empty_repository,
+            shallow: run.shallow,
+        }
+    }
+}
+
+/// `{"meta": {...}, "commits": [...]}` for the history of `repo`.
+pub fn wrap(repo: &str, indexed: &IndexedRepo, options: &IndexOptions) -> serde_json::Value {
+    serde_json::json!({
+        "meta": Meta::new(repo, indexed, options),
+        "commits": indexed.commits,
+    })
+}
@@ -0,0 +1,426 @@
+use crate::options::PatchFormat;
+use crate::redact::Redactor;
+use crate::{
+    commit_summary, commit_tree_diff, diff_entries, head_is_unborn, CommitHistory, CustomError,
+};
+use base64::Engine;
+use git2::{Delta, DiffFindOptions, DiffOptions, ObjectType, Repository, Sort};
+use serde::Serialize;
+use std::path::Path;
+
+/// Default for `--max-blob-bytes`.
+pub const DEFAULT_MAX_BLOB_BYTES: u64 = 1024 * 1024;
+
+pub struct FileHistoryOptions {
+    /// Add the file's content after each commit
+    pub emit_content: bool,
+    /// Content beyond this many bytes is cut off
+    pub max_blob_bytes: u64,
+}
+
+impl Default for FileHistoryOptions {
+    fn default() -> Self {
+        FileHistoryOptions {
+            emit_content: false,
+            max_blob_bytes: DEFAULT_MAX_BLOB_BYTES,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct FileHistory {
+    path: String,
+    path_exists_at_head: bool,
+    commits: Vec<FileCommit>,
+}
+
+#[derive(Serialize)]
+pub struct FileCommit {
+    #[serde(flatten)]
+    commit: CommitHistory,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    revision: Option<FileRevision>,
+}
+
+/// The file as a commit left it, read from the commit's tree.
+#[derive(Serialize)]
+struct FileRevision {
+    /// The file's name at this commit, which differs from the requested path before
+    /// a rename
+    path: String,
+    /// Whether the commit deleted the file, leaving no content
+    deleted: bool,
+    /// `utf-8` text, or `base64` for binary and non-UTF-8 files; null when deleted or
+    /// not a file, such as a submodule
+    content: Option<String>,
+    content_encoding: Option<&'static str>,
+    /// Size of the whole blob in bytes
+    size: Option<u64>,
+    /// Whether `content` was cut off at `--max-blob-bytes`
+    content_truncated: bool,
+}
+
+impl FileHistory {
+    pub fn redact(&mut self, redactor: &Redactor) {
+        for entry in &mut self.commits {
+            redactor.commit(&mut entry.commit);
+            let Some(revision) = &mut entry.revision else {
+                continue;
+            };
+            match (&mut revision.content, revision.content_encoding) {
+                (Some(content), Some("base64")) => redactor.base64(content),
+                (Some(content), _) => redactor.text(content),
+                (None, _) => {}
+            }
+        }
+    }
+}
+
+/// Equivalent of `git log --follow -- <path>`: every commit that changed the file,
+/// newest first, each carrying only that file's diff. Renames are followed by
+/// carrying the file's earlier name backwards through the walk. With `emit_content`,
+/// each commit also carries the whole file as that commit left it.
+pub fn file_history(
+    repo_path: &Path,
+    path: &str,
+    options: &FileHistoryOptions,
+) -> Result<FileHistory, CustomError> {
+    let repo = Repository::open(repo_path)?;
+    if head_is_unborn(&repo) {
+        return Ok(FileHistory {
+            path: path.to_string(),
+            path_exists_at_head: false,
+            commits: Vec::new(),
+        });
+    }
+    let head_commit = repo.head()?.peel_to_commit()?;
+    let path_exists_at_head = head_commit.tree()?.get_path(Path::new(path)).is_ok();
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    revwalk.push(head_commit.id())?;
+
+    let mut current_path = path.to_string();
+    let mut commits = Vec::new();
+
+    for commit_id in revwalk {
+        let commit = repo.find_commit(commit_id?)?;
+
+        let diff = commit_tree_diff(&repo, &commit, &mut pathspec_options(&[&current_path]))?;
+        let Some(delta) = diff.deltas().next() else {
+            continue;
+        };
+
+        let mut entry = commit_summary(&repo, &commit, None)?;
+        // Read before a rename moves `current_path` to the file's earlier name
+        let revision = if options.emit_content {
+            let deleted = delta.status() == Delta::Deleted;
+            Some(file_revision(
+                &repo,
+                &commit,
+                &current_path,
+                deleted,
+                options.max_blob_bytes,
+            )?)
+        } else {
+            None
+        };
+        let renamed_from = if delta.status() == Delta::Added && commit.parent_count() > 0 {
+            find_rename_source(&repo, &commit, &current_path)?
+        } else {
+            None
+        };
+
+        entry.git_diff = match renamed_from {
+            Some(old_path) => {
+                // Diff just the two names so the rename shows up as a single entry
+                let mut diff = commit_tree_diff(
+                    &repo,
+                    &commit,
+                    &mut pathspec_options(&[&old_path, &current_path]),
+                )?;
+                diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+                current_path = old_path;
+                diff_entries(&diff, PatchFormat::default())?
+            }
+            None => diff_entries(&diff, PatchFormat::default())?,
+        };
+
+        commits.push(FileCommit {
+            commit: entry,
+            revision,
+        });
+    }
+
+    Ok(FileHistory {
+        path: path.to_string(),
+        path_exists_at_head,
+        commits,
+    })
+}
+
+/// The content of `path` in `commit`'s tree, cut off after `max_bytes`. Text is kept
+/// as a string as long as it is valid UTF-8, anything else is base64 encoded.
+fn file_revision(
+    repo: &Repository,
+    commit: &git2::Commit,
+    path: &str,
+    deleted: bool,
+    max_bytes: u64,
+) -> Result<FileRevision, CustomError> {
+    let mut revision = FileRevision {
+        path: path.to_string(),
+        deleted,
+        content: None,
+        content_encoding: None,
+        size: None,
+        content_truncated: false,
+    };
+    if deleted {
+        return Ok(revision);
+    }
+    let entry = commit.tree()?.get_path(Path::new(path))?;
+    if entry.kind() != Some(ObjectType::Blob) {
+        return Ok(revision);
+    }
+    let blob = repo.find_blob(entry.id())?;
+    let bytes = blob.content();
+    let kept = &bytes[..bytes.len().min(max_bytes as usize)];
+    revision.size = Some(bytes.len() as u64);
+    revision.content_truncated = kept.len() < bytes.len();
+    let text = if blob.is_binary() {
+        None
+    } else {
+        match std::str::from_utf8(kept) {
+            Ok(text) => Some(text),
+            // The cut may split a character; drop its first bytes
+            Err(e) if e.error_len().is_none() => std::str::from_utf8(&kept[..e.valid_up_to()]).ok(),
+            Err(_) => None,
+        }
+    };
+    (revision.content, revision.content_encoding) = match text {
+        Some(text) => (Some(text.to_string()), Some("utf-8")),
+        None => (
+            Some(base64::engine::general_purpose::STANDARD.encode(kept)),
+            Some("base64"),
+        ),
+    };
+    Ok(revision)
+}
+
+/// Returns the path `new_path` was renamed from in this commit, if it was.
+///
+/// Rename detection needs the whole tree diff, so this only runs for commits where the
+/// followed path appears as newly added.
+fn find_rename_source(
+    repo: &Repository,
+    commit: &git2::Commit,
+    new_path: &str,
+) -> Result<Option<String>, CustomError> {
+    let mut diff = commit_tree_diff(repo, commit, &mut DiffOptions::new())?;
+    diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+
+    Ok(diff
+        .deltas()
+        .filter(|delta| delta.status() == Delta::Renamed)
+        .find(|delta| delta.new_file().path() == Some(Path::new(new_path)))
+        .and_then(|delta| delta.old_file().path())
+        .map(|old| old.to_string_lossy().to_string()))
+}
+
+fn pathspec_options(paths: &[&str]) -> DiffOptions {
+    let mut options = DiffOptions::new();
+    // Match the paths literally rather than as globs
+    options.disable_pathspec_match(true);
+    for path in paths {
+        options.pathspec(path);
+    }
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_repo::TestRepo;
+    use serde_json::Value;
+
+    /// `lib.rs` added and edited, moved to `src/lib.rs` with `git mv`, then edited again.
+    fn moved_file() -> TestRepo {
+        let repo = TestRepo::new();
+        repo.write("README.md", "hello\n").commit("Add a readme");
+        repo.write("lib.rs", "fn one() {}\n").commit("Add lib.rs");
+        repo.write("lib.rs", "fn one() {}\nfn two() {}\n")
+            .commit("Add two");
+        std::fs::create_dir(repo.path().join("src")).unwrap();
+        repo.git(["mv", "lib.rs", "src/lib.rs"]);
+        repo.commit("Move lib.rs into src");
+        repo.write("src/lib.rs", "fn one() {}\nfn two() {}\nfn three() {}\n")
+            .commit("Add three");
+        repo
+    }
+
+    fn follow(repo: &TestRepo, path: &str, options: &FileHistoryOptions) -> Value {
+        serde_json::to_value(file_history(repo.path(), path, options).unwrap()).unwrap()
+    }
+
+    fn messages(history: &Value) -> Vec<&str> {
+        history["commits"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|commit| commit["commit_message"].as_str().unwrap().trim_end())
+            .collect()
+    }
+
+    #[test]
+    fn renames_are_followed_to_the_old_path() {
+        let repo = moved_file();
+        let history = follow(&repo, "src/lib.rs", &FileHistoryOptions::default());
+        assert_eq!(history["path"], "src/lib.rs");
+        assert_eq!(history["path_exists_at_head"], true);
+        assert_eq!(
+            messages(&history),
+            ["Add three", "Move lib.rs into src", "Add two", "Add lib.rs"]
+        );
+
+        let files = |index: usize| -> Vec<Value> {
+            history["commits"][index]["git_diff"]
+                .as_array()
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(files(0).len(), 1);
+        assert_eq!(files(0)[0]["file"], "src/lib.rs");
+
+        let rename = files(1);
+        assert_eq!(rename.len(), 1);
+        assert_eq!(rename[0]["file"], "src/lib.rs");
+        assert_eq!(rename[0]["status"], "renamed");
+        assert_eq!(rename[0]["old_file"], "lib.rs");
+        assert_eq!(rename[0]["similarity"], 100);
+
+        // Older entries carry the name the file had then
+        for index in [2, 3] {
+            assert_eq!(files(index).len(), 1);
+            assert_eq!(files(index)[0]["file"], "lib.rs");
+        }
+        assert!(files(3)[0]["diff"]
+            .as_str()
+            .unwrap()
+            .contains("new file mode"));
+    }
+
+    #[test]
+    fn missing_paths_have_no_history() {
+        let repo = moved_file();
+        let history = follow(&repo, "nope.rs", &FileHistoryOptions::default());
+        assert_eq!(history["path"], "nope.rs");
+        assert_eq!(history["path_exists_at_head"], false);
+        assert_eq!(history["commits"], serde_json::json!([]));
+
+        // The old name is gone at HEAD, though its commits are still found
+        let history = follow(&repo, "lib.rs", &FileHistoryOptions::default());
+        assert_eq!(history["path_exists_at_head"], false);
+        assert_eq!(
+            messages(&history),
+            ["Move lib.rs into src", "Add two", "Add lib.rs"]
+        );
+    }
+
+    fn with_content(max_blob_bytes: u64) -> FileHistoryOptions {
+        FileHistoryOptions {
+            emit_content: true,
+            max_blob_bytes,
+        }
+    }
+
+    #[test]
+    fn content_follows_the_file_across_a_rename() {
+        let repo = moved_file();
+        let history = follow(&repo, "src/lib.rs", &with_content(DEFAULT_MAX_BLOB_BYTES));
+        let revisions: Vec<(&str, &str)> = history["commits"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|commit| {
+                assert_eq!(commit["deleted"], false);
+                assert_eq!(commit["content_encoding"], "utf-8");
+                assert_eq!(commit["content_truncated"], false);
+                (
+                    commit["path"].as_str().unwrap(),
+                    commit["content"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            revisions,
+            [
+                ("src/lib.rs", "fn one() {}\nfn two() {}\nfn three() {}\n"),
+                ("src/lib.rs", "fn one() {}\nfn two() {}\n"),
+                ("lib.rs", "fn one() {}\nfn two() {}\n"),
+                ("lib.rs", "fn one() {}\n"),
+            ]
+        );
+        assert_eq!(history["commits"][3]["size"], 12);
+    }
+
+    #[test]
+    fn deleted_files_have_no_content() {
+        let repo = TestRepo::new();
+        repo.write("notes.txt", "notes\n").commit("Add notes");
+        repo.remove("notes.txt").commit("Remove notes");
+
+        let history = follow(&repo, "notes.txt", &with_content(DEFAULT_MAX_BLOB_BYTES));
+        assert_eq!(history["path_exists_at_head"], false);
+        let removal = &history["commits"][0];
+        assert_eq!(
+            removal["commit_message"].as_str().unwrap().trim_end(),
+            "Remove notes"
+        );
+        assert_eq!(removal["deleted"], true);
+        assert_eq!(removal["content"], Value::Null);
+        assert_eq!(removal["content_encoding"], Value::Null);
+        assert_eq!(removal["size"], Value::Null);
+        assert_eq!(history["commits"][1]["content"], "notes\n");
+    }
+
+    #[test]
+    fn binary_content_is_base64() {
+        let bytes = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR";
+        let repo = TestRepo::new();
+        repo.write("logo.png", bytes).commit("Add a logo");
+
+        let history = follow(&repo, "logo.png", &with_content(DEFAULT_MAX_BLOB_BYTES));
+        let revision = &history["commits"][0];
+        assert_eq!(revision["content_encoding"], "base64");
+        assert_eq!(
+            revision["content"],
+            base64::engine::general_purpose::STANDARD.encode(bytes)
+        );
+        assert_eq!(revision["size"], bytes.len());
+        assert_eq!(revision["content_truncated"], false);
+    }
+
+    #[test]
+    fn content_is_cut_off_at_max_blob_bytes() {
+        let repo = moved_file();
+        let history = follow(&repo, "src/lib.rs", &with_content(16));
+        let newest = &history["commits"][0];
+        assert_eq!(newest["content"], "fn one() {}\nfn t");
+        assert_eq!(newest["content_truncated"], true);
+        assert_eq!(newest["size"], 38);
+
+        let oldest = &history["commits"][3];
+        assert_eq!(oldest["content"], "fn one() {}\n");
+        assert_eq!(oldest["content_truncated"], false);
+
+        // A cut through a multi-byte character drops the character's first bytes
+        let repo = TestRepo::new();
+        repo.write("greeting.txt", "héllo\n")
+            .commit("Add a greeting");
+        let history = follow(&repo, "greeting.txt", &with_content(2));
+        assert_eq!(history["commits"][0]["content"], "h");
+        assert_eq!(history["commits"][0]["content_encoding"], "utf-8");
+        assert_eq!(history["commits"][0]["content_truncated"], true);
+    }
+}
@@ -0,0 +1,139 @@
+use crate::enrich::{self, IssueProvider, Linked, PullRequest, Reference};
+use crate::options::IndexOptions;
+use crate::CommitHistory;
+use regex::Regex;
+use reqwest::header::{HeaderMap, AUTHORIZATION, RETRY_AFTER};
+use reqwest::{Client, RequestBuilder};
+use serde::Deserialize;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+const GITHUB_API: &str = "https://api.github.com";
+
+/// Pull request lookups through the GitHub REST API.
+pub struct GitHub {
+    client: Client,
+    /// `owner/repo`
+    repo_path: String,
+}
+
+#[derive(Deserialize)]
+struct ApiPullRequest {
+    number: u64,
+    title: String,
+    body: Option<String>,
+    user: Option<ApiUser>,
+    labels: Vec<ApiLabel>,
+    merged_at: Option<String>,
+    html_url: String,
+}
+
+#[derive(Deserialize)]
+struct ApiUser {
+    login: String,
+}
+
+#[derive(Deserialize)]
+struct ApiLabel {
+    name: String,
+}
+
+impl GitHub {
+    pub fn new(remote_url: Option<&str>, options: &IndexOptions) -> Result<Self, String> {
+        let (owner, repo) = remote_url
+            .and_then(github_repo)
+            .ok_or("the repository's origin is not on github.com")?;
+        let auth = options
+            .github_token
+            .as_ref()
+            .map(|token| (AUTHORIZATION.as_str(), format!("Bearer {}", token)));
+        Ok(GitHub {
+            client: enrich::client(auth)?,
+            repo_path: format!("{}/{}", owner, repo),
+        })
+    }
+}
+
+impl IssueProvider for GitHub {
+    fn name(&self) -> &'static str {
+        "GitHub"
+    }
+
+    fn references(&self, commit: &CommitHistory) -> Vec<Reference> {
+        pull_request_number(&commit.commit_message, &commit.pl_and_issue_id)
+            .map(Reference::PullRequest)
+            .into_iter()
+            .collect()
+    }
+
+    fn request(&self, reference: Reference) -> RequestBuilder {
+        let (kind, number) = match reference {
+            Reference::PullRequest(number) => ("pulls", number),
+            Reference::Issue(number) => ("issues", number),
+        };
+        let url = format!(
+            "{}/repos/{}/{}/{}",
+            GITHUB_API, self.repo_path, kind, number
+        );
+        self.client
+            .get(url)
+            .header("Accept", "application/vnd.github+json")
+    }
+
+    fn parse(
+        &self,
+        _reference: Reference,
+        body: serde_json::Value,
+    ) -> Result<Linked, serde_json::Error> {
+        let api: ApiPullRequest = serde_json::from_value(body)?;
+        Ok(Linked::PullRequest(PullRequest {
+            number: api.number,
+            title: api.title,
+            body: api.body,
+            author: api.user.map(|user| user.login),
+            labels: api.labels.into_iter().map(|label| label.name).collect(),
+            merged_at: api.merged_at,
+            url: api.html_url,
+        }))
+    }
+
+    /// `Retry-After` for secondary limits, or until `X-RateLimit-Reset` once the
+    /// primary limit is used up. GitHub answers other permission problems with 403 too.
+    fn rate_limit_wait(&self, headers: &HeaderMap) -> Option<Duration> {
+        if let Some(secs) = enrich::numeric_header(headers, RETRY_AFTER.as_str()) {
+            return Some(Duration::from_secs(secs));
+        }
+        if enrich::numeric_header(headers, "x-ratelimit-remaining") == Some(0) {
+            return enrich::numeric_header(headers, "x-ratelimit-reset").map(enrich::until_reset);
+        }
+        None
+    }
+}
+
+/// `owner` and `repo` of a github.com remote, in HTTPS, SSH or bare `github.com/o/r`
+/// form.
+fn github_repo(url: &str) -> Option<(String, String)> {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern = PATTERN
+        .get_or_init(|| Regex::new(r"github\.com[:/]([^/]+)/([^/]+?)(?:\.git)?/?$").unwrap());
+    let captures = pattern.captures(url)?;
+    Some((captures[1].to_string(), captures[2].to_string()))
+}
+
+/// The pull request a commit came from: a squash-merge `(#123)` suffix, a merge
+/// commit's `Merge pull request #123`, or else the extracted issue reference.
+pub fn pull_request_number(message: &str, pl_and_issue_id: &str) -> Option<u64> {
+    static SQUASH: OnceLock<Regex> = OnceLock::new();
+    static MERGE: OnceLock<Regex> = OnceLock::new();
+    static REFERENCE: OnceLock<Regex> = OnceLock::new();
+    let squash = SQUASH.get_or_init(|| Regex::new(r"\(#(\d+)\)\s*$").unwrap());
+    let merge = MERGE.get_or_init(|| Regex::new(r"^Merge pull request #(\d+)").unwrap());
+    let reference = REFERENCE.get_or_init(|| Regex::new(r"#(\d+)").unwrap());
+
+    let subject = message.lines().next().unwrap_or("");
+    squash
+        .captures(subject)
+        .or_else(|| merge.captures(subject))
+        .or_else(|| reference.captures(pl_and_issue_id))
+        .and_then(|captures| captures[1].parse().ok())
+}
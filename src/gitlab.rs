@@ -0,0 +1,174 @@
+use crate::enrich::{self, Issue, IssueProvider, Linked, PullRequest, Reference};
+use crate::options::IndexOptions;
+use crate::CommitHistory;
+use regex::Regex;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Client, RequestBuilder};
+use serde::Deserialize;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Default for `--gitlab-url`.
+const GITLAB_URL: &str = "https://gitlab.com";
+
+/// Merge request and issue lookups through the GitLab REST API, on gitlab.com or a
+/// self-hosted instance.
+pub struct GitLab {
+    client: Client,
+    /// `<base>/api/v4/projects/<url-encoded project path>`
+    project_url: String,
+}
+
+/// The fields merge requests and issues have in common, plus the ones only one of them has.
+#[derive(Deserialize)]
+struct ApiItem {
+    iid: u64,
+    title: String,
+    description: Option<String>,
+    author: Option<ApiUser>,
+    labels: Vec<String>,
+    merged_at: Option<String>,
+    closed_at: Option<String>,
+    web_url: String,
+}
+
+#[derive(Deserialize)]
+struct ApiUser {
+    username: String,
+}
+
+impl GitLab {
+    pub fn new(remote_url: Option<&str>, options: &IndexOptions) -> Result<Self, String> {
+        let base = options
+            .gitlab_url
+            .as_deref()
+            .unwrap_or(GITLAB_URL)
+            .trim_end_matches('/');
+        let project = remote_url
+            .and_then(|remote| gitlab_project(remote, base))
+            .ok_or_else(|| format!("the repository's origin is not on {}", base))?;
+        let auth = options
+            .gitlab_token
+            .as_ref()
+            .map(|token| ("private-token", token.clone()));
+        let project: String = form_urlencoded::byte_serialize(project.as_bytes()).collect();
+        Ok(GitLab {
+            client: enrich::client(auth)?,
+            project_url: format!("{}/api/v4/projects/{}", base, project),
+        })
+    }
+}
+
+impl IssueProvider for GitLab {
+    fn name(&self) -> &'static str {
+        "GitLab"
+    }
+
+    fn references(&self, commit: &CommitHistory) -> Vec<Reference> {
+        message_references(&commit.commit_message)
+    }
+
+    fn request(&self, reference: Reference) -> RequestBuilder {
+        let url = match reference {
+            Reference::PullRequest(iid) => format!("{}/merge_requests/{}", self.project_url, iid),
+            Reference::Issue(iid) => format!("{}/issues/{}", self.project_url, iid),
+        };
+        self.client.get(url)
+    }
+
+    fn parse(
+        &self,
+        reference: Reference,
+        body: serde_json::Value,
+    ) -> Result<Linked, serde_json::Error> {
+        let api: ApiItem = serde_json::from_value(body)?;
+        let author = api.author.map(|user| user.username);
+        Ok(match reference {
+            Reference::PullRequest(_) => Linked::PullRequest(PullRequest {
+                number: api.iid,
+                title: api.title,
+                body: api.description,
+                author,
+                labels: api.labels,
+                merged_at: api.merged_at,
+                url: api.web_url,
+            }),
+            Reference::Issue(_) => Linked::Issue(Issue {
+                number: api.iid,
+                title: api.title,
+                body: api.description,
+                author,
+                labels: api.labels,
+                closed_at: api.closed_at,
+                url: api.web_url,
+            }),
+        })
+    }
+
+    /// `Retry-After` on 429s, or until `RateLimit-Reset` once the limit is used up.
+    fn rate_limit_wait(&self, headers: &HeaderMap) -> Option<Duration> {
+        if let Some(secs) = enrich::numeric_header(headers, RETRY_AFTER.as_str()) {
+            return Some(Duration::from_secs(secs));
+        }
+        if enrich::numeric_header(headers, "ratelimit-remaining") == Some(0) {
+            return enrich::numeric_header(headers, "ratelimit-reset").map(enrich::until_reset);
+        }
+        None
+    }
+}
+
+/// The project path (`group/subgroup/project`) of a remote on the GitLab instance at
+/// `base`, from an HTTPS, `ssh://` or `git@host:path` remote.
+fn gitlab_project(remote: &str, base: &str) -> Option<String> {
+    let (base_host, base_path) = split_url(base);
+    let (host, path) = split_url(remote);
+    if base_host.is_empty() || !host.eq_ignore_ascii_case(base_host) {
+        return None;
+    }
+    // HTTPS remotes of an instance served below a path repeat that path
+    let path = path
+        .strip_prefix(base_path)
+        .filter(|_| !base_path.is_empty())
+        .map_or(path, |rest| rest.trim_start_matches('/'));
+    let project = path.strip_suffix(".git").unwrap_or(path);
+    (project.contains('/')).then(|| project.to_string())
+}
+
+/// Splits a URL or scp-style remote into its host and its path, without the scheme,
+/// user, port or surrounding slashes.
+fn split_url(url: &str) -> (&str, &str) {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let rest = rest.split_once('@').map_or(rest, |(_, rest)| rest);
+    let Some(end) = rest.find([':', '/']) else {
+        return (rest, "");
+    };
+    let host = &rest[..end];
+    let mut path = &rest[end + 1..];
+    if rest[end..].starts_with(':') {
+        if let Some((port, after)) = path.split_once('/') {
+            if !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()) {
+                path = after;
+            }
+        }
+    }
+    (host, path.trim_matches('/'))
+}
+
+/// The first `!123` merge request and the first `#123` issue of `message`. Issues of
+/// other projects (`group/project#123`) and HTML entities are skipped.
+pub fn message_references(message: &str) -> Vec<Reference> {
+    static MERGE_REQUEST: OnceLock<Regex> = OnceLock::new();
+    static ISSUE: OnceLock<Regex> = OnceLock::new();
+    let merge_request = MERGE_REQUEST.get_or_init(|| Regex::new(r"!(\d+)\b").unwrap());
+    let issue = ISSUE.get_or_init(|| Regex::new(r"(?:^|[^\w&/])#(\d+)\b").unwrap());
+
+    let first = |pattern: &Regex| {
+        pattern
+            .captures(message)
+            .and_then(|captures| captures[1].parse().ok())
+    };
+    let mut references = Vec::new();
+    references.extend(first(merge_request).map(Reference::PullRequest));
+    references.extend(first(issue).map(Reference::Issue));
+    references
+}
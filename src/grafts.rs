@@ -0,0 +1,223 @@
+use crate::CustomError;
+use git2::{Oid, Repository, Sort};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fs;
+
+/// Commits that have no parents here but do in the history they were cut from, so
+/// their diff against the empty tree would list the whole tree as added.
+pub struct Boundaries {
+    /// Commits of `.git/shallow`, whose parents a shallow clone didn't fetch
+    shallow: HashSet<Oid>,
+    /// Root commits that `refs/replace/*` or `.git/info/grafts` give parents to
+    attached: HashSet<Oid>,
+}
+
+impl Boundaries {
+    pub fn load(repo: &Repository) -> Result<Self, CustomError> {
+        let mut attached = HashSet::new();
+        for reference in repo.references_glob("refs/replace/*")? {
+            let reference = reference?;
+            let Some(original) = reference
+                .name()
+                .and_then(|name| name.strip_prefix("refs/replace/"))
+                .and_then(|id| Oid::from_str(id).ok())
+            else {
+                continue;
+            };
+            let has_parents = reference
+                .peel_to_commit()
+                .is_ok_and(|replacement| replacement.parent_count() > 0);
+            if has_parents {
+                attached.insert(original);
+            }
+        }
+        // Grafts predate replace refs: `<commit> <parent>...` per line
+        let grafts = fs::read_to_string(repo.path().join("info/grafts")).unwrap_or_default();
+        for line in grafts.lines() {
+            let mut ids = line.split_whitespace().map(Oid::from_str);
+            if let (Some(Ok(commit)), Some(Ok(_))) = (ids.next(), ids.next()) {
+                attached.insert(commit);
+            }
+        }
+        Ok(Boundaries {
+            shallow: shallow_commits(repo),
+            attached,
+        })
+    }
+
+    pub fn contains(&self, commit: &git2::Commit) -> bool {
+        if commit.parent_count() == 0 {
+            self.attached.contains(&commit.id())
+        } else {
+            self.shallow.contains(&commit.id())
+        }
+    }
+}
+
+/// The commits listed in `.git/shallow`. Empty unless the repository is shallow.
+fn shallow_commits(repo: &Repository) -> HashSet<Oid> {
+    fs::read_to_string(repo.path().join("shallow"))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| Oid::from_str(line.trim()).ok())
+        .collect()
+}
+
+/// A history walk that also works in shallow clones. libgit2 fails on the missing
+/// parents of their shallow commits, so there the walk is done here instead, always in
+/// topological order with newer commits first.
+pub struct Walk<'r> {
+    inner: Inner<'r>,
+}
+
+enum Inner<'r> {
+    Revwalk(git2::Revwalk<'r>),
+    Shallow {
+        repo: &'r Repository,
+        push: Vec<Oid>,
+        hide: Vec<Oid>,
+        first_parent: bool,
+        /// Filled on the first call to `next`
+        order: Option<std::vec::IntoIter<Oid>>,
+    },
+}
+
+impl<'r> Walk<'r> {
+    /// Walks the history of `push`, leaving out everything reachable from `hide`.
+    pub fn new(repo: &'r Repository, push: Vec<Oid>, hide: Vec<Oid>) -> Result<Self, CustomError> {
+        if repo.is_shallow() {
+            return Ok(Walk {
+                inner: Inner::Shallow {
+                    repo,
+                    push,
+                    hide,
+                    first_parent: false,
+                    order: None,
+                },
+            });
+        }
+        let mut revwalk = repo.revwalk()?;
+        for id in push {
+            revwalk.push(id)?;
+        }
+        for id in hide {
+            revwalk.hide(id)?;
+        }
+        Ok(Walk {
+            inner: Inner::Revwalk(revwalk),
+        })
+    }
+
+    /// Ignored in shallow clones, which are always walked topologically.
+    pub fn set_sorting(&mut self, sort: Sort) -> Result<(), git2::Error> {
+        match &mut self.inner {
+            Inner::Revwalk(revwalk) => revwalk.set_sorting(sort),
+            Inner::Shallow { .. } => Ok(()),
+        }
+    }
+
+    pub fn simplify_first_parent(&mut self) -> Result<(), git2::Error> {
+        match &mut self.inner {
+            Inner::Revwalk(revwalk) => revwalk.simplify_first_parent(),
+            Inner::Shallow { first_parent, .. } => {
+                *first_parent = true;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Iterator for Walk<'_> {
+    type Item = Result<Oid, git2::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.inner {
+            Inner::Revwalk(revwalk) => revwalk.next(),
+            Inner::Shallow {
+                repo,
+                push,
+                hide,
+                first_parent,
+                order,
+            } => {
+                if order.is_none() {
+                    match shallow_order(repo, push, hide, *first_parent) {
+                        Ok(ids) => *order = Some(ids.into_iter()),
+                        Err(e) => {
+                            *order = Some(Vec::new().into_iter());
+                            return Some(Err(e));
+                        }
+                    }
+                }
+                order.as_mut().and_then(Iterator::next).map(Ok)
+            }
+        }
+    }
+}
+
+/// The commits reachable from `push` but not from `hide`, without crossing shallow
+/// commits, children before parents and otherwise newest first.
+fn shallow_order(
+    repo: &Repository,
+    push: &[Oid],
+    hide: &[Oid],
+    first_parent: bool,
+) -> Result<Vec<Oid>, git2::Error> {
+    let shallow = shallow_commits(repo);
+    // The fetched parents of `id`
+    let parents = |id: Oid, first_only: bool| -> Result<Vec<Oid>, git2::Error> {
+        if shallow.contains(&id) {
+            return Ok(Vec::new());
+        }
+        let commit = repo.find_commit(id)?;
+        let take = if first_only { 1 } else { usize::MAX };
+        Ok(commit.parent_ids().take(take).collect())
+    };
+
+    let mut hidden = HashSet::new();
+    let mut queue: VecDeque<Oid> = hide.iter().copied().collect();
+    while let Some(id) = queue.pop_front() {
+        if hidden.insert(id) {
+            queue.extend(parents(id, false)?);
+        }
+    }
+
+    // Parents of every walked commit, and how many walked children each one has
+    let mut edges: HashMap<Oid, Vec<Oid>> = HashMap::new();
+    let mut children: HashMap<Oid, usize> = HashMap::new();
+    let mut queue: VecDeque<Oid> = push.iter().copied().collect();
+    while let Some(id) = queue.pop_front() {
+        if hidden.contains(&id) || edges.contains_key(&id) {
+            continue;
+        }
+        let walked: Vec<Oid> = parents(id, first_parent)?
+            .into_iter()
+            .filter(|parent| !hidden.contains(parent))
+            .collect();
+        for parent in &walked {
+            *children.entry(*parent).or_default() += 1;
+        }
+        queue.extend(walked.iter().copied());
+        edges.insert(id, walked);
+    }
+
+    let time = |id: Oid| repo.find_commit(id).map(|commit| commit.time().seconds());
+    let mut ready = BinaryHeap::new();
+    for &id in edges.keys() {
+        if !children.contains_key(&id) {
+            ready.push((time(id)?, id));
+        }
+    }
+    let mut order = Vec::with_capacity(edges.len());
+    while let Some((_, id)) = ready.pop() {
+        order.push(id);
+        for parent in &edges[&id] {
+            let remaining = children.get_mut(parent).expect("parents are counted");
+            *remaining -= 1;
+            if *remaining == 0 {
+                ready.push((time(*parent)?, *parent));
+            }
+        }
+    }
+    Ok(order)
+}
@@ -0,0 +1,456 @@
+use crate::options::IndexOptions;
+use crate::redact::Redactor;
+use crate::{history_revwalk, CustomError};
+use git2::{Oid, Repository, Sort};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::path::Path;
+
+/// Default for `--max-commits`.
+pub const DEFAULT_MAX_COMMITS: usize = 200;
+
+/// Longest first message line shown on a node.
+const MAX_SUMMARY_CHARS: usize = 50;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum GraphFormat {
+    /// Graphviz DOT
+    #[default]
+    Dot,
+    /// A Mermaid `gitGraph` block
+    Mermaid,
+}
+
+impl GraphFormat {
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "dot" => Ok(GraphFormat::Dot),
+            "mermaid" => Ok(GraphFormat::Mermaid),
+            _ => Err(format!("expected dot or mermaid, got '{}'", raw)),
+        }
+    }
+}
+
+struct Node {
+    id: Oid,
+    short: String,
+    summary: String,
+    /// Parents drawn as edges; only the first one with `--first-parent`
+    parents: Vec<Oid>,
+}
+
+/// Renders the commit graph of the walked history, newest commits first, capped at
+/// `max_commits` nodes. Branch tips and tags are annotated. With `first_parent` only
+/// the first parent of each commit is followed.
+pub fn graph(
+    repo_path: &Path,
+    options: &IndexOptions,
+    max_commits: usize,
+    first_parent: bool,
+    format: GraphFormat,
+) -> Result<String, CustomError> {
+    let repo = Repository::open(repo_path)?;
+    let mut revwalk = history_revwalk(&repo, options)?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    if first_parent {
+        revwalk.simplify_first_parent()?;
+    }
+
+    let redactor = Redactor::new(options);
+    let mut nodes = Vec::new();
+    let mut truncated = false;
+    for commit_id in revwalk {
+        let commit = repo.find_commit(commit_id?)?;
+        if !options.accepts(&commit) {
+            continue;
+        }
+        if nodes.len() == max_commits {
+            truncated = true;
+            break;
+        }
+        let parents = commit
+            .parent_ids()
+            .take(if first_parent { 1 } else { usize::MAX });
+        nodes.push(Node {
+            id: commit.id(),
+            short: commit
+                .as_object()
+                .short_id()?
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            summary: summary(&commit, &redactor),
+            parents: parents.collect(),
+        });
+    }
+
+    let refs = ref_names(&repo)?;
+    Ok(match format {
+        GraphFormat::Dot => dot(&nodes, &refs, truncated, max_commits),
+        GraphFormat::Mermaid => {
+            let head = repo.head().ok().and_then(|head| head.target());
+            mermaid(&nodes, head, &refs, truncated, max_commits)
+        }
+    })
+}
+
+/// The first line of the message, redacted before it is cut so no partial email is left.
+fn summary(commit: &git2::Commit, redactor: &Redactor) -> String {
+    let mut line = String::from_utf8_lossy(commit.message_bytes())
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    redactor.text(&mut line);
+    if line.chars().count() > MAX_SUMMARY_CHARS {
+        let cut: String = line.chars().take(MAX_SUMMARY_CHARS - 1).collect();
+        format!("{}…", cut)
+    } else {
+        line
+    }
+}
+
+/// Branches, remote branches and tags pointing at each commit, decorated like `git log
+/// --decorate`: `main`, `origin/main`, `tag: v1.0`.
+fn ref_names(repo: &Repository) -> Result<HashMap<Oid, Vec<String>>, CustomError> {
+    let mut names: HashMap<Oid, Vec<String>> = HashMap::new();
+    for reference in repo.references()? {
+        let reference = reference?;
+        let Some(shorthand) = reference.shorthand() else {
+            continue;
+        };
+        let name = if reference.is_tag() {
+            format!("tag: {}", shorthand)
+        } else if reference.is_branch() || reference.is_remote() {
+            shorthand.to_string()
+        } else {
+            continue;
+        };
+        if let Ok(commit) = reference.peel_to_commit() {
+            names.entry(commit.id()).or_default().push(name);
+        }
+    }
+    for list in names.values_mut() {
+        list.sort();
+    }
+    Ok(names)
+}
+
+fn dot(
+    nodes: &[Node],
+    refs: &HashMap<Oid, Vec<String>>,
+    truncated: bool,
+    max_commits: usize,
+) -> String {
+    let shown: HashSet<Oid> = nodes.iter().map(|node| node.id).collect();
+    let mut out = String::from("digraph history {\n");
+    out.push_str("  node [shape=box, fontname=\"monospace\"];\n");
+    for node in nodes {
+        let mut label = format!("{} {}", node.short, node.summary);
+        let mut style = "";
+        if let Some(names) = refs.get(&node.id) {
+            label.push_str(&format!("\n({})", names.join(", ")));
+            style = ", style=bold";
+        }
+        writeln!(
+            out,
+            "  \"{}\" [label=\"{}\"{}];",
+            node.id,
+            dot_escape(&label),
+            style
+        )
+        .unwrap();
+    }
+    let mut cut = false;
+    for node in nodes {
+        for (idx, parent) in node.parents.iter().enumerate() {
+            // Later parents are the merged-in side
+            let attributes = if idx > 0 { " [style=dashed]" } else { "" };
+            if shown.contains(parent) {
+                writeln!(out, "  \"{}\" -> \"{}\"{};", node.id, parent, attributes).unwrap();
+            } else if truncated {
+                writeln!(out, "  \"{}\" -> truncated{};", node.id, attributes).unwrap();
+                cut = true;
+            }
+        }
+    }
+    if cut {
+        writeln!(
+            out,
+            "  truncated [shape=note, label=\"Older history not shown (limited to {} commits)\"];",
+            max_commits
+        )
+        .unwrap();
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn dot_escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Mermaid draws commits on branches, so every commit is put on a lane: the
+/// first-parent chain of HEAD is the first lane, and every other tip, newest first,
+/// gets a lane for its first-parent chain down to a commit that already has one. A lane
+/// branches off the first parent of its oldest commit. Lanes are named after the branch
+/// pointing at their newest commit, if any.
+fn mermaid(
+    nodes: &[Node],
+    head: Option<Oid>,
+    refs: &HashMap<Oid, Vec<String>>,
+    truncated: bool,
+    max_commits: usize,
+) -> String {
+    let by_id: HashMap<Oid, &Node> = nodes.iter().map(|node| (node.id, node)).collect();
+    let mut lane_of: HashMap<Oid, usize> = HashMap::new();
+    let mut tips: Vec<Oid> = Vec::new();
+    // Lanes starting right after a commit
+    let mut branches_after: HashMap<Oid, Vec<usize>> = HashMap::new();
+    let seeds = head
+        .filter(|head| by_id.contains_key(head))
+        .into_iter()
+        .chain(nodes.iter().map(|node| node.id));
+    for seed in seeds {
+        if lane_of.contains_key(&seed) {
+            continue;
+        }
+        let lane = tips.len();
+        tips.push(seed);
+        let mut commit = by_id[&seed];
+        lane_of.insert(commit.id, lane);
+        while let Some(parent) = commit.parents.first().and_then(|p| by_id.get(p)) {
+            if lane_of.contains_key(&parent.id) {
+                branches_after.entry(parent.id).or_default().push(lane);
+                break;
+            }
+            lane_of.insert(parent.id, lane);
+            commit = parent;
+        }
+    }
+    let names = lane_names(&tips, refs);
+
+    let mut out = String::new();
+    if truncated {
+        writeln!(
+            out,
+            "---\ntitle: Newest {} commits, older history not shown\n---",
+            max_commits
+        )
+        .unwrap();
+    }
+    if let Some(main) = names.first() {
+        writeln!(
+            out,
+            "%%{{init: {{'gitGraph': {{'mainBranchName': '{}'}}}}}}%%",
+            main
+        )
+        .unwrap();
+    }
+    out.push_str("gitGraph\n");
+    let mut current = 0;
+    // Mermaid starts out on the first lane
+    let mut started = HashSet::from([0]);
+    // Oldest first, so every parent comes before its children
+    for node in nodes.iter().rev() {
+        let lane = lane_of[&node.id];
+        if started.insert(lane) {
+            writeln!(out, "  branch {}", names[lane]).unwrap();
+        } else if lane != current {
+            writeln!(out, "  checkout {}", names[lane]).unwrap();
+        }
+        current = lane;
+
+        let mut line = match node.parents.get(1).and_then(|p| lane_of.get(p)) {
+            Some(&merged) if merged != lane => format!("  merge {}", names[merged]),
+            _ => "  commit".to_string(),
+        };
+        let label = format!("{} {}", node.short, node.summary);
+        line.push_str(&format!(" id: \"{}\"", label.replace('"', "'")));
+        let tags: Vec<&str> = refs
+            .get(&node.id)
+            .into_iter()
+            .flatten()
+            .filter_map(|name| name.strip_prefix("tag: "))
+            .collect();
+        if !tags.is_empty() {
+            line.push_str(&format!(" tag: \"{}\"", tags.join(", ").replace('"', "'")));
+        }
+        writeln!(out, "{}", line).unwrap();
+
+        for &branch in branches_after.get(&node.id).into_iter().flatten() {
+            writeln!(out, "  branch {}", names[branch]).unwrap();
+            started.insert(branch);
+            current = branch;
+        }
+    }
+    out
+}
+
+/// A unique Mermaid branch name per lane: the branch at its tip, or `lane-<n>`.
+fn lane_names(tips: &[Oid], refs: &HashMap<Oid, Vec<String>>) -> Vec<String> {
+    let mut taken = HashSet::new();
+    tips.iter()
+        .enumerate()
+        .map(|(lane, tip)| {
+            let branch = refs
+                .get(tip)
+                .into_iter()
+                .flatten()
+                .find(|name| !name.starts_with("tag: "))
+                .map(|name| {
+                    name.chars()
+                        .map(|c| match c {
+                            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '.' | '/' => c,
+                            _ => '-',
+                        })
+                        .collect::<String>()
+                });
+            let name = match branch {
+                Some(name) if !taken.contains(&name) => name,
+                _ => format!("lane-{}", lane),
+            };
+            taken.insert(name.clone());
+            name
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_repo::TestRepo;
+
+    /// `main` with a feature branch merged back in and tagged, plus an unmerged topic
+    /// branch.
+    fn branch_and_merge() -> TestRepo {
+        let repo = TestRepo::new();
+        repo.write("README.md", "# Demo\n").commit("Initial commit");
+        repo.git(["checkout", "-q", "-b", "feature"]);
+        repo.write("feature.txt", "feature\n")
+            .commit("Add the feature");
+        repo.git(["checkout", "-q", "main"]);
+        repo.write("README.md", "# Demo\n\nUsage\n")
+            .commit("Document usage");
+        repo.git([
+            "merge",
+            "-q",
+            "--no-ff",
+            "-m",
+            "Merge branch 'feature'",
+            "feature",
+        ]);
+        repo.git(["tag", "v1.0"]);
+        repo.git(["checkout", "-q", "-b", "topic"]);
+        repo.write("topic.txt", "topic\n")
+            .commit("Start a \"topic\"");
+        repo.git(["checkout", "-q", "main"]);
+        repo
+    }
+
+    fn render(repo: &TestRepo, max_commits: usize, format: GraphFormat) -> String {
+        let options = IndexOptions {
+            all: true,
+            ..IndexOptions::default()
+        };
+        graph(repo.path(), &options, max_commits, false, format).unwrap()
+    }
+
+    #[test]
+    fn dot_draws_both_parents_of_merges_and_annotates_refs() {
+        let repo = branch_and_merge();
+        assert_eq!(
+            render(&repo, 200, GraphFormat::Dot),
+            r#"digraph history {
+  node [shape=box, fontname="monospace"];
+  "cc40597272756a74cc8f2b60a5d9737fe4799595" [label="cc40597 Start a \"topic\"\n(topic)", style=bold];
+  "2ef075775ac607f3fb5c27b66ee2f0d80e57f64d" [label="2ef0757 Merge branch 'feature'\n(main, tag: v1.0)", style=bold];
+  "ef7f7a9c531a6086911052d39e3a7d4d452987e8" [label="ef7f7a9 Document usage"];
+  "2df0809fa1407e54acfdd9fd4770bcf213dd6ab5" [label="2df0809 Add the feature\n(feature)", style=bold];
+  "70832c086d96752716c5013e24233050d77ceac7" [label="70832c0 Initial commit"];
+  "cc40597272756a74cc8f2b60a5d9737fe4799595" -> "2ef075775ac607f3fb5c27b66ee2f0d80e57f64d";
+  "2ef075775ac607f3fb5c27b66ee2f0d80e57f64d" -> "ef7f7a9c531a6086911052d39e3a7d4d452987e8";
+  "2ef075775ac607f3fb5c27b66ee2f0d80e57f64d" -> "2df0809fa1407e54acfdd9fd4770bcf213dd6ab5" [style=dashed];
+  "ef7f7a9c531a6086911052d39e3a7d4d452987e8" -> "70832c086d96752716c5013e24233050d77ceac7";
+  "2df0809fa1407e54acfdd9fd4770bcf213dd6ab5" -> "70832c086d96752716c5013e24233050d77ceac7";
+}
+"#
+        );
+    }
+
+    #[test]
+    fn dot_points_cut_off_parents_at_a_truncation_note() {
+        let repo = branch_and_merge();
+        assert_eq!(
+            render(&repo, 3, GraphFormat::Dot),
+            r#"digraph history {
+  node [shape=box, fontname="monospace"];
+  "cc40597272756a74cc8f2b60a5d9737fe4799595" [label="cc40597 Start a \"topic\"\n(topic)", style=bold];
+  "2ef075775ac607f3fb5c27b66ee2f0d80e57f64d" [label="2ef0757 Merge branch 'feature'\n(main, tag: v1.0)", style=bold];
+  "ef7f7a9c531a6086911052d39e3a7d4d452987e8" [label="ef7f7a9 Document usage"];
+  "cc40597272756a74cc8f2b60a5d9737fe4799595" -> "2ef075775ac607f3fb5c27b66ee2f0d80e57f64d";
+  "2ef075775ac607f3fb5c27b66ee2f0d80e57f64d" -> "ef7f7a9c531a6086911052d39e3a7d4d452987e8";
+  "2ef075775ac607f3fb5c27b66ee2f0d80e57f64d" -> truncated [style=dashed];
+  "ef7f7a9c531a6086911052d39e3a7d4d452987e8" -> truncated;
+  truncated [shape=note, label="Older history not shown (limited to 3 commits)"];
+}
+"#
+        );
+    }
+
+    #[test]
+    fn mermaid_merges_branches_into_their_lane() {
+        let repo = branch_and_merge();
+        assert_eq!(
+            render(&repo, 200, GraphFormat::Mermaid),
+            r#"%%{init: {'gitGraph': {'mainBranchName': 'main'}}}%%
+gitGraph
+  commit id: "70832c0 Initial commit"
+  branch feature
+  commit id: "2df0809 Add the feature"
+  checkout main
+  commit id: "ef7f7a9 Document usage"
+  merge feature id: "2ef0757 Merge branch 'feature'" tag: "v1.0"
+  branch topic
+  commit id: "cc40597 Start a 'topic'"
+"#
+        );
+    }
+
+    #[test]
+    fn mermaid_titles_truncated_graphs() {
+        let repo = branch_and_merge();
+        assert_eq!(
+            render(&repo, 3, GraphFormat::Mermaid),
+            r#"---
+title: Newest 3 commits, older history not shown
+---
+%%{init: {'gitGraph': {'mainBranchName': 'main'}}}%%
+gitGraph
+  commit id: "ef7f7a9 Document usage"
+  commit id: "2ef0757 Merge branch 'feature'" tag: "v1.0"
+  branch topic
+  commit id: "cc40597 Start a 'topic'"
+"#
+        );
+    }
+
+    #[test]
+    fn first_parent_leaves_out_merged_branches() {
+        let repo = branch_and_merge();
+        let graph = graph(
+            repo.path(),
+            &IndexOptions::default(),
+            200,
+            true,
+            GraphFormat::Dot,
+        )
+        .unwrap();
+        assert!(!graph.contains("Add the feature"), "{}", graph);
+        assert!(!graph.contains("style=dashed"), "{}", graph);
+        assert_eq!(graph.matches(" -> ").count(), 2, "{}", graph);
+    }
+}
@@ -0,0 +1,357 @@
+// tonic fixes the error type of every handler to `Status`, which is large
+#![allow(clippy::result_large_err)]
+
+use crate::options::IndexOptions;
+use crate::redact::Redactor;
+use crate::sink::Event;
+use crate::{
+    show_commit, spawn_index, with_cloned_repo, CloneKind, CommitDiff, CommitHistory, CommitStats,
+    CustomError, ServerState, STREAM_BUFFER,
+};
+use hyper::StatusCode;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tokio_stream::Stream;
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Request, Response, Status};
+
+mod proto {
+    tonic::include_proto!("git_history.v1");
+}
+
+use proto::git_history_server::{GitHistory, GitHistoryServer};
+
+/// The `GitHistory` service. It answers from the same indexing code as the HTTP
+/// routes, sharing their job slots and API keys.
+struct Service {
+    state: Arc<ServerState>,
+}
+
+/// Serves the gRPC API on `listener` until the process exits.
+pub async fn serve(
+    state: Arc<ServerState>,
+    listener: TcpListener,
+) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(GitHistoryServer::new(Service { state }))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await
+}
+
+impl Service {
+    /// Checks the `authorization: Bearer <key>` or `x-api-key` metadata, like the HTTP
+    /// headers of the same names.
+    fn authorize<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let value = |name| request.metadata().get(name).and_then(|v| v.to_str().ok());
+        if self
+            .state
+            .api_keys
+            .authorize_values(value("authorization"), value("x-api-key"))
+        {
+            Ok(())
+        } else {
+            Err(Status::unauthenticated("Missing or invalid API key"))
+        }
+    }
+}
+
+type CommitStream = Pin<Box<dyn Stream<Item = Result<proto::Commit, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl GitHistory for Service {
+    type IndexStream = CommitStream;
+
+    /// Sends one commit per message as the history is indexed, through the same
+    /// `ChannelSink` as a streamed `POST /git_history`, so neither the server nor any
+    /// message grows with the repository. Commits go out before duplicates could be
+    /// marked, so `duplicate_of` is unset, and `enrich` is rejected. An empty repository
+    /// is marked with `x-empty-repository: true` and a shallow one with
+    /// `x-shallow-repository: true` in the response metadata; a history cut short by
+    /// `--max-response-bytes` ends with `x-next-cursor: <sha>` in the trailers.
+    async fn index(
+        &self,
+        request: Request<proto::IndexRequest>,
+    ) -> Result<Response<CommitStream>, Status> {
+        self.authorize(&request)?;
+        let body = request_body(request.into_inner());
+        let mut options = IndexOptions::from_json(&body, &self.state.config.index_defaults)
+            .map_err(Status::invalid_argument)?;
+        if options.enrich.is_some() {
+            return Err(Status::invalid_argument(
+                "enrich needs the whole history and can't be streamed",
+            ));
+        }
+        options.max_output_bytes = Some(self.state.config.max_response_bytes);
+        let source = self
+            .state
+            .repo_source(&body)
+            .map_err(|(status, message)| match status {
+                StatusCode::FORBIDDEN => Status::permission_denied(message),
+                _ => Status::invalid_argument(message),
+            })?;
+        let permit = self.state.acquire_job().await.ok_or_else(overloaded)?;
+        let settings = self.state.config.clone.clone();
+        let (mut events, indexing) = spawn_index(source, options, settings, permit);
+
+        // Failures before the walk starts, such as a failed clone, fail the call itself
+        let Some(Event::Start(run)) = events.recv().await else {
+            return Err(match indexing.await {
+                Ok(Err(e)) => error_status(e),
+                Ok(Ok(_)) => Status::internal("Indexing ended before it started"),
+                Err(e) => Status::internal(e.to_string()),
+            });
+        };
+
+        let (sender, messages) = mpsc::channel(STREAM_BUFFER);
+        let state = self.state.clone();
+        tokio::spawn(async move {
+            let mut next_cursor = None;
+            while let Some(event) = events.recv().await {
+                let commit = match event {
+                    Event::Start(_) => continue,
+                    Event::Commit(commit) => proto::Commit::from(*commit),
+                    Event::Finish(run) => {
+                        state
+                            .response_sizes
+                            .record(run.output_bytes, run.next_cursor.is_some());
+                        next_cursor = run.next_cursor;
+                        continue;
+                    }
+                };
+                // Dropping `events` on the way out makes the sink fail and the walk stop
+                if sender.send(Ok(commit)).await.is_err() {
+                    return;
+                }
+            }
+            let end = match indexing.await {
+                Ok(Ok(_)) => {
+                    let Some(cursor) = next_cursor else {
+                        return;
+                    };
+                    let mut trailers = MetadataMap::new();
+                    trailers.insert("x-next-cursor", cursor.parse().unwrap());
+                    // tonic sends the metadata of a closing status as trailers
+                    Status::with_metadata(Code::Ok, "", trailers)
+                }
+                Ok(Err(e)) => error_status(e),
+                Err(e) => Status::internal(e.to_string()),
+            };
+            let _ = sender.send(Err(end)).await;
+        });
+
+        let mut response = Response::new(Box::pin(ReceiverStream::new(messages)) as CommitStream);
+        if run.empty_repository {
+            response
+                .metadata_mut()
+                .insert("x-empty-repository", "true".parse().unwrap());
+        }
+        if run.shallow {
+            response
+                .metadata_mut()
+                .insert("x-shallow-repository", "true".parse().unwrap());
+        }
+        Ok(response)
+    }
+
+    async fn get_commit(
+        &self,
+        request: Request<proto::GetCommitRequest>,
+    ) -> Result<Response<proto::Commit>, Status> {
+        self.authorize(&request)?;
+        let proto::GetCommitRequest {
+            repo_url,
+            sha,
+            redact,
+            redact_salt,
+        } = request.into_inner();
+        if repo_url.is_empty() || sha.is_empty() {
+            return Err(Status::invalid_argument("repo_url and sha are required"));
+        }
+        let options = IndexOptions::from_json(
+            &serde_json::json!({
+                "redact": redact,
+                "redact_salt": redact_salt,
+            }),
+            &self.state.config.index_defaults,
+        )
+        .map_err(Status::invalid_argument)?;
+        let _permit = self.state.acquire_job().await.ok_or_else(overloaded)?;
+        let settings = &self.state.config.clone;
+        let commit = with_cloned_repo(&repo_url, CloneKind::Checkout, settings, move |path| {
+            let mut commit = show_commit(path, &sha)?;
+            Redactor::new(&options).commit(&mut commit);
+            Ok(commit)
+        })
+        .await
+        .map_err(error_status)?;
+        Ok(Response::new(commit.into()))
+    }
+
+    async fn health(
+        &self,
+        _request: Request<proto::HealthRequest>,
+    ) -> Result<Response<proto::HealthResponse>, Status> {
+        Ok(Response::new(proto::HealthResponse {
+            status: "ok".to_string(),
+            max_concurrent_jobs: self.state.config.max_concurrent_jobs as u64,
+            in_flight_jobs: self.state.in_flight() as u64,
+            queued_jobs: self.state.queued.load(Ordering::SeqCst) as u64,
+            max_response_bytes: self.state.config.max_response_bytes,
+            largest_response_bytes: self.state.response_sizes.largest(),
+            last_response_bytes: self.state.response_sizes.last(),
+            truncated_responses: self.state.response_sizes.truncated(),
+        }))
+    }
+}
+
+/// The request as the JSON body of `POST /git_history`, so both servers read their
+/// options through `IndexOptions::from_json` and `ServerState::repo_source`.
+fn request_body(request: proto::IndexRequest) -> serde_json::Value {
+    serde_json::json!({
+        "repo_url": request.repo_url,
+        "repo_path": request.repo_path,
+        "since": request.since,
+        "until": request.until,
+        "paths": request.paths,
+        "author": request.author,
+        "all": request.all,
+        "exclude_paths": request.exclude_paths,
+        "recurse_submodules": request.recurse_submodules,
+        "include_generated": request.include_generated,
+        "context_lines": request.context_lines,
+        "ignore_whitespace": request.ignore_whitespace,
+        "ignore_whitespace_change": request.ignore_whitespace_change,
+        "diff_format": request.diff_format,
+        "word_diff": request.word_diff,
+        "word_diff_max_bytes": request.word_diff_max_bytes,
+        "max_diff_bytes": request.max_diff_bytes,
+        "include_diffs": request.include_diffs,
+        "stats_only": request.stats_only,
+        "flag_large_additions": request.flag_large_additions,
+        "boundary_diff": request.boundary_diff,
+        "cursor": request.cursor,
+        "rename_threshold": request.rename_threshold,
+        "copy_threshold": request.copy_threshold,
+        "rename_limit": request.rename_limit,
+        "sha_length": request.sha_length,
+        "enrich": request.enrich,
+        "github_token": request.github_token,
+        "gitlab_url": request.gitlab_url,
+        "gitlab_token": request.gitlab_token,
+        "redact": request.redact,
+        "redact_salt": request.redact_salt,
+    })
+}
+
+fn overloaded() -> Status {
+    Status::resource_exhausted("Too many concurrent jobs, retry later")
+}
+
+/// The gRPC code closest to the status of the HTTP error response.
+fn error_status(err: CustomError) -> Status {
+    let message = format!("Error: {}", err);
+    match err.http_status() {
+        StatusCode::NOT_FOUND => Status::not_found(message),
+        StatusCode::BAD_REQUEST => Status::invalid_argument(message),
+        StatusCode::UNPROCESSABLE_ENTITY => Status::failed_precondition(message),
+        StatusCode::BAD_GATEWAY => Status::unavailable(message),
+        StatusCode::GATEWAY_TIMEOUT => Status::deadline_exceeded(message),
+        StatusCode::INSUFFICIENT_STORAGE => Status::resource_exhausted(message),
+        _ => Status::internal(message),
+    }
+}
+
+impl From<CommitHistory> for proto::Commit {
+    fn from(commit: CommitHistory) -> Self {
+        proto::Commit {
+            commit_id: commit.commit_id,
+            commit_id_short: commit.commit_id_short,
+            parent_ids: commit.parent_ids,
+            parent_ids_short: commit.parent_ids_short,
+            author: commit.author,
+            commit_message: commit.commit_message,
+            message_encoding: commit.message_encoding,
+            message_is_lossy: commit.message_is_lossy,
+            pl_and_issue_id: commit.pl_and_issue_id,
+            patch_id: commit.patch_id,
+            duplicate_of: commit.duplicate_of,
+            all_changes_excluded: commit.all_changes_excluded,
+            whitespace_only: commit.whitespace_only,
+            graft_boundary: commit.graft_boundary,
+            rename_detection_skipped: commit.rename_detection_skipped,
+            pull_request: commit.pull_request.map(|pr| proto::PullRequest {
+                number: pr.number,
+                title: pr.title,
+                body: pr.body,
+                author: pr.author,
+                labels: pr.labels,
+                merged_at: pr.merged_at,
+                url: pr.url,
+            }),
+            issue: commit.issue.map(|issue| proto::Issue {
+                number: issue.number,
+                title: issue.title,
+                body: issue.body,
+                author: issue.author,
+                labels: issue.labels,
+                closed_at: issue.closed_at,
+                url: issue.url,
+            }),
+            stats: commit.stats.map(proto::CommitStats::from),
+            large_files: commit
+                .large_files
+                .into_iter()
+                .map(|file| proto::LargeFile {
+                    path: file.path,
+                    size: file.size,
+                })
+                .collect(),
+            git_diff: commit.git_diff.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<CommitStats> for proto::CommitStats {
+    fn from(stats: CommitStats) -> Self {
+        proto::CommitStats {
+            files_changed: stats.files_changed as u64,
+            insertions: stats.insertions as u64,
+            deletions: stats.deletions as u64,
+        }
+    }
+}
+
+impl From<CommitDiff> for proto::CommitDiff {
+    fn from(diff: CommitDiff) -> Self {
+        proto::CommitDiff {
+            file: diff.file,
+            diff: diff.diff,
+            additions: diff.additions.map(|n| n as u64),
+            deletions: diff.deletions.map(|n| n as u64),
+            old_mode: diff.old_mode,
+            new_mode: diff.new_mode,
+            old_blob_id: diff.old_blob_id,
+            new_blob_id: diff.new_blob_id,
+            lfs: diff.lfs.map(|lfs| proto::LfsChange {
+                old_oid: lfs.old_oid,
+                new_oid: lfs.new_oid,
+                old_size: lfs.old_size,
+                new_size: lfs.new_size,
+            }),
+            kind: diff.kind,
+            old_sha: diff.old_sha,
+            new_sha: diff.new_sha,
+            submodule_commits: diff.submodule_commits.unwrap_or_default(),
+            generated: diff.generated,
+            diff_too_large: diff.diff_too_large,
+            word_diff: diff.word_diff,
+            status: diff.status,
+            old_file: diff.old_file,
+            similarity: diff.similarity.map(u32::from),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use crate::identities::Identities;
+use crate::options::IndexOptions;
+use crate::{for_each_commit, format_git_time, CustomError};
+use git2::{DiffFindOptions, Patch, Repository};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Default for `--top`.
+pub const DEFAULT_TOP: usize = 50;
+
+#[derive(Serialize)]
+pub struct Hotspot {
+    /// Latest name of the file; changes made under earlier names count towards it
+    path: String,
+    commits: usize,
+    insertions: usize,
+    deletions: usize,
+    authors: usize,
+    last_changed: String,
+}
+
+#[derive(Default)]
+struct Tally {
+    commits: usize,
+    insertions: usize,
+    deletions: usize,
+    authors: HashSet<String>,
+    last: Option<git2::Time>,
+}
+
+/// Per-file churn over the filtered history, sorted by the number of commits touching
+/// each file. Renames are followed, so a file keeps its history across `git mv`.
+/// Authors are told apart by their email, canonicalized through .mailmap and
+/// `--identity-map`.
+pub fn hotspots(
+    repo_path: &Path,
+    options: &IndexOptions,
+    top: usize,
+) -> Result<Vec<Hotspot>, CustomError> {
+    let repo = Repository::open(repo_path)?;
+    let identities = Identities::load(&repo, options)?;
+
+    let mut tallies: HashMap<String, Tally> = HashMap::new();
+    // Earlier names of renamed files, mapped to their latest name. The walk goes from
+    // new to old, so a rename is seen before the changes made under the old name.
+    let mut renamed: HashMap<String, String> = HashMap::new();
+
+    for_each_commit(&repo, options, |commit, diff, _| {
+        let author = identities.author(commit)?;
+        let email = String::from_utf8_lossy(author.email_bytes()).to_lowercase();
+        let when = commit.time();
+
+        diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+        for idx in 0..diff.deltas().len() {
+            let delta = diff.get_delta(idx).expect("index within deltas");
+            let Some(path) = delta.new_file().path().or(delta.old_file().path()) else {
+                continue;
+            };
+            let path = path.to_string_lossy().to_string();
+            let current = renamed.get(&path).cloned().unwrap_or(path);
+            if delta.status() == git2::Delta::Renamed {
+                if let Some(old) = delta.old_file().path() {
+                    renamed.insert(old.to_string_lossy().to_string(), current.clone());
+                }
+            }
+
+            let (insertions, deletions) = match Patch::from_diff(diff, idx)? {
+                Some(patch) => {
+                    let (_, insertions, deletions) = patch.line_stats()?;
+                    (insertions, deletions)
+                }
+                None => (0, 0),
+            };
+            let tally = tallies.entry(current).or_default();
+            tally.commits += 1;
+            tally.insertions += insertions;
+            tally.deletions += deletions;
+            tally.authors.insert(email.clone());
+            if tally
+                .last
+                .is_none_or(|last| when.seconds() > last.seconds())
+            {
+                tally.last = Some(when);
+            }
+        }
+        Ok(())
+    })?;
+
+    let mut hotspots: Vec<Hotspot> = tallies
+        .into_iter()
+        .map(|(path, tally)| Hotspot {
+            path,
+            commits: tally.commits,
+            insertions: tally.insertions,
+            deletions: tally.deletions,
+            authors: tally.authors.len(),
+            last_changed: tally.last.map(format_git_time).unwrap_or_default(),
+        })
+        .collect();
+
+    hotspots.sort_by(|a, b| {
+        b.commits
+            .cmp(&a.commits)
+            .then_with(|| (b.insertions + b.deletions).cmp(&(a.insertions + a.deletions)))
+            .then_with(|| a.path.cmp(&b.path))
+    });
+    hotspots.truncate(top);
+
+    Ok(hotspots)
+}
+
+/// Renders hotspots as an aligned text table.
+pub fn table(hotspots: &[Hotspot]) -> String {
+    let mut out = format!(
+        "{:>7} {:>9} {:>9} {:>7}  {:<25}  {}\n",
+        "COMMITS", "ADDED", "REMOVED", "AUTHORS", "LAST CHANGED", "PATH"
+    );
+    for hotspot in hotspots {
+        out.push_str(&format!(
+            "{:>7} {:>9} {:>9} {:>7}  {:<25}  {}\n",
+            hotspot.commits,
+            hotspot.insertions,
+            hotspot.deletions,
+            hotspot.authors,
+            hotspot.last_changed,
+            hotspot.path
+        ));
+    }
+    out
+}
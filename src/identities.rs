@@ -0,0 +1,568 @@
+use crate::options::IndexOptions;
+use crate::redact::Redactor;
+use crate::{for_each_commit, CustomError};
+use git2::{Mailmap, Repository, Signature};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+/// An `--identity-map` file: corrections applied on top of .mailmap, e.g.
+///
+/// ```toml
+/// [[identity]]
+/// name = "John Smith"
+/// email = "john@example.com"
+/// aliases = ["J. Smith <jsmith@example.org>", "john.smith@old.example.com", "jsmith"]
+/// ```
+///
+/// An alias is `Name <email>` (both must match), an email, or a name.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IdentityFile {
+    #[serde(default)]
+    identity: Vec<MappedIdentity>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MappedIdentity {
+    name: String,
+    email: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+}
+
+enum Alias {
+    Both { name: String, email: String },
+    Email(String),
+    Name(String),
+}
+
+impl Alias {
+    fn parse(raw: &str) -> Alias {
+        let raw = raw.trim();
+        match raw.strip_suffix('>').and_then(|rest| rest.split_once('<')) {
+            Some((name, email)) if !name.trim().is_empty() => Alias::Both {
+                name: name.trim().to_lowercase(),
+                email: email.trim().to_lowercase(),
+            },
+            Some((_, email)) => Alias::Email(email.trim().to_lowercase()),
+            None if raw.contains('@') => Alias::Email(raw.to_lowercase()),
+            None => Alias::Name(raw.to_lowercase()),
+        }
+    }
+
+    fn matches(&self, name: &str, email: &str) -> bool {
+        match self {
+            Alias::Both {
+                name: alias_name,
+                email: alias_email,
+            } => *alias_name == name && *alias_email == email,
+            Alias::Email(alias_email) => *alias_email == email,
+            Alias::Name(alias_name) => *alias_name == name,
+        }
+    }
+}
+
+/// Canonicalizes authors through the repository's .mailmap and, with
+/// `--identity-map`, the corrections listed there, which win over .mailmap. With
+/// `--redact`, the canonical identities come out redacted.
+pub struct Identities {
+    mailmap: Option<Mailmap>,
+    /// Canonical name and email for each alias, in file order
+    mapped: Vec<(Alias, String, String)>,
+    redactor: Redactor,
+}
+
+impl Identities {
+    pub fn load(repo: &Repository, options: &IndexOptions) -> Result<Self, CustomError> {
+        let mut mapped = Vec::new();
+        if let Some(path) = &options.identity_map {
+            let invalid =
+                |e: String| CustomError::InvalidRequest(format!("{}: {}", path.display(), e));
+            let raw = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+            let file: IdentityFile = toml::from_str(&raw).map_err(|e| invalid(e.to_string()))?;
+            for identity in file.identity {
+                if identity.name.trim().is_empty() || identity.email.trim().is_empty() {
+                    return Err(invalid(
+                        "every identity needs a name and an email".to_string(),
+                    ));
+                }
+                // The canonical identity maps to itself, so corrections can target it too
+                let own = format!("{} <{}>", identity.name, identity.email);
+                for alias in identity.aliases.iter().chain([&own]) {
+                    mapped.push((
+                        Alias::parse(alias),
+                        identity.name.clone(),
+                        identity.email.clone(),
+                    ));
+                }
+            }
+        }
+        Ok(Identities {
+            mailmap: repo.mailmap().ok(),
+            mapped,
+            redactor: Redactor::new(options),
+        })
+    }
+
+    /// Whether an `--identity-map` was given.
+    pub fn is_mapped(&self) -> bool {
+        !self.mapped.is_empty()
+    }
+
+    /// The canonical author of `commit`, redacted as the aggregations report it.
+    pub fn author(&self, commit: &git2::Commit) -> Result<Signature<'static>, CustomError> {
+        let author = self.resolve(&commit.author())?;
+        if !self.redactor.is_enabled() {
+            return Ok(author);
+        }
+        let mut name = String::from_utf8_lossy(author.name_bytes()).to_string();
+        let mut email = String::from_utf8_lossy(author.email_bytes()).to_string();
+        self.redactor.name(&mut name);
+        self.redactor.email(&mut email);
+        Ok(Signature::new(&name, &email, &author.when())?)
+    }
+
+    /// The canonical form of `signature`: an identity map entry matching it as recorded
+    /// or as .mailmap rewrites it, else the .mailmap rewrite.
+    pub fn resolve(&self, signature: &Signature) -> Result<Signature<'static>, CustomError> {
+        let mailmapped = match &self.mailmap {
+            Some(mailmap) => mailmap.resolve_signature(signature)?,
+            None => signature.to_owned(),
+        };
+        for candidate in [signature, &mailmapped] {
+            if let Some((name, email)) = self.lookup(candidate) {
+                return Ok(Signature::new(name, email, &signature.when())?);
+            }
+        }
+        Ok(mailmapped)
+    }
+
+    pub fn redactor(&self) -> &Redactor {
+        &self.redactor
+    }
+
+    fn lookup(&self, signature: &Signature) -> Option<(&str, &str)> {
+        let name = String::from_utf8_lossy(signature.name_bytes()).to_lowercase();
+        let email = String::from_utf8_lossy(signature.email_bytes()).to_lowercase();
+        self.mapped
+            .iter()
+            .find(|(alias, _, _)| alias.matches(name.trim(), email.trim()))
+            .map(|(_, name, email)| (name.as_str(), email.as_str()))
+    }
+}
+
+#[derive(Serialize)]
+pub struct Identity {
+    /// Name and email as recorded in the commits
+    name: String,
+    email: String,
+    commits: usize,
+    /// What .mailmap and `--identity-map` turn it into
+    canonical: Canonical,
+}
+
+#[derive(Serialize)]
+struct Canonical {
+    name: String,
+    email: String,
+}
+
+/// Identities that are likely the same person, for review. `reasons` says why they
+/// were grouped and is empty for identities that matched nothing.
+#[derive(Serialize)]
+pub struct IdentityGroup {
+    commits: usize,
+    reasons: Vec<String>,
+    identities: Vec<Identity>,
+}
+
+/// Every distinct author name and email pair of the filtered history, grouped with the
+/// pairs that look like the same person: mapped to the same identity, sharing an email
+/// (ignoring case and `+tags`) or a name (ignoring case and punctuation), or a name
+/// abbreviating exactly one other (`J. Smith` and `John Smith`). Groups with the most
+/// commits come first.
+pub fn authors(
+    repo_path: &Path,
+    options: &IndexOptions,
+) -> Result<Vec<IdentityGroup>, CustomError> {
+    let repo = Repository::open(repo_path)?;
+    let identities = Identities::load(&repo, options)?;
+
+    let mut counts: HashMap<(String, String), (usize, Canonical)> = HashMap::new();
+    for_each_commit(&repo, options, |commit, _, _| {
+        let author = commit.author();
+        let key = (
+            String::from_utf8_lossy(author.name_bytes()).to_string(),
+            String::from_utf8_lossy(author.email_bytes()).to_string(),
+        );
+        if let Some((commits, _)) = counts.get_mut(&key) {
+            *commits += 1;
+            return Ok(());
+        }
+        let canonical = identities.resolve(&author)?;
+        let mut canonical = Canonical {
+            name: String::from_utf8_lossy(canonical.name_bytes()).to_string(),
+            email: String::from_utf8_lossy(canonical.email_bytes()).to_string(),
+        };
+        identities.redactor.name(&mut canonical.name);
+        identities.redactor.email(&mut canonical.email);
+        counts.insert(key, (1, canonical));
+        Ok(())
+    })?;
+
+    let mut observed: Vec<Identity> = counts
+        .into_iter()
+        .map(|((name, email), (commits, canonical))| Identity {
+            name,
+            email,
+            commits,
+            canonical,
+        })
+        .collect();
+    // Sorted and grouped as recorded, so redaction changes nothing but the strings
+    let redactor = &identities.redactor;
+    observed.sort_by(|a, b| {
+        b.commits
+            .cmp(&a.commits)
+            .then_with(|| a.name.cmp(&b.name))
+            .then_with(|| a.email.cmp(&b.email))
+    });
+
+    let names: Vec<String> = observed.iter().map(|id| normalize_name(&id.name)).collect();
+    let mut groups = Groups::new(observed.len());
+    let mut link_by = |key: &dyn Fn(usize) -> Option<String>, reason: &dyn Fn(&str) -> String| {
+        let mut first: HashMap<String, usize> = HashMap::new();
+        for idx in 0..observed.len() {
+            let Some(key) = key(idx) else {
+                continue;
+            };
+            match first.get(&key) {
+                Some(&other) => groups.join(other, idx, reason(&key)),
+                None => {
+                    first.insert(key, idx);
+                }
+            }
+        }
+    };
+    link_by(
+        &|idx| {
+            let canonical = &observed[idx].canonical;
+            Some(format!("{} <{}>", canonical.name, canonical.email))
+        },
+        &|key| format!("mapped to {}", key),
+    );
+    link_by(
+        &|idx| Some(normalize_email(&observed[idx].email)).filter(|email| !email.is_empty()),
+        &|key| {
+            let mut email = key.to_string();
+            redactor.email(&mut email);
+            format!("same email {}", email)
+        },
+    );
+    link_by(
+        &|idx| Some(names[idx].clone()).filter(|name| !name.is_empty()),
+        &|key| {
+            let mut name = key.to_string();
+            redactor.name(&mut name);
+            format!("same name {}", name)
+        },
+    );
+
+    // An abbreviated name is only linked when it fits a single full name, so that
+    // `J. Smith` doesn't pull John and Jane Smith into one group
+    let distinct: BTreeSet<&str> = names.iter().map(String::as_str).collect();
+    for (idx, short) in names.iter().enumerate() {
+        let full: Vec<&str> = distinct
+            .iter()
+            .copied()
+            .filter(|full| *full != short && abbreviates(short, full))
+            .collect();
+        if let [full] = full[..] {
+            for (other, name) in names.iter().enumerate() {
+                if name == full {
+                    let [mut short, mut full] = [short.clone(), full.to_string()];
+                    redactor.name(&mut short);
+                    redactor.name(&mut full);
+                    groups.join(idx, other, format!("initials {} ~ {}", short, full));
+                }
+            }
+        }
+    }
+
+    let mut by_root: HashMap<usize, IdentityGroup> = HashMap::new();
+    let mut order = Vec::new();
+    for (idx, mut identity) in observed.into_iter().enumerate() {
+        let root = groups.root(idx);
+        let group = by_root.entry(root).or_insert_with(|| {
+            order.push(root);
+            IdentityGroup {
+                commits: 0,
+                reasons: groups
+                    .reasons
+                    .remove(&root)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .collect(),
+                identities: Vec::new(),
+            }
+        });
+        group.commits += identity.commits;
+        redactor.name(&mut identity.name);
+        redactor.email(&mut identity.email);
+        group.identities.push(identity);
+    }
+    let mut result: Vec<IdentityGroup> = order
+        .into_iter()
+        .filter_map(|root| by_root.remove(&root))
+        .collect();
+    // Identities are sorted, so ties keep the order of their most active member
+    result.sort_by_key(|group| std::cmp::Reverse(group.commits));
+    Ok(result)
+}
+
+/// Union-find over the observed identities, remembering why sets were joined.
+struct Groups {
+    parent: Vec<usize>,
+    reasons: HashMap<usize, BTreeSet<String>>,
+}
+
+impl Groups {
+    fn new(len: usize) -> Self {
+        Groups {
+            parent: (0..len).collect(),
+            reasons: HashMap::new(),
+        }
+    }
+
+    fn root(&mut self, mut idx: usize) -> usize {
+        while self.parent[idx] != idx {
+            self.parent[idx] = self.parent[self.parent[idx]];
+            idx = self.parent[idx];
+        }
+        idx
+    }
+
+    /// Joins the groups of `a` and `b`, keeping the smaller index, i.e. the more
+    /// active identity, as the root.
+    fn join(&mut self, a: usize, b: usize, reason: String) {
+        let (a, b) = (self.root(a), self.root(b));
+        let (root, child) = (a.min(b), a.max(b));
+        if root != child {
+            self.parent[child] = root;
+            let moved = self.reasons.remove(&child).unwrap_or_default();
+            self.reasons.entry(root).or_default().extend(moved);
+        }
+        self.reasons.entry(root).or_default().insert(reason);
+    }
+}
+
+/// Lowercase, with the `+tag` of plus-addressing removed. GitHub's
+/// `<id>+<user>@users.noreply.github.com` becomes `<id>@…`, which is still unique.
+fn normalize_email(email: &str) -> String {
+    let email = email.trim().to_lowercase();
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            let local = local.split('+').next().unwrap_or(local);
+            format!("{}@{}", local, domain)
+        }
+        None => email,
+    }
+}
+
+/// Lowercase words without punctuation: `Smith, J.` and `smith j` are the same.
+fn normalize_name(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Whether `short` is `full` with some words cut to their initial, e.g. `j smith` and
+/// `john smith`. The last word has to match in full.
+fn abbreviates(short: &str, full: &str) -> bool {
+    let short: Vec<&str> = short.split(' ').collect();
+    let full: Vec<&str> = full.split(' ').collect();
+    if short.len() != full.len() || short.len() < 2 || short.last() != full.last() {
+        return false;
+    }
+    let mut abbreviated = false;
+    for (s, f) in short.iter().zip(&full) {
+        if s == f {
+            continue;
+        }
+        if s.chars().count() == 1 && f.starts_with(s) {
+            abbreviated = true;
+        } else {
+            return false;
+        }
+    }
+    abbreviated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_repo::TestRepo;
+
+    /// One commit per `(name, email)`.
+    fn history(authors: &[(&str, &str)]) -> TestRepo {
+        let repo = TestRepo::new();
+        for (n, (name, email)) in authors.iter().enumerate() {
+            repo.write("log.txt", format!("{}\n", n)).commit_as(
+                name,
+                email,
+                &format!("Change {}", n),
+            );
+        }
+        repo
+    }
+
+    /// The groups of `repo` as sorted lists of `name <email>`.
+    fn grouped(repo: &TestRepo, options: &IndexOptions) -> Vec<Vec<String>> {
+        let mut groups: Vec<Vec<String>> = authors(repo.path(), options)
+            .unwrap()
+            .iter()
+            .map(|group| {
+                let mut ids: Vec<String> = group
+                    .identities
+                    .iter()
+                    .map(|id| format!("{} <{}>", id.name, id.email))
+                    .collect();
+                ids.sort();
+                ids
+            })
+            .collect();
+        groups.sort();
+        groups
+    }
+
+    #[test]
+    fn normalizes_emails_and_names() {
+        let cases = [
+            ("Ann+Work@Example.com", "ann@example.com"),
+            ("ann+a+b@example.com", "ann@example.com"),
+            (
+                "12345+octo@users.noreply.github.com",
+                "12345@users.noreply.github.com",
+            ),
+            ("not-an-email", "not-an-email"),
+        ];
+        for (email, normalized) in cases {
+            assert_eq!(normalize_email(email), normalized, "{}", email);
+        }
+        assert_eq!(normalize_name("Smith, J."), "smith j");
+        assert_eq!(normalize_name("  John   SMITH "), "john smith");
+        assert_eq!(normalize_name("Jean-Luc Picard"), "jean luc picard");
+    }
+
+    #[test]
+    fn abbreviations_need_initials_and_the_full_last_name() {
+        let cases = [
+            ("j smith", "john smith", true),
+            ("j r smith", "john ronald smith", true),
+            ("j ronald smith", "john ronald smith", true),
+            ("john smith", "john smith", false),
+            ("j smith", "jane smythe", false),
+            ("john s", "john smith", false),
+            ("jo smith", "john smith", false),
+            ("j smith", "john ronald smith", false),
+            ("j", "john", false),
+        ];
+        for (short, full, expected) in cases {
+            assert_eq!(abbreviates(short, full), expected, "{} ~ {}", short, full);
+        }
+    }
+
+    #[test]
+    fn groups_initials_and_plus_addresses() {
+        let repo = history(&[
+            ("John Smith", "john@example.com"),
+            ("J. Smith", "jsmith@other.org"),
+            ("Ann Lee", "ann+work@example.com"),
+            ("ann", "ANN@example.com"),
+            ("Bob Stone", "bob@example.com"),
+        ]);
+        assert_eq!(
+            grouped(&repo, &IndexOptions::default()),
+            [
+                vec!["Ann Lee <ann+work@example.com>", "ann <ANN@example.com>"],
+                vec!["Bob Stone <bob@example.com>"],
+                vec![
+                    "J. Smith <jsmith@other.org>",
+                    "John Smith <john@example.com>"
+                ],
+            ]
+        );
+
+        let groups = authors(repo.path(), &IndexOptions::default()).unwrap();
+        let smiths = groups
+            .iter()
+            .find(|group| group.identities.len() == 2 && group.identities[0].name.contains("Smith"))
+            .unwrap();
+        assert_eq!(smiths.reasons, ["initials j smith ~ john smith"]);
+        assert_eq!(smiths.commits, 2);
+    }
+
+    #[test]
+    fn ambiguous_initials_stay_apart() {
+        let repo = history(&[
+            ("John Smith", "john@example.com"),
+            ("Jane Smith", "jane@example.com"),
+            ("J. Smith", "js@example.org"),
+        ]);
+        assert_eq!(
+            grouped(&repo, &IndexOptions::default()),
+            [
+                vec!["J. Smith <js@example.org>"],
+                vec!["Jane Smith <jane@example.com>"],
+                vec!["John Smith <john@example.com>"],
+            ]
+        );
+    }
+
+    #[test]
+    fn same_names_group_across_emails_and_the_identity_map_wins() {
+        let repo = history(&[
+            ("John-Smith", "john@home.example"),
+            ("john smith", "john@work.example"),
+            ("Build Bot", "ci@example.com"),
+            ("jenkins", "jenkins@example.com"),
+        ]);
+        assert_eq!(
+            grouped(&repo, &IndexOptions::default()),
+            [
+                vec!["Build Bot <ci@example.com>"],
+                vec![
+                    "John-Smith <john@home.example>",
+                    "john smith <john@work.example>"
+                ],
+                vec!["jenkins <jenkins@example.com>"],
+            ]
+        );
+
+        let map = repo.path().join("identities.toml");
+        std::fs::write(
+            &map,
+            "[[identity]]\nname = \"CI\"\nemail = \"ci@example.com\"\naliases = [\"jenkins\", \"Build Bot\"]\n",
+        )
+        .unwrap();
+        let options = IndexOptions {
+            identity_map: Some(map),
+            ..IndexOptions::default()
+        };
+        let groups = authors(repo.path(), &options).unwrap();
+        let bots = groups
+            .iter()
+            .find(|group| group.reasons == ["mapped to CI <ci@example.com>"])
+            .unwrap();
+        let names: BTreeSet<&str> = bots.identities.iter().map(|id| id.name.as_str()).collect();
+        assert_eq!(names, BTreeSet::from(["Build Bot", "jenkins"]));
+        assert!(bots
+            .identities
+            .iter()
+            .all(|id| id.canonical.name == "CI" && id.canonical.email == "ci@example.com"));
+    }
+}
@@ -0,0 +1,239 @@
+use crate::{CommitDiff, CustomError};
+use git2::{Delta, Oid, Repository};
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::HashSet;
+
+/// Pointer files are small text files; the spec caps them at 1024 bytes.
+const MAX_POINTER_SIZE: usize = 1024;
+
+/// The objects a changed Git LFS pointer file refers to. A side that wasn't a pointer
+/// (or didn't exist) is `null`.
+#[derive(Clone, Serialize, JsonSchema)]
+pub struct LfsChange {
+    pub old_oid: Option<String>,
+    pub new_oid: Option<String>,
+    pub old_size: Option<u64>,
+    pub new_size: Option<u64>,
+}
+
+/// A blob above `--flag-large-additions` that a commit introduced.
+#[derive(Clone, Serialize, JsonSchema)]
+pub struct LargeFile {
+    pub path: String,
+    pub size: u64,
+}
+
+struct Pointer {
+    oid: String,
+    size: u64,
+}
+
+/// Replaces the patch of every LFS pointer file in `entries` with an `lfs` object
+/// describing the objects it points to, since the pointer text says little about the
+/// change. Line counts are kept, so they still add up to git's.
+pub fn mark_lfs_pointers(repo: &Repository, entries: &mut [CommitDiff]) -> Result<(), CustomError> {
+    for entry in entries.iter_mut().filter(|entry| entry.kind.is_none()) {
+        let old = pointer(repo, entry.old_blob_id.as_deref())?;
+        let new = pointer(repo, entry.new_blob_id.as_deref())?;
+        if old.is_none() && new.is_none() {
+            continue;
+        }
+        entry.lfs = Some(LfsChange {
+            old_oid: old.as_ref().map(|p| p.oid.clone()),
+            new_oid: new.as_ref().map(|p| p.oid.clone()),
+            old_size: old.map(|p| p.size),
+            new_size: new.map(|p| p.size),
+        });
+        entry.diff.clear();
+        entry.word_diff = None;
+    }
+    Ok(())
+}
+
+/// Parses the blob as an LFS pointer (`version https://git-lfs.github.com/spec/v1`,
+/// `oid sha256:<hex>`, `size <bytes>`). Larger blobs are ruled out from their header,
+/// without reading them.
+fn pointer(repo: &Repository, blob_id: Option<&str>) -> Result<Option<Pointer>, CustomError> {
+    let Some(id) = blob_id.and_then(|id| Oid::from_str(id).ok()) else {
+        return Ok(None);
+    };
+    let (size, _) = repo.odb()?.read_header(id)?;
+    if size > MAX_POINTER_SIZE {
+        return Ok(None);
+    }
+    let blob = repo.find_blob(id)?;
+    let Ok(text) = std::str::from_utf8(blob.content()) else {
+        return Ok(None);
+    };
+    let mut lines = text.lines();
+    let is_pointer = lines.next().is_some_and(|version| {
+        version == "version https://git-lfs.github.com/spec/v1"
+            || version == "version https://hawser.github.com/spec/v1"
+    });
+    if !is_pointer {
+        return Ok(None);
+    }
+    let mut oid = None;
+    let mut size = None;
+    for line in lines {
+        if let Some(value) = line.strip_prefix("oid ") {
+            oid = Some(value.to_string());
+        } else if let Some(value) = line.strip_prefix("size ") {
+            size = value.parse().ok();
+        }
+    }
+    Ok(oid.zip(size).map(|(oid, size)| Pointer { oid, size }))
+}
+
+/// Files of `diff` whose new blob is larger than `threshold` bytes and differs from
+/// the old one, so a large file is reported by the commit that brought it in, not by
+/// every later commit renaming it or changing its mode.
+pub fn large_additions(
+    repo: &Repository,
+    diff: &git2::Diff,
+    threshold: u64,
+) -> Result<Vec<LargeFile>, CustomError> {
+    let odb = repo.odb()?;
+    // Without rename detection a move is a deletion plus an addition of the same blob
+    let old_ids: HashSet<Oid> = diff.deltas().map(|delta| delta.old_file().id()).collect();
+    let mut large = Vec::new();
+    for delta in diff.deltas() {
+        let new = delta.new_file();
+        if matches!(delta.status(), Delta::Deleted)
+            || new.id().is_zero()
+            || old_ids.contains(&new.id())
+            || new.mode() == git2::FileMode::Commit
+        {
+            continue;
+        }
+        let (size, _) = odb.read_header(new.id())?;
+        if size as u64 > threshold {
+            large.push(LargeFile {
+                path: new
+                    .path()
+                    .map(|p| p.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                size: size as u64,
+            });
+        }
+    }
+    large.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(large)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::options::IndexOptions;
+    use crate::test_repo::TestRepo;
+    use crate::{index_repo, CommitHistory};
+
+    fn pointer_text(oid: char, size: u64) -> String {
+        format!(
+            "version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize {}\n",
+            oid.to_string().repeat(64),
+            size
+        )
+    }
+
+    /// The indexed commits of `repo`, oldest first.
+    fn commits(repo: &TestRepo, options: &IndexOptions) -> Vec<CommitHistory> {
+        let mut commits = index_repo(repo.path(), options).unwrap().commits;
+        commits.reverse();
+        commits
+    }
+
+    #[test]
+    fn lfs_pointers_are_described_instead_of_diffed() {
+        let repo = TestRepo::new();
+        repo.write("model.bin", pointer_text('a', 12345))
+            .write("notes.txt", "version 2 of the notes\n")
+            .commit("Track the model");
+        repo.write("model.bin", pointer_text('b', 999))
+            .commit("Retrain the model");
+        repo.write("model.bin", "no longer in LFS\n")
+            .commit("Stop tracking the model");
+
+        let commits = commits(&repo, &IndexOptions::default());
+        let lfs: Vec<_> = commits
+            .iter()
+            .map(|commit| {
+                let file = &commit.git_diff[0];
+                assert_eq!(file.file, "model.bin");
+                assert_eq!(file.diff, "", "{}", commit.commit_message);
+                let lfs = file.lfs.as_ref().unwrap();
+                (
+                    lfs.old_oid.as_deref().map(|oid| &oid[..8]),
+                    lfs.new_oid.as_deref().map(|oid| &oid[..8]),
+                    lfs.old_size,
+                    lfs.new_size,
+                )
+            })
+            .collect();
+        assert_eq!(
+            lfs,
+            [
+                (None, Some("sha256:a"), None, Some(12345)),
+                (Some("sha256:a"), Some("sha256:b"), Some(12345), Some(999)),
+                (Some("sha256:b"), None, Some(999), None),
+            ]
+        );
+        // Line counts are still git's
+        assert_eq!(commits[1].git_diff[0].additions, Some(2));
+        assert_eq!(commits[1].git_diff[0].deletions, Some(2));
+
+        // Text that merely starts like a pointer is diffed as usual
+        let notes = &commits[0].git_diff[1];
+        assert_eq!(notes.file, "notes.txt");
+        assert!(notes.lfs.is_none());
+        assert!(notes.diff.contains("version 2 of the notes"));
+    }
+
+    #[test]
+    fn flags_large_blobs_in_the_commit_that_added_them() {
+        let repo = TestRepo::new();
+        let blob: Vec<u8> = (0..4096u32).map(|n| (n % 251) as u8).collect();
+        repo.write("assets/video.bin", &blob)
+            .write("small.txt", "small\n")
+            .commit("Add a video");
+        repo.rename("assets/video.bin", "media/video.bin")
+            .commit("Move the video");
+        repo.write("media/video.bin", [&blob[..], b"more"].concat())
+            .commit("Re-encode the video");
+        repo.remove("media/video.bin").commit("Drop the video");
+
+        let options = IndexOptions {
+            flag_large_additions: Some(4096),
+            ..IndexOptions::default()
+        };
+        let history = commits(&repo, &options);
+        let flagged: Vec<Vec<_>> = history
+            .iter()
+            .map(|commit| {
+                commit
+                    .large_files
+                    .iter()
+                    .map(|file| (file.path.as_str(), file.size))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            flagged,
+            [vec![], vec![], vec![("media/video.bin", 4100)], vec![]]
+        );
+
+        let options = IndexOptions {
+            flag_large_additions: Some(1000),
+            ..IndexOptions::default()
+        };
+        let first = &commits(&repo, &options)[0];
+        let flagged: Vec<_> = first.large_files.iter().map(|file| &file.path).collect();
+        assert_eq!(flagged, ["assets/video.bin"]);
+        // A binary file's patch isn't affected by the flag
+        assert_eq!(first.git_diff[0].additions, None);
+
+        assert!(commits(&repo, &IndexOptions::default())
+            .iter()
+            .all(|commit| commit.large_files.is_empty()));
+    }
+}
@@ -0,0 +1,673 @@
+use crate::identities::Identities;
+use crate::options::IndexOptions;
+use crate::{
+    commit_message, extract_pl_and_issue_id, for_each_commit, github, gitlab, CustomError,
+};
+use git2::Repository;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Default for `--max-subject-length`.
+pub const DEFAULT_MAX_SUBJECT_LENGTH: usize = 72;
+
+/// Types a conventional commit subject may start with.
+const CONVENTIONAL_TYPES: &[&str] = &[
+    "feat", "fix", "docs", "style", "refactor", "perf", "test", "build", "ci", "chore", "revert",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Off,
+    Info,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "off" => Ok(Severity::Off),
+            "info" => Ok(Severity::Info),
+            "warning" => Ok(Severity::Warning),
+            "error" => Ok(Severity::Error),
+            other => Err(format!(
+                "expected off, info, warning or error, got '{}'",
+                other
+            )),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Off => "off",
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+
+    /// Points a finding takes off its commit's score of 100.
+    fn penalty(self) -> f64 {
+        match self {
+            Severity::Off => 0.0,
+            Severity::Info => 5.0,
+            Severity::Warning => 15.0,
+            Severity::Error => 40.0,
+        }
+    }
+}
+
+/// A commit message split the way the rules look at it.
+struct Message<'a> {
+    text: &'a str,
+    subject: &'a str,
+    /// The line after the subject, if there is one
+    second_line: Option<&'a str>,
+}
+
+impl<'a> Message<'a> {
+    fn new(text: &'a str) -> Self {
+        let mut lines = text.lines();
+        Message {
+            text,
+            subject: lines.next().unwrap_or("").trim_end(),
+            second_line: lines.next(),
+        }
+    }
+}
+
+/// One check. It returns what is wrong with the message, or `None` if nothing is.
+pub struct Rule {
+    /// Also the name of the flag and `[lint]` key that set its severity
+    pub name: &'static str,
+    pub default: Severity,
+    check: fn(&Message, &LintOptions) -> Option<String>,
+}
+
+/// Every rule, in the order findings are reported.
+pub const RULES: &[Rule] = &[
+    Rule {
+        name: "subject-length",
+        default: Severity::Warning,
+        check: subject_length,
+    },
+    Rule {
+        name: "imperative-mood",
+        default: Severity::Warning,
+        check: imperative_mood,
+    },
+    Rule {
+        name: "blank-line-after-subject",
+        default: Severity::Warning,
+        check: blank_line_after_subject,
+    },
+    Rule {
+        name: "issue-reference",
+        default: Severity::Info,
+        check: issue_reference,
+    },
+    Rule {
+        name: "conventional-commit",
+        default: Severity::Off,
+        check: conventional_commit,
+    },
+];
+
+fn subject_length(message: &Message, options: &LintOptions) -> Option<String> {
+    let length = message.subject.chars().count();
+    (length > options.max_subject_length).then(|| {
+        format!(
+            "subject is {} characters long, more than {}",
+            length, options.max_subject_length
+        )
+    })
+}
+
+/// Verbs whose third person form (`Adds`, `Fixes`) gives a subject away as not
+/// imperative. Past tense and gerunds are told by their ending instead.
+const COMMON_VERBS: &[&str] = &[
+    "add",
+    "allow",
+    "avoid",
+    "bump",
+    "change",
+    "check",
+    "clean",
+    "create",
+    "delete",
+    "disable",
+    "document",
+    "drop",
+    "enable",
+    "ensure",
+    "extract",
+    "fix",
+    "handle",
+    "implement",
+    "improve",
+    "introduce",
+    "make",
+    "merge",
+    "move",
+    "prevent",
+    "reduce",
+    "refactor",
+    "release",
+    "remove",
+    "rename",
+    "replace",
+    "return",
+    "revert",
+    "set",
+    "show",
+    "skip",
+    "split",
+    "support",
+    "test",
+    "update",
+    "upgrade",
+    "use",
+];
+
+/// Imperative words that happen to end like past tense or gerunds.
+const IMPERATIVE_EXCEPTIONS: &[&str] = &[
+    "bring", "embed", "exceed", "feed", "need", "proceed", "seed", "shred", "speed", "string",
+    "succeed",
+];
+
+/// A heuristic: the first word after any conventional commit prefix shouldn't be past
+/// tense (`Added`), a gerund (`Adding`) or third person (`Adds`).
+fn imperative_mood(message: &Message, _: &LintOptions) -> Option<String> {
+    static PREFIX: OnceLock<Regex> = OnceLock::new();
+    let prefix = PREFIX.get_or_init(|| Regex::new(r"^\w+(\([^()]*\))?!?:\s*").unwrap());
+    let description = prefix.replace(message.subject, "");
+    let first = description.split_whitespace().next()?;
+    let word = first
+        .trim_matches(|c: char| !c.is_alphanumeric())
+        .to_lowercase();
+    if IMPERATIVE_EXCEPTIONS.contains(&word.as_str()) {
+        return None;
+    }
+    let third_person = [word.strip_suffix("es"), word.strip_suffix('s')]
+        .into_iter()
+        .flatten()
+        .any(|stem| COMMON_VERBS.contains(&stem));
+    let not_imperative = (word.len() > 4 && word.ends_with("ing"))
+        || (word.len() > 3 && word.ends_with("ed"))
+        || third_person;
+    not_imperative.then(|| {
+        format!(
+            "subject should start with a verb in the imperative, like 'Fix', not '{}'",
+            first
+        )
+    })
+}
+
+fn blank_line_after_subject(message: &Message, _: &LintOptions) -> Option<String> {
+    message
+        .second_line
+        .is_some_and(|line| !line.trim().is_empty())
+        .then(|| "the body should be separated from the subject by a blank line".to_string())
+}
+
+/// Any reference the history output or enrichment would pick up: `PL#123` and
+/// `Issue #123`, a GitHub pull request, a GitLab merge request or issue.
+fn issue_reference(message: &Message, _: &LintOptions) -> Option<String> {
+    let extracted = extract_pl_and_issue_id(message.text);
+    let referenced = !extracted.is_empty()
+        || github::pull_request_number(message.text, &extracted).is_some()
+        || !gitlab::message_references(message.text).is_empty();
+    (!referenced).then(|| "no issue or pull request reference".to_string())
+}
+
+fn conventional_commit(message: &Message, _: &LintOptions) -> Option<String> {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern = PATTERN.get_or_init(|| Regex::new(r"^(\w+)(\([^()]+\))?!?: \S").unwrap());
+    let commit_type = pattern
+        .captures(message.subject)
+        .map(|captures| captures[1].to_string());
+    match commit_type {
+        Some(commit_type) if CONVENTIONAL_TYPES.contains(&commit_type.as_str()) => None,
+        Some(commit_type) => Some(format!(
+            "'{}' is not a conventional commit type ({})",
+            commit_type,
+            CONVENTIONAL_TYPES.join(", ")
+        )),
+        None => Some("subject is not of the form 'type(scope): description'".to_string()),
+    }
+}
+
+/// The rules' severities and settings, from `lint-messages` flags or `[lint]`.
+#[derive(Clone, Debug)]
+pub struct LintOptions {
+    /// Severity of each rule of `RULES`, in the same order
+    severities: Vec<Severity>,
+    pub max_subject_length: usize,
+    /// Fail when the overall score is lower
+    pub fail_under: Option<f64>,
+}
+
+impl Default for LintOptions {
+    fn default() -> Self {
+        LintOptions {
+            severities: RULES.iter().map(|rule| rule.default).collect(),
+            max_subject_length: DEFAULT_MAX_SUBJECT_LENGTH,
+            fail_under: None,
+        }
+    }
+}
+
+impl LintOptions {
+    pub fn from_args(args: &[String]) -> Result<LintOptions, String> {
+        let mut options = LintOptions::default();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let mut value = || {
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| "missing value".to_string())
+            };
+            let known = options
+                .apply_flag(flag, &mut value)
+                .map_err(|e| format!("{}: {}", flag, e))?;
+            if !known {
+                return Err(format!("Unknown option: {}", flag));
+            }
+        }
+        Ok(options)
+    }
+
+    /// Applies one `lint-messages` flag: `--<rule> <severity>`, `--max-subject-length`
+    /// or `--fail-under`. Returns false for flags it doesn't know.
+    pub fn apply_flag(
+        &mut self,
+        flag: &str,
+        value: &mut dyn FnMut() -> Result<String, String>,
+    ) -> Result<bool, String> {
+        match flag {
+            "--max-subject-length" => {
+                let raw = value()?;
+                self.max_subject_length = raw
+                    .parse()
+                    .map_err(|_| format!("expected an integer, got '{}'", raw))?;
+            }
+            "--fail-under" => {
+                let raw = value()?;
+                self.fail_under = match raw.parse::<f64>() {
+                    Ok(score) if (0.0..=100.0).contains(&score) => Some(score),
+                    _ => return Err(format!("expected a score from 0 to 100, got '{}'", raw)),
+                };
+            }
+            _ => {
+                let Some(idx) = flag
+                    .strip_prefix("--")
+                    .and_then(|name| RULES.iter().position(|rule| rule.name == name))
+                else {
+                    return Ok(false);
+                };
+                self.severities[idx] = Severity::parse(&value()?)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Each rule with the severity it runs at.
+    pub fn rules(&self) -> impl Iterator<Item = (&'static Rule, Severity)> + '_ {
+        RULES.iter().zip(self.severities.iter().copied())
+    }
+}
+
+#[derive(Serialize)]
+pub struct Finding {
+    rule: &'static str,
+    severity: Severity,
+    message: String,
+}
+
+#[derive(Serialize)]
+pub struct CommitReport {
+    commit_id: String,
+    author: String,
+    subject: String,
+    score: f64,
+    findings: Vec<Finding>,
+}
+
+#[derive(Serialize)]
+pub struct AuthorScore {
+    author: String,
+    commits: usize,
+    findings: usize,
+    score: f64,
+}
+
+#[derive(Serialize)]
+pub struct LintReport {
+    /// Average commit score, from 0 to 100
+    pub score: f64,
+    commits_checked: usize,
+    /// The severity each rule ran at
+    rules: BTreeMap<&'static str, Severity>,
+    /// Per author, lowest score first
+    authors: Vec<AuthorScore>,
+    /// Only the commits with findings, newest first
+    commits: Vec<CommitReport>,
+}
+
+/// Checks the message of every commit of the filtered history against the enabled
+/// rules. A commit scores 100 minus the penalty of each finding (5 for info, 15 for a
+/// warning, 40 for an error), at least 0; authors and the repository get the average
+/// of their commits. Merge commits are skipped, since their messages are generated.
+pub fn lint_messages(
+    repo_path: &Path,
+    options: &IndexOptions,
+    lint: &LintOptions,
+) -> Result<LintReport, CustomError> {
+    let repo = Repository::open(repo_path)?;
+    let identities = Identities::load(&repo, options)?;
+
+    let mut commits = Vec::new();
+    let mut total = 0.0;
+    let mut checked = 0;
+    // Commit count, finding count and score sum per author, in order of appearance
+    let mut authors: Vec<(String, usize, usize, f64)> = Vec::new();
+    let mut author_index: HashMap<String, usize> = HashMap::new();
+
+    for_each_commit(&repo, options, |commit, _, _| {
+        if commit.parent_count() > 1 {
+            return Ok(());
+        }
+        let text = commit_message(commit).text;
+        let message = Message::new(&text);
+        let findings: Vec<Finding> = lint
+            .rules()
+            .filter(|(_, severity)| *severity != Severity::Off)
+            .filter_map(|(rule, severity)| {
+                (rule.check)(&message, lint).map(|message| Finding {
+                    rule: rule.name,
+                    severity,
+                    message,
+                })
+            })
+            .collect();
+        let penalty: f64 = findings.iter().map(|f| f.severity.penalty()).sum();
+        let score = (100.0 - penalty).max(0.0);
+
+        let author = identities.author(commit)?;
+        let author = format!(
+            "{} <{}>",
+            String::from_utf8_lossy(author.name_bytes()),
+            String::from_utf8_lossy(author.email_bytes())
+        );
+        let idx = *author_index.entry(author.clone()).or_insert_with(|| {
+            authors.push((author.clone(), 0, 0, 0.0));
+            authors.len() - 1
+        });
+        let tally = &mut authors[idx];
+        tally.1 += 1;
+        tally.2 += findings.len();
+        tally.3 += score;
+        total += score;
+        checked += 1;
+
+        if !findings.is_empty() {
+            let mut subject = message.subject.to_string();
+            identities.redactor().text(&mut subject);
+            commits.push(CommitReport {
+                commit_id: commit.id().to_string(),
+                author,
+                subject,
+                score,
+                findings,
+            });
+        }
+        Ok(())
+    })?;
+
+    let mut authors: Vec<AuthorScore> = authors
+        .into_iter()
+        .map(|(author, commits, findings, sum)| AuthorScore {
+            author,
+            commits,
+            findings,
+            score: round(sum / commits as f64),
+        })
+        .collect();
+    authors.sort_by(|a, b| a.score.total_cmp(&b.score));
+    Ok(LintReport {
+        score: if checked == 0 {
+            100.0
+        } else {
+            round(total / checked as f64)
+        },
+        commits_checked: checked,
+        rules: lint
+            .rules()
+            .map(|(rule, severity)| (rule.name, severity))
+            .collect(),
+        authors,
+        commits,
+    })
+}
+
+/// To one decimal, so scores read well in JSON.
+fn round(score: f64) -> f64 {
+    (score * 10.0).round() / 10.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_repo::TestRepo;
+
+    /// Runs the rule `name` with the default options on each message and checks
+    /// whether it finds something.
+    fn check_rule(name: &str, cases: &[(&str, bool)]) {
+        let rule = RULES.iter().find(|rule| rule.name == name).unwrap();
+        let options = LintOptions::default();
+        for (text, flagged) in cases {
+            let finding = (rule.check)(&Message::new(text), &options);
+            assert_eq!(
+                finding.is_some(),
+                *flagged,
+                "{}: {:?} {:?}",
+                name,
+                text,
+                finding
+            );
+        }
+    }
+
+    #[test]
+    fn checks_subject_length() {
+        let limit = "a".repeat(DEFAULT_MAX_SUBJECT_LENGTH);
+        let over = "é".repeat(DEFAULT_MAX_SUBJECT_LENGTH + 1);
+        check_rule(
+            "subject-length",
+            &[
+                ("Fix the build", false),
+                (&limit, false),
+                (&format!("{}   \n\nA long body line is fine", limit), false),
+                (&over, true),
+            ],
+        );
+    }
+
+    #[test]
+    fn checks_imperative_mood() {
+        check_rule(
+            "imperative-mood",
+            &[
+                ("Fix the build", false),
+                ("Add tests", false),
+                ("fix(parser): handle empty input", false),
+                ("Embed the fonts", false),
+                ("Need a newer compiler", false),
+                ("Update", false),
+                ("Red button", false),
+                ("Fixed the build", true),
+                ("Adding tests", true),
+                ("Adds tests", true),
+                ("Fixes #12", true),
+                ("feat!: removed the v1 API", true),
+            ],
+        );
+    }
+
+    #[test]
+    fn checks_blank_line_after_subject() {
+        check_rule(
+            "blank-line-after-subject",
+            &[
+                ("Fix the build", false),
+                ("Fix the build\n", false),
+                ("Fix the build\n\nBecause it broke", false),
+                ("Fix the build\n   \nBecause it broke", false),
+                ("Fix the build\nBecause it broke", true),
+            ],
+        );
+    }
+
+    #[test]
+    fn checks_issue_reference() {
+        check_rule(
+            "issue-reference",
+            &[
+                ("Fix the build (#12)", false),
+                ("Fix the build\n\nIssue #7", false),
+                ("Fix the build PL#3", false),
+                ("Merge pull request #5 from o/branch", false),
+                ("Fix the build\n\nSee merge request group/project!9", false),
+                ("Fix the build", true),
+                ("Bump to 2.0", true),
+            ],
+        );
+    }
+
+    #[test]
+    fn checks_conventional_commit() {
+        check_rule(
+            "conventional-commit",
+            &[
+                ("feat: add the API", false),
+                ("fix(parser): handle empty input", false),
+                ("refactor!: drop the v1 API", false),
+                ("Add the API", true),
+                ("feature: add the API", true),
+                ("feat:add the API", true),
+                ("feat(): add the API", true),
+            ],
+        );
+    }
+
+    #[test]
+    fn flags_set_severities_and_limits() {
+        let args: Vec<String> = [
+            "--conventional-commit",
+            "error",
+            "--issue-reference",
+            "off",
+            "--max-subject-length",
+            "50",
+            "--fail-under",
+            "90",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        let options = LintOptions::from_args(&args).unwrap();
+        let severities: Vec<_> = options
+            .rules()
+            .map(|(rule, severity)| (rule.name, severity))
+            .collect();
+        assert_eq!(
+            severities,
+            [
+                ("subject-length", Severity::Warning),
+                ("imperative-mood", Severity::Warning),
+                ("blank-line-after-subject", Severity::Warning),
+                ("issue-reference", Severity::Off),
+                ("conventional-commit", Severity::Error),
+            ]
+        );
+        assert_eq!(options.max_subject_length, 50);
+        assert_eq!(options.fail_under, Some(90.0));
+
+        for bad in [
+            &["--imperative-mood", "fatal"][..],
+            &["--fail-under", "101"],
+            &["--no-such-rule", "off"],
+        ] {
+            let args: Vec<String> = bad.iter().map(|arg| arg.to_string()).collect();
+            assert!(LintOptions::from_args(&args).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn scores_commits_authors_and_the_repository() {
+        let repo = TestRepo::new();
+        repo.write("a.txt", "a\n")
+            .commit_as("Ann", "ann@example.com", "Add a readme (#1)");
+        repo.write("a.txt", "b\n")
+            .commit_as("Bob", "bob@example.com", "Added stuff\nin one line");
+        repo.write("a.txt", "c\n")
+            .commit_as("Bob", "bob@example.com", "Fix the typo");
+
+        let report = lint_messages(
+            repo.path(),
+            &IndexOptions::default(),
+            &LintOptions::default(),
+        )
+        .unwrap();
+        let report = serde_json::to_value(report).unwrap();
+        // 100, 100 - 15 - 15 - 5 and 100 - 5, averaged
+        assert_eq!(report["score"], 86.7);
+        assert_eq!(report["commits_checked"], 3);
+        let authors: Vec<_> = report["authors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|author| {
+                (
+                    author["author"].as_str().unwrap(),
+                    author["score"].as_f64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            authors,
+            [
+                ("Bob <bob@example.com>", 80.0),
+                ("Ann <ann@example.com>", 100.0)
+            ]
+        );
+        let findings: Vec<Vec<_>> = report["commits"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|commit| {
+                commit["findings"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|finding| finding["rule"].as_str().unwrap())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            findings,
+            [
+                vec!["issue-reference"],
+                vec![
+                    "imperative-mood",
+                    "blank-line-after-subject",
+                    "issue-reference"
+                ],
+            ]
+        );
+    }
+}
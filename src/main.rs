@@ -1,38 +1,202 @@
-use git2::{DiffOptions, Repository};
+use chrono::TimeZone;
+use git2::{DiffOptions, ErrorCode, Repository};
+use hyper::server::conn::Http;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
 use regex::Regex;
+use schemars::JsonSchema;
 use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::convert::Infallible;
 use std::env;
 use std::fmt;
 use std::fs;
-use std::path::Path;
-use std::process::Command;
-use tempfile::tempdir;
+use std::net::SocketAddr;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
-#[derive(Serialize)]
+mod activity;
+mod attributes;
+mod auth;
+mod batch;
+mod blame;
+mod budget;
+mod clone;
+mod compare;
+mod compression;
+mod conditional;
+mod config;
+mod contributors;
+mod cors;
+mod enrich;
+mod envelope;
+mod file_history;
+mod github;
+mod gitlab;
+mod grafts;
+mod graph;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod hotspots;
+mod identities;
+mod large_files;
+mod lint;
+mod options;
+mod ownership;
+mod patch_id;
+mod range;
+mod redact;
+mod refs;
+mod search;
+mod sink;
+#[cfg(test)]
+mod test_repo;
+mod tls;
+mod tree;
+mod watch;
+mod webhooks;
+mod word_diff;
+mod workdir;
+
+use auth::ApiKeys;
+use compression::ContentEncoding;
+use cors::Cors;
+use options::{BoundaryDiff, IndexOptions, PatchFormat};
+use sink::CommitSink;
+use tls::TlsSettings;
+
+#[derive(Clone, Default, Serialize, JsonSchema)]
 struct CommitDiff {
     file: String,
     diff: String,
+    /// Lines added to the file, `null` for binary files
+    additions: Option<usize>,
+    /// Lines removed from the file, `null` for binary files
+    deletions: Option<usize>,
+    /// Octal file mode before the change, e.g. `100644`; `null` for added files
+    old_mode: Option<String>,
+    /// Octal file mode after the change; `null` for deleted files
+    new_mode: Option<String>,
+    /// Blob before the change; `null` for added files
+    old_blob_id: Option<String>,
+    /// Blob after the change; `null` for deleted files
+    new_blob_id: Option<String>,
+    /// `submodule` for submodule pointer changes, which carry SHAs instead of a patch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kind: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_sha: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_sha: Option<String>,
+    /// Subjects of the submodule commits between the two SHAs, with `--recurse-submodules`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    submodule_commits: Option<Vec<String>>,
+    /// Set for files marked `linguist-generated` or `-diff`, whose patch is left out
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    generated: bool,
+    /// Set for files whose patch is longer than `--max-diff-bytes`, which is left out
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    diff_too_large: bool,
+    /// The patch with intraline changes marked as `[-old-]{+new+}`, with `--word-diff`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    word_diff: Option<String>,
+    /// Set for Git LFS pointer files, whose pointer-text patch is left out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lfs: Option<large_files::LfsChange>,
+    /// `renamed` or `copied`, when rename detection paired this file with another
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
+    /// The path the file was renamed or copied from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_file: Option<String>,
+    /// How similar the two files are, in percent, as rename detection scored them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    similarity: Option<u16>,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize, JsonSchema)]
 struct CommitHistory {
     commit_id: String,
+    /// The shortest prefix of `commit_id`, at least `--sha-length` (default: the
+    /// repository's `core.abbrev`, 7) long, that no other object in the repository shares
+    commit_id_short: String,
+    parent_ids: Vec<String>,
+    /// `parent_ids`, abbreviated like `commit_id_short`
+    parent_ids_short: Vec<String>,
     author: String,
     commit_message: String,
+    /// Charset the message was stored in, from the commit's `encoding` header
+    message_encoding: Option<String>,
+    /// Set when the message was not valid in its declared encoding and had bytes replaced
+    message_is_lossy: bool,
     pl_and_issue_id: String,
+    /// `git patch-id --stable` of the change; null for merges and empty commits
+    patch_id: Option<String>,
+    /// Earliest indexed commit carrying the same change, e.g. the original of a cherry-pick
+    duplicate_of: Option<String>,
+    /// Set when the commit changed only files matched by `--exclude`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    all_changes_excluded: bool,
+    /// Set when whitespace was ignored and the commit changed nothing else
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    whitespace_only: bool,
+    /// Set when the commit's parents were cut off by a shallow clone, a replace ref or
+    /// a graft, so its diff would list the whole tree; see `--boundary-diff`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    graft_boundary: bool,
+    /// Set when the commit had too many candidates for `--rename-limit`, so renames
+    /// and copies are listed as deleted and added files
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    rename_detection_skipped: bool,
+    /// Pull or merge request metadata, with `--enrich`; null when unavailable
+    pull_request: Option<enrich::PullRequest>,
+    /// Issue metadata, with `--enrich gitlab`; null when unavailable
+    issue: Option<enrich::Issue>,
+    /// Totals over the commit's files, with `--stats-only`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<CommitStats>,
+    /// Blobs above `--flag-large-additions` the commit introduced
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    large_files: Vec<large_files::LargeFile>,
+    /// Empty with `--no-diff` and `--stats-only`
     git_diff: Vec<CommitDiff>,
 }
 
-#[allow(dead_code)]
+#[derive(Clone, Serialize, JsonSchema)]
+struct CommitStats {
+    files_changed: usize,
+    insertions: usize,
+    deletions: usize,
+}
+
+#[allow(dead_code, clippy::enum_variant_names)]
 #[derive(Debug)]
 enum CustomError {
     GitError(git2::Error),
     JsonError(serde_json::Error),
     IoError(std::io::Error),
     MissingFieldError(String),
+    PathNotFound(String),
+    BinaryFile(String),
+    RefNotFound(String),
+    InvalidRequest(String),
+    /// The remote has no such repository
+    RepoNotFound(String),
+    /// `git clone` failed for any other reason
+    CloneFailed(String),
+    DiffTooLarge(String),
+    Timeout(String),
+    /// Bad command-line arguments; the message is printed as is, usage included
+    Usage(String),
+    /// `lint-messages` scored lower than `--fail-under`
+    ScoreTooLow(String),
+    /// Too little disk left for a clone, or the clone outgrew `--max-clone-bytes`
+    InsufficientStorage(String),
 }
 
 impl fmt::Display for CustomError {
@@ -42,6 +206,112 @@ impl fmt::Display for CustomError {
             CustomError::JsonError(err) => write!(f, "JSON error: {}", err),
             CustomError::IoError(err) => write!(f, "IO error: {}", err),
             CustomError::MissingFieldError(field) => write!(f, "Missing field in JSON: {}", field),
+            CustomError::PathNotFound(message) => write!(f, "Path not found: {}", message),
+            CustomError::BinaryFile(message) => write!(f, "Unsupported file: {}", message),
+            CustomError::RefNotFound(spec) => write!(f, "Cannot resolve ref: {}", spec),
+            CustomError::InvalidRequest(message) => write!(f, "Invalid request: {}", message),
+            CustomError::RepoNotFound(message) => write!(f, "Repository not found: {}", message),
+            CustomError::CloneFailed(message) => {
+                write!(f, "Failed to clone repository: {}", message)
+            }
+            CustomError::DiffTooLarge(message) => write!(f, "Diff too large: {}", message),
+            CustomError::Timeout(message) => write!(f, "Timed out: {}", message),
+            CustomError::Usage(message) => write!(f, "{}", message),
+            CustomError::ScoreTooLow(message) => write!(f, "Score too low: {}", message),
+            CustomError::InsufficientStorage(message) => {
+                write!(f, "Insufficient storage: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CustomError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CustomError::GitError(err) => Some(err),
+            CustomError::JsonError(err) => Some(err),
+            CustomError::IoError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl CustomError {
+    /// Status of the HTTP (and, through it, gRPC) error response.
+    fn http_status(&self) -> StatusCode {
+        match self {
+            CustomError::GitError(e)
+                if matches!(e.code(), ErrorCode::NotFound | ErrorCode::Ambiguous) =>
+            {
+                StatusCode::NOT_FOUND
+            }
+            CustomError::PathNotFound(_) | CustomError::RepoNotFound(_) => StatusCode::NOT_FOUND,
+            CustomError::MissingFieldError(_)
+            | CustomError::InvalidRequest(_)
+            | CustomError::Usage(_) => StatusCode::BAD_REQUEST,
+            // The repository exists, the request named a ref it doesn't have
+            CustomError::RefNotFound(_)
+            | CustomError::BinaryFile(_)
+            | CustomError::DiffTooLarge(_)
+            | CustomError::ScoreTooLow(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            CustomError::CloneFailed(_) => StatusCode::BAD_GATEWAY,
+            CustomError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            CustomError::InsufficientStorage(_) => StatusCode::INSUFFICIENT_STORAGE,
+            CustomError::GitError(_) | CustomError::JsonError(_) | CustomError::IoError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// Stable machine-readable name of the error, the `code` of JSON error responses.
+    fn code(&self) -> &'static str {
+        match self {
+            CustomError::GitError(e)
+                if matches!(e.code(), ErrorCode::NotFound | ErrorCode::Ambiguous) =>
+            {
+                "not_found"
+            }
+            CustomError::GitError(_) => "git_error",
+            CustomError::JsonError(_) => "json_error",
+            CustomError::IoError(_) => "io_error",
+            CustomError::MissingFieldError(_) => "missing_field",
+            CustomError::PathNotFound(_) => "path_not_found",
+            CustomError::BinaryFile(_) => "binary_file",
+            CustomError::RefNotFound(_) => "ref_not_found",
+            CustomError::InvalidRequest(_) | CustomError::Usage(_) => "invalid_request",
+            CustomError::RepoNotFound(_) => "repo_not_found",
+            CustomError::CloneFailed(_) => "clone_failed",
+            CustomError::DiffTooLarge(_) => "diff_too_large",
+            CustomError::Timeout(_) => "timeout",
+            CustomError::ScoreTooLow(_) => "score_too_low",
+            CustomError::InsufficientStorage(_) => "insufficient_storage",
+        }
+    }
+
+    /// Exit status of the CLI, as documented in readme.md: 2 for usage errors, 3 when
+    /// a repository, ref or path doesn't exist, 4 for git and clone failures, 5 for
+    /// timeouts, 6 for content that can't be processed, 7 for a failed `lint-messages`
+    /// gate and 1 for anything else.
+    fn exit_code(&self) -> i32 {
+        match self {
+            CustomError::MissingFieldError(_)
+            | CustomError::InvalidRequest(_)
+            | CustomError::Usage(_) => 2,
+            CustomError::GitError(e)
+                if matches!(e.code(), ErrorCode::NotFound | ErrorCode::Ambiguous) =>
+            {
+                3
+            }
+            CustomError::PathNotFound(_)
+            | CustomError::RefNotFound(_)
+            | CustomError::RepoNotFound(_) => 3,
+            CustomError::GitError(_)
+            | CustomError::CloneFailed(_)
+            | CustomError::InsufficientStorage(_) => 4,
+            CustomError::Timeout(_) => 5,
+            CustomError::BinaryFile(_) | CustomError::DiffTooLarge(_) => 6,
+            CustomError::JsonError(_) | CustomError::IoError(_) => 1,
+            CustomError::ScoreTooLow(_) => 7,
         }
     }
 }
@@ -64,24 +334,521 @@ impl From<serde_json::Error> for CustomError {
     }
 }
 
+const USAGE: &str = "
+         Git History
+Usage: cargo run --release [COMMAND] <args>
+Index Code: cargo run --release index <path_to_repo> [--range <range>] [--format json|ndjson] [filters]
+Index Several Repositories: cargo run --release index --repos-file <file> [filters]
+Re-index on Every Commit: cargo run --release watch <path_to_repo> [--output <file>] [--interval <secs>] [filters]
+Run Server: cargo run --release server [options]
+Show One Commit: cargo run --release show <path_to_repo> <sha>
+File History: cargo run --release file <path_to_repo> <path_in_repo> [--emit-content] [--max-blob-bytes <n>]
+Blame: cargo run --release blame <path_to_repo> <path_in_repo> [ref]
+Compare Refs: cargo run --release compare <path_to_repo> <base> <head> [--squash]
+List Branches and Tags: cargo run --release refs <path_to_repo>
+List Files: cargo run --release ls <path_to_repo> <ref> [path] [--recursive]
+Search Messages: cargo run --release search <path_to_repo> <pattern> [filters] [--regex] [--include-diffs] [--max-results <n>]
+Author Identities: cargo run --release authors <path_to_repo> [filters]
+Lint Commit Messages: cargo run --release lint-messages <path_to_repo> [filters] [rule options] [--fail-under <score>]
+Contributors: cargo run --release contributors <path_to_repo> [filters] [--top <n>]
+Commit Activity: cargo run --release activity <path_to_repo> [filters] [--bucket day|week|month] [--timezone <zone>]
+Directory Ownership: cargo run --release ownership <path_to_repo> [filters] [--depth <n>] [--months <n>] [--top <n>]
+Churn Hotspots: cargo run --release hotspots <path_to_repo> [filters] [--top <n>] [--format json|table]
+Commit Graph: cargo run --release graph <path_to_repo> [--format dot|mermaid] [--max-commits <n>] [--range <range>] [--first-parent] [filters]
+Print JSON Schema of the output: cargo run --release schema
+Print Effective Configuration: cargo run --release config --print-effective
+
+Every command reads defaults from ./git-history.toml, or from the file given with --config <path>,
+and from GIT_HISTORY_* environment variables named after the options, e.g. GIT_HISTORY_MAX_CONCURRENT_JOBS.
+
+Filters:
+  --since <date>                     Only commits committed on or after this date (YYYY-MM-DD or RFC 3339)
+  --until <date>                     Only commits committed on or before this date
+  --path <path>                      Only commits touching this path, with diffs limited to it (repeatable)
+  --author <pattern>                 Only commits whose author name or email contains this
+  --all                              Walk every branch, remote branch and tag, not just HEAD
+  --rev <ref>                        Walk from this branch, tag or commit instead of HEAD
+  --exclude <glob>                   Leave matching files out of diffs and stats, .gitignore style (repeatable)
+  --identity-map <file>              Canonicalize authors with this TOML file on top of .mailmap (index and aggregations)
+
+Index options:
+  --recurse-submodules               List the commits a submodule moved through, if it is checked out
+  --include-generated                Keep patches of files marked linguist-generated or -diff in .gitattributes
+  --context-lines <n>                Lines of context around each hunk (default: 3)
+  --ignore-whitespace                Ignore whitespace when comparing lines
+  --ignore-whitespace-change         Ignore changes in the amount of whitespace
+  --diff-format legacy|unified       Render diffs as an applyable unified patch (default: legacy)
+  --word-diff                        Add a word_diff with intraline changes marked [-old-]{+new+}
+  --word-diff-max-bytes <n>          Skip the word diff for files larger than this (default: 65536)
+  --max-diff-bytes <n>               Leave out patches longer than this, marking the file diff_too_large
+  --no-diff                          Leave out git_diff and skip patch generation
+  --stats-only                       Like --no-diff, but add per-commit stats (files changed, insertions, deletions)
+  --flag-large-additions <bytes>     List files larger than this in large_files of the commit that added them
+  --boundary-diff full|empty|omit    Diff of commits cut off from their parents by a shallow clone or graft (default: empty)
+  --rename-threshold <percent>       Detect renames at least this similar (default: 50 once detection is on)
+  --copy-threshold <percent>         Also detect copies at least this similar
+  --rename-limit <n>                 Skip rename detection in commits with more candidates than this (default: 1000)
+  --sha-length <n>                   Abbreviate commit_id_short and parent_ids_short to at least n characters
+  --envelope                         Wrap the commits with a meta object describing the run (the server does by default)
+  --enrich github|gitlab             Attach title, body, author, labels and merge time of referenced pull requests
+  --github-token <token>             Token for the GitHub API, for higher rate limits and private repositories
+  --gitlab-url <url>                 Base URL of a self-hosted GitLab (default: https://gitlab.com)
+  --gitlab-token <token>             Token for the GitLab API
+
+Redaction (every command; server requests take redact and redact_salt):
+  --redact emails|authors|both       Replace emails, names or both with stable tokens everywhere in the output:
+                                     authors, messages and their trailers, diffs, blame, taggers and aggregations
+  --redact-salt <salt>               Key of the token hash, so the same person gets the same token in every run
+                                     (default: random per run)
+  --redaction-map <file>             Write which identity each token stands for to this JSON file
+
+Lint options (lint-messages):
+  --<rule> off|info|warning|error    Severity of a rule: subject-length, imperative-mood, blank-line-after-subject,
+                                     issue-reference (default: info) or conventional-commit (default: off)
+  --max-subject-length <n>           Longest subject subject-length accepts (default: 72)
+  --fail-under <score>               Exit with 7 when the overall score (0 to 100) is lower
+
+Server options:
+  --bind <addr>                      Address and port to listen on (default: 0.0.0.0:8080)
+  --max-concurrent-jobs <n>          Maximum clone/index jobs running at once (default: CPU count)
+  --overload-policy queue|reject     Queue excess requests or reject them with 429 (default: queue)
+  --queue-timeout <secs>             How long a queued request waits for a slot (default: 30)
+  --api-key <key>                    Require this API key (repeatable)
+  --api-key-file <path>              Require a key listed in this file, reloaded on SIGHUP
+  --cors-origin <origin>             Allow browser requests from this origin, or * for any (repeatable)
+  --no-compression                   Never gzip/deflate responses, even if the client accepts it
+  --tls-cert <pem> --tls-key <pem>   Serve HTTPS with this certificate chain and private key
+  --allow-local-paths <prefix>       Allow indexing repositories below this directory via repo_path (repeatable)
+  --print-config                     Log the effective configuration at startup, with API keys and secrets redacted
+  --webhook-secret <secret>          Enable the push webhooks, signed with this secret
+  --clone-retries <n>                Retry clones failing with network or server errors this often (default: 2)
+  --clone-timeout <secs>             Give up on a clone after this long, retries included (default: 600)
+  --http-proxy <url>                 Clone through this proxy (git's http.proxy)
+  --git-config <key=value>           Pass this setting to git when cloning, e.g. http.sslVerify=false (repeatable)
+  --allowed-host <host>              Only clone repositories from this host, e.g. github.com (repeatable)
+  --work-dir <dir>                   Clone repositories below this directory (default: the system temp directory)
+  --work-dir-ttl <secs>              Remove clone directories older than this left in the work dir at startup (default: 86400)
+  --min-free-bytes <n>               Answer 507 instead of cloning when the work dir has less space free
+  --max-clone-bytes <n>              Abort clones growing larger than this with 507
+  --max-response-bytes <n>           Keep history responses to this many bytes as sent, answering with the commits
+                                     that fit and a next_cursor (default: 268435456)
+  --grpc-bind <addr>                 Also serve the gRPC API on this address (needs the grpc feature)
+";
+
+// Every endpoint and the method it answers. `route` only dispatches paths listed here,
+// answers CORS preflights for all of them and refuses other methods with 405, so a
+// new endpoint is added here along with its handler.
+const ENDPOINTS: &[(&str, Method)] = &[
+    ("/health", Method::GET),
+    ("/schema", Method::GET),
+    ("/git_history", Method::POST),
+    ("/git_history/batch", Method::POST),
+    ("/webhooks/github", Method::POST),
+    ("/webhooks/generic", Method::POST),
+    ("/commit", Method::GET),
+    ("/file_history", Method::GET),
+    ("/blame", Method::GET),
+    ("/authors", Method::GET),
+    ("/contributors", Method::GET),
+    ("/activity", Method::GET),
+    ("/compare", Method::GET),
+    ("/refs", Method::GET),
+    ("/tree", Method::GET),
+    ("/search", Method::GET),
+];
+
+// Version of the output format described by the JSON Schema. Bump the minor version
+// for added fields and the major version for anything that breaks existing consumers.
+const FORMAT_VERSION: &str = "1.18";
+
+// Seconds a rejected client is asked to wait before retrying
+const RETRY_AFTER_SECS: u64 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OverloadPolicy {
+    Queue,
+    Reject,
+}
+
+#[derive(Debug)]
+struct ServerConfig {
+    bind: SocketAddr,
+    max_concurrent_jobs: usize,
+    overload_policy: OverloadPolicy,
+    queue_timeout: Duration,
+    api_keys: Vec<String>,
+    api_key_file: Option<PathBuf>,
+    cors_origins: Vec<String>,
+    compression: bool,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    allow_local_paths: Vec<PathBuf>,
+    /// Log the effective configuration at startup
+    print_config: bool,
+    /// Shared secret of the push webhooks; they are disabled without one
+    webhook_secret: Option<String>,
+    /// Address of the gRPC server, which only runs when set
+    grpc_bind: Option<SocketAddr>,
+    /// Retries, proxy and git configuration of clones
+    clone: clone::CloneSettings,
+    /// Stop indexing a repository once its commits take this many bytes of JSON
+    max_response_bytes: u64,
+    /// What requests start from: the `[index]` settings and `GIT_HISTORY_*` index
+    /// variables, see `IndexOptions::request_defaults`
+    index_defaults: IndexOptions,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: ([0, 0, 0, 0], 8080).into(),
+            max_concurrent_jobs: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            overload_policy: OverloadPolicy::Queue,
+            queue_timeout: Duration::from_secs(30),
+            api_keys: Vec::new(),
+            api_key_file: None,
+            cors_origins: Vec::new(),
+            compression: true,
+            tls_cert: None,
+            tls_key: None,
+            allow_local_paths: Vec::new(),
+            print_config: false,
+            webhook_secret: None,
+            grpc_bind: None,
+            clone: clone::CloneSettings::default(),
+            max_response_bytes: budget::DEFAULT_MAX_RESPONSE_BYTES,
+            index_defaults: IndexOptions::default().request_defaults(),
+        }
+    }
+}
+
+impl ServerConfig {
+    fn from_args(args: &[String]) -> Result<ServerConfig, String> {
+        let mut config = ServerConfig::default();
+        let mut args = args.iter();
+
+        while let Some(flag) = args.next() {
+            let mut value = || {
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| "missing value".to_string())
+            };
+            let known = config
+                .apply_flag(flag, &mut value)
+                .map_err(|e| format!("{}: {}", flag, e))?;
+            if !known {
+                return Err(format!("Unknown option: {}", flag));
+            }
+        }
+
+        if config.tls_cert.is_some() != config.tls_key.is_some() {
+            return Err("--tls-cert and --tls-key must be given together".to_string());
+        }
+
+        Ok(config)
+    }
+
+    /// Applies `flag` if it is a server option, pulling its value from `value`.
+    /// Returns `Ok(false)` for unknown flags. Errors describe just the bad value, since
+    /// it may come from a flag, the config file or the environment.
+    fn apply_flag(
+        &mut self,
+        flag: &str,
+        value: &mut dyn FnMut() -> Result<String, String>,
+    ) -> Result<bool, String> {
+        match flag {
+            "--bind" => {
+                let raw = value()?;
+                self.bind = raw
+                    .parse()
+                    .map_err(|_| format!("expected an address like 0.0.0.0:8080, got '{}'", raw))?;
+            }
+            "--max-concurrent-jobs" => {
+                let raw = value()?;
+                self.max_concurrent_jobs = match raw.parse::<usize>() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(format!("expected a positive integer, got '{}'", raw)),
+                };
+            }
+            "--overload-policy" => {
+                self.overload_policy = match value()?.as_str() {
+                    "queue" => OverloadPolicy::Queue,
+                    "reject" => OverloadPolicy::Reject,
+                    other => return Err(format!("expected queue or reject, got '{}'", other)),
+                };
+            }
+            "--queue-timeout" => {
+                let raw = value()?;
+                let secs = raw
+                    .parse::<u64>()
+                    .map_err(|_| format!("expected integer seconds, got '{}'", raw))?;
+                self.queue_timeout = Duration::from_secs(secs);
+            }
+            "--api-key" => self.api_keys.push(value()?),
+            "--api-key-file" => self.api_key_file = Some(PathBuf::from(value()?)),
+            "--cors-origin" => self.cors_origins.push(value()?),
+            "--no-compression" => self.compression = false,
+            "--tls-cert" => self.tls_cert = Some(PathBuf::from(value()?)),
+            "--tls-key" => self.tls_key = Some(PathBuf::from(value()?)),
+            "--allow-local-paths" => self.allow_local_paths.push(PathBuf::from(value()?)),
+            "--print-config" => self.print_config = true,
+            "--webhook-secret" => self.webhook_secret = Some(value()?),
+            "--clone-retries" => {
+                let raw = value()?;
+                self.clone.retries = raw
+                    .parse()
+                    .map_err(|_| format!("expected a non-negative integer, got '{}'", raw))?;
+            }
+            "--clone-timeout" => {
+                let raw = value()?;
+                let secs = raw
+                    .parse::<u64>()
+                    .map_err(|_| format!("expected integer seconds, got '{}'", raw))?;
+                self.clone.timeout = Duration::from_secs(secs);
+            }
+            "--http-proxy" => self.clone.http_proxy = Some(value()?),
+            "--git-config" => self
+                .clone
+                .git_config
+                .push(clone::parse_config_entry(&value()?)?),
+            "--allowed-host" => self.clone.allowed_hosts.push(value()?),
+            "--work-dir" => self.clone.work_dir.path = Some(PathBuf::from(value()?)),
+            "--work-dir-ttl" => {
+                let raw = value()?;
+                let secs = raw
+                    .parse::<u64>()
+                    .map_err(|_| format!("expected integer seconds, got '{}'", raw))?;
+                self.clone.work_dir.ttl = Duration::from_secs(secs);
+            }
+            "--min-free-bytes" => {
+                let raw = value()?;
+                self.clone.work_dir.min_free_bytes = Some(
+                    raw.parse()
+                        .map_err(|_| format!("expected a non-negative integer, got '{}'", raw))?,
+                );
+            }
+            "--max-clone-bytes" => {
+                let raw = value()?;
+                self.clone.work_dir.max_clone_bytes = match raw.parse::<u64>() {
+                    Ok(n) if n > 0 => Some(n),
+                    _ => return Err(format!("expected a positive integer, got '{}'", raw)),
+                };
+            }
+            "--max-response-bytes" => {
+                let raw = value()?;
+                self.max_response_bytes = match raw.parse::<u64>() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(format!("expected a positive integer, got '{}'", raw)),
+                };
+            }
+            "--grpc-bind" => {
+                let raw = value()?;
+                if !cfg!(feature = "grpc") {
+                    return Err(
+                        "this build has no gRPC support, rebuild with --features grpc".to_string(),
+                    );
+                }
+                self.grpc_bind = Some(raw.parse().map_err(|_| {
+                    format!("expected an address like 0.0.0.0:50051, got '{}'", raw)
+                })?);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+struct ServerState {
+    config: ServerConfig,
+    jobs: Arc<Semaphore>,
+    queued: AtomicUsize,
+    api_keys: Arc<ApiKeys>,
+    cors: Cors,
+    /// Canonicalized `--allow-local-paths` prefixes
+    local_roots: Vec<PathBuf>,
+    webhooks: webhooks::Webhooks,
+    last_modified: conditional::LastModified,
+    response_sizes: budget::ResponseSizes,
+}
+
+impl ServerState {
+    fn new(config: ServerConfig) -> Result<Self, CustomError> {
+        let api_keys =
+            ApiKeys::new(config.api_keys.clone(), config.api_key_file.clone()).map_err(|e| {
+                eprintln!("Failed to read API key file: {}", e);
+                CustomError::IoError(e)
+            })?;
+        let local_roots = config
+            .allow_local_paths
+            .iter()
+            .map(|root| {
+                fs::canonicalize(root).map_err(|e| {
+                    eprintln!("Invalid --allow-local-paths {}: {}", root.display(), e);
+                    CustomError::IoError(e)
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ServerState {
+            local_roots,
+            jobs: Arc::new(Semaphore::new(config.max_concurrent_jobs)),
+            queued: AtomicUsize::new(0),
+            api_keys: Arc::new(api_keys),
+            cors: Cors::new(config.cors_origins.clone()),
+            webhooks: webhooks::Webhooks::new(config.webhook_secret.clone()),
+            last_modified: conditional::LastModified::default(),
+            response_sizes: budget::ResponseSizes::default(),
+            config,
+        })
+    }
+
+    /// Resolves a client-supplied `repo_path`, following symlinks and `..`, and checks
+    /// that the result lies below one of the allowed prefixes.
+    fn resolve_local_path(&self, raw: &str) -> Result<PathBuf, String> {
+        if self.local_roots.is_empty() {
+            return Err("Local repository paths are disabled on this server".to_string());
+        }
+        let path =
+            fs::canonicalize(raw).map_err(|_| format!("Local path {} is not accessible", raw))?;
+        if self.local_roots.iter().any(|root| path.starts_with(root)) {
+            Ok(path)
+        } else {
+            Err(format!(
+                "Local path {} is outside the allowed directories",
+                raw
+            ))
+        }
+    }
+
+    /// Reads `repo_url` or `repo_path` from a history request body. Errors come with
+    /// the status to answer them with.
+    fn repo_source(&self, body: &serde_json::Value) -> Result<RepoSource, (StatusCode, String)> {
+        match (body["repo_url"].as_str(), body["repo_path"].as_str()) {
+            (Some(_), Some(_)) => Err((
+                StatusCode::BAD_REQUEST,
+                "Ambiguous request: give either repo_url or repo_path, not both".to_string(),
+            )),
+            (Some(repo_url), None) => Ok(RepoSource::Url(repo_url.to_string())),
+            (None, Some(repo_path)) => self
+                .resolve_local_path(repo_path)
+                .map(RepoSource::Local)
+                .map_err(|message| (StatusCode::FORBIDDEN, message)),
+            (None, None) => Err((
+                StatusCode::BAD_REQUEST,
+                "Missing field: repo_url".to_string(),
+            )),
+        }
+    }
+
+    fn in_flight(&self) -> usize {
+        self.config.max_concurrent_jobs - self.jobs.available_permits()
+    }
+
+    /// Waits for (or, under the reject policy, tries once for) a job slot.
+    /// Returns `None` when the server is overloaded.
+    async fn acquire_job(&self) -> Option<OwnedSemaphorePermit> {
+        match self.config.overload_policy {
+            OverloadPolicy::Reject => self.jobs.clone().try_acquire_owned().ok(),
+            OverloadPolicy::Queue => {
+                self.queued.fetch_add(1, Ordering::SeqCst);
+                // Decrement on every exit path, including the request being dropped
+                let _queued = QueuedGuard(&self.queued);
+                tokio::time::timeout(self.config.queue_timeout, self.jobs.clone().acquire_owned())
+                    .await
+                    .ok()
+                    .and_then(Result::ok)
+            }
+        }
+    }
+}
+
+struct QueuedGuard<'a>(&'a AtomicUsize);
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 #[tokio::main]
-async fn main() -> Result<(), CustomError> {
+async fn main() {
     // Capture command-line arguments
     let args: Vec<String> = env::args().collect();
+    if let Err(e) = run(args).await.and_then(|()| redact::save_map()) {
+        match &e {
+            CustomError::Usage(message) => eprintln!("{}", message),
+            _ => eprintln!("Error: {}", e),
+        }
+        std::process::exit(e.exit_code());
+    }
+}
+
+/// Runs the command named in `args`. Errors are reported by `main`, which exits with
+/// their `exit_code`.
+async fn run(mut args: Vec<String>) -> Result<(), CustomError> {
+    let config = config::take_config_flag(&mut args)
+        .and_then(|path| config::Config::load(path.as_deref()))
+        .map_err(CustomError::Usage)?;
 
     if args.len() < 2 {
-        eprintln!("\n\n         Git History \nUsage: cargo run --release [COMMAND] <args> \nIndex Code: cargo run --release index <path_to_repo> \nRun Server: cargo run --release server\n");
-        return Ok(());
+        return Err(CustomError::Usage(USAGE.to_string()));
     }
 
     match args[1].as_str() {
         "index" => {
-            if args.len() != 3 {
-                eprintln!("Usage: cargo run --release index <path_to_repo>");
+            let mut repos_file = None;
+            let mut range = None;
+            let mut format = options::OutputFormat::default();
+            let parsed =
+                options::parse_args(&config.index_command_args(&args[2..]), |flag, value| {
+                    match flag {
+                        "--repos-file" => repos_file = Some(PathBuf::from(value()?)),
+                        "--range" => range = Some(value()?),
+                        "--format" => format = options::OutputFormat::parse(&value()?)?,
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                });
+            let (positionals, mut options) = match parsed {
+                Ok(_) if repos_file.is_some() && range.is_some() => {
+                    return Err(CustomError::Usage(
+                        "--range can't be combined with --repos-file, give each repository its ref in the file".to_string(),
+                    ));
+                }
+                Ok(parsed) if parsed.0.len() == usize::from(repos_file.is_none()) => parsed,
+                Ok(_) => {
+                    return Err(CustomError::Usage("Usage: cargo run --release index <path_to_repo> [--range <range>] [filters]\n       cargo run --release index --repos-file <file> [filters]".to_string()));
+                }
+                Err(e) => {
+                    return Err(CustomError::Usage(format!("{}\n{}", e, USAGE)));
+                }
+            };
+            options.range = range;
+            if format == options::OutputFormat::Ndjson {
+                if repos_file.is_some() || options.enrich.is_some() {
+                    return Err(CustomError::Usage(
+                        "--format ndjson writes each commit as it is indexed and can't be combined with --repos-file or --enrich".to_string(),
+                    ));
+                }
+                let output = Path::new(".").join("commit_history.ndjson");
+                let file = std::io::BufWriter::new(fs::File::create(&output)?);
+                let mut sink = if options.envelope {
+                    sink::NdjsonSink::with_meta(file, &positionals[0], &options)
+                } else {
+                    sink::NdjsonSink::new(file)
+                };
+                let indexed = index_into(Path::new(&positionals[0]), &options, &mut sink)?;
+                if indexed.empty_repository {
+                    eprintln!("Repository has no commits yet, the history is empty");
+                }
+                println!("Commit history written {}", output.display());
                 return Ok(());
             }
-            let repo_path = &args[2];
-            let json_data = git_index(repo_path)?;
+            let json_data = match &repos_file {
+                Some(repos_file) => batch::index_file(repos_file, &options).await?,
+                None => git_index(Path::new(&positionals[0]), &options).await?,
+            };
             fs::write(Path::new(".").join("commit_history.json"), json_data).map_err(|e| {
                 eprintln!("Failed to write commit history to file: {}", e);
                 CustomError::IoError(e)
@@ -92,303 +859,4350 @@ async fn main() -> Result<(), CustomError> {
             );
             Ok(())
         }
-        "server" => run_server().await,
-        _ => {
-            eprintln!("\n\n         Git History \nUsage: cargo run --release [COMMAND] <args> \nIndex Code: cargo run --release index <path_to_repo> \nRun Server: cargo run --release server\n");
+        "watch" => {
+            let mut output = PathBuf::from("commit_history.json");
+            let mut interval = watch::DEFAULT_INTERVAL;
+            let mut format = options::OutputFormat::default();
+            let mut range = None;
+            // `format` and `range` are read like `index` does, to refuse what watch can't do
+            let parsed =
+                options::parse_args(&config.index_command_args(&args[2..]), |flag, value| {
+                    match flag {
+                        "--output" => output = PathBuf::from(value()?),
+                        "--format" => format = options::OutputFormat::parse(&value()?)?,
+                        "--range" => range = Some(value()?),
+                        "--interval" => {
+                            let raw = value()?;
+                            interval = match raw.parse::<u64>() {
+                                Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                                _ => {
+                                    return Err(format!(
+                                        "expected a positive integer, got '{}'",
+                                        raw
+                                    ))
+                                }
+                            };
+                        }
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                });
+            let (positionals, options) = match parsed {
+                Ok(parsed) if parsed.0.len() == 1 => parsed,
+                Ok(_) => {
+                    return Err(CustomError::Usage("Usage: cargo run --release watch <path_to_repo> [--output <file>] [--interval <secs>] [filters]".to_string()));
+                }
+                Err(e) => {
+                    return Err(CustomError::Usage(format!("{}\n{}", e, USAGE)));
+                }
+            };
+            if format == options::OutputFormat::Ndjson {
+                return Err(CustomError::Usage(
+                    "watch rewrites a JSON array and can't write --format ndjson".to_string(),
+                ));
+            }
+            let options = IndexOptions { range, ..options };
+            watch::watch(Path::new(&positionals[0]), &output, interval, &options)
+                .await
+                .map_err(|e| {
+                    eprintln!("Watch failed: {}", e);
+                    e
+                })
+        }
+        "show" => {
+            let (args, redactor) = take_redaction(&config, &args)?;
+            if args.len() != 4 {
+                return Err(CustomError::Usage(
+                    "Usage: cargo run --release show <path_to_repo> <sha>".to_string(),
+                ));
+            }
+            let mut commit = show_commit(Path::new(&args[2]), &args[3]).map_err(|e| {
+                eprintln!("Failed to find commit {}: {}", args[3], e);
+                e
+            })?;
+            redactor.commit(&mut commit);
+            println!("{}", serde_json::to_string_pretty(&commit)?);
+            Ok(())
+        }
+        "file" => {
+            let (args, redactor) = take_redaction(&config, &args)?;
+            let usage = || {
+                CustomError::Usage(
+                    "Usage: cargo run --release file <path_to_repo> <path_in_repo> [--emit-content] [--max-blob-bytes <n>]"
+                        .to_string(),
+                )
+            };
+            let mut file_options = file_history::FileHistoryOptions::default();
+            let mut positionals = Vec::new();
+            let mut rest = args[2..].iter();
+            while let Some(arg) = rest.next() {
+                match arg.as_str() {
+                    "--emit-content" => file_options.emit_content = true,
+                    "--max-blob-bytes" => {
+                        let raw = rest.next().ok_or_else(usage)?;
+                        file_options.max_blob_bytes = raw.parse().map_err(|_| {
+                            CustomError::Usage(format!(
+                                "--max-blob-bytes: expected an integer, got '{}'",
+                                raw
+                            ))
+                        })?;
+                    }
+                    flag if flag.starts_with("--") => return Err(usage()),
+                    _ => positionals.push(arg),
+                }
+            }
+            let [repo_path, path] = positionals[..] else {
+                return Err(usage());
+            };
+            let mut history =
+                file_history::file_history(Path::new(repo_path), path, &file_options)?;
+            history.redact(&redactor);
+            println!("{}", serde_json::to_string_pretty(&history)?);
             Ok(())
         }
-    }
-}
-
-async fn run_server() -> Result<(), CustomError> {
-    let make_svc =
-        make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle_request)) });
-
-    let addr = ([0, 0, 0, 0], 8080).into();
-    let server = Server::bind(&addr).serve(make_svc);
-
-    println!("Server running on http://127.0.0.1:8080");
-
-    server
-        .await
-        .map_err(|e| CustomError::IoError(std::io::Error::new(std::io::ErrorKind::Other, e)))
-}
-
-async fn handle_request(req: Request<Body>) -> Result<Response<Body>, Infallible> {
-    let response = match (req.method(), req.uri().path()) {
-        (&Method::POST, "/git_history") => {
-            let full_body = hyper::body::to_bytes(req.into_body()).await.unwrap();
-            let parsed_body: serde_json::Value = serde_json::from_slice(&full_body).unwrap();
-            if let Some(repo_url) = parsed_body["repo_url"].as_str() {
-                match process_git_repo(repo_url).await {
-                    Ok(json_response) => Response::new(Body::from(json_response)),
-                    Err(e) => {
-                        let error_message = format!("Error: {}", e);
-                        Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(Body::from(error_message))
-                            .unwrap()
+        "blame" => {
+            let (args, redactor) = take_redaction(&config, &args)?;
+            if args.len() != 4 && args.len() != 5 {
+                return Err(CustomError::Usage(
+                    "Usage: cargo run --release blame <path_to_repo> <path_in_repo> [ref]"
+                        .to_string(),
+                ));
+            }
+            let rev = args.get(4).map_or("HEAD", String::as_str);
+            let mut hunks = blame::blame_file(Path::new(&args[2]), &args[3], rev).map_err(|e| {
+                eprintln!("Failed to blame {}: {}", args[3], e);
+                e
+            })?;
+            hunks.iter_mut().for_each(|hunk| hunk.redact(&redactor));
+            println!("{}", serde_json::to_string_pretty(&hunks)?);
+            Ok(())
+        }
+        "compare" => {
+            let (args, redactor) = take_redaction(&config, &args)?;
+            let squash = args[2..].iter().any(|arg| arg == "--squash");
+            let positionals: Vec<&String> =
+                args[2..].iter().filter(|arg| *arg != "--squash").collect();
+            let [repo, base, head] = positionals[..] else {
+                return Err(CustomError::Usage(
+                    "Usage: cargo run --release compare <path_to_repo> <base> <head> [--squash]"
+                        .to_string(),
+                ));
+            };
+            let output = if squash {
+                let mut squashed = compare::squash(Path::new(repo), base, head)?;
+                squashed.redact(&redactor);
+                serde_json::to_string_pretty(&squashed)?
+            } else {
+                let mut comparison = compare::compare(Path::new(repo), base, head)?;
+                comparison.redact(&redactor);
+                serde_json::to_string_pretty(&comparison)?
+            };
+            println!("{}", output);
+            Ok(())
+        }
+        "refs" => {
+            let (args, redactor) = take_redaction(&config, &args)?;
+            if args.len() != 3 {
+                return Err(CustomError::Usage(
+                    "Usage: cargo run --release refs <path_to_repo>".to_string(),
+                ));
+            }
+            let mut refs = refs::list_refs(Path::new(&args[2]))?;
+            refs.redact(&redactor);
+            println!("{}", serde_json::to_string_pretty(&refs)?);
+            Ok(())
+        }
+        "ls" => {
+            let recursive = args.iter().any(|arg| arg == "--recursive");
+            let positionals: Vec<&String> = args[2..]
+                .iter()
+                .filter(|arg| *arg != "--recursive")
+                .collect();
+            if positionals.len() != 2 && positionals.len() != 3 {
+                return Err(CustomError::Usage(
+                    "Usage: cargo run --release ls <path_to_repo> <ref> [path] [--recursive]"
+                        .to_string(),
+                ));
+            }
+            let subdir = positionals.get(2).map(|dir| dir.as_str());
+            let entries =
+                tree::list_tree(Path::new(positionals[0]), positionals[1], subdir, recursive)
+                    .map_err(|e| {
+                        eprintln!("Failed to list tree: {}", e);
+                        e
+                    })?;
+            println!("{}", serde_json::to_string_pretty(&entries)?);
+            Ok(())
+        }
+        "search" => {
+            let mut search_options = search::SearchOptions::default();
+            let parsed = options::parse_args(&config.index_args(&args[2..]), |flag, value| {
+                match flag {
+                    "--regex" => search_options.regex = true,
+                    "--include-diffs" => search_options.include_diffs = true,
+                    "--max-results" => {
+                        let raw = value()?;
+                        search_options.max_results = Some(
+                            raw.parse::<usize>()
+                                .map_err(|_| format!("expected an integer, got '{}'", raw))?,
+                        );
+                    }
+                    _ => return Ok(false),
+                }
+                Ok(true)
+            });
+            let (positionals, options) = match parsed {
+                Ok(parsed) if parsed.0.len() == 2 => parsed,
+                Ok(_) => {
+                    return Err(CustomError::Usage("Usage: cargo run --release search <path_to_repo> <pattern> [filters] [--regex] [--include-diffs] [--max-results <n>]".to_string()));
+                }
+                Err(e) => {
+                    return Err(CustomError::Usage(format!("{}\n{}", e, USAGE)));
+                }
+            };
+            let results = search::search(
+                Path::new(&positionals[0]),
+                &positionals[1],
+                &options,
+                &search_options,
+            )
+            .map_err(|e| {
+                eprintln!("Search failed: {}", e);
+                e
+            })?;
+            println!("{}", serde_json::to_string_pretty(&results)?);
+            Ok(())
+        }
+        "authors" => {
+            let parsed = options::parse_args(&config.index_args(&args[2..]), |_, _| Ok(false));
+            let (positionals, options) = match parsed {
+                Ok(parsed) if parsed.0.len() == 1 => parsed,
+                Ok(_) => {
+                    return Err(CustomError::Usage(
+                        "Usage: cargo run --release authors <path_to_repo> [filters]".to_string(),
+                    ));
+                }
+                Err(e) => {
+                    return Err(CustomError::Usage(format!("{}\n{}", e, USAGE)));
+                }
+            };
+            let groups = identities::authors(Path::new(&positionals[0]), &options)?;
+            println!("{}", serde_json::to_string_pretty(&groups)?);
+            Ok(())
+        }
+        "lint-messages" => {
+            let mut lint = lint::LintOptions::default();
+            let args = config.index_args(&config.lint_args(&args[2..]));
+            let parsed = options::parse_args(&args, |flag, value| lint.apply_flag(flag, value));
+            let (positionals, options) = match parsed {
+                Ok(parsed) if parsed.0.len() == 1 => parsed,
+                Ok(_) => {
+                    return Err(CustomError::Usage("Usage: cargo run --release lint-messages <path_to_repo> [filters] [rule options] [--fail-under <score>]".to_string()));
+                }
+                Err(e) => {
+                    return Err(CustomError::Usage(format!("{}\n{}", e, USAGE)));
+                }
+            };
+            let report = lint::lint_messages(Path::new(&positionals[0]), &options, &lint)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            match lint.fail_under {
+                Some(minimum) if report.score < minimum => Err(CustomError::ScoreTooLow(format!(
+                    "{} is below --fail-under {}",
+                    report.score, minimum
+                ))),
+                _ => Ok(()),
+            }
+        }
+        "contributors" => {
+            let mut top = None;
+            let parsed =
+                options::parse_args(&config.index_args(&args[2..]), |flag, value| match flag {
+                    "--top" => {
+                        let raw = value()?;
+                        top = Some(
+                            raw.parse::<usize>()
+                                .map_err(|_| format!("expected an integer, got '{}'", raw))?,
+                        );
+                        Ok(true)
+                    }
+                    _ => Ok(false),
+                });
+            let (positionals, options) = match parsed {
+                Ok(parsed) if parsed.0.len() == 1 => parsed,
+                Ok(_) => {
+                    return Err(CustomError::Usage("Usage: cargo run --release contributors <path_to_repo> [filters] [--top <n>]".to_string()));
+                }
+                Err(e) => {
+                    return Err(CustomError::Usage(format!("{}\n{}", e, USAGE)));
+                }
+            };
+            let stats = contributors::contributors(Path::new(&positionals[0]), &options, top)?;
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+            Ok(())
+        }
+        "activity" => {
+            let mut bucket = activity::Bucket::default();
+            let mut timezone = chrono_tz::UTC;
+            let parsed = options::parse_args(&config.index_args(&args[2..]), |flag, value| {
+                match flag {
+                    "--bucket" => bucket = activity::Bucket::parse(&value()?)?,
+                    "--timezone" => timezone = activity::parse_timezone(&value()?)?,
+                    _ => return Ok(false),
+                }
+                Ok(true)
+            });
+            let (positionals, options) = match parsed {
+                Ok(parsed) if parsed.0.len() == 1 => parsed,
+                Ok(_) => {
+                    return Err(CustomError::Usage("Usage: cargo run --release activity <path_to_repo> [filters] [--bucket day|week|month] [--timezone <zone>]".to_string()));
+                }
+                Err(e) => {
+                    return Err(CustomError::Usage(format!("{}\n{}", e, USAGE)));
+                }
+            };
+            let series =
+                activity::activity(Path::new(&positionals[0]), &options, bucket, timezone)?;
+            println!("{}", serde_json::to_string_pretty(&series)?);
+            Ok(())
+        }
+        "ownership" => {
+            let mut depth = ownership::DEFAULT_DEPTH;
+            let mut months = ownership::DEFAULT_MONTHS;
+            let mut top = ownership::DEFAULT_TOP;
+            let parsed = options::parse_args(&config.index_args(&args[2..]), |flag, value| {
+                match flag {
+                    "--depth" => {
+                        let raw = value()?;
+                        depth = raw
+                            .parse()
+                            .map_err(|_| format!("expected an integer, got '{}'", raw))?;
+                    }
+                    "--months" => {
+                        let raw = value()?;
+                        months = raw
+                            .parse()
+                            .map_err(|_| format!("expected an integer, got '{}'", raw))?;
+                    }
+                    "--top" => {
+                        let raw = value()?;
+                        top = raw
+                            .parse()
+                            .map_err(|_| format!("expected an integer, got '{}'", raw))?;
+                    }
+                    _ => return Ok(false),
+                }
+                Ok(true)
+            });
+            let (positionals, mut options) = match parsed {
+                Ok(parsed) if parsed.0.len() == 1 => parsed,
+                Ok(_) => {
+                    return Err(CustomError::Usage("Usage: cargo run --release ownership <path_to_repo> [filters] [--depth <n>] [--months <n>] [--top <n>]".to_string()));
+                }
+                Err(e) => {
+                    return Err(CustomError::Usage(format!("{}\n{}", e, USAGE)));
+                }
+            };
+            if options.since.is_none() {
+                options.since = Some(ownership::window_start(months));
+            }
+            let report = ownership::ownership(Path::new(&positionals[0]), &options, depth, top)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(())
+        }
+        "hotspots" => {
+            let mut top = hotspots::DEFAULT_TOP;
+            let mut as_table = false;
+            let parsed = options::parse_args(&config.index_args(&args[2..]), |flag, value| {
+                match flag {
+                    "--top" => {
+                        let raw = value()?;
+                        top = raw
+                            .parse::<usize>()
+                            .map_err(|_| format!("expected an integer, got '{}'", raw))?;
+                    }
+                    "--format" => {
+                        as_table = match value()?.as_str() {
+                            "json" => false,
+                            "table" => true,
+                            other => {
+                                return Err(format!("expected json or table, got '{}'", other))
+                            }
+                        };
                     }
+                    _ => return Ok(false),
                 }
+                Ok(true)
+            });
+            let (positionals, options) = match parsed {
+                Ok(parsed) if parsed.0.len() == 1 => parsed,
+                Ok(_) => {
+                    return Err(CustomError::Usage("Usage: cargo run --release hotspots <path_to_repo> [filters] [--top <n>] [--format json|table]".to_string()));
+                }
+                Err(e) => {
+                    return Err(CustomError::Usage(format!("{}\n{}", e, USAGE)));
+                }
+            };
+            let hotspots = hotspots::hotspots(Path::new(&positionals[0]), &options, top)?;
+            if as_table {
+                print!("{}", hotspots::table(&hotspots));
             } else {
-                Response::builder()
-                    .status(StatusCode::BAD_REQUEST)
-                    .body(Body::from("Missing field: repo_url"))
-                    .unwrap()
+                println!("{}", serde_json::to_string_pretty(&hotspots)?);
             }
+            Ok(())
         }
-        _ => Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(Body::from("Not Found"))
-            .unwrap(),
-    };
-
-    Ok(response)
+        "graph" => {
+            let mut format = graph::GraphFormat::default();
+            let mut max_commits = graph::DEFAULT_MAX_COMMITS;
+            let mut first_parent = false;
+            let mut range = None;
+            let parsed = options::parse_args(&config.index_args(&args[2..]), |flag, value| {
+                match flag {
+                    "--format" => format = graph::GraphFormat::parse(&value()?)?,
+                    "--max-commits" => {
+                        let raw = value()?;
+                        max_commits = match raw.parse::<usize>() {
+                            Ok(n) if n > 0 => n,
+                            _ => return Err(format!("expected a positive integer, got '{}'", raw)),
+                        };
+                    }
+                    "--first-parent" => first_parent = true,
+                    "--range" => range = Some(value()?),
+                    _ => return Ok(false),
+                }
+                Ok(true)
+            });
+            let (positionals, mut options) = match parsed {
+                Ok(parsed) if parsed.0.len() == 1 => parsed,
+                Ok(_) => {
+                    return Err(CustomError::Usage("Usage: cargo run --release graph <path_to_repo> [--format dot|mermaid] [--max-commits <n>] [--range <range>] [--first-parent] [filters]".to_string()));
+                }
+                Err(e) => {
+                    return Err(CustomError::Usage(format!("{}\n{}", e, USAGE)));
+                }
+            };
+            if !options.paths.is_empty() || !options.exclude.is_empty() {
+                return Err(CustomError::Usage(
+                    "graph draws whole commits, --path and --exclude don't apply".to_string(),
+                ));
+            }
+            options.range = range;
+            let graph = graph::graph(
+                Path::new(&positionals[0]),
+                &options,
+                max_commits,
+                first_parent,
+                format,
+            )?;
+            print!("{}", graph);
+            Ok(())
+        }
+        "schema" => {
+            println!("{}", serde_json::to_string_pretty(&response_schema())?);
+            Ok(())
+        }
+        "server" => {
+            let mut server = match ServerConfig::from_args(&config.server_args(&args[2..])) {
+                Ok(server) => server,
+                Err(e) => {
+                    return Err(CustomError::Usage(format!("{}\n{}", e, USAGE)));
+                }
+            };
+            let (_, index) = options::parse_args(&config.index_args(&[]), |_, _| Ok(false))
+                .map_err(|e| CustomError::Usage(format!("[index]: {}", e)))?;
+            server.index_defaults = index.request_defaults();
+            run_server(server).await
+        }
+        "config" => {
+            if args.len() != 3 || args[2] != "--print-effective" {
+                return Err(CustomError::Usage(
+                    "Usage: cargo run --release config --print-effective [--config <path>]"
+                        .to_string(),
+                ));
+            }
+            let mut format = options::OutputFormat::default();
+            let mut range = None;
+            let effective = options::parse_args(&config.index_command_args(&[]), |flag, value| {
+                match flag {
+                    "--format" => format = options::OutputFormat::parse(&value()?)?,
+                    "--range" => range = Some(value()?),
+                    _ => return Ok(false),
+                }
+                Ok(true)
+            })
+            .and_then(|(_, mut index)| {
+                index.range = range;
+                ServerConfig::from_args(&config.server_args(&[])).map(|server| (index, server))
+            })
+            .and_then(|(index, server)| {
+                lint::LintOptions::from_args(&config.lint_args(&[]))
+                    .map(|lint| (index, server, lint))
+            });
+            let (index, server, lint) = effective.map_err(CustomError::Usage)?;
+            if let Some(path) = &config.path {
+                println!("# Settings from {}", path.display());
+            }
+            print!("{}", config::effective_toml(&index, format, &server, &lint));
+            Ok(())
+        }
+        _ => Err(CustomError::Usage(USAGE.to_string())),
+    }
 }
 
-async fn process_git_repo(repo_url: &str) -> Result<String, CustomError> {
-    let temp_dir = tempdir().map_err(|e| {
-        eprintln!("Failed to create temporary directory: {}", e);
-        CustomError::IoError(e)
-    })?;
-    let clone_dir = temp_dir.path().join("repo");
+/// Takes the redaction flags out of the arguments of a command without filters, such
+/// as `show` or `blame`. Returns the other arguments and the `Redactor` those flags
+/// and the `[index]` settings ask for; no other setting applies to these commands.
+fn take_redaction(
+    config: &config::Config,
+    args: &[String],
+) -> Result<(Vec<String>, redact::Redactor), CustomError> {
+    let merged = config.index_args(&args[2..]);
+    // The settings come first
+    let from_config = merged.len() - (args.len() - 2);
+    let mut options = IndexOptions::default();
+    let mut rest = args[..2].to_vec();
+    let mut merged = merged.into_iter().enumerate();
+    while let Some((idx, arg)) = merged.next() {
+        if matches!(
+            arg.as_str(),
+            "--redact" | "--redact-salt" | "--redaction-map"
+        ) {
+            let mut value = || {
+                merged
+                    .next()
+                    .map(|(_, value)| value)
+                    .ok_or_else(|| "missing value".to_string())
+            };
+            options
+                .apply_flag(&arg, &mut value)
+                .map_err(|e| CustomError::Usage(format!("{}: {}", arg, e)))?;
+        } else if idx >= from_config {
+            rest.push(arg);
+        }
+    }
+    Ok((rest, redact::Redactor::new(&options)))
+}
 
-    let status = Command::new("git")
-        .arg("clone")
-        .arg(format!("https://{}", repo_url))
-        .arg(&clone_dir)
-        .status()
-        .map_err(|e| {
-            eprintln!("Failed to run git command: {}", e);
+async fn run_server(config: ServerConfig) -> Result<(), CustomError> {
+    println!(
+        "Max concurrent jobs: {} (overload policy: {:?})",
+        config.max_concurrent_jobs, config.overload_policy
+    );
+    if config.print_config {
+        println!("Configuration: {}", config::server_summary(&config));
+    }
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(Arc::new(TlsSettings::load(cert, key).map_err(|e| {
+            eprintln!("Failed to load TLS certificate: {}", e);
             CustomError::IoError(e)
-        })?;
-
-    if !status.success() {
-        return Err(CustomError::IoError(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("Failed to clone repository: {}", repo_url),
-        )));
+        })?)),
+        _ => None,
+    };
+    let work_dir = &config.clone.work_dir;
+    match work_dir.sweep() {
+        Ok(0) => {}
+        Ok(removed) => println!(
+            "Removed {} stale clone directories from {}",
+            removed,
+            work_dir.root().display()
+        ),
+        Err(e) => eprintln!(
+            "Warning: failed to sweep {}: {}",
+            work_dir.root().display(),
+            e
+        ),
+    }
+    let state = Arc::new(ServerState::new(config)?);
+    if state.api_keys.is_enabled() {
+        auth::reload_on_sighup(state.api_keys.clone());
+    } else {
+        eprintln!("Warning: no API keys configured, the server accepts unauthenticated requests");
     }
 
-    let json_data = git_index(clone_dir.to_str().unwrap()).map_err(|e| {
-        eprintln!("Failed to index git repository: {}", e);
-        e
-    })?;
-
-    // Delete the temporary directory
-    temp_dir.close().map_err(|e| {
-        eprintln!("Failed to delete temporary directory: {}", e);
-        CustomError::IoError(e)
-    })?;
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_addr) = state.config.grpc_bind {
+        // Bound here so a taken port stops the server instead of leaving it HTTP-only
+        let listener = TcpListener::bind(grpc_addr).await?;
+        println!("gRPC server running on {}", grpc_addr);
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = grpc::serve(state, listener).await {
+                eprintln!("gRPC server failed: {}", e);
+            }
+        });
+    }
 
-    Ok(json_data)
-}
+    let addr = state.config.bind;
+    if let Some(tls) = tls {
+        return run_tls_server(state, tls, addr).await;
+    }
 
-fn git_index(repo_path: &str) -> Result<String, CustomError> {
-    let repo = Repository::open(Path::new(repo_path))?;
+    let make_svc = make_service_fn(move |_conn| {
+        let state = state.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle_request(state.clone(), req))) }
+    });
 
-    // Get the HEAD commit
-    let head = repo.head()?;
-    let head_commit = head.peel_to_commit()?;
-    let mut revwalk = repo.revwalk()?;
-    revwalk.push(head_commit.id())?;
+    let server = Server::bind(&addr).serve(make_svc);
 
-    let mut commit_history = Vec::new();
+    println!("Server running on http://{}", addr);
 
-    for commit_id in revwalk {
-        let commit = repo.find_commit(commit_id?)?;
-        let author = commit.author();
-        let message = commit.message().unwrap_or("");
-        let commit_id = commit.id().to_string();
+    server
+        .await
+        .map_err(|e| CustomError::IoError(std::io::Error::other(e)))
+}
 
-        // Extract Pull Request or Issue ID if present in the commit message
-        let pl_and_issue_id = extract_pl_and_issue_id(message);
+async fn run_tls_server(
+    state: Arc<ServerState>,
+    tls: Arc<TlsSettings>,
+    addr: SocketAddr,
+) -> Result<(), CustomError> {
+    tls::reload_on_sighup(tls.clone());
+    let listener = TcpListener::bind(addr).await?;
 
-        // Get the diff for the commit
-        let diff = get_commit_diff(&repo, &commit)?;
+    println!("Server running on https://{}", addr);
 
-        // Create the commit history object
-        let commit_entry = CommitHistory {
-            commit_id,
-            author: author.name().unwrap_or("").to_string(),
-            commit_message: message.to_string(),
-            pl_and_issue_id,
-            git_diff: diff,
-        };
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let acceptor = tls.acceptor();
+        let state = state.clone();
 
-        commit_history.push(commit_entry);
+        // Handshakes run per connection so a slow client can't stall the accept loop
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            };
+            let service = service_fn(move |req| handle_request(state.clone(), req));
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                eprintln!("Connection with {} failed: {}", peer, e);
+            }
+        });
     }
-
-    // Serialize the commit history to JSON
-    let json_output = serde_json::to_string_pretty(&commit_history).map_err(|e| {
-        eprintln!("Failed to serialize commit history to JSON: {}", e);
-        CustomError::JsonError(e)
-    })?;
-    println!("Completed");
-
-    Ok(json_output)
-}
-
-fn extract_pl_and_issue_id(commit_message: &str) -> String {
-    // Assuming the PR or Issue ID is mentioned with a pattern like "PL#123" or "Issue #123"
-    let pr_pattern = Regex::new(r"(PL|Issue)\s*#\d+").unwrap();
-    pr_pattern
-        .find(commit_message)
-        .map_or("".to_string(), |m| m.as_str().to_string())
 }
 
-fn get_commit_diff(
-    repo: &Repository,
-    commit: &git2::Commit,
-) -> Result<Vec<CommitDiff>, CustomError> {
-    let mut diffs = Vec::new();
-    let tree = commit.tree()?;
-
-    // Get the parent commit, if available
-    let parent = if commit.parents().len() > 0 {
-        Some(commit.parent(0)?)
+async fn handle_request(
+    state: Arc<ServerState>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let origin = req.headers().get("Origin").cloned();
+    let encoding = if state.config.compression {
+        ContentEncoding::negotiate(req.headers())
     } else {
         None
     };
 
-    let parent_tree = parent.as_ref().map(|p| p.tree().unwrap());
-    let mut diff_options = DiffOptions::new();
-    let diff =
-        repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), Some(&mut diff_options))?;
-
-    let mut current_file: Option<String> = None;
-    let mut accumulated_diff = String::new();
+    let mut response = route(&state, req).await;
+    state.cors.apply(origin.as_ref(), &mut response);
+    compression::compress_response(encoding, &mut response);
 
-    diff.print(git2::DiffFormat::Patch, |delta, _hunk, line| {
-        if let Some(file_path) = delta.new_file().path() {
-            let file_path_str = file_path.to_string_lossy().to_string();
+    Ok(response)
+}
 
-            if let Some(current) = &current_file {
-                if current != &file_path_str {
-                    // Push the previous file's accumulated diff
-                    diffs.push(CommitDiff {
-                        file: current.clone(),
-                        diff: accumulated_diff.clone(),
-                    });
-                    // Reset the accumulated diff for the new file
-                    accumulated_diff.clear();
+async fn route(state: &Arc<ServerState>, req: Request<Body>) -> Response<Body> {
+    let path = req.uri().path().to_string();
+    let Some((_, method)) = ENDPOINTS.iter().find(|(endpoint, _)| *endpoint == path) else {
+        return json_error(StatusCode::NOT_FOUND, "Not Found");
+    };
+    // Preflights carry no credentials, so they are answered before authentication
+    if req.method() == Method::OPTIONS {
+        return state.cors.preflight();
+    }
+    if req.method() != method {
+        let mut response = json_error(
+            StatusCode::METHOD_NOT_ALLOWED,
+            &format!("{} only answers {}", path, method),
+        );
+        response.headers_mut().insert(
+            "Allow",
+            hyper::header::HeaderValue::from_str(&format!("{}, OPTIONS", method)).unwrap(),
+        );
+        return response;
+    }
+
+    // Webhooks are authenticated by their signature, since senders can't add API keys
+    let public = matches!(
+        path.as_str(),
+        "/health" | "/webhooks/github" | "/webhooks/generic"
+    );
+    if !public && !state.api_keys.authorize(req.headers()) {
+        return json_error(StatusCode::UNAUTHORIZED, "Missing or invalid API key");
+    }
+
+    match path.as_str() {
+        "/health" => {
+            let health = serde_json::json!({
+                "status": "ok",
+                "jobs": {
+                    "max_concurrent": state.config.max_concurrent_jobs,
+                    "in_flight": state.in_flight(),
+                    "queued": state.queued.load(Ordering::SeqCst),
+                },
+                "responses": state.response_sizes.to_json(state.config.max_response_bytes),
+            });
+            Response::builder()
+                .header("Content-Type", "application/json")
+                .body(Body::from(health.to_string()))
+                .unwrap()
+        }
+        "/schema" => Response::builder()
+            .header("Content-Type", "application/schema+json")
+            .body(Body::from(response_schema().to_string()))
+            .unwrap(),
+        "/git_history" => git_history(state, req).await,
+        "/git_history/batch" => git_history_batch(state, req).await,
+        "/webhooks/github" => webhook(state, req, WebhookSource::GitHub).await,
+        "/webhooks/generic" => webhook(state, req, WebhookSource::Generic).await,
+        "/commit" => {
+            let params = query_params(&req);
+            let (Some(repo_url), Some(sha)) = (params.get("repo_url"), params.get("sha")) else {
+                return missing_parameter("repo_url and sha");
+            };
+            let sha = sha.clone();
+            let options =
+                match IndexOptions::redaction_from_query(&params, &state.config.index_defaults) {
+                    Ok(options) => options,
+                    Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+                };
+            repo_job_response(state, repo_url, CloneKind::Checkout, move |path| {
+                let mut commit = show_commit(path, &sha)?;
+                redact::Redactor::new(&options).commit(&mut commit);
+                Ok(commit)
+            })
+            .await
+        }
+        "/file_history" => {
+            let params = query_params(&req);
+            let (Some(repo_url), Some(path)) = (params.get("repo_url"), params.get("path")) else {
+                return missing_parameter("repo_url and path");
+            };
+            let mut file_options = file_history::FileHistoryOptions {
+                emit_content: params.get("emit_content").is_some_and(|e| e == "true"),
+                ..Default::default()
+            };
+            if let Some(raw) = params.get("max_blob_bytes") {
+                match raw.parse() {
+                    Ok(max) => file_options.max_blob_bytes = max,
+                    Err(_) => {
+                        return json_error(
+                            StatusCode::BAD_REQUEST,
+                            "max_blob_bytes must be a non-negative integer",
+                        )
+                    }
+                }
+            }
+            let path = path.clone();
+            let options =
+                match IndexOptions::redaction_from_query(&params, &state.config.index_defaults) {
+                    Ok(options) => options,
+                    Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+                };
+            repo_job_response(state, repo_url, CloneKind::Checkout, move |repo| {
+                let mut history = file_history::file_history(repo, &path, &file_options)?;
+                history.redact(&redact::Redactor::new(&options));
+                Ok(history)
+            })
+            .await
+        }
+        "/blame" => {
+            let params = query_params(&req);
+            let (Some(repo_url), Some(path)) = (params.get("repo_url"), params.get("path")) else {
+                return missing_parameter("repo_url and path");
+            };
+            let path = path.clone();
+            let rev = params
+                .get("ref")
+                .cloned()
+                .unwrap_or_else(|| "HEAD".to_string());
+            let options =
+                match IndexOptions::redaction_from_query(&params, &state.config.index_defaults) {
+                    Ok(options) => options,
+                    Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+                };
+            repo_job_response(state, repo_url, CloneKind::Checkout, move |repo| {
+                let mut hunks = blame::blame_file(repo, &path, &rev)?;
+                let redactor = redact::Redactor::new(&options);
+                hunks.iter_mut().for_each(|hunk| hunk.redact(&redactor));
+                Ok(hunks)
+            })
+            .await
+        }
+        "/authors" => {
+            let params = query_params(&req);
+            let Some(repo_url) = params.get("repo_url") else {
+                return missing_parameter("repo_url");
+            };
+            let options = match IndexOptions::from_query(&params, &state.config.index_defaults) {
+                Ok(options) => options,
+                Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+            };
+            repo_job_response(state, repo_url, CloneKind::Checkout, move |repo| {
+                identities::authors(repo, &options)
+            })
+            .await
+        }
+        "/contributors" => {
+            let params = query_params(&req);
+            let Some(repo_url) = params.get("repo_url") else {
+                return missing_parameter("repo_url");
+            };
+            let top = match params
+                .get("top")
+                .map(|top| top.parse::<usize>())
+                .transpose()
+            {
+                Ok(top) => top,
+                Err(_) => {
+                    return json_error(StatusCode::BAD_REQUEST, "top must be a positive integer")
+                }
+            };
+            let options = match IndexOptions::from_query(&params, &state.config.index_defaults) {
+                Ok(options) => options,
+                Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+            };
+            repo_job_response(state, repo_url, CloneKind::Checkout, move |repo| {
+                contributors::contributors(repo, &options, top)
+            })
+            .await
+        }
+        "/activity" => {
+            let params = query_params(&req);
+            let Some(repo_url) = params.get("repo_url") else {
+                return missing_parameter("repo_url");
+            };
+            let bucket = match params.get("bucket").map(|raw| activity::Bucket::parse(raw)) {
+                None => activity::Bucket::default(),
+                Some(Ok(bucket)) => bucket,
+                Some(Err(e)) => {
+                    return json_error(StatusCode::BAD_REQUEST, &format!("bucket: {}", e))
+                }
+            };
+            let timezone = match params
+                .get("timezone")
+                .map(|raw| activity::parse_timezone(raw))
+            {
+                None => chrono_tz::UTC,
+                Some(Ok(timezone)) => timezone,
+                Some(Err(e)) => {
+                    return json_error(StatusCode::BAD_REQUEST, &format!("timezone: {}", e))
+                }
+            };
+            let options = match IndexOptions::from_query(&params, &state.config.index_defaults) {
+                Ok(options) => options,
+                Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+            };
+            repo_job_response(state, repo_url, CloneKind::Checkout, move |repo| {
+                activity::activity(repo, &options, bucket, timezone)
+            })
+            .await
+        }
+        "/compare" => {
+            let params = query_params(&req);
+            let (Some(repo_url), Some(base), Some(head)) = (
+                params.get("repo_url"),
+                params.get("base"),
+                params.get("head"),
+            ) else {
+                return missing_parameter("repo_url, base and head");
+            };
+            let (base, head) = (base.clone(), head.clone());
+            let options =
+                match IndexOptions::redaction_from_query(&params, &state.config.index_defaults) {
+                    Ok(options) => options,
+                    Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+                };
+            if params.get("squash").is_some_and(|squash| squash == "true") {
+                return repo_job_response(state, repo_url, CloneKind::Checkout, move |repo| {
+                    let mut squashed = compare::squash(repo, &base, &head)?;
+                    squashed.redact(&redact::Redactor::new(&options));
+                    Ok(squashed)
+                })
+                .await;
+            }
+            repo_job_response(state, repo_url, CloneKind::Checkout, move |repo| {
+                let mut comparison = compare::compare(repo, &base, &head)?;
+                comparison.redact(&redact::Redactor::new(&options));
+                Ok(comparison)
+            })
+            .await
+        }
+        "/refs" => {
+            let params = query_params(&req);
+            let Some(repo_url) = params.get("repo_url") else {
+                return missing_parameter("repo_url");
+            };
+            let options =
+                match IndexOptions::redaction_from_query(&params, &state.config.index_defaults) {
+                    Ok(options) => options,
+                    Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+                };
+            repo_job_response(state, repo_url, CloneKind::Bare, move |repo| {
+                let mut refs = refs::list_refs(repo)?;
+                refs.redact(&redact::Redactor::new(&options));
+                Ok(refs)
+            })
+            .await
+        }
+        "/tree" => {
+            let params = query_params(&req);
+            let Some(repo_url) = params.get("repo_url") else {
+                return missing_parameter("repo_url");
+            };
+            let rev = params
+                .get("ref")
+                .cloned()
+                .unwrap_or_else(|| "HEAD".to_string());
+            let subdir = params.get("path").cloned();
+            let recursive = params.get("recursive").is_some_and(|r| r == "true");
+            repo_job_response(state, repo_url, CloneKind::Checkout, move |repo| {
+                tree::list_tree(repo, &rev, subdir.as_deref(), recursive)
+            })
+            .await
+        }
+        "/search" => {
+            let params = query_params(&req);
+            let (Some(repo_url), Some(query)) = (params.get("repo_url"), params.get("q")) else {
+                return missing_parameter("repo_url and q");
+            };
+            let max_results = match params
+                .get("max_results")
+                .map(|m| m.parse::<usize>())
+                .transpose()
+            {
+                Ok(max_results) => max_results,
+                Err(_) => {
+                    return json_error(
+                        StatusCode::BAD_REQUEST,
+                        "max_results must be a positive integer",
+                    )
+                }
+            };
+            let search_options = search::SearchOptions {
+                regex: params.get("regex").is_some_and(|r| r == "true"),
+                include_diffs: params.get("include_diffs").is_some_and(|d| d == "true"),
+                max_results,
+            };
+            // Reject a bad pattern before paying for the clone
+            if search_options.regex {
+                if let Err(e) = Regex::new(query) {
+                    return json_error(
+                        StatusCode::BAD_REQUEST,
+                        &format!("Invalid regex '{}': {}", query, e),
+                    );
+                }
+            }
+            let options = match IndexOptions::from_query(&params, &state.config.index_defaults) {
+                Ok(options) => options,
+                Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+            };
+            let query = query.clone();
+            repo_job_response(state, repo_url, CloneKind::Checkout, move |repo| {
+                search::search(repo, &query, &options, &search_options)
+            })
+            .await
+        }
+        _ => unreachable!("{} is listed in ENDPOINTS without a handler", path),
+    }
+}
+
+async fn git_history(state: &ServerState, req: Request<Body>) -> Response<Body> {
+    let (parts, body) = req.into_parts();
+    let full_body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let parsed_body: serde_json::Value = match serde_json::from_slice(&full_body) {
+        Ok(body) => body,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, &format!("Invalid JSON: {}", e)),
+    };
+    let mut options = match IndexOptions::from_json(&parsed_body, &state.config.index_defaults) {
+        Ok(options) => options,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+    };
+    options.max_output_bytes = Some(state.config.max_response_bytes);
+
+    // Requests without any options can be answered from the webhook-maintained cache
+    let default_options = parsed_body.as_object().is_some_and(|body| body.len() == 1);
+    if let Some(cached) = parsed_body["repo_url"]
+        .as_str()
+        .filter(|_| default_options)
+        .and_then(|repo_url| state.webhooks.cached(repo_url))
+    {
+        let mut response = match &cached.tip {
+            Some(tip) => {
+                let etag = conditional::etag(&tip.id, &parsed_body);
+                let time = state.last_modified.observe(&parsed_body, &etag);
+                if conditional::is_not_modified(&parts.headers, &etag, time) {
+                    conditional::not_modified(&etag, time)
+                } else {
+                    let mut response = json_response(cached.history);
+                    conditional::add_validators(&mut response, &etag, time);
+                    response
                 }
             }
+            None => json_response(cached.history),
+        };
+        response
+            .headers_mut()
+            .insert("X-Cache", hyper::header::HeaderValue::from_static("hit"));
+        return response;
+    }
+
+    let source = match state.repo_source(&parsed_body) {
+        Ok(source) => source,
+        Err((status, message)) => return json_error(status, &message),
+    };
+    if parsed_body["stream"].as_bool().unwrap_or(false) {
+        if options.enrich.is_some() {
+            return json_error(
+                StatusCode::BAD_REQUEST,
+                "enrich needs the whole history and can't be streamed",
+            );
+        }
+        let Some(permit) = state.acquire_job().await else {
+            return overloaded_response();
+        };
+        return stream_history(source, options, state.config.clone.clone(), permit);
+    }
+    // Enriched histories change with the provider's data, not just with the tip, and
+    // tokens of the server's random redaction salt with every restart
+    let cacheable =
+        options.enrich.is_none() && (options.redact.is_none() || options.redact_salt.is_some());
+    if cacheable {
+        if let Some(tip) = conditional::resolve_tip(&source, &options, &state.config.clone).await {
+            let etag = conditional::etag(&tip.id, &parsed_body);
+            let time = state.last_modified.observe(&parsed_body, &etag);
+            if conditional::is_not_modified(&parts.headers, &etag, time) {
+                return conditional::not_modified(&etag, time);
+            }
+        }
+    }
+    let _permit = match state.acquire_job().await {
+        Some(permit) => permit,
+        None => return overloaded_response(),
+    };
+    let repo = source.name();
+    let result = index_source(source, options.clone(), &state.config.clone)
+        .await
+        .and_then(|mut indexed| {
+            let history = server_index(&repo, &mut indexed, &options)?;
+            state
+                .response_sizes
+                .record(indexed.output_bytes, indexed.next_cursor.is_some());
+            Ok((history, indexed.validator))
+        });
 
-            current_file = Some(file_path_str);
+    match result {
+        Ok((history, tip)) => {
+            let mut response = json_response(history);
+            if let Some(tip) = tip.filter(|_| cacheable) {
+                let etag = conditional::etag(&tip.id, &parsed_body);
+                let time = state.last_modified.observe(&parsed_body, &etag);
+                // Remote requests for other refs than HEAD skip the fast path, but an
+                // unchanged history still needn't be sent again
+                if conditional::is_not_modified(&parts.headers, &etag, time) {
+                    return conditional::not_modified(&etag, time);
+                }
+                conditional::add_validators(&mut response, &etag, time);
+            }
+            response
         }
+        Err(e) => error_response(&e),
+    }
+}
+
+/// Indexes several repositories at once. Answers with an object keyed by repository
+/// or, with `"stream": true`, with one NDJSON record per repository as each finishes.
+async fn git_history_batch(state: &ServerState, req: Request<Body>) -> Response<Body> {
+    let full_body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let parsed_body: serde_json::Value = match serde_json::from_slice(&full_body) {
+        Ok(body) => body,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, &format!("Invalid JSON: {}", e)),
+    };
+    let mut options = match IndexOptions::from_json(&parsed_body, &state.config.index_defaults) {
+        Ok(options) => options,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+    };
+    // Each repository gets the whole budget, as it is a result of its own
+    options.max_output_bytes = Some(state.config.max_response_bytes);
+    let entries = match batch::parse_request(state, &parsed_body) {
+        Ok(entries) => entries,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+    };
 
-        accumulated_diff.push_str(&String::from_utf8_lossy(line.content()).to_string());
+    let mut results = batch::run(entries, &options, state.jobs.clone(), &state.config.clone);
+    if parsed_body["stream"].as_bool().unwrap_or(false) {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            while let Some(result) = results.recv().await {
+                if sender.send_data(result.ndjson_line().into()).await.is_err() {
+                    return;
+                }
+            }
+        });
+        return Response::builder()
+            .header("Content-Type", "application/x-ndjson")
+            .body(body)
+            .unwrap();
+    }
 
-        true
+    let histories = batch::collect(results).await;
+    match serde_json::to_string_pretty(&histories) {
+        Ok(json) => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::from(json))
+            .unwrap(),
+        Err(e) => error_response(&CustomError::JsonError(e)),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum WebhookSource {
+    GitHub,
+    Generic,
+}
+
+/// Accepts a signed push notification and re-indexes the repository in the
+/// background, answering `202` right away.
+async fn webhook(
+    state: &Arc<ServerState>,
+    req: Request<Body>,
+    source: WebhookSource,
+) -> Response<Body> {
+    if !state.webhooks.is_enabled() {
+        return json_error(
+            StatusCode::NOT_FOUND,
+            "Webhooks are disabled, start the server with --webhook-secret",
+        );
+    }
+    let (parts, body) = req.into_parts();
+    // Read before the signature can be checked, so anyone can send it
+    let body = match read_limited(&parts.headers, body, webhooks::MAX_PAYLOAD_BYTES).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+    if !state.webhooks.verify(&parts.headers, &body) {
+        return json_error(StatusCode::UNAUTHORIZED, "Missing or invalid signature");
+    }
+
+    let push = match source {
+        WebhookSource::GitHub => webhooks::github_push(&parts.headers, &body),
+        WebhookSource::Generic => webhooks::generic_push(&body).map(Some),
+    };
+    let push = match push {
+        Ok(Some(push)) => push,
+        Ok(None) => return accepted(serde_json::json!({ "status": "ignored" })),
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, &e),
+    };
+
+    let status = if state.webhooks.begin(&push.repo_url) {
+        tokio::spawn(reindex(state.clone(), push.repo_url.clone()));
+        "queued"
+    } else {
+        // Folded into the re-index already running
+        "coalesced"
+    };
+    accepted(serde_json::json!({
+        "status": status,
+        "repo_url": push.repo_url,
+        "ref": push.git_ref,
+    }))
+}
+
+/// Reads a request body of at most `limit` bytes. A larger `Content-Length` is refused
+/// before anything is read, and a body without one once it grows past the limit.
+async fn read_limited(
+    headers: &hyper::HeaderMap,
+    mut body: Body,
+    limit: usize,
+) -> Result<hyper::body::Bytes, Response<Body>> {
+    use hyper::body::HttpBody;
+    let too_large = || {
+        json_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &format!("Request bodies are limited to {} bytes", limit),
+        )
+    };
+    let declared = headers
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+    if declared.is_some_and(|length| length > limit as u64) {
+        return Err(too_large());
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| json_error(StatusCode::BAD_REQUEST, &e.to_string()))?;
+        if bytes.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes.into())
+}
+
+fn accepted(body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Indexes `repo_url` into the webhook cache, once more for every burst of pushes that
+/// arrived while it ran. Background jobs wait for a slot regardless of the overload
+/// policy, which only applies to clients.
+async fn reindex(state: Arc<ServerState>, repo_url: String) {
+    loop {
+        let Ok(_permit) = state.jobs.clone().acquire_owned().await else {
+            return;
+        };
+        // What a request with just the `repo_url` gets, since that is what is cached
+        let options = IndexOptions {
+            envelope: true,
+            max_output_bytes: Some(state.config.max_response_bytes),
+            ..IndexOptions::default()
+        };
+        match process_git_repo(&repo_url, options, &state.config.clone).await {
+            Ok((history, tip)) => {
+                state.webhooks.store(&repo_url, history, tip);
+                println!("Re-indexed {}", repo_url);
+            }
+            Err(e) => eprintln!("Failed to re-index {}: {}", repo_url, e),
+        }
+        if !state.webhooks.finish(&repo_url) {
+            return;
+        }
+    }
+}
+
+fn query_params(req: &Request<Body>) -> HashMap<String, String> {
+    let query = req.uri().query().unwrap_or("");
+    form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect()
+}
+
+fn json_response(body: String) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn overloaded_response() -> Response<Body> {
+    let mut response = json_error(
+        StatusCode::TOO_MANY_REQUESTS,
+        "Too many concurrent jobs, retry later",
+    );
+    response
+        .headers_mut()
+        .insert("Retry-After", RETRY_AFTER_SECS.into());
+    response
+}
+
+fn error_response(err: &CustomError) -> Response<Body> {
+    coded_error(err.http_status(), err.code(), &err.to_string())
+}
+
+/// Answers a request lacking the query parameters `names`.
+fn missing_parameter(names: &str) -> Response<Body> {
+    coded_error(
+        StatusCode::BAD_REQUEST,
+        "missing_parameter",
+        &format!("Missing query parameter: {}", names),
+    )
+}
+
+/// Clones `repo_url` under a job slot, runs `job` on the clone and returns its result
+/// as JSON.
+async fn repo_job_response<T, F>(
+    state: &ServerState,
+    repo_url: &str,
+    kind: CloneKind,
+    job: F,
+) -> Response<Body>
+where
+    T: Serialize + Send + 'static,
+    F: FnOnce(&Path) -> Result<T, CustomError> + Send + 'static,
+{
+    let _permit = match state.acquire_job().await {
+        Some(permit) => permit,
+        None => return overloaded_response(),
+    };
+    let result = with_cloned_repo(repo_url, kind, &state.config.clone, job)
+        .await
+        .and_then(|value| serde_json::to_string_pretty(&value).map_err(CustomError::JsonError));
+    match result {
+        Ok(json) => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::from(json))
+            .unwrap(),
+        Err(e) => error_response(&e),
+    }
+}
+
+/// A JSON error response, `{"error": message, "code": code}`, whose code is named
+/// after the status, e.g. `bad_request`.
+fn json_error(status: StatusCode, message: &str) -> Response<Body> {
+    let code = status
+        .canonical_reason()
+        .unwrap_or("error")
+        .to_ascii_lowercase()
+        .replace([' ', '-'], "_");
+    coded_error(status, &code, message)
+}
+
+/// A JSON error response with a code more specific than its status, such as
+/// `binary_file` or `missing_parameter`.
+fn coded_error(status: StatusCode, code: &str, message: &str) -> Response<Body> {
+    let body = serde_json::json!({ "error": message, "code": code });
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// The repository a history request names: `repo_url` to clone, or `repo_path` on the
+/// server's disk.
+enum RepoSource {
+    Url(String),
+    Local(PathBuf),
+}
+
+impl RepoSource {
+    /// The repository as the request named it, for the `meta` of responses.
+    fn name(&self) -> String {
+        match self {
+            RepoSource::Url(repo_url) => repo_url.clone(),
+            RepoSource::Local(repo_path) => repo_path.display().to_string(),
+        }
+    }
+}
+
+/// Indexes `repo_url` for a server response, returning it with the tip it was
+/// indexed from.
+async fn process_git_repo(
+    repo_url: &str,
+    options: IndexOptions,
+    settings: &clone::CloneSettings,
+) -> Result<(String, Option<conditional::Tip>), CustomError> {
+    let mut indexed = index_source(
+        RepoSource::Url(repo_url.to_string()),
+        options.clone(),
+        settings,
+    )
+    .await?;
+    let history = server_index(repo_url, &mut indexed, &options)?;
+    Ok((history, indexed.tip))
+}
+
+/// Indexes and enriches a repository for the HTTP server and batches. Local
+/// repositories skip the clone, URLs are cloned with `settings`.
+async fn index_source(
+    source: RepoSource,
+    options: IndexOptions,
+    settings: &clone::CloneSettings,
+) -> Result<IndexedRepo, CustomError> {
+    let job_options = options.clone();
+    let mut indexed = match source {
+        RepoSource::Url(repo_url) => {
+            with_cloned_repo(&repo_url, CloneKind::Checkout, settings, move |path| {
+                index_repo(path, &job_options)
+            })
+            .await?
+        }
+        RepoSource::Local(repo_path) => {
+            tokio::task::spawn_blocking(move || index_repo(&repo_path, &job_options))
+                .await
+                .map_err(|e| CustomError::IoError(std::io::Error::other(e)))??
+        }
+    };
+    enrich::enrich(
+        &mut indexed.commits,
+        indexed.remote_url.as_deref(),
+        &options,
+    )
+    .await;
+    Ok(indexed)
+}
+
+/// Serializes an index for server responses, cut to `max_output_bytes` as sent, see
+/// `budget::fit`.
+fn server_index(
+    repo: &str,
+    indexed: &mut IndexedRepo,
+    options: &IndexOptions,
+) -> Result<String, CustomError> {
+    budget::fit(indexed, options.max_output_bytes, |indexed| {
+        render_server_index(repo, indexed, options)
+    })
+}
+
+/// Serializes an index in an envelope unless the request asked for
+/// `"envelope": false`. Without one, an empty repository is answered with an explicit
+/// marker instead of a bare `[]`, so clients can tell it apart from filters that
+/// matched nothing, and a shallow one with a marker that its history is incomplete.
+fn render_server_index(
+    repo: &str,
+    indexed: &IndexedRepo,
+    options: &IndexOptions,
+) -> Result<String, CustomError> {
+    if options.envelope {
+        let output = envelope::wrap(repo, indexed, options);
+        return Ok(serde_json::to_string_pretty(&output)?);
+    }
+    let mut meta = serde_json::Map::new();
+    if indexed.empty_repository {
+        meta.insert("empty_repository".into(), true.into());
+    }
+    if indexed.shallow {
+        meta.insert("shallow".into(), true.into());
+    }
+    if let Some(cursor) = &indexed.next_cursor {
+        meta.insert("truncated".into(), true.into());
+        meta.insert("next_cursor".into(), cursor.as_str().into());
+    }
+    if meta.is_empty() {
+        return history_json(&indexed.commits);
+    }
+    let output = serde_json::json!({
+        "meta": meta,
+        "commits": indexed.commits,
+    });
+    Ok(serde_json::to_string_pretty(&output)?)
+}
+
+/// Answers `"stream": true` with NDJSON written as the history is indexed: a
+/// `{"start": {...}}` record, one `{"commit": {...}}` per commit and a
+/// `{"finish": {...}}` record, or `{"error": {"status", "message"}}` if indexing fails
+/// on the way. Commits go out before duplicates could be marked. A client that
+/// disconnects stops the indexing, which frees the job slot.
+fn stream_history(
+    source: RepoSource,
+    options: IndexOptions,
+    settings: clone::CloneSettings,
+    permit: OwnedSemaphorePermit,
+) -> Response<Body> {
+    let (mut events, indexing) = spawn_index(source, options, settings, permit);
+    let (mut body, response) = Body::channel();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            let record = match event {
+                sink::Event::Start(run) => serde_json::json!({ "start": run }),
+                sink::Event::Commit(commit) => serde_json::json!({ "commit": commit }),
+                sink::Event::Finish(run) => serde_json::json!({ "finish": run }),
+            };
+            // Dropping `events` on the way out makes the sink fail and the walk stop
+            if body
+                .send_data(format!("{}\n", record).into())
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+        let error = match indexing.await {
+            Ok(Ok(_)) => return,
+            Ok(Err(e)) => serde_json::json!({
+                "error": { "status": e.http_status().as_u16(), "message": e.to_string() }
+            }),
+            Err(e) => serde_json::json!({
+                "error": { "status": 500, "message": e.to_string() }
+            }),
+        };
+        let _ = body.send_data(format!("{}\n", error).into()).await;
+    });
+    Response::builder()
+        .header("Content-Type", "application/x-ndjson")
+        .body(response)
+        .unwrap()
+}
+
+/// Commits a streamed response holds before indexing waits for the client.
+const STREAM_BUFFER: usize = 16;
+
+type IndexTask = tokio::task::JoinHandle<Result<IndexedRepo, CustomError>>;
+
+/// Indexes `source` on a task of its own, which holds `permit` until it is done, and
+/// hands every step of the walk to the returned channel through a `ChannelSink`.
+/// Dropping the receiver stops the walk. The task's `IndexedRepo` has no `commits`.
+fn spawn_index(
+    source: RepoSource,
+    options: IndexOptions,
+    settings: clone::CloneSettings,
+    permit: OwnedSemaphorePermit,
+) -> (tokio::sync::mpsc::Receiver<sink::Event>, IndexTask) {
+    let (sender, events) = tokio::sync::mpsc::channel(STREAM_BUFFER);
+    let indexing = tokio::spawn(async move {
+        let _permit = permit;
+        let mut sink = sink::ChannelSink::new(sender);
+        match source {
+            RepoSource::Url(repo_url) => {
+                with_cloned_repo(&repo_url, CloneKind::Checkout, &settings, move |path| {
+                    index_into(path, &options, &mut sink)
+                })
+                .await
+            }
+            RepoSource::Local(repo_path) => {
+                tokio::task::spawn_blocking(move || index_into(&repo_path, &options, &mut sink))
+                    .await
+                    .map_err(|e| CustomError::IoError(std::io::Error::other(e)))?
+            }
+        }
+    });
+    (events, indexing)
+}
+
+/// Clones `repo_url` into a temporary directory and runs `job` on it. The job is
+/// synchronous libgit2 work, so it runs off the hyper executor threads.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CloneKind {
+    /// Regular clone with a working tree
+    Checkout,
+    /// Bare clone, which keeps every remote branch as a local branch
+    Bare,
+}
+
+async fn with_cloned_repo<T, F>(
+    repo_url: &str,
+    kind: CloneKind,
+    settings: &clone::CloneSettings,
+    job: F,
+) -> Result<T, CustomError>
+where
+    T: Send + 'static,
+    F: FnOnce(&Path) -> Result<T, CustomError> + Send + 'static,
+{
+    // Dropping the directory removes it, so every early return below cleans up
+    let temp_dir = settings.work_dir.create().map_err(|e| {
+        eprintln!("Failed to create clone directory: {}", e);
+        e
+    })?;
+    let clone_dir = temp_dir.path().join("repo");
+
+    clone::clone(repo_url, &clone_dir, kind, settings).await?;
+
+    let job_dir = clone_dir.clone();
+    let output = tokio::task::spawn_blocking(move || job(&job_dir))
+        .await
+        .map_err(|e| CustomError::IoError(std::io::Error::other(e)))?
+        .map_err(|e| {
+            eprintln!("Failed to process git repository: {}", e);
+            e
+        })?;
+
+    // Delete the temporary directory
+    temp_dir.close().map_err(|e| {
+        eprintln!("Failed to delete temporary directory: {}", e);
+        CustomError::IoError(e)
     })?;
 
-    if let Some(current) = current_file {
-        diffs.push(CommitDiff {
-            file: current,
-            diff: accumulated_diff,
+    Ok(output)
+}
+
+/// A repository's indexed commits, before enrichment.
+struct IndexedRepo {
+    commits: Vec<CommitHistory>,
+    empty_repository: bool,
+    /// Whether the history is incomplete because the repository is a shallow clone
+    shallow: bool,
+    /// URL of the `origin` remote, which tells enrichment where pull requests live
+    remote_url: Option<String>,
+    /// The ref the history was walked from, see `walked_ref`
+    git_ref: Option<String>,
+    /// The commit the history was walked from, unless the repository is empty
+    tip: Option<conditional::Tip>,
+    /// What the history's ETag is made from, see `conditional::walk_tip`
+    validator: Option<conditional::Tip>,
+    /// What `--range` resolved to
+    range: Option<range::RevRange>,
+    /// The first commit left out because the history outgrew `max_output_bytes`
+    next_cursor: Option<String>,
+    /// Size of the commits as pretty-printed JSON, or of the whole response once the
+    /// server has rendered it
+    output_bytes: u64,
+}
+
+async fn git_index(repo_path: &Path, options: &IndexOptions) -> Result<String, CustomError> {
+    let mut indexed = index_repo(repo_path, options)?;
+    if indexed.empty_repository {
+        eprintln!("Repository has no commits yet, the history is empty");
+    }
+    enrich::enrich(&mut indexed.commits, indexed.remote_url.as_deref(), options).await;
+    if options.envelope {
+        let output = envelope::wrap(&repo_path.display().to_string(), &indexed, options);
+        println!("Completed");
+        return Ok(serde_json::to_string_pretty(&output)?);
+    }
+    if indexed.range.is_none() && !indexed.shallow {
+        return history_json(&indexed.commits);
+    }
+    // Echo what the range resolved to, so scripts can check what was indexed
+    let mut meta = serde_json::Map::new();
+    if let Some(range) = &indexed.range {
+        meta.insert("range".into(), serde_json::to_value(range)?);
+    }
+    if indexed.shallow {
+        meta.insert("shallow".into(), true.into());
+    }
+    let output = serde_json::json!({
+        "meta": meta,
+        "commits": indexed.commits,
+    });
+    println!("Completed");
+    Ok(serde_json::to_string_pretty(&output)?)
+}
+
+/// Indexes `repo_path` into memory, with duplicates marked. This is what every output
+/// but the streamed ones is made from.
+fn index_repo(repo_path: &Path, options: &IndexOptions) -> Result<IndexedRepo, CustomError> {
+    let mut sink = sink::VecSink::default();
+    let mut indexed = index_into(repo_path, options, &mut sink)?;
+    indexed.commits = sink.commits;
+    patch_id::mark_duplicates(&Repository::open(repo_path)?, &mut indexed.commits);
+    Ok(indexed)
+}
+
+/// Indexes `repo_path`, handing each commit to `sink` as soon as it is ready. The
+/// returned `IndexedRepo` describes the run and has no `commits`.
+fn index_into<S: CommitSink>(
+    repo_path: &Path,
+    options: &IndexOptions,
+    sink: &mut S,
+) -> Result<IndexedRepo, CustomError> {
+    let repo = Repository::open(repo_path)?;
+    let remote_url = repo
+        .find_remote("origin")
+        .ok()
+        .and_then(|remote| remote.url().map(String::from));
+    let tip = repo
+        .revparse_single(options.rev.as_deref().unwrap_or("HEAD"))
+        .and_then(|object| object.peel_to_commit())
+        .ok()
+        .map(|commit| conditional::Tip {
+            id: commit.id().to_string(),
         });
+    let range = options
+        .range
+        .as_deref()
+        .map(|spec| range::resolve(&repo, spec))
+        .transpose()?;
+    let mut indexed = IndexedRepo {
+        commits: Vec::new(),
+        empty_repository: head_is_unborn(&repo),
+        shallow: repo.is_shallow(),
+        remote_url,
+        git_ref: walked_ref(&repo, options),
+        tip,
+        validator: conditional::walk_tip(&repo, options),
+        range,
+        next_cursor: None,
+        output_bytes: 0,
+    };
+    sink.on_start(&sink::RunStart {
+        git_ref: indexed.git_ref.clone(),
+        tip: indexed.tip.as_ref().map(|tip| tip.id.clone()),
+        range: indexed.range.clone(),
+        empty_repository: indexed.empty_repository,
+        shallow: indexed.shallow,
+    })?;
+
+    let mut commit_count = 0;
+    let boundaries = grafts::Boundaries::load(&repo)?;
+    let mut boundary_commits = 0;
+    // Authors stay as recorded unless corrections were asked for
+    let identities = identities::Identities::load(&repo, options)?;
+    let redactor = redact::Redactor::new(options);
+    let mut budget = budget::Budget::new(options.max_output_bytes);
+    let mut next_cursor = None;
+
+    // The entry for one commit, or None if it is left out
+    let mut history_entry = |commit: &git2::Commit,
+                             diff: &mut git2::Diff,
+                             all_changes_excluded: bool|
+     -> Result<Option<CommitHistory>, CustomError> {
+        let mut entry = commit_summary(&repo, commit, options.sha_length)?;
+        entry.all_changes_excluded = all_changes_excluded;
+        if identities.is_mapped() {
+            // Canonical but not yet redacted, like the rest of the entry
+            let author = identities.resolve(&commit.author())?;
+            entry.author = String::from_utf8_lossy(author.name_bytes()).to_string();
+        }
+        if boundaries.contains(commit) {
+            boundary_commits += 1;
+            entry.graft_boundary = true;
+            match options.boundary_diff {
+                BoundaryDiff::Full => {}
+                BoundaryDiff::Empty => {
+                    if options.stats_only {
+                        entry.stats = Some(CommitStats {
+                            files_changed: 0,
+                            insertions: 0,
+                            deletions: 0,
+                        });
+                    }
+                    return Ok(Some(entry));
+                }
+                BoundaryDiff::Omit => return Ok(None),
+            }
+        }
+        if options.finds_renames() {
+            entry.rename_detection_skipped = !find_renames(diff, options)?;
+        }
+        if let Some(threshold) = options.flag_large_additions {
+            entry.large_files = large_files::large_additions(&repo, diff, threshold)?;
+        }
+        if !options.includes_patches() {
+            // Listing the changed files is cheap, it's the patches that take time
+            if options.stats_only {
+                let stats = diff.stats()?;
+                entry.stats = Some(CommitStats {
+                    files_changed: stats.files_changed(),
+                    insertions: stats.insertions(),
+                    deletions: stats.deletions(),
+                });
+            }
+            return Ok(Some(entry));
+        }
+        entry.git_diff = diff_entries(diff, options.diff_format)?;
+        if options.ignores_whitespace() {
+            let whitespace_only = whitespace_only_files(diff)?;
+            entry
+                .git_diff
+                .retain(|file| !whitespace_only.contains(&file.file));
+            entry.whitespace_only = !whitespace_only.is_empty() && entry.git_diff.is_empty();
+        }
+        entry.patch_id = if options.narrows_diff() {
+            // The filtered diff is only part of the change
+            let full = commit_tree_diff(&repo, commit, &mut DiffOptions::new())?;
+            patch_id::patch_id(commit, &full)?
+        } else {
+            patch_id::patch_id(commit, diff)?
+        };
+        if options.word_diff {
+            add_word_diffs(diff, &mut entry.git_diff, options.word_diff_max_bytes())?;
+        }
+        large_files::mark_lfs_pointers(&repo, &mut entry.git_diff)?;
+        if !options.include_generated {
+            let mut generated = attributes::GeneratedFiles::new(&repo, commit)?;
+            for file in entry.git_diff.iter_mut().filter(|file| file.kind.is_none()) {
+                if generated.is_generated(Path::new(&file.file))? {
+                    file.diff.clear();
+                    file.word_diff = None;
+                    file.generated = true;
+                }
+            }
+        }
+        if let Some(max_bytes) = options.max_diff_bytes {
+            for file in &mut entry.git_diff {
+                if file.diff.len() as u64 > max_bytes {
+                    file.diff.clear();
+                    file.word_diff = None;
+                    file.diff_too_large = true;
+                }
+            }
+        }
+        if options.recurse_submodules {
+            for file in entry
+                .git_diff
+                .iter_mut()
+                .filter(|file| file.kind.as_deref() == Some("submodule"))
+            {
+                file.submodule_commits = submodule_commits(&repo, file);
+            }
+        }
+        Ok(Some(entry))
+    };
+    try_for_each_commit(&repo, options, |commit, diff, all_changes_excluded| {
+        let Some(mut entry) = history_entry(commit, diff, all_changes_excluded)? else {
+            return Ok(ControlFlow::Continue(()));
+        };
+        redactor.commit(&mut entry);
+        // Checked as the history grows, so an oversized one is never held in full
+        if !budget.admit(&entry)? {
+            next_cursor = Some(entry.commit_id);
+            return Ok(ControlFlow::Break(()));
+        }
+        sink.on_commit(&entry)?;
+        commit_count += 1;
+        Ok(ControlFlow::Continue(()))
+    })?;
+    if boundary_commits > 0 {
+        let handling = match options.boundary_diff {
+            BoundaryDiff::Full => "kept their whole-tree diffs",
+            BoundaryDiff::Empty => "got an empty git_diff",
+            BoundaryDiff::Omit => "were left out",
+        };
+        eprintln!(
+            "Warning: {} commits lost their parents to a shallow clone, replace ref or graft and {} (see --boundary-diff)",
+            boundary_commits, handling
+        );
+    }
+
+    indexed.next_cursor = next_cursor;
+    indexed.output_bytes = budget.used();
+    sink.on_finish(&sink::RunEnd {
+        commit_count,
+        next_cursor: indexed.next_cursor.clone(),
+        output_bytes: indexed.output_bytes,
+    })?;
+    Ok(indexed)
+}
+
+/// The full name of the ref the history was walked from: the branch HEAD names, even
+/// before its first commit, or `HEAD` when detached. A batch entry's ref is expanded
+/// like git does, and kept as given if it is a SHA.
+fn walked_ref(repo: &Repository, options: &IndexOptions) -> Option<String> {
+    if options.range.is_some() {
+        return None;
+    }
+    if let Some(rev) = &options.rev {
+        return Some(
+            repo.resolve_reference_from_short_name(rev)
+                .ok()
+                .and_then(|reference| reference.name().map(String::from))
+                .unwrap_or_else(|| rev.clone()),
+        );
+    }
+    let head = repo.find_reference("HEAD").ok()?;
+    Some(head.symbolic_target().unwrap_or("HEAD").to_string())
+}
+
+fn history_json(commit_history: &[CommitHistory]) -> Result<String, CustomError> {
+    // Serialize the commit history to JSON
+    let mut json = sink::JsonSink::new(Vec::new());
+    for commit in commit_history {
+        json.on_commit(commit).map_err(|e| {
+            eprintln!("Failed to serialize commit history to JSON: {}", e);
+            e
+        })?;
+    }
+    let json_output = String::from_utf8(json.finish()?).expect("serde_json writes UTF-8");
+    println!("Completed");
+
+    Ok(json_output)
+}
+
+/// Walks the history from HEAD and calls `f` with every commit that passes the
+/// filters, together with its diff against the first parent (limited to the filtered
+/// paths, if any, and without excluded files) and whether exclusion emptied that diff.
+/// The diff is mutable so `f` can run rename detection on it.
+fn for_each_commit<F>(
+    repo: &Repository,
+    options: &IndexOptions,
+    mut f: F,
+) -> Result<(), CustomError>
+where
+    F: FnMut(&git2::Commit, &mut git2::Diff, bool) -> Result<(), CustomError>,
+{
+    try_for_each_commit(repo, options, |commit, diff, all_changes_excluded| {
+        f(commit, diff, all_changes_excluded).map(ControlFlow::Continue)
+    })
+}
+
+/// `for_each_commit`, stopping early when `f` breaks. With a cursor the walk starts
+/// at that commit, so the commits before it get no diff.
+fn try_for_each_commit<F>(
+    repo: &Repository,
+    options: &IndexOptions,
+    mut f: F,
+) -> Result<(), CustomError>
+where
+    F: FnMut(&git2::Commit, &mut git2::Diff, bool) -> Result<ControlFlow<()>, CustomError>,
+{
+    let exclude = options
+        .exclude_matcher()
+        .map_err(CustomError::InvalidRequest)?;
+    let mut before_cursor = options.cursor.is_some();
+
+    for commit_id in history_revwalk(repo, options)? {
+        let commit_id = commit_id?;
+        if before_cursor {
+            if Some(commit_id) != options.cursor {
+                continue;
+            }
+            before_cursor = false;
+        }
+        let commit = repo.find_commit(commit_id)?;
+        if !options.accepts(&commit) {
+            continue;
+        }
+
+        let mut diff = commit_tree_diff(repo, &commit, &mut options.diff_options())?;
+        if !options.paths.is_empty() && diff.deltas().len() == 0 {
+            continue;
+        }
+
+        let flow = match exclude.as_ref() {
+            Some(exclude) => {
+                let (mut diff, all_changes_excluded) =
+                    exclude_from_diff(repo, &commit, diff, exclude, options)?;
+                f(&commit, &mut diff, all_changes_excluded)?
+            }
+            None => f(&commit, &mut diff, false)?,
+        };
+        if flow.is_break() {
+            return Ok(());
+        }
     }
 
-    Ok(diffs)
-}
-
-// use reqwest::header::{HeaderMap, HeaderValue, USER_AGENT};
-// use reqwest::Client;
-// use serde::Deserialize;
-// use tokio;
-// use std::fs::File;
-// use std::io::{self, Write};
-// use std::io::BufRead;
-
-// #[derive(Deserialize, Debug)]
-// struct PullRequest {
-//     number: u32,
-//     title: Option<String>,
-//     body: Option<String>,
-//     head: Option<Head>,
-// }
-
-// #[derive(Deserialize, Debug)]
-// struct Head {
-//     sha: Option<String>,
-// }
-
-// async fn get_pull_request_details(owner: &str, repo: &str, pr_number: u32) -> Result<PullRequest, Box<dyn std::error::Error>> {
-//     let client = Client::new();
-//     let mut headers = HeaderMap::new();
-//     headers.insert(USER_AGENT, HeaderValue::from_static("reqwest"));
-
-//     let url = format!("https://api.github.com/repos/{}/{}/pulls/{}", owner, repo, pr_number);
-//     let response = client.get(&url).headers(headers).send().await?;
-//     let text = response.text().await?;
-
-//     // Log the raw JSON response for debugging
-//     println!("Raw JSON response for PR {}: {}", pr_number, text);
-
-//     let pr: PullRequest = serde_json::from_str(&text)?;
-//     Ok(pr)
-// }
-
-// async fn fetch_all_pull_requests(owner: &str, repo: &str) -> Result<Vec<PullRequest>, Box<dyn std::error::Error>> {
-//     let client = Client::new();
-//     let mut headers = HeaderMap::new();
-//     headers.insert(USER_AGENT, HeaderValue::from_static("reqwest"));
-
-//     let mut page = 1;
-//     const PER_PAGE: u32 = 100;
-//     let mut all_pull_requests = Vec::new();
-
-//     loop {
-//         println!("Fetching page {}", page);
-//         let url = format!(
-//             "https://api.github.com/repos/{}/{}/pulls?state=closed&per_page={}&page={}",
-//             owner, repo, PER_PAGE, page
-//         );
-
-//         // Print the raw response for debugging
-//         let raw_response = client.get(&url).headers(headers.clone()).send().await?;
-//         let text = raw_response.text().await?;
-//         println!("Response from page {}: {}", page, text);
-
-//         // Attempt to parse the response
-//         match serde_json::from_str::<Vec<PullRequest>>(&text) {
-//             Ok(response) => {
-//                 if response.is_empty() {
-//                     break;
-//                 }
-//                 all_pull_requests.extend(response);
-//                 page += 1;
-//             },
-//             Err(e) => {
-//                 eprintln!("Failed to parse JSON: {:?}", e);
-//                 break;
-//             }
-//         }
-//     }
-
-//     // Write all PR numbers to a file after fetching all pages
-//     let mut file = File::create("prs.txt")?;
-//     for pr in &all_pull_requests {
-//         writeln!(file, "{}", pr.number)?;
-//     }
-
-//     Ok(all_pull_requests)
-// }
-
-// #[tokio::main]
-// async fn main() -> Result<(), Box<dyn std::error::Error>> {
-//     let owner = "facebook";
-//     let repo = "react";
-
-//     let file = File::open("prs.txt")?;
-//     let reader = io::BufReader::new(file);
-
-//     for line in reader.lines() {
-//         let pr_number: u32 = line?.parse()?;
-//         let pr = get_pull_request_details(owner, repo, pr_number).await?;
-//         println!("{:?}", pr);
-//     }
-
-//     Ok(())
-// }
+    if before_cursor {
+        return Err(CustomError::InvalidRequest(format!(
+            "cursor {} is not part of the history",
+            options.cursor.expect("a cursor was given")
+        )));
+    }
+    Ok(())
+}
+
+/// Drops excluded files from `diff`. The deltas are listed without generating any
+/// patch text, and the diff is recomputed for just the remaining files, so excluded
+/// files never get a patch. Returns whether every change was excluded.
+fn exclude_from_diff<'r>(
+    repo: &'r Repository,
+    commit: &git2::Commit,
+    diff: git2::Diff<'r>,
+    exclude: &ignore::gitignore::Gitignore,
+    options: &IndexOptions,
+) -> Result<(git2::Diff<'r>, bool), CustomError> {
+    let is_excluded = |path: Option<&Path>| {
+        path.is_some_and(|path| exclude.matched_path_or_any_parents(path, false).is_ignore())
+    };
+    let mut kept = Vec::new();
+    let mut kept_deltas = 0;
+    for delta in diff.deltas() {
+        let (old, new) = (delta.old_file().path(), delta.new_file().path());
+        if !is_excluded(old) && !is_excluded(new) {
+            kept.extend([old, new].into_iter().flatten().map(Path::to_path_buf));
+            kept_deltas += 1;
+        }
+    }
+    if kept_deltas == diff.deltas().len() {
+        return Ok((diff, false));
+    }
+    if kept_deltas == 0 {
+        let tree = commit.tree()?;
+        let empty = repo.diff_tree_to_tree(Some(&tree), Some(&tree), None)?;
+        return Ok((empty, true));
+    }
+
+    let mut diff_options = options.patch_options();
+    diff_options.disable_pathspec_match(true);
+    for path in kept {
+        diff_options.pathspec(path);
+    }
+    Ok((commit_tree_diff(repo, commit, &mut diff_options)?, false))
+}
+
+/// Revwalk starting at HEAD, or at every branch, remote branch and tag as well with
+/// `--all`. A repository without commits yields an empty walk.
+fn history_revwalk<'r>(
+    repo: &'r Repository,
+    options: &IndexOptions,
+) -> Result<grafts::Walk<'r>, CustomError> {
+    let (push, mut hide) = walk_starts(repo, options)?;
+    if let Some(after) = options.after_commit {
+        hide.push(after);
+    }
+    grafts::Walk::new(repo, push, hide)
+}
+
+/// The commits the history of `options` is walked from and those whose history is
+/// left out: every ref with `all`, the range or `rev` or HEAD. Both are empty for
+/// a repository without commits.
+fn walk_starts(
+    repo: &Repository,
+    options: &IndexOptions,
+) -> Result<(Vec<git2::Oid>, Vec<git2::Oid>), CustomError> {
+    let mut push = Vec::new();
+    let mut hide = Vec::new();
+    if options.all {
+        for glob in ["refs/heads/*", "refs/remotes/*", "refs/tags/*"] {
+            for reference in repo.references_glob(glob)? {
+                // Like `git log --all`, refs to anything but commits are skipped
+                if let Ok(commit) = reference?.peel_to_commit() {
+                    push.push(commit.id());
+                }
+            }
+        }
+    }
+    if let Some(spec) = &options.range {
+        let range = range::resolve(repo, spec)?;
+        push.extend(range.include);
+        hide.extend(range.exclude);
+    } else if let Some(rev) = &options.rev {
+        let start = repo.revparse_single(rev)?.peel_to_commit()?;
+        push.push(start.id());
+    } else if !head_is_unborn(repo) {
+        // Get the HEAD commit
+        let head = repo.head()?;
+        let head_commit = head.peel_to_commit()?;
+        push.push(head_commit.id());
+    }
+    Ok((push, hide))
+}
+
+/// True for freshly initialized repositories, whose HEAD names a branch that has no
+/// commits yet.
+fn head_is_unborn(repo: &Repository) -> bool {
+    matches!(repo.head(), Err(e) if matches!(e.code(), ErrorCode::UnbornBranch | ErrorCode::NotFound))
+}
+
+fn commit_entry(repo: &Repository, commit: &git2::Commit) -> Result<CommitHistory, CustomError> {
+    let mut entry = commit_summary(repo, commit, None)?;
+
+    // Get the diff for the commit
+    let diff = commit_tree_diff(repo, commit, &mut DiffOptions::new())?;
+    entry.git_diff = diff_entries(&diff, PatchFormat::default())?;
+    large_files::mark_lfs_pointers(repo, &mut entry.git_diff)?;
+    entry.patch_id = patch_id::patch_id(commit, &diff)?;
+    // A single commit asked for by SHA keeps its diff, but is still marked
+    entry.graft_boundary = grafts::Boundaries::load(repo)?.contains(commit);
+
+    Ok(entry)
+}
+
+/// Commit metadata without any diff, for callers that compute their own `git_diff`.
+/// SHAs are abbreviated to at least `sha_length` characters, see `abbreviate`.
+fn commit_summary(
+    repo: &Repository,
+    commit: &git2::Commit,
+    sha_length: Option<usize>,
+) -> Result<CommitHistory, CustomError> {
+    let author = commit.author();
+    let message = commit_message(commit);
+
+    // Extract Pull Request or Issue ID if present in the commit message
+    let pl_and_issue_id = extract_pl_and_issue_id(&message.text);
+    let parent_ids: Vec<git2::Oid> = commit.parent_ids().collect();
+
+    Ok(CommitHistory {
+        commit_id: commit.id().to_string(),
+        commit_id_short: abbreviate(repo, commit.id(), sha_length)?,
+        parent_ids: parent_ids.iter().map(|id| id.to_string()).collect(),
+        parent_ids_short: parent_ids
+            .iter()
+            .map(|id| abbreviate(repo, *id, sha_length))
+            .collect::<Result<_, _>>()?,
+        author: String::from_utf8_lossy(author.name_bytes()).to_string(),
+        commit_message: message.text,
+        message_encoding: message.encoding,
+        message_is_lossy: message.lossy,
+        patch_id: None,
+        duplicate_of: None,
+        all_changes_excluded: false,
+        whitespace_only: false,
+        graft_boundary: false,
+        rename_detection_skipped: false,
+        pull_request: None,
+        issue: None,
+        pl_and_issue_id,
+        stats: None,
+        large_files: Vec::new(),
+        git_diff: Vec::new(),
+    })
+}
+
+/// The shortest prefix of `id` that is at least `min_length` long and names no other
+/// object of the repository, like `git rev-parse --short=<n>`. Without a `min_length`,
+/// libgit2's `short_id` picks the length from `core.abbrev`. Parents missing from a
+/// shallow clone are abbreviated among the objects that are there.
+fn abbreviate(
+    repo: &Repository,
+    id: git2::Oid,
+    min_length: Option<usize>,
+) -> Result<String, CustomError> {
+    let full = id.to_string();
+    let Some(min_length) = min_length else {
+        if let Ok(object) = repo.find_object(id, None) {
+            let short = object.short_id()?;
+            return Ok(short.as_str().unwrap_or(&full).to_string());
+        }
+        return abbreviate(repo, id, Some(7));
+    };
+    let odb = repo.odb()?;
+    for length in min_length..full.len() {
+        match odb.exists_prefix(id, length) {
+            Err(e) if e.code() == ErrorCode::Ambiguous => continue,
+            Ok(_) | Err(_) => return Ok(full[..length].to_string()),
+        }
+    }
+    Ok(full)
+}
+
+/// A commit message decoded to UTF-8.
+struct DecodedMessage {
+    text: String,
+    /// The charset named by the commit's `encoding` header, if any
+    encoding: Option<String>,
+    /// Whether undecodable bytes were replaced with U+FFFD
+    lossy: bool,
+}
+
+/// Decodes a commit message. Git stores messages as raw bytes with an optional
+/// `encoding` header, set by `i18n.commitEncoding` when the message isn't UTF-8 (old
+/// commits often carry Latin-1, ISO-2022-JP or GBK). Messages are transcoded from the
+/// declared charset; unknown or misdeclared charsets fall back to lossy UTF-8.
+fn commit_message(commit: &git2::Commit) -> DecodedMessage {
+    let raw = commit.message_bytes();
+    let label = commit.message_encoding();
+    let declared = label.and_then(|label| encoding_rs::Encoding::for_label(label.as_bytes()));
+
+    if let Some(encoding) = declared.filter(|encoding| *encoding != encoding_rs::UTF_8) {
+        if let Some(text) = encoding.decode_without_bom_handling_and_without_replacement(raw) {
+            return DecodedMessage {
+                text: text.into_owned(),
+                encoding: Some(encoding.name().to_string()),
+                lossy: false,
+            };
+        }
+    }
+
+    let encoding = declared
+        .map(|encoding| encoding.name().to_string())
+        .or(label.map(String::from));
+    match std::str::from_utf8(raw) {
+        Ok(text) => DecodedMessage {
+            text: text.to_string(),
+            encoding,
+            lossy: false,
+        },
+        Err(_) => DecodedMessage {
+            text: String::from_utf8_lossy(raw).to_string(),
+            encoding,
+            lossy: true,
+        },
+    }
+}
+
+/// Resolves a ref, SHA or other revision expression to the commit it points at.
+fn resolve_commit<'r>(repo: &'r Repository, spec: &str) -> Result<git2::Commit<'r>, CustomError> {
+    repo.revparse_single(spec)
+        .and_then(|object| object.peel_to_commit())
+        .map_err(|_| CustomError::RefNotFound(spec.to_string()))
+}
+
+/// Looks up a single commit by full or abbreviated SHA (or any revision git
+/// understands) without walking the history.
+fn show_commit(repo_path: &Path, sha: &str) -> Result<CommitHistory, CustomError> {
+    let repo = Repository::open(repo_path)?;
+    let commit = repo.revparse_single(sha)?.peel_to_commit()?;
+    commit_entry(&repo, &commit)
+}
+
+/// JSON Schema of the history output, tagged with the format and tool versions.
+fn response_schema() -> serde_json::Value {
+    let mut schema = serde_json::to_value(schemars::schema_for!(Vec<CommitHistory>))
+        .expect("schema always serializes");
+    schema["title"] = "git-history commit history".into();
+    schema["x-format-version"] = FORMAT_VERSION.into();
+    schema["x-tool-version"] = env!("CARGO_PKG_VERSION").into();
+    schema
+}
+
+/// Formats a git timestamp as RFC 3339, keeping the committer's UTC offset.
+fn format_git_time(time: git2::Time) -> String {
+    chrono::FixedOffset::east_opt(time.offset_minutes() * 60)
+        .and_then(|offset| offset.timestamp_opt(time.seconds(), 0).single())
+        .map(|date| date.to_rfc3339())
+        .unwrap_or_default()
+}
+
+fn extract_pl_and_issue_id(commit_message: &str) -> String {
+    // Assuming the PR or Issue ID is mentioned with a pattern like "PL#123" or "Issue #123"
+    static PR_PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pr_pattern = PR_PATTERN.get_or_init(|| Regex::new(r"(PL|Issue)\s*#\d+").unwrap());
+    pr_pattern
+        .find(commit_message)
+        .map_or("".to_string(), |m| m.as_str().to_string())
+}
+
+/// Fills in `word_diff` for the files of `diff` small enough for it.
+fn add_word_diffs(
+    diff: &git2::Diff,
+    files: &mut [CommitDiff],
+    max_bytes: u64,
+) -> Result<(), CustomError> {
+    for idx in 0..diff.deltas().len() {
+        let Some(patch) = git2::Patch::from_diff(diff, idx)? else {
+            continue;
+        };
+        let path = patch.delta().new_file().path().map(Path::to_path_buf);
+        let Some(path) = path else {
+            continue;
+        };
+        let path = path.to_string_lossy();
+        if let Some(file) = files.iter_mut().find(|file| file.file == path) {
+            file.word_diff = word_diff::render(&patch, max_bytes)?;
+        }
+    }
+    Ok(())
+}
+
+/// Paths of the modified files left without any hunk once whitespace is ignored.
+/// libgit2 still lists them, with file headers only.
+fn whitespace_only_files(diff: &git2::Diff) -> Result<HashSet<String>, CustomError> {
+    let mut files = HashSet::new();
+    for idx in 0..diff.deltas().len() {
+        let Some(patch) = git2::Patch::from_diff(diff, idx)? else {
+            continue;
+        };
+        let delta = patch.delta();
+        let content_only = delta.status() == git2::Delta::Modified
+            && delta.old_file().mode() == delta.new_file().mode()
+            && !delta.flags().is_binary();
+        if content_only && patch.num_hunks() == 0 {
+            if let Some(path) = delta.new_file().path() {
+                files.insert(path.to_string_lossy().to_string());
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Diffs a commit's tree against its first parent (or the empty tree for a root commit).
+fn commit_tree_diff<'r>(
+    repo: &'r Repository,
+    commit: &git2::Commit,
+    diff_options: &mut DiffOptions,
+) -> Result<git2::Diff<'r>, CustomError> {
+    let tree = commit.tree()?;
+
+    // Get the parent commit, if available. Shallow clones lack the parents of their
+    // oldest commits, which are then diffed against the empty tree like roots.
+    let parent = match commit.parent(0) {
+        Ok(parent) => Some(parent),
+        Err(e) if e.code() == ErrorCode::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let parent_tree = parent.as_ref().map(|p| p.tree()).transpose()?;
+    Ok(repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), Some(diff_options))?)
+}
+
+/// Similarity scores of the renamed and copied files of `diff`, by old and new path.
+/// libgit2 keeps them to itself except in its raw output (`R087`, `C095`); its patch
+/// headers only show those of renames.
+fn similarity_scores(diff: &git2::Diff) -> Result<HashMap<(String, String), u16>, CustomError> {
+    let mut scores = HashMap::new();
+    diff.print(git2::DiffFormat::Raw, |delta, _, line| {
+        if !matches!(delta.status(), git2::Delta::Renamed | git2::Delta::Copied) {
+            return true;
+        }
+        // `:<mode> <mode> <id> <id> <status><score>\t<old path>\t<new path>`
+        let content = String::from_utf8_lossy(line.content());
+        let score = content
+            .split('\t')
+            .next()
+            .and_then(|fields| fields.split(' ').nth(4))
+            .and_then(|status| status.get(1..))
+            .and_then(|score| score.parse().ok());
+        let path = |file: git2::DiffFile| {
+            file.path()
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_default()
+        };
+        if let Some(score) = score {
+            scores.insert((path(delta.old_file()), path(delta.new_file())), score);
+        }
+        true
+    })?;
+    Ok(scores)
+}
+
+/// Renders a diff as one `CommitDiff` entry per file, sorted by new path and then old
+/// path so the output doesn't depend on libgit2's delta order.
+fn diff_entries(diff: &git2::Diff, format: PatchFormat) -> Result<Vec<CommitDiff>, CustomError> {
+    let path = |file: git2::DiffFile| {
+        file.path()
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_default()
+    };
+    let scores = similarity_scores(diff)?;
+    // (new path, old path, entry)
+    let mut diffs: Vec<(String, String, CommitDiff)> = Vec::new();
+
+    for (idx, delta) in diff.deltas().enumerate() {
+        let new_path = path(delta.new_file());
+        let old_path = path(delta.old_file());
+        let mode = |file: git2::DiffFile| {
+            Some(format!("{:06o}", u32::from(file.mode()))).filter(|_| file.exists())
+        };
+        let blob_id = |file: git2::DiffFile| {
+            Some(file.id().to_string()).filter(|_| file.exists() && !file.id().is_zero())
+        };
+
+        let patch = git2::Patch::from_diff(diff, idx)?;
+        // Counted like `Diff::stats`, so the files of a commit add up to its totals
+        let (additions, deletions) = match &patch {
+            Some(patch) if !patch.delta().flags().is_binary() => {
+                let (_, additions, deletions) = patch.line_stats()?;
+                (Some(additions), Some(deletions))
+            }
+            _ => (None, None),
+        };
+
+        // Submodules only get a "Subproject commit" pseudo-patch, so list their SHAs
+        if is_submodule_delta(&delta) {
+            let sha = |file: git2::DiffFile| Some(file.id()).filter(|id| !id.is_zero());
+            let file = if new_path.is_empty() {
+                &old_path
+            } else {
+                &new_path
+            };
+            diffs.push((
+                new_path.clone(),
+                old_path.clone(),
+                CommitDiff {
+                    file: file.clone(),
+                    additions,
+                    deletions,
+                    old_mode: mode(delta.old_file()),
+                    new_mode: mode(delta.new_file()),
+                    old_blob_id: blob_id(delta.old_file()),
+                    new_blob_id: blob_id(delta.new_file()),
+                    kind: Some("submodule".to_string()),
+                    old_sha: sha(delta.old_file()).map(|id| id.to_string()),
+                    new_sha: sha(delta.new_file()).map(|id| id.to_string()),
+                    ..Default::default()
+                },
+            ));
+            continue;
+        }
+
+        // Each delta is rendered on its own, so two deltas never share an entry
+        let Some(mut patch) = patch else {
+            continue;
+        };
+        let mut accumulated_diff = String::new();
+        patch.print(&mut |_delta, _hunk, line| {
+            // Legacy output drops the +/-/space markers. Headers carry no marker either way.
+            if format == PatchFormat::Unified && matches!(line.origin(), '+' | '-' | ' ') {
+                accumulated_diff.push(line.origin());
+            }
+            accumulated_diff.push_str(&String::from_utf8_lossy(line.content()));
+            true
+        })?;
+        let status = match delta.status() {
+            git2::Delta::Renamed => Some("renamed"),
+            git2::Delta::Copied => Some("copied"),
+            _ => None,
+        };
+        let similarity = scores.get(&(old_path.clone(), new_path.clone())).copied();
+        diffs.push((
+            new_path.clone(),
+            old_path.clone(),
+            CommitDiff {
+                file: new_path,
+                diff: accumulated_diff,
+                additions,
+                deletions,
+                old_mode: mode(delta.old_file()),
+                new_mode: mode(delta.new_file()),
+                old_blob_id: blob_id(delta.old_file()),
+                new_blob_id: blob_id(delta.new_file()),
+                status: status.map(String::from),
+                old_file: status.map(|_| old_path),
+                similarity,
+                ..Default::default()
+            },
+        ));
+    }
+
+    diffs.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+    Ok(diffs.into_iter().map(|(_, _, entry)| entry).collect())
+}
+
+/// Runs rename (and copy) detection on `diff`, unless pairing every deleted with
+/// every added file would take more than `rename_limit` squared comparisons, as git
+/// does. Returns whether it ran.
+fn find_renames(diff: &mut git2::Diff, options: &IndexOptions) -> Result<bool, CustomError> {
+    let copies = options.copy_threshold.is_some();
+    let (mut sources, mut targets) = (0, 0);
+    for delta in diff.deltas() {
+        match delta.status() {
+            git2::Delta::Added => targets += 1,
+            git2::Delta::Deleted => sources += 1,
+            git2::Delta::Modified if copies => sources += 1,
+            _ => {}
+        }
+    }
+    let limit = options.rename_limit();
+    if limit > 0 && (sources > limit || targets > limit) && sources * targets > limit * limit {
+        return Ok(false);
+    }
+    diff.find_similar(Some(&mut options.find_options()))?;
+    Ok(true)
+}
+
+fn is_submodule_delta(delta: &git2::DiffDelta) -> bool {
+    delta.new_file().mode() == git2::FileMode::Commit
+        || delta.old_file().mode() == git2::FileMode::Commit
+}
+
+/// Subjects of the commits a submodule moved through between `old` and `new`, newest
+/// first. `None` when the submodule isn't checked out or lacks either commit.
+fn submodule_commits(repo: &Repository, entry: &CommitDiff) -> Option<Vec<String>> {
+    let old = git2::Oid::from_str(entry.old_sha.as_deref()?).ok()?;
+    let new = git2::Oid::from_str(entry.new_sha.as_deref()?).ok()?;
+    let submodule = repo.find_submodule(&entry.file).ok()?.open().ok()?;
+
+    let mut revwalk = submodule.revwalk().ok()?;
+    revwalk.push(new).ok()?;
+    revwalk.hide(old).ok()?;
+    revwalk
+        .map(|id| {
+            let commit = submodule.find_commit(id.ok()?).ok()?;
+            Some(String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default()).to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_repo::TestRepo;
+
+    fn server(config: ServerConfig) -> Arc<ServerState> {
+        Arc::new(ServerState::new(config).unwrap())
+    }
+
+    /// Sends `request` through everything a connection's requests go through.
+    async fn send(
+        state: &Arc<ServerState>,
+        request: Request<Body>,
+    ) -> (hyper::http::response::Parts, String) {
+        let response = handle_request(state.clone(), request).await.unwrap();
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        (parts, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn header<'a>(parts: &'a hyper::http::response::Parts, name: &str) -> Option<&'a str> {
+        parts.headers.get(name).map(|value| value.to_str().unwrap())
+    }
+
+    fn fixture() -> TestRepo {
+        let repo = TestRepo::new();
+        repo.write("README.md", "hello\n").commit("Add a readme");
+        repo
+    }
+
+    fn local_server(repo: &TestRepo, cors_origins: &[&str]) -> Arc<ServerState> {
+        server(ServerConfig {
+            allow_local_paths: vec![repo.path().to_path_buf()],
+            cors_origins: cors_origins.iter().map(|o| o.to_string()).collect(),
+            ..ServerConfig::default()
+        })
+    }
+
+    /// A server whose clones of `repos` clone the fixtures instead.
+    fn cloning_server(repos: &[&TestRepo]) -> Arc<ServerState> {
+        let mut config = ServerConfig::default();
+        config.clone.retries = 0;
+        config.clone.git_config = repos.iter().map(|repo| repo.clone_config()).collect();
+        server(config)
+    }
+
+    async fn get(state: &Arc<ServerState>, uri: &str) -> (StatusCode, serde_json::Value) {
+        let (parts, body) = send(state, Request::get(uri).body(Body::empty()).unwrap()).await;
+        assert_eq!(
+            header(&parts, "Content-Type"),
+            Some("application/json"),
+            "{}",
+            body
+        );
+        (parts.status, serde_json::from_str(&body).unwrap())
+    }
+
+    async fn post(
+        state: &Arc<ServerState>,
+        uri: &str,
+        body: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let request = Request::post(uri)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let (parts, body) = send(state, request).await;
+        (parts.status, serde_json::from_str(&body).unwrap())
+    }
+
+    fn history_request(repo: &TestRepo, origin: &str) -> Request<Body> {
+        let body = serde_json::json!({ "repo_path": repo.path() });
+        Request::post("/git_history")
+            .header("Origin", origin)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    /// Indexes `repo` with the `index` flags `flags`, as the JSON `index` writes.
+    /// Every history made this way is checked against the published schema.
+    fn index(repo: &TestRepo, flags: &[&str]) -> serde_json::Value {
+        let args: Vec<String> = flags.iter().map(|flag| flag.to_string()).collect();
+        let (_, options) = options::parse_args(&args, |_, _| Ok(false)).unwrap();
+        let indexed = index_repo(repo.path(), &options).unwrap();
+        let history = serde_json::from_str(&history_json(&indexed.commits).unwrap()).unwrap();
+        assert_matches_schema(&history);
+        history
+    }
+
+    /// Checks `history` against the schema `GET /schema` and `schema` publish.
+    fn assert_matches_schema(history: &serde_json::Value) {
+        let schema = response_schema();
+        let validator = jsonschema::JSONSchema::compile(&schema).unwrap();
+        if let Err(errors) = validator.validate(history) {
+            let errors: Vec<String> = errors
+                .map(|e| format!("{} at {}", e, e.instance_path))
+                .collect();
+            panic!("output doesn't match the schema:\n{}", errors.join("\n"));
+        };
+    }
+
+    /// A history with most kinds of change: additions, edits, a mode change, a
+    /// binary file, an LFS pointer, a rename, a deletion, a generated file, a
+    /// submodule and a merge.
+    fn varied_fixture() -> (TestRepo, TestRepo) {
+        let submodule = TestRepo::new();
+        submodule
+            .write("lib.c", "int x;\n")
+            .commit("Start the library");
+
+        let repo = TestRepo::new();
+        repo.write("src/lib.rs", "fn main() {}\n")
+            .write("README.md", "# Fixture\n\nSome text to rename later.\n")
+            .write("bin.dat", b"\x00\x01\x02binary")
+            .write("run.sh", "echo hi\n")
+            .commit("Add the first files\n\nSigned-off-by: Jane Doe <jane@example.com>");
+        repo.write("src/lib.rs", "fn main() {\n    println!(\"hi\");\n}\n")
+            .commit("Print a greeting (#12)");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let script = repo.path().join("run.sh");
+            fs::set_permissions(script, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        repo.commit("Make the script executable");
+        repo.rename("README.md", "docs/README.md")
+            .remove("bin.dat")
+            .write(
+            "model.bin",
+            "version https://git-lfs.github.com/spec/v1\noid sha256:4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393\nsize 12345\n",
+        )
+        .commit("Move the readme and drop the binary");
+        repo.git(["checkout", "-q", "-b", "feature"]);
+        repo.write(".gitattributes", "gen/** linguist-generated\n")
+            .write("gen/schema.rs", "// generated\n")
+            .commit("Generate the schema");
+        repo.git([
+            "-c",
+            "protocol.file.allow=always",
+            "submodule",
+            "add",
+            "-q",
+            submodule.path().to_str().unwrap(),
+            "vendor/lib",
+        ]);
+        repo.commit("Vendor the library");
+        repo.git(["checkout", "-q", "main"]);
+        repo.write("src/lib.rs", "fn main() {\n    println!(\"hello\");\n}\n")
+            .commit("Greet more formally");
+        repo.git([
+            "merge",
+            "-q",
+            "--no-ff",
+            "-m",
+            "Merge the feature",
+            "feature",
+        ]);
+        (repo, submodule)
+    }
+
+    #[test]
+    fn outputs_match_the_published_schema() {
+        let (repo, _submodule) = varied_fixture();
+        for flags in [
+            &[][..],
+            &["--diff-format", "unified", "--word-diff"],
+            &["--rename-threshold", "50", "--copy-threshold", "60"],
+            &["--flag-large-additions", "4", "--include-generated"],
+            &["--stats-only"],
+            &["--no-diff"],
+            &["--all", "--sha-length", "10"],
+            &["--redact", "both", "--redact-salt", "pepper"],
+        ] {
+            // `index` checks the output against the schema
+            let history = index(&repo, flags);
+            assert!(history.as_array().unwrap().len() >= 7, "{:?}", flags);
+        }
+    }
+
+    #[test]
+    fn varied_fixture_covers_the_optional_fields() {
+        let (repo, _submodule) = varied_fixture();
+        let full = index(&repo, &["--word-diff", "--rename-threshold", "50"]).to_string();
+        let flagged = index(&repo, &["--flag-large-additions", "4", "--stats-only"]).to_string();
+        for field in [
+            r#""kind":"submodule""#,
+            r#""lfs":{"#,
+            r#""generated":true"#,
+            r#""word_diff":"#,
+            r#""status":"renamed""#,
+            r#""new_mode":"100755""#,
+            r#""additions":null"#,
+        ] {
+            assert!(full.contains(field), "{} missing", field);
+        }
+        assert!(flagged.contains(r#""large_files":[{"#));
+        assert!(flagged.contains(r#""stats":{"#));
+    }
+
+    #[test]
+    fn schema_rejects_output_that_drifted() {
+        let repo = fixture();
+        let mut history = index(&repo, &[]);
+        history[0]["commit_id"] = 42.into();
+        let schema = response_schema();
+        let validator = jsonschema::JSONSchema::compile(&schema).unwrap();
+        assert!(!validator.is_valid(&history));
+    }
+
+    #[tokio::test]
+    async fn schema_endpoint_publishes_the_schema() {
+        let state = server(ServerConfig::default());
+        let (parts, body) =
+            send(&state, Request::get("/schema").body(Body::empty()).unwrap()).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(
+            header(&parts, "Content-Type"),
+            Some("application/schema+json")
+        );
+        let schema: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(schema, response_schema());
+        assert_eq!(schema["x-format-version"], FORMAT_VERSION);
+    }
+
+    #[tokio::test]
+    async fn preflight_allows_configured_origin() {
+        let state = server(ServerConfig {
+            cors_origins: vec!["https://dash.example".to_string()],
+            // Preflights carry no credentials and must not need them
+            api_keys: vec!["secret".to_string()],
+            ..ServerConfig::default()
+        });
+        let request = Request::options("/git_history")
+            .header("Origin", "https://dash.example")
+            .header("Access-Control-Request-Method", "POST")
+            .header("Access-Control-Request-Headers", "content-type")
+            .body(Body::empty())
+            .unwrap();
+
+        let (parts, body) = send(&state, request).await;
+        assert_eq!(parts.status, StatusCode::NO_CONTENT);
+        assert!(body.is_empty());
+        assert_eq!(
+            header(&parts, "Access-Control-Allow-Origin"),
+            Some("https://dash.example")
+        );
+        assert!(header(&parts, "Access-Control-Allow-Methods")
+            .unwrap()
+            .contains("POST"));
+        let allowed = header(&parts, "Access-Control-Allow-Headers").unwrap();
+        for request_header in ["Content-Type", "If-None-Match", "If-Modified-Since"] {
+            assert!(allowed.contains(request_header), "{}", request_header);
+        }
+        assert_eq!(header(&parts, "Access-Control-Max-Age"), Some("7200"));
+        assert_eq!(header(&parts, "Access-Control-Allow-Credentials"), None);
+        assert_eq!(header(&parts, "Vary"), Some("Origin"));
+    }
+
+    #[tokio::test]
+    async fn post_with_allowed_origin_gets_cors_headers() {
+        let repo = fixture();
+        let state = local_server(&repo, &["https://dash.example"]);
+
+        let (parts, body) = send(&state, history_request(&repo, "https://dash.example")).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(
+            header(&parts, "Access-Control-Allow-Origin"),
+            Some("https://dash.example")
+        );
+        // Scripts can read the validators to send them back
+        assert_eq!(
+            header(&parts, "Access-Control-Expose-Headers"),
+            Some("ETag, Last-Modified")
+        );
+        assert!(header(&parts, "ETag").unwrap().starts_with("W/\""));
+        assert!(body.contains("Add a readme"));
+    }
+
+    #[tokio::test]
+    async fn post_with_other_origin_gets_no_allow_origin() {
+        let repo = fixture();
+        let state = local_server(&repo, &["https://dash.example"]);
+
+        let (parts, _) = send(&state, history_request(&repo, "https://evil.example")).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(header(&parts, "Access-Control-Allow-Origin"), None);
+        assert_eq!(header(&parts, "Vary"), Some("Origin"));
+    }
+
+    #[tokio::test]
+    async fn wildcard_origin_allows_everyone() {
+        let repo = fixture();
+        let state = local_server(&repo, &["*"]);
+
+        let (parts, _) = send(&state, history_request(&repo, "https://any.example")).await;
+        assert_eq!(header(&parts, "Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(header(&parts, "Vary"), None);
+    }
+
+    #[tokio::test]
+    async fn cors_is_disabled_by_default() {
+        let repo = fixture();
+        let state = local_server(&repo, &[]);
+
+        let (parts, _) = send(&state, history_request(&repo, "https://dash.example")).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(header(&parts, "Access-Control-Allow-Origin"), None);
+
+        let request = Request::options("/git_history")
+            .header("Origin", "https://dash.example")
+            .body(Body::empty())
+            .unwrap();
+        let (parts, _) = send(&state, request).await;
+        assert_eq!(header(&parts, "Access-Control-Allow-Methods"), None);
+    }
+
+    #[tokio::test]
+    async fn blame_errors_are_json_with_a_code() {
+        let repo = fixture();
+        repo.write("bin.dat", b"\x00\x01binary")
+            .commit("Add a binary");
+        let state = cloning_server(&[&repo]);
+        let url = repo.url();
+
+        let (status, body) = get(&state, &format!("/blame?repo_url={}&path=bin.dat", url)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["code"], "binary_file");
+        assert!(body["error"].as_str().unwrap().contains("bin.dat"));
+
+        let (status, body) = get(&state, &format!("/blame?repo_url={}&path=nope.rs", url)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "path_not_found");
+
+        let (status, body) = get(&state, &format!("/blame?repo_url={}&path=README.md", url)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["line_count"], 1);
+    }
+
+    #[tokio::test]
+    async fn missing_parameters_are_json_with_a_code() {
+        let state = server(ServerConfig::default());
+        for uri in [
+            "/commit?repo_url=x",
+            "/file_history?path=a",
+            "/blame?repo_url=x",
+            "/authors",
+            "/contributors",
+            "/activity",
+            "/compare?repo_url=x&base=a",
+            "/refs",
+            "/tree",
+            "/search?repo_url=x",
+        ] {
+            let (status, body) = get(&state, uri).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", uri);
+            assert_eq!(body["code"], "missing_parameter", "{}", uri);
+            assert!(body["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("Missing query parameter"));
+        }
+    }
+
+    #[tokio::test]
+    async fn other_errors_are_named_after_their_status() {
+        let state = server(ServerConfig::default());
+        let (status, body) = get(&state, "/nowhere").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["code"], "not_found");
+
+        let request = Request::post("/git_history")
+            .body(Body::from(r#"{"repo_url": "x", "since": "yesterday"}"#))
+            .unwrap();
+        let (parts, body) = send(&state, request).await;
+        assert_eq!(parts.status, StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["code"], "bad_request");
+    }
+
+    #[tokio::test]
+    async fn compare_with_an_unknown_ref_is_unprocessable() {
+        let repo = fixture();
+        let state = cloning_server(&[&repo]);
+        let uri = format!("/compare?repo_url={}&base=v9.9&head=main", repo.url());
+        let (status, body) = get(&state, &uri).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body["code"], "ref_not_found");
+
+        let uri = format!("/compare?repo_url={}&base=main&head=main", repo.url());
+        let (status, body) = get(&state, &uri).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["commits"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn refs_endpoint_sees_every_remote_branch() {
+        let repo = fixture();
+        repo.git(["branch", "release"]);
+        repo.git(["tag", "-a", "v1", "-m", "First release"]);
+        let state = cloning_server(&[&repo]);
+
+        let (status, body) = get(&state, &format!("/refs?repo_url={}", repo.url())).await;
+        assert_eq!(status, StatusCode::OK);
+        let names: Vec<_> = body["branches"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|branch| branch["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["main", "release"]);
+        assert_eq!(body["tags"][0]["name"], "v1");
+        assert_eq!(body["tags"][0]["annotated"], true);
+    }
+
+    /// Emails of the `email_fixture`, none of them under a dotted public domain but one.
+    const RAW_EMAILS: [&str; 3] = ["root@localhost", "dev@buildhost", "jane@example.com"];
+
+    /// Commits, a trailer, file contents and an annotated tag naming `RAW_EMAILS`.
+    fn email_fixture() -> TestRepo {
+        let repo = TestRepo::new();
+        repo.write("CONTACT", "root@localhost\ndev@buildhost\n")
+            .commit_as(
+                "Root",
+                "root@localhost",
+                "Add contacts\n\nCo-authored-by: Dev <dev@buildhost>",
+            );
+        repo.write("README.md", "Ask jane@example.com or root@localhost\n")
+            .commit_as("Dev", "dev@buildhost", "Add a readme for dev@buildhost");
+        repo.git_as(
+            "Root",
+            "root@localhost",
+            ["tag", "-a", "v1", "-m", "Released by root@localhost"],
+        );
+        repo
+    }
+
+    fn assert_no_raw_email(output: &str, what: &str) {
+        for email in RAW_EMAILS {
+            assert!(
+                !output.contains(email),
+                "{} leaks {}: {}",
+                what,
+                email,
+                output
+            );
+        }
+    }
+
+    #[test]
+    fn redacted_histories_keep_no_raw_email() {
+        let repo = email_fixture();
+        let plain = index(&repo, &[]).to_string();
+        assert!(RAW_EMAILS.iter().all(|email| plain.contains(email)));
+
+        let redacted = index(&repo, &["--redact", "emails", "--redact-salt", "pepper"]);
+        assert_no_raw_email(&redacted.to_string(), "index");
+        assert!(redacted.to_string().contains("@redacted.invalid"));
+    }
+
+    #[tokio::test]
+    async fn every_endpoint_redacts_on_request() {
+        let repo = email_fixture();
+        let first = repo.git(["rev-list", "--max-parents=0", "HEAD"]);
+        let state = cloning_server(&[&repo]);
+        let url = repo.url();
+
+        let uris = [
+            format!("/commit?repo_url={}&sha=HEAD", url),
+            format!(
+                "/file_history?repo_url={}&path=CONTACT&emit_content=true",
+                url
+            ),
+            format!("/blame?repo_url={}&path=README.md", url),
+            format!("/authors?repo_url={}", url),
+            format!("/contributors?repo_url={}", url),
+            format!("/compare?repo_url={}&base={}&head=main", url, first.trim()),
+            format!("/compare?repo_url={}&base=v1~1&head=v1&squash=true", url),
+            format!("/refs?repo_url={}", url),
+            format!(
+                "/search?repo_url={}&q=root@localhost&include_diffs=true",
+                url
+            ),
+        ];
+        for uri in &uris {
+            let (status, plain) = get(&state, uri).await;
+            assert_eq!(status, StatusCode::OK, "{}: {}", uri, plain);
+            assert!(
+                RAW_EMAILS
+                    .iter()
+                    .any(|email| plain.to_string().contains(email)),
+                "{} has no email to redact: {}",
+                uri,
+                plain
+            );
+            let redacted = format!("{}&redact=both&redact_salt=pepper", uri);
+            let (status, body) = get(&state, &redacted).await;
+            assert_eq!(status, StatusCode::OK, "{}: {}", redacted, body);
+            assert_no_raw_email(&body.to_string(), uri);
+        }
+
+        let body = serde_json::json!({
+            "repo_url": url,
+            "redact": "emails",
+            "redact_salt": "pepper",
+        });
+        let (status, history) = post(&state, "/git_history", body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_no_raw_email(&history.to_string(), "/git_history");
+
+        let uri = format!("/blame?repo_url={}&path=README.md&redact=everyone", url);
+        let (status, body) = get(&state, &uri).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().starts_with("redact: "));
+    }
+
+    #[tokio::test]
+    async fn every_endpoint_answers_preflights() {
+        let state = server(ServerConfig {
+            cors_origins: vec!["*".to_string()],
+            ..ServerConfig::default()
+        });
+        for (path, _) in ENDPOINTS {
+            let request = Request::options(*path)
+                .header("Origin", "https://dash.example")
+                .body(Body::empty())
+                .unwrap();
+            let (parts, _) = send(&state, request).await;
+            assert_eq!(parts.status, StatusCode::NO_CONTENT, "{}", path);
+            assert_eq!(header(&parts, "Access-Control-Allow-Origin"), Some("*"));
+        }
+
+        let request = Request::options("/nowhere").body(Body::empty()).unwrap();
+        let (parts, _) = send(&state, request).await;
+        assert_eq!(parts.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn every_endpoint_has_a_handler() {
+        let state = server(ServerConfig::default());
+        for (path, method) in ENDPOINTS {
+            let request = Request::builder()
+                .method(method)
+                .uri(*path)
+                .body(Body::empty())
+                .unwrap();
+            // A missing handler would panic; everything else is answered
+            let (parts, _) = send(&state, request).await;
+            assert_ne!(parts.status, StatusCode::METHOD_NOT_ALLOWED, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn wrong_methods_are_not_allowed() {
+        let state = server(ServerConfig::default());
+        let request = Request::get("/git_history").body(Body::empty()).unwrap();
+        let (parts, body) = send(&state, request).await;
+        assert_eq!(parts.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(header(&parts, "Allow"), Some("POST, OPTIONS"));
+        assert!(body.contains("method_not_allowed"));
+
+        let request = Request::post("/refs").body(Body::empty()).unwrap();
+        let (parts, _) = send(&state, request).await;
+        assert_eq!(parts.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(header(&parts, "Allow"), Some("GET, OPTIONS"));
+    }
+
+    #[tokio::test]
+    async fn empty_repository_indexes_to_an_empty_history() {
+        let repo = TestRepo::new();
+        assert!(head_is_unborn(&repo.open()));
+
+        assert_eq!(
+            git_index(repo.path(), &IndexOptions::default())
+                .await
+                .unwrap(),
+            "[]"
+        );
+        assert_eq!(index(&repo, &[]), serde_json::json!([]));
+        let indexed = index_repo(repo.path(), &IndexOptions::default()).unwrap();
+        assert!(indexed.empty_repository);
+        assert!(indexed.tip.is_none());
+    }
+
+    #[tokio::test]
+    async fn server_marks_empty_repositories() {
+        let repo = TestRepo::new();
+        let state = local_server(&repo, &[]);
+        let path = repo.path().display().to_string();
+
+        let body = serde_json::json!({ "repo_path": path, "envelope": false });
+        let (status, body) = post(&state, "/git_history", body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            serde_json::json!({ "meta": { "empty_repository": true }, "commits": [] })
+        );
+
+        let (status, body) = post(
+            &state,
+            "/git_history",
+            serde_json::json!({ "repo_path": path }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["meta"]["empty_repository"], true);
+        assert_eq!(body["meta"]["tip"], serde_json::Value::Null);
+        assert_eq!(body["commits"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn single_root_commit_diffs_against_the_empty_tree() {
+        let repo = TestRepo::new();
+        let root = repo
+            .write("a.txt", "one\ntwo\n")
+            .write("b/c.txt", "three\n")
+            .commit("Root");
+
+        let history = index(&repo, &["--diff-format", "unified"]);
+        assert_eq!(history.as_array().unwrap().len(), 1);
+        let commit = &history[0];
+        assert_eq!(commit["commit_id"], root.as_str());
+        assert_eq!(commit["parent_ids"], serde_json::json!([]));
+        let files: Vec<_> = commit["git_diff"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| {
+                (
+                    file["file"].as_str().unwrap(),
+                    &file["additions"],
+                    &file["old_mode"],
+                )
+            })
+            .collect();
+        assert_eq!(
+            files,
+            [
+                ("a.txt", &2.into(), &serde_json::Value::Null),
+                ("b/c.txt", &1.into(), &serde_json::Value::Null),
+            ]
+        );
+        assert!(commit["git_diff"][0]["diff"]
+            .as_str()
+            .unwrap()
+            .contains("@@ -0,0 +1,2 @@\n+one\n+two\n"));
+    }
+
+    /// Writes a commit object on top of HEAD byte by byte, as old tools did: git
+    /// itself would convert a message that isn't UTF-8. Returns it as indexed.
+    fn raw_commit(
+        repo: &TestRepo,
+        author: &[u8],
+        message: &[u8],
+        encoding: Option<&str>,
+    ) -> CommitHistory {
+        let git_repo = repo.open();
+        let head = git_repo.head().unwrap().peel_to_commit().unwrap();
+        let mut raw = format!("tree {}\nparent {}\n", head.tree_id(), head.id()).into_bytes();
+        for role in ["author", "committer"] {
+            raw.extend_from_slice(role.as_bytes());
+            raw.push(b' ');
+            raw.extend_from_slice(author);
+            raw.extend_from_slice(b" <dev@example.com> 1700000000 +0000\n");
+        }
+        if let Some(encoding) = encoding {
+            raw.extend_from_slice(format!("encoding {}\n", encoding).as_bytes());
+        }
+        raw.push(b'\n');
+        raw.extend_from_slice(message);
+        raw.push(b'\n');
+        let id = git_repo
+            .odb()
+            .unwrap()
+            .write(git2::ObjectType::Commit, &raw)
+            .unwrap();
+        let commit = git_repo.find_commit(id).unwrap();
+        commit_entry(&git_repo, &commit).unwrap()
+    }
+
+    /// Two commits on top of HEAD, on branches `left` and `right`, whose ids share their
+    /// first 7 hex digits. Found by varying the messages: the ids are the same in every
+    /// run, as the fixture's are.
+    fn colliding_commits(repo: &TestRepo) -> (git2::Oid, git2::Oid) {
+        let git_repo = repo.open();
+        let head = git_repo.head().unwrap().peel_to_commit().unwrap();
+        let raw = |n: u32| {
+            format!(
+                "tree {}\nparent {}\nauthor Jane Doe <jane@example.com> 1700000000 +0000\n\
+                 committer Jane Doe <jane@example.com> 1700000000 +0000\n\nAttempt {}\n",
+                head.tree_id(),
+                head.id(),
+                n
+            )
+        };
+        let mut seen = HashMap::new();
+        let (first, second) = (0..)
+            .find_map(|n| {
+                let id =
+                    git2::Oid::hash_object(git2::ObjectType::Commit, raw(n).as_bytes()).unwrap();
+                let previous = seen.insert(id.to_string()[..7].to_string(), n)?;
+                Some((previous, n))
+            })
+            .unwrap();
+        let odb = git_repo.odb().unwrap();
+        let mut ids = [first, second].map(|n| {
+            odb.write(git2::ObjectType::Commit, raw(n).as_bytes())
+                .unwrap()
+        });
+        ids.sort();
+        repo.git(["branch", "left", &ids[0].to_string()]);
+        repo.git(["branch", "right", &ids[1].to_string()]);
+        (ids[0], ids[1])
+    }
+
+    #[test]
+    fn colliding_prefixes_get_longer_short_ids() {
+        let repo = fixture();
+        let (left, right) = colliding_commits(&repo);
+        assert_eq!(left.to_string()[..7], right.to_string()[..7]);
+
+        for flags in [&["--all"][..], &["--all", "--sha-length", "7"]] {
+            let history = index(&repo, flags);
+            let short = |id: git2::Oid| -> String {
+                let entry = history
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .find(|entry| entry["commit_id"] == id.to_string().as_str())
+                    .unwrap();
+                entry["commit_id_short"].as_str().unwrap().to_string()
+            };
+            let (left_short, right_short) = (short(left), short(right));
+            assert!(left_short.len() > 7, "{:?}: {}", flags, left_short);
+            assert!(right_short.len() > 7, "{:?}: {}", flags, right_short);
+            assert_ne!(left_short, right_short);
+            // Each still names its own commit, and the unrelated commit keeps 7 digits
+            for (short, id) in [(left_short, left), (right_short, right)] {
+                let resolved = repo.open().revparse_single(&short).unwrap().id();
+                assert_eq!(resolved, id, "{:?}", flags);
+                assert_eq!(repo.git(["rev-parse", &short]).trim(), id.to_string());
+            }
+            let root = &history.as_array().unwrap().last().unwrap()["commit_id_short"];
+            assert_eq!(root.as_str().unwrap().len(), 7, "{:?}", flags);
+        }
+    }
+
+    #[test]
+    fn transcodes_declared_latin1_messages() {
+        let repo = fixture();
+        let commit = raw_commit(&repo, b"Jane Doe", b"Caf\xe9 cr\xe8me", Some("ISO-8859-1"));
+        assert_eq!(commit.commit_message, "Café crème\n");
+        assert_eq!(commit.message_encoding.as_deref(), Some("windows-1252"));
+        assert!(!commit.message_is_lossy);
+    }
+
+    #[test]
+    fn transcodes_declared_gbk_messages() {
+        let repo = fixture();
+        let (gbk, _, _) = encoding_rs::GBK.encode("修复中文提交");
+        let commit = raw_commit(&repo, b"Jane Doe", &gbk, Some("GBK"));
+        assert_eq!(commit.commit_message, "修复中文提交\n");
+        assert_eq!(commit.message_encoding.as_deref(), Some("GBK"));
+        assert!(!commit.message_is_lossy);
+    }
+
+    #[test]
+    fn undeclared_latin1_messages_are_decoded_lossily() {
+        let repo = fixture();
+        let commit = raw_commit(&repo, b"Jane Doe", b"Caf\xe9", None);
+        assert_eq!(commit.commit_message, "Caf\u{fffd}\n");
+        assert_eq!(commit.message_encoding, None);
+        assert!(commit.message_is_lossy);
+
+        let commit = raw_commit(&repo, b"Jane Doe", b"Caf\xe9", Some("x-no-such-charset"));
+        assert_eq!(commit.commit_message, "Caf\u{fffd}\n");
+        assert_eq!(
+            commit.message_encoding.as_deref(),
+            Some("x-no-such-charset")
+        );
+        assert!(commit.message_is_lossy);
+    }
+
+    #[test]
+    fn non_utf8_author_names_are_decoded_lossily() {
+        let repo = fixture();
+        let commit = raw_commit(&repo, b"Ren\xe9", b"Fix", None);
+        assert_eq!(commit.author, "Ren\u{fffd}");
+        assert_eq!(commit.commit_message, "Fix\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn indexes_non_utf8_file_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let repo = TestRepo::new();
+        repo.write(OsStr::from_bytes(b"caf\xe9.txt"), "latin-1 name\n")
+            .commit("Add a file");
+
+        let history = index(&repo, &[]);
+        assert_eq!(history[0]["git_diff"][0]["file"], "caf\u{fffd}.txt");
+        assert_eq!(history[0]["git_diff"][0]["additions"], 1);
+    }
+
+    #[test]
+    fn submodule_bumps_carry_both_shas() {
+        let library = TestRepo::new();
+        let first = library
+            .write("lib.c", "int a;\n")
+            .commit("Start the library");
+        let repo = fixture();
+        repo.git([
+            "-c",
+            "protocol.file.allow=always",
+            "submodule",
+            "add",
+            "-q",
+            library.path().to_str().unwrap(),
+            "vendor/lib",
+        ]);
+        repo.commit("Vendor the library");
+        library.write("lib.c", "int a, b;\n").commit("Add b");
+        let last = library.write("lib.c", "int a, b, c;\n").commit("Add c");
+        repo.git(["-C", "vendor/lib", "fetch", "-q", "origin"]);
+        repo.git(["-C", "vendor/lib", "checkout", "-q", &last]);
+        repo.commit("Bump the library");
+
+        let history = index(&repo, &["--recurse-submodules"]);
+        let bump = &history[0]["git_diff"];
+        assert_eq!(bump.as_array().unwrap().len(), 1);
+        assert_eq!(bump[0]["file"], "vendor/lib");
+        assert_eq!(bump[0]["kind"], "submodule");
+        assert_eq!(bump[0]["old_sha"], first.as_str());
+        assert_eq!(bump[0]["new_sha"], last.as_str());
+        assert_eq!(bump[0]["diff"], "");
+        assert_eq!(
+            bump[0]["submodule_commits"],
+            serde_json::json!(["Add c", "Add b"])
+        );
+
+        // Adding the submodule has no old SHA, and `.gitmodules` is a regular file
+        let added = &history[1]["git_diff"];
+        let files: Vec<_> = added
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| (file["file"].as_str().unwrap(), file.get("kind")))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                (".gitmodules", None),
+                ("vendor/lib", Some(&"submodule".into()))
+            ]
+        );
+        assert_eq!(added[1]["old_sha"], serde_json::Value::Null);
+        assert_eq!(added[1]["new_sha"], first.as_str());
+
+        // Without the flag the pointer change is all there is
+        let history = index(&repo, &[]);
+        assert!(history[0]["git_diff"][0].get("submodule_commits").is_none());
+    }
+
+    #[test]
+    fn generated_files_follow_the_attributes_of_their_commit() {
+        let repo = TestRepo::new();
+        repo.write("api/schema.gen.rs", "// v1\n")
+            .write("src/main.rs", "fn main() {}\n")
+            .commit("Add the schema");
+        repo.write("api/.gitattributes", "*.gen.rs linguist-generated\n")
+            .write("api/schema.gen.rs", "// v2\n")
+            .commit("Mark the schema as generated");
+
+        let history = index(&repo, &["--stats-only"]);
+        assert_eq!(history[0]["stats"]["files_changed"], 2);
+
+        let history = index(&repo, &[]);
+        let files = history[0]["git_diff"].as_array().unwrap();
+        let schema = files
+            .iter()
+            .find(|file| file["file"] == "api/schema.gen.rs")
+            .unwrap();
+        assert_eq!(schema["generated"], true);
+        assert_eq!(schema["diff"], "");
+        // The attributes file came with the second commit, so the first one is diffed
+        let first = &history[1]["git_diff"][0];
+        assert_eq!(first["file"], "api/schema.gen.rs");
+        assert!(first.get("generated").is_none());
+        assert!(first["diff"].as_str().unwrap().ends_with("\n// v1\n"));
+
+        let history = index(&repo, &["--include-generated"]);
+        let schema = history[0]["git_diff"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|file| file["file"] == "api/schema.gen.rs")
+            .unwrap();
+        assert!(schema.get("generated").is_none());
+        assert!(schema["diff"].as_str().unwrap().contains("// v2"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unified_diffs_concatenate_to_git_show() {
+        let (repo, _submodule) = varied_fixture();
+        let history = index(&repo, &["--diff-format", "unified", "--include-generated"]);
+        // The submodule and the LFS pointer get entries of their own kind, not patches
+        for commit in history.as_array().unwrap() {
+            let id = commit["commit_id"].as_str().unwrap();
+            let patch: String = commit["git_diff"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|file| file["diff"].as_str().unwrap())
+                .collect();
+            assert_eq!(
+                patch,
+                repo.git([
+                    "show",
+                    "--format=",
+                    "--no-renames",
+                    "--diff-merges=first-parent",
+                    id,
+                    "--",
+                    ":!vendor/lib",
+                    ":!model.bin",
+                ]),
+                "{}",
+                commit["commit_message"]
+            );
+        }
+    }
+
+    /// `file`, `status`, `old_file` and `similarity` of an entry.
+    type Status = (String, Option<String>, Option<String>, Option<u64>);
+
+    /// The `Status` of each entry of the newest commit.
+    fn statuses(repo: &TestRepo, flags: &[&str]) -> Vec<Status> {
+        let history = index(repo, flags);
+        history[0]["git_diff"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| {
+                (
+                    file["file"].as_str().unwrap().to_string(),
+                    file["status"].as_str().map(String::from),
+                    file["old_file"].as_str().map(String::from),
+                    file["similarity"].as_u64(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rename_thresholds_above_and_below_the_similarity() {
+        let repo = TestRepo::new();
+        let lines: String = (1..=20).map(|n| format!("line {}\n", n)).collect();
+        repo.write("old.txt", &lines).commit("Add the file");
+        repo.remove("old.txt")
+            .write("new.txt", lines.replace("line 7\n", "line seven\n"))
+            .commit("Rename the file with a small edit");
+
+        let renamed = statuses(&repo, &["--rename-threshold", "50"]);
+        let [(file, status, old_file, Some(similarity))] = &renamed[..] else {
+            panic!("expected one rename, got {:?}", renamed);
+        };
+        assert_eq!(
+            (file.as_str(), status.as_deref(), old_file.as_deref()),
+            ("new.txt", Some("renamed"), Some("old.txt"))
+        );
+        assert!((80..100).contains(similarity), "{}", similarity);
+
+        // At its similarity it is still a rename, above it an addition and a deletion
+        let at = similarity.to_string();
+        assert_eq!(statuses(&repo, &["--rename-threshold", &at]), renamed);
+        let above = (similarity + 1).to_string();
+        assert_eq!(
+            statuses(&repo, &["--rename-threshold", &above]),
+            [
+                ("new.txt".to_string(), None, None, None),
+                ("old.txt".to_string(), None, None, None),
+            ]
+        );
+        let commit = &index(&repo, &["--rename-threshold", &above])[0];
+        assert_eq!(commit.get("rename_detection_skipped"), None);
+    }
+
+    #[test]
+    fn copies_and_the_rename_limit() {
+        let repo = TestRepo::new();
+        let lines: String = (1..=20).map(|n| format!("line {}\n", n)).collect();
+        repo.write("a.txt", &lines).commit("Add the file");
+        repo.write("a.txt", format!("{}line 21\n", lines))
+            .write("b.txt", lines.replace("line 3\n", "line three\n"))
+            .commit("Copy the file with a small edit");
+
+        let copied = statuses(&repo, &["--copy-threshold", "50"]);
+        assert_eq!(copied.len(), 2);
+        assert_eq!(copied[1].0, "b.txt");
+        assert_eq!(copied[1].1.as_deref(), Some("copied"));
+        assert_eq!(copied[1].2.as_deref(), Some("a.txt"));
+        let similarity = copied[1].3.unwrap();
+        let above = (similarity + 1).to_string();
+        let not_copied = statuses(&repo, &["--copy-threshold", &above]);
+        assert_eq!(not_copied[1], ("b.txt".to_string(), None, None, None));
+
+        // Two renames are more than a limit of one allows
+        repo.rename("a.txt", "c.txt")
+            .rename("b.txt", "d.txt")
+            .commit("Move both files");
+        let history = index(&repo, &["--rename-threshold", "50", "--rename-limit", "1"]);
+        assert_eq!(history[0]["rename_detection_skipped"], true);
+        assert!(history[0]["git_diff"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|file| file.get("status").is_none()));
+        let history = index(&repo, &["--rename-threshold", "50", "--rename-limit", "2"]);
+        assert_eq!(history[0].get("rename_detection_skipped"), None);
+        assert_eq!(history[0]["git_diff"][0]["status"], "renamed");
+    }
+
+    #[tokio::test]
+    async fn aborted_clones_leave_nothing_behind() {
+        // Accepts connections into its backlog and never answers them, so clones hang
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let root = tempfile::TempDir::new().unwrap();
+        let settings = clone::CloneSettings {
+            git_config: vec![format!(
+                "url.http://{}/.insteadOf=https://hanging/",
+                listener.local_addr().unwrap()
+            )],
+            work_dir: workdir::WorkDir {
+                path: Some(root.path().to_path_buf()),
+                ..workdir::WorkDir::default()
+            },
+            ..clone::CloneSettings::default()
+        };
+
+        let mut capped = settings.clone();
+        capped.work_dir.max_clone_bytes = Some(1);
+        let result = with_cloned_repo("hanging/repo", CloneKind::Bare, &capped, |_| Ok(())).await;
+        assert!(matches!(result, Err(CustomError::InsufficientStorage(_))));
+        assert_eq!(fs::read_dir(root.path()).unwrap().count(), 0);
+
+        let mut timed = settings.clone();
+        timed.timeout = Duration::from_millis(500);
+        let result = with_cloned_repo("hanging/repo", CloneKind::Bare, &timed, |_| Ok(())).await;
+        assert!(matches!(result, Err(CustomError::Timeout(_))));
+        assert_eq!(fs::read_dir(root.path()).unwrap().count(), 0);
+
+        let mut full = settings;
+        full.work_dir.min_free_bytes = Some(u64::MAX);
+        let result = with_cloned_repo("hanging/repo", CloneKind::Bare, &full, |_| Ok(())).await;
+        assert!(matches!(result, Err(CustomError::InsufficientStorage(_))));
+        assert_eq!(fs::read_dir(root.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn envelope_wraps_the_bare_array_only_when_asked() {
+        let repo = fixture();
+        let bare: serde_json::Value = serde_json::from_str(
+            &git_index(repo.path(), &IndexOptions::default())
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(bare.as_array().unwrap().len(), 1);
+        assert_eq!(bare[0]["commit_id"], repo.head().as_str());
+
+        let options = IndexOptions {
+            envelope: true,
+            ..IndexOptions::default()
+        };
+        let wrapped: serde_json::Value =
+            serde_json::from_str(&git_index(repo.path(), &options).await.unwrap()).unwrap();
+        let keys: Vec<_> = wrapped.as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["commits", "meta"]);
+        assert_eq!(wrapped["commits"], bare);
+        let meta = &wrapped["meta"];
+        assert_eq!(meta["repo"], repo.path().display().to_string());
+        assert_eq!(meta["git_ref"], "refs/heads/main");
+        assert_eq!(meta["tip"], repo.head().as_str());
+        assert_eq!(meta["commit_count"], 1);
+        assert_eq!(meta["truncated"], false);
+        assert_eq!(meta["next_cursor"], serde_json::Value::Null);
+        assert_eq!(meta["format_version"], FORMAT_VERSION);
+    }
+
+    #[test]
+    fn ndjson_starts_with_the_meta_only_when_asked() {
+        let repo = fixture();
+        repo.write("README.md", "hello again\n")
+            .commit("Edit the readme");
+        let lines = |options: &IndexOptions| -> Vec<serde_json::Value> {
+            let mut out = Vec::new();
+            let mut sink = if options.envelope {
+                sink::NdjsonSink::with_meta(&mut out, "fixture", options)
+            } else {
+                sink::NdjsonSink::new(&mut out)
+            };
+            index_into(repo.path(), options, &mut sink).unwrap();
+            String::from_utf8(out)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        };
+
+        let bare = lines(&IndexOptions::default());
+        assert_eq!(bare.len(), 2);
+        assert!(bare.iter().all(|line| line["commit_id"].is_string()));
+
+        let options = IndexOptions {
+            envelope: true,
+            ..IndexOptions::default()
+        };
+        let wrapped = lines(&options);
+        assert_eq!(wrapped[1..], bare[..]);
+        let first = wrapped[0].as_object().unwrap();
+        assert_eq!(first.keys().collect::<Vec<_>>(), ["meta"]);
+        let meta = &first["meta"];
+        assert_eq!(meta["repo"], "fixture");
+        assert_eq!(meta["tip"], repo.head().as_str());
+        assert_eq!(meta["shallow"], false);
+        for unknown in ["commit_count", "truncated", "next_cursor"] {
+            assert!(meta.get(unknown).is_none(), "{}", unknown);
+        }
+    }
+
+    /// Counts what it is handed, and fails on commit number `fail_at` if set.
+    #[derive(Default)]
+    struct CountingSink {
+        starts: usize,
+        commits: usize,
+        finished_with: Option<usize>,
+        fail_at: Option<usize>,
+    }
+
+    impl CommitSink for CountingSink {
+        fn on_start(&mut self, run: &sink::RunStart) -> Result<(), CustomError> {
+            assert!(run.tip.is_some());
+            self.starts += 1;
+            Ok(())
+        }
+
+        fn on_commit(&mut self, _commit: &CommitHistory) -> Result<(), CustomError> {
+            self.commits += 1;
+            if self.fail_at == Some(self.commits) {
+                return Err(CustomError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "storage went away",
+                )));
+            }
+            Ok(())
+        }
+
+        fn on_finish(&mut self, run: &sink::RunEnd) -> Result<(), CustomError> {
+            self.finished_with = Some(run.commit_count);
+            Ok(())
+        }
+    }
+
+    fn five_commits() -> TestRepo {
+        let repo = fixture();
+        for n in 2..=5 {
+            repo.write("README.md", format!("hello {}\n", n))
+                .commit(&format!("Edit the readme, take {}", n));
+        }
+        repo
+    }
+
+    #[test]
+    fn custom_sinks_see_every_commit_once() {
+        let repo = five_commits();
+        let mut sink = CountingSink::default();
+        let indexed = index_into(repo.path(), &IndexOptions::default(), &mut sink).unwrap();
+        assert_eq!(sink.starts, 1);
+        assert_eq!(sink.commits, 5);
+        assert_eq!(sink.finished_with, Some(5));
+        assert!(indexed.commits.is_empty());
+
+        let options = IndexOptions {
+            range: Some("HEAD~2..HEAD".to_string()),
+            ..IndexOptions::default()
+        };
+        let mut sink = CountingSink::default();
+        index_into(repo.path(), &options, &mut sink).unwrap();
+        assert_eq!((sink.commits, sink.finished_with), (2, Some(2)));
+    }
+
+    #[test]
+    fn sink_errors_stop_the_walk_and_are_returned() {
+        let repo = five_commits();
+        let mut sink = CountingSink {
+            fail_at: Some(2),
+            ..CountingSink::default()
+        };
+        let err = index_into(repo.path(), &IndexOptions::default(), &mut sink)
+            .err()
+            .unwrap();
+        assert!(
+            matches!(&err, CustomError::IoError(err) if err.to_string() == "storage went away"),
+            "{}",
+            err
+        );
+        // Nothing past the failing commit, and no finish for a failed run
+        assert_eq!(sink.commits, 2);
+        assert_eq!(sink.finished_with, None);
+    }
+
+    #[test]
+    fn shallow_clones_mark_their_boundary_commit() {
+        let origin = TestRepo::new();
+        for n in 1..=4 {
+            origin
+                .write(format!("file{}.txt", n), format!("{}\n", n))
+                .commit(&format!("Commit {}", n));
+        }
+        let clone = tempfile::TempDir::new().unwrap();
+        let url = format!("file://{}", origin.path().display());
+        origin.git([
+            "clone",
+            "-q",
+            "--depth",
+            "2",
+            &url,
+            clone.path().to_str().unwrap(),
+        ]);
+
+        let index = |mode: &str| {
+            let options = IndexOptions {
+                boundary_diff: options::BoundaryDiff::parse(mode).unwrap(),
+                ..IndexOptions::default()
+            };
+            index_repo(clone.path(), &options).unwrap()
+        };
+        let files = |commit: &CommitHistory| -> Vec<String> {
+            commit
+                .git_diff
+                .iter()
+                .map(|file| file.file.clone())
+                .collect()
+        };
+
+        let empty = index("empty");
+        assert!(empty.shallow);
+        let messages: Vec<_> = empty
+            .commits
+            .iter()
+            .map(|c| c.commit_message.as_str())
+            .collect();
+        assert_eq!(messages, ["Commit 4\n", "Commit 3\n"]);
+        assert!(!empty.commits[0].graft_boundary);
+        assert_eq!(files(&empty.commits[0]), ["file4.txt"]);
+        assert!(empty.commits[1].graft_boundary);
+        assert!(empty.commits[1].git_diff.is_empty());
+
+        // Without its parents the boundary commit diffs against the empty tree
+        let full = index("full");
+        assert!(full.commits[1].graft_boundary);
+        assert_eq!(
+            files(&full.commits[1]),
+            ["file1.txt", "file2.txt", "file3.txt"]
+        );
+
+        let omitted = index("omit");
+        assert_eq!(omitted.commits.len(), 1);
+        assert_eq!(omitted.commits[0].commit_message, "Commit 4\n");
+
+        // The complete history has no boundary
+        let complete = index_repo(origin.path(), &IndexOptions::default()).unwrap();
+        assert!(!complete.shallow);
+        assert_eq!(complete.commits.len(), 4);
+        assert!(complete.commits.iter().all(|commit| !commit.graft_boundary));
+
+        let envelope = envelope::wrap("shallow", &empty, &IndexOptions::default());
+        let envelope = serde_json::to_value(envelope).unwrap();
+        assert_eq!(envelope["meta"]["shallow"], true);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mode_changes_alone_get_an_entry() {
+        let (repo, _submodule) = varied_fixture();
+        let history = index(&repo, &[]);
+        let commit = history
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|commit| commit["commit_message"] == "Make the script executable\n")
+            .unwrap();
+        let file = &commit["git_diff"][0];
+        assert_eq!(commit["git_diff"].as_array().unwrap().len(), 1);
+        assert_eq!(file["file"], "run.sh");
+        assert_eq!(file["old_mode"], "100644");
+        assert_eq!(file["new_mode"], "100755");
+        assert_eq!(file["old_blob_id"], file["new_blob_id"]);
+        assert_eq!(
+            file["diff"],
+            "diff --git a/run.sh b/run.sh\nold mode 100644\nnew mode 100755\n"
+        );
+    }
+
+    #[test]
+    fn unified_patches_apply_to_the_parent() {
+        let repo = TestRepo::new();
+        let lines: String = (1..=40).map(|n| format!("line {}\n", n)).collect();
+        let parent = repo
+            .write("notes.txt", &lines)
+            .write("old.txt", "going away\n")
+            .write("no-newline.txt", "last line")
+            .commit("Parent");
+        let changed = lines
+            .replace("line 3\n", "line three\n")
+            .replace("line 20\n", "")
+            .replace("line 38\n", "line 38\ninserted\n");
+        let child = repo
+            .write("notes.txt", changed)
+            .remove("old.txt")
+            .write("new/file.txt", "brand new\n")
+            .write("no-newline.txt", "last line, edited")
+            .commit("Child");
+
+        let history = index(&repo, &["--diff-format", "unified"]);
+        let patch: String = history[0]["git_diff"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| file["diff"].as_str().unwrap())
+            .collect();
+        repo.git(["checkout", "-q", &parent]);
+        std::fs::write(repo.path().join("child.patch"), &patch).unwrap();
+        repo.git(["apply", "child.patch"]);
+        repo.remove("child.patch");
+
+        for path in ["notes.txt", "new/file.txt", "no-newline.txt"] {
+            assert_eq!(
+                std::fs::read_to_string(repo.path().join(path)).unwrap(),
+                repo.git(["show", &format!("{}:{}", child, path)]),
+                "{}",
+                path
+            );
+        }
+        assert!(!repo.path().join("old.txt").exists());
+    }
+
+    #[test]
+    fn word_diff_max_bytes_falls_back_to_the_line_diff() {
+        let repo = TestRepo::new();
+        repo.write("small.md", "one two three\n")
+            .write("large.md", "one two three\n".repeat(20))
+            .commit("Add prose");
+        repo.write("small.md", "one 2 three\n")
+            .write("large.md", "one 2 three\n".repeat(20))
+            .commit("Use digits");
+
+        let history = index(&repo, &["--word-diff", "--word-diff-max-bytes", "100"]);
+        let files = history[0]["git_diff"].as_array().unwrap();
+        assert_eq!(files[0]["file"], "large.md");
+        assert!(files[0].get("word_diff").is_none());
+        assert!(files[0]["diff"].as_str().unwrap().contains("one 2 three"));
+        assert_eq!(files[1]["file"], "small.md");
+        assert_eq!(
+            files[1]["word_diff"],
+            "@@ -1 +1 @@\none [-two-]{+2+} three\n"
+        );
+    }
+
+    #[test]
+    fn rev_walks_from_the_given_ref() {
+        let repo = fixture();
+        repo.git(["checkout", "-q", "-b", "feature"]);
+        let feature = repo.write("feature.txt", "new\n").commit("Add the feature");
+        repo.git(["checkout", "-q", "main"]);
+
+        assert_eq!(index(&repo, &[]).as_array().unwrap().len(), 1);
+        let history = index(&repo, &["--rev", "feature"]);
+        assert_eq!(history.as_array().unwrap().len(), 2);
+        assert_eq!(history[0]["commit_id"], feature.as_str());
+    }
+
+    #[tokio::test]
+    async fn clones_only_from_allowed_hosts() {
+        let repo = fixture();
+        let mut config = ServerConfig::default();
+        config.clone.retries = 0;
+        config.clone.git_config = vec![repo.clone_config()];
+        config.clone.allowed_hosts = vec!["github.com".to_string()];
+        let state = server(config);
+
+        let (status, body) = get(&state, &format!("/refs?repo_url={}", repo.url())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "invalid_request");
+        assert!(body["error"].as_str().unwrap().contains("--allowed-host"));
+
+        let mut config = ServerConfig::default();
+        config.clone.retries = 0;
+        config.clone.git_config = vec![repo.clone_config()];
+        config.clone.allowed_hosts = vec!["github.com".to_string(), "FIXTURE".to_string()];
+        let (status, _) = get(&server(config), &format!("/refs?repo_url={}", repo.url())).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn max_diff_bytes_leaves_out_long_patches() {
+        let repo = TestRepo::new();
+        repo.write("short.txt", "a\n")
+            .write("long.txt", "line\n".repeat(100))
+            .commit("Add files");
+
+        let history = index(&repo, &["--max-diff-bytes", "200", "--word-diff"]);
+        let files = history[0]["git_diff"].as_array().unwrap();
+        assert_eq!(files[0]["file"], "long.txt");
+        assert_eq!(files[0]["diff"], "");
+        assert_eq!(files[0]["diff_too_large"], true);
+        assert_eq!(files[0]["additions"], 100);
+        assert!(files[0].get("word_diff").is_none());
+        assert_eq!(files[1]["file"], "short.txt");
+        assert!(files[1].get("diff_too_large").is_none());
+        assert!(files[1]["diff"].as_str().unwrap().ends_with("a\n"));
+    }
+
+    #[tokio::test]
+    async fn index_settings_are_the_defaults_of_requests() {
+        let repo = TestRepo::new();
+        repo.write("long.txt", "line\n".repeat(100))
+            .commit("Add a long file");
+        // What `server` makes of `[index] max_diff_bytes` or GIT_HISTORY_MAX_DIFF_BYTES
+        let args = ["--max-diff-bytes", "200", "--rev", "main"].map(String::from);
+        let (_, index) = options::parse_args(&args, |_, _| Ok(false)).unwrap();
+        let state = server(ServerConfig {
+            allow_local_paths: vec![repo.path().to_path_buf()],
+            index_defaults: index.request_defaults(),
+            ..ServerConfig::default()
+        });
+        assert_eq!(state.config.index_defaults.rev, None);
+
+        let body = serde_json::json!({ "repo_path": repo.path(), "envelope": false });
+        let (status, history) = post(&state, "/git_history", body.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(history[0]["git_diff"][0]["diff_too_large"], true);
+
+        let mut body = body;
+        body["max_diff_bytes"] = 1_000_000.into();
+        let (_, history) = post(&state, "/git_history", body).await;
+        assert!(history[0]["git_diff"][0].get("diff_too_large").is_none());
+        assert_eq!(history[0]["git_diff"][0]["additions"], 100);
+    }
+
+    #[tokio::test]
+    async fn oversized_webhook_payloads_are_refused() {
+        let state = server(ServerConfig {
+            webhook_secret: Some("secret".to_string()),
+            ..ServerConfig::default()
+        });
+        let limit = webhooks::MAX_PAYLOAD_BYTES;
+
+        // Refused from the header, before the body is read
+        let request = Request::post("/webhooks/generic")
+            .header("Content-Length", (limit + 1).to_string())
+            .body(Body::from("{}"))
+            .unwrap();
+        let (parts, body) = send(&state, request).await;
+        assert_eq!(parts.status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(body.contains("payload_too_large"));
+
+        // A chunked body is cut off once it passes the limit
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            let chunk = hyper::body::Bytes::from(vec![b' '; 1024 * 1024]);
+            while sender.send_data(chunk.clone()).await.is_ok() {}
+        });
+        let request = Request::post("/webhooks/generic").body(body).unwrap();
+        let (parts, _) = send(&state, request).await;
+        assert_eq!(parts.status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Payloads within the limit still reach the signature check
+        let request = Request::post("/webhooks/generic")
+            .body(Body::from("{}"))
+            .unwrap();
+        let (parts, _) = send(&state, request).await;
+        assert_eq!(parts.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn webhook_cache_hits_are_json_with_validators() {
+        use hmac::{Hmac, Mac};
+        let repo = fixture();
+        let mut config = ServerConfig::default();
+        config.clone.retries = 0;
+        config.clone.git_config = vec![repo.clone_config()];
+        config.webhook_secret = Some("secret".to_string());
+        let state = server(config);
+
+        let push = serde_json::json!({ "repo_url": repo.url() }).to_string();
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(push.as_bytes());
+        let signature: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let request = Request::post("/webhooks/generic")
+            .header("X-Hub-Signature-256", format!("sha256={}", signature))
+            .body(Body::from(push))
+            .unwrap();
+        assert_eq!(send(&state, request).await.0.status, StatusCode::ACCEPTED);
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while state.webhooks.cached(&repo.url()).is_none() {
+            assert!(std::time::Instant::now() < deadline, "never re-indexed");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let body = serde_json::json!({ "repo_url": repo.url() }).to_string();
+        let request = Request::post("/git_history")
+            .body(Body::from(body.clone()))
+            .unwrap();
+        let (parts, history) = send(&state, request).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(header(&parts, "X-Cache"), Some("hit"));
+        assert_eq!(header(&parts, "Content-Type"), Some("application/json"));
+        let history: serde_json::Value = serde_json::from_str(&history).unwrap();
+        assert_eq!(history["commits"][0]["commit_id"], repo.head().as_str());
+        let etag = header(&parts, "ETag").unwrap().to_string();
+        assert!(header(&parts, "Last-Modified").is_some());
+
+        let request = Request::post("/git_history")
+            .header("If-None-Match", &etag)
+            .body(Body::from(body))
+            .unwrap();
+        let (parts, _) = send(&state, request).await;
+        assert_eq!(parts.status, StatusCode::NOT_MODIFIED);
+        assert_eq!(header(&parts, "X-Cache"), Some("hit"));
+        assert_eq!(header(&parts, "ETag"), Some(etag.as_str()));
+    }
+
+    /// Posts `body` to `/git_history`, with `If-None-Match: etag` if given.
+    async fn history_if_none_match(
+        state: &Arc<ServerState>,
+        body: &serde_json::Value,
+        etag: Option<&str>,
+    ) -> (hyper::http::response::Parts, String) {
+        let mut request = Request::post("/git_history");
+        if let Some(etag) = etag {
+            request = request.header("If-None-Match", etag);
+        }
+        send(state, request.body(Body::from(body.to_string())).unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn if_modified_since_sees_branches_moved_back() {
+        let repo = fixture();
+        repo.write("README.md", "hello again\n")
+            .commit("Edit the readme");
+        let state = local_server(&repo, &[]);
+        let body = serde_json::json!({ "repo_path": repo.path() }).to_string();
+        let since = |date: &str| {
+            Request::post("/git_history")
+                .header("If-Modified-Since", date)
+                .body(Body::from(body.clone()))
+                .unwrap()
+        };
+
+        let (parts, _) = history_if_none_match(&state, &body.parse().unwrap(), None).await;
+        let last_modified = header(&parts, "Last-Modified").unwrap().to_string();
+        let (parts, _) = send(&state, since(&last_modified)).await;
+        assert_eq!(parts.status, StatusCode::NOT_MODIFIED);
+
+        // Back to a commit older than anything the client has seen: still a change
+        repo.git(["reset", "-q", "--hard", "HEAD~1"]);
+        let (parts, history) = send(&state, since(&last_modified)).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert!(!history.contains("Edit the readme"));
+        let moved_back = header(&parts, "Last-Modified").unwrap().to_string();
+        assert_ne!(moved_back, last_modified);
+        let (parts, _) = send(&state, since(&moved_back)).await;
+        assert_eq!(parts.status, StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn history_responses_stay_within_the_byte_limit() {
+        let repo = fixture();
+        for n in 1..=12 {
+            repo.write(format!("notes/{}.txt", n), "a line of notes\n".repeat(n))
+                .commit(&format!("Add notes {}", n));
+        }
+        let limit = 4000;
+        let state = server(ServerConfig {
+            allow_local_paths: vec![repo.path().to_path_buf()],
+            max_response_bytes: limit,
+            ..ServerConfig::default()
+        });
+
+        for envelope in [true, false] {
+            let mut seen = Vec::new();
+            let mut cursor: Option<String> = None;
+            loop {
+                let mut body = serde_json::json!({
+                    "repo_path": repo.path(),
+                    "envelope": envelope,
+                });
+                if let Some(cursor) = &cursor {
+                    body["cursor"] = cursor.as_str().into();
+                }
+                let request = Request::post("/git_history")
+                    .body(Body::from(body.to_string()))
+                    .unwrap();
+                let (parts, response) = send(&state, request).await;
+                assert_eq!(parts.status, StatusCode::OK, "{}", response);
+                assert!(response.len() as u64 <= limit, "{} bytes", response.len());
+                let (_, health) = get(&state, "/health").await;
+                assert_eq!(health["responses"]["last_bytes"], response.len());
+
+                let response: serde_json::Value = serde_json::from_str(&response).unwrap();
+                // The last page of a bare history is a bare array
+                let commits = response
+                    .as_array()
+                    .or(response["commits"].as_array())
+                    .unwrap();
+                assert!(!commits.is_empty());
+                seen.extend(commits.iter().map(|c| c["commit_id"].clone()));
+                cursor = response["meta"]["next_cursor"].as_str().map(String::from);
+                if cursor.is_none() {
+                    break;
+                }
+                assert_eq!(response["meta"]["truncated"], true);
+            }
+            // Every commit on some page, none on two
+            let expected: Vec<serde_json::Value> = repo
+                .git(["rev-list", "HEAD"])
+                .lines()
+                .map(|id| id.into())
+                .collect();
+            assert_eq!(seen, expected, "envelope: {}", envelope);
+        }
+    }
+
+    #[tokio::test]
+    async fn history_etags_hit_miss_and_follow_the_options() {
+        let repo = fixture();
+        let state = local_server(&repo, &[]);
+        let body = serde_json::json!({ "repo_path": repo.path() });
+        let (parts, _) = history_if_none_match(&state, &body, None).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let etag = header(&parts, "ETag").unwrap().to_string();
+        assert!(header(&parts, "Last-Modified").is_some());
+
+        // Hit: same tip, same options
+        let (parts, response) = history_if_none_match(&state, &body, Some(&etag)).await;
+        assert_eq!(parts.status, StatusCode::NOT_MODIFIED);
+        assert_eq!(response, "");
+        assert_eq!(header(&parts, "ETag"), Some(etag.as_str()));
+
+        // Changed options: same tip, another history
+        let no_diff = serde_json::json!({ "repo_path": repo.path(), "no_diff": true });
+        let (parts, _) = history_if_none_match(&state, &no_diff, Some(&etag)).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_ne!(header(&parts, "ETag"), Some(etag.as_str()));
+
+        // Miss: the tip moved
+        repo.write("README.md", "hello again\n")
+            .commit("Edit the readme");
+        let (parts, response) = history_if_none_match(&state, &body, Some(&etag)).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_ne!(header(&parts, "ETag"), Some(etag.as_str()));
+        assert!(response.contains("Edit the readme"));
+    }
+
+    #[tokio::test]
+    async fn all_history_etags_change_with_any_branch() {
+        let repo = fixture();
+        repo.git(["branch", "feature"]);
+        let state = local_server(&repo, &[]);
+        let head = serde_json::json!({ "repo_path": repo.path() });
+        let all = serde_json::json!({ "repo_path": repo.path(), "all": true });
+        let head_etag = header(&history_if_none_match(&state, &head, None).await.0, "ETag")
+            .unwrap()
+            .to_string();
+        let all_etag = header(&history_if_none_match(&state, &all, None).await.0, "ETag")
+            .unwrap()
+            .to_string();
+        let (parts, _) = history_if_none_match(&state, &all, Some(&all_etag)).await;
+        assert_eq!(parts.status, StatusCode::NOT_MODIFIED);
+
+        // A commit on a branch that isn't checked out leaves HEAD alone
+        repo.git(["checkout", "-q", "feature"]);
+        repo.write("feature.txt", "feature\n")
+            .commit("Add the feature");
+        repo.git(["checkout", "-q", "main"]);
+        let (parts, _) = history_if_none_match(&state, &head, Some(&head_etag)).await;
+        assert_eq!(parts.status, StatusCode::NOT_MODIFIED);
+        let (parts, response) = history_if_none_match(&state, &all, Some(&all_etag)).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert!(response.contains("Add the feature"));
+    }
+
+    #[tokio::test]
+    async fn remote_all_history_etags_are_checked_after_indexing() {
+        let repo = fixture();
+        repo.git(["branch", "feature"]);
+        let state = cloning_server(&[&repo]);
+        let all = serde_json::json!({ "repo_url": repo.url(), "all": true });
+        let (parts, _) = history_if_none_match(&state, &all, None).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        let etag = header(&parts, "ETag").unwrap().to_string();
+        let (parts, response) = history_if_none_match(&state, &all, Some(&etag)).await;
+        assert_eq!(parts.status, StatusCode::NOT_MODIFIED);
+        assert_eq!(response, "");
+
+        repo.git(["checkout", "-q", "feature"]);
+        repo.write("feature.txt", "feature\n")
+            .commit("Add the feature");
+        repo.git(["checkout", "-q", "main"]);
+        let (parts, response) = history_if_none_match(&state, &all, Some(&etag)).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert!(response.contains("Add the feature"));
+    }
+
+    #[test]
+    fn repeated_runs_are_byte_identical() {
+        let (repo, _submodule) = varied_fixture();
+        let flags = ["--all", "--rename-threshold", "50", "--word-diff"];
+        let first = index(&repo, &flags).to_string();
+        assert_eq!(index(&repo, &flags).to_string(), first);
+
+        for commit in serde_json::from_str::<serde_json::Value>(&first)
+            .unwrap()
+            .as_array()
+            .unwrap()
+        {
+            let files: Vec<_> = commit["git_diff"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|file| file["file"].as_str().unwrap())
+                .collect();
+            let mut sorted = files.clone();
+            sorted.sort();
+            assert_eq!(files, sorted);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn deltas_for_the_same_path_stay_separate_entries() {
+        let repo = TestRepo::new();
+        repo.write("b.txt", "b\n")
+            .write("link", "one\ntwo\n")
+            .write("a.txt", "a\n")
+            .commit("Add files");
+        repo.remove("link");
+        std::os::unix::fs::symlink("a.txt", repo.path().join("link")).unwrap();
+        repo.write("a.txt", "a2\n")
+            .commit("Replace the file with a link");
+
+        let history = index(&repo, &["--diff-format", "unified"]);
+        let files: Vec<_> = history[0]["git_diff"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| {
+                (
+                    file["file"].as_str().unwrap(),
+                    file["additions"].as_u64().unwrap(),
+                    file["deletions"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        // The type change is a deletion and an addition of the same path
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[0], ("a.txt", 1, 1));
+        assert_eq!(files[1].0, "link");
+        assert_eq!(files[2].0, "link");
+        let mut counts = [(files[1].1, files[1].2), (files[2].1, files[2].2)];
+        counts.sort();
+        assert_eq!(counts, [(0, 2), (1, 0)]);
+    }
+
+    #[test]
+    fn per_file_counts_add_up_to_the_commit_stats() {
+        let (repo, _submodule) = varied_fixture();
+        for flags in [
+            &[][..],
+            &["--rename-threshold", "50"],
+            &["--context-lines", "0"],
+        ] {
+            let mut full_flags = flags.to_vec();
+            full_flags.push("--include-generated");
+            let full = index(&repo, &full_flags);
+            let mut stats_flags = flags.to_vec();
+            stats_flags.push("--stats-only");
+            let stats = index(&repo, &stats_flags);
+
+            for (commit, stats) in full
+                .as_array()
+                .unwrap()
+                .iter()
+                .zip(stats.as_array().unwrap())
+            {
+                let files = commit["git_diff"].as_array().unwrap();
+                let sum = |field: &str| -> u64 {
+                    files.iter().filter_map(|file| file[field].as_u64()).sum()
+                };
+                let message = commit["commit_message"].as_str().unwrap();
+                assert_eq!(
+                    sum("additions"),
+                    stats["stats"]["insertions"],
+                    "{} {:?}",
+                    message,
+                    flags
+                );
+                assert_eq!(
+                    sum("deletions"),
+                    stats["stats"]["deletions"],
+                    "{} {:?}",
+                    message,
+                    flags
+                );
+                assert_eq!(
+                    files.len() as u64,
+                    stats["stats"]["files_changed"],
+                    "{}",
+                    message
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn counts_only_added_and_removed_lines() {
+        let repo = TestRepo::new();
+        let lines: String = (1..=10).map(|n| format!("line {}\n", n)).collect();
+        repo.write("a.txt", &lines)
+            .write("image.bin", b"\x89PNG\0\x01")
+            .commit("Add files");
+        repo.write(
+            "a.txt",
+            lines.replace("line 5\n", "five\nfive and a half\n"),
+        )
+        .write("image.bin", b"\x89PNG\0\x02")
+        .commit("Edit files");
+
+        let history = index(&repo, &[]);
+        let files = history[0]["git_diff"].as_array().unwrap();
+        assert_eq!(files[0]["file"], "a.txt");
+        assert_eq!(
+            (&files[0]["additions"], &files[0]["deletions"]),
+            (&2.into(), &1.into())
+        );
+        assert_eq!(files[1]["file"], "image.bin");
+        assert_eq!(files[1]["additions"], serde_json::Value::Null);
+        assert_eq!(files[1]["deletions"], serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn diff_modes_emit_what_they_promise() {
+        let repo = TestRepo::new();
+        repo.write("a.txt", "one\n").commit("Add a");
+        repo.write("a.txt", "one\ntwo\n")
+            .write("b.txt", "b\n")
+            .commit("Grow a, add b");
+        let state = local_server(&repo, &[]);
+
+        // (flags, request fields, whether diffs, stats and patch ids are present)
+        let modes: [(&[&str], serde_json::Value, bool, bool, bool); 4] = [
+            (&[], serde_json::json!({}), true, false, true),
+            (
+                &["--no-diff"],
+                serde_json::json!({ "include_diffs": false }),
+                false,
+                false,
+                false,
+            ),
+            (
+                &["--stats-only"],
+                serde_json::json!({ "stats_only": true }),
+                false,
+                true,
+                false,
+            ),
+            (
+                &["--no-diff", "--stats-only"],
+                serde_json::json!({ "include_diffs": false, "stats_only": true }),
+                false,
+                true,
+                false,
+            ),
+        ];
+        for (flags, mut fields, diffs, stats, patch_ids) in modes {
+            fields["repo_path"] = repo.path().to_str().unwrap().into();
+            fields["envelope"] = false.into();
+            let (status, served) = post(&state, "/git_history", fields).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(served, index(&repo, flags), "{:?}", flags);
+
+            for commit in served.as_array().unwrap() {
+                let files = commit["git_diff"].as_array().unwrap();
+                assert_eq!(!files.is_empty(), diffs, "{:?}", flags);
+                assert_eq!(commit.get("stats").is_some(), stats, "{:?}", flags);
+                assert_eq!(!commit["patch_id"].is_null(), patch_ids, "{:?}", flags);
+                assert!(commit["author"].is_string());
+                assert!(commit["commit_message"].is_string());
+            }
+        }
+
+        let history = index(&repo, &["--stats-only"]);
+        assert_eq!(
+            history[0]["stats"],
+            serde_json::json!({ "files_changed": 2, "insertions": 2, "deletions": 0 })
+        );
+    }
+
+    #[tokio::test]
+    async fn batches_index_one_repository_at_several_refs() {
+        let repo = fixture();
+        repo.git(["tag", "v1.0"]);
+        repo.write("CHANGELOG.md", "- more\n")
+            .commit("Add a changelog");
+        let state = local_server(&repo, &[]);
+        let path = repo.path().to_str().unwrap();
+
+        let body = serde_json::json!({
+            "repos": [
+                { "repo_path": path },
+                { "repo_path": path, "ref": "v1.0" },
+                { "repo_path": path, "ref": "main" },
+                { "repo_path": path, "ref": "v1.0" },
+            ],
+            "envelope": false,
+        });
+        let (status, results) = post(&state, "/git_history/batch", body).await;
+        assert_eq!(status, StatusCode::OK);
+        let results = results.as_object().unwrap();
+        assert_eq!(results.len(), 3);
+        let count = |key: &str| results[key]["commits"].as_array().unwrap().len();
+        assert_eq!(count(path), 2);
+        assert_eq!(count(&format!("{}@main", path)), 2);
+        assert_eq!(count(&format!("{}@v1.0", path)), 1);
+    }
+}
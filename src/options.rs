@@ -0,0 +1,602 @@
+use crate::redact::RedactMode;
+use chrono::{DateTime, NaiveDate};
+use git2::{DiffFindOptions, DiffOptions};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Filters shared by `index` and every subcommand that walks the history, plus the
+/// options controlling how `index` renders diffs.
+#[derive(Clone, Debug, Default)]
+pub struct IndexOptions {
+    /// Only commits committed at or after this Unix timestamp
+    pub since: Option<i64>,
+    /// Only commits committed at or before this Unix timestamp
+    pub until: Option<i64>,
+    /// Only commits touching these paths; diffs are limited to them as well
+    pub paths: Vec<String>,
+    /// Only commits whose author name or email contains this, case-insensitively
+    pub author: Option<String>,
+    /// List the commits a submodule pointer moved through, when it is checked out
+    pub recurse_submodules: bool,
+    /// Keep patches of files `.gitattributes` marks as generated
+    pub include_generated: bool,
+    /// Lines of context around each hunk, instead of libgit2's default of 3
+    pub context_lines: Option<u32>,
+    /// Ignore all whitespace when comparing lines
+    pub ignore_whitespace: bool,
+    /// Ignore changes in the amount of whitespace
+    pub ignore_whitespace_change: bool,
+    /// Walk every branch, remote branch and tag instead of just HEAD
+    pub all: bool,
+    /// .gitignore-style patterns for files left out of diffs; `!` re-includes
+    pub exclude: Vec<String>,
+    pub diff_format: PatchFormat,
+    /// Add a `word_diff` rendering of intraline changes to each text file
+    pub word_diff: bool,
+    /// Files larger than this (both sides together) get no word diff
+    pub word_diff_max_bytes: Option<u64>,
+    /// Leave out the patch of files whose diff is longer than this, keeping their counts
+    pub max_diff_bytes: Option<u64>,
+    /// Attach pull request and issue metadata from this provider (`github` or `gitlab`)
+    pub enrich: Option<String>,
+    /// Token for the GitHub API, raising its rate limit and reaching private repos
+    pub github_token: Option<String>,
+    /// Base URL of the GitLab instance, for self-hosted ones
+    pub gitlab_url: Option<String>,
+    /// Token for the GitLab API
+    pub gitlab_token: Option<String>,
+    /// Leave out `git_diff`, so no patch is generated
+    pub no_diff: bool,
+    /// Leave out `git_diff` but report each commit's `stats`
+    pub stats_only: bool,
+    /// Report blobs larger than this many bytes in the commit that added them
+    pub flag_large_additions: Option<u64>,
+    /// What to do with the diff of commits whose parents were cut off by a shallow
+    /// clone, a replace ref or a graft
+    pub boundary_diff: BoundaryDiff,
+    /// Identity corrections applied on top of .mailmap
+    pub identity_map: Option<PathBuf>,
+    /// Minimum similarity, in percent, of a deleted and an added file to be a rename.
+    /// Rename detection only runs when this, `copy_threshold` or `rename_limit` is set
+    pub rename_threshold: Option<u16>,
+    /// Minimum similarity of a changed and an added file to be a copy; setting it
+    /// turns on copy detection
+    pub copy_threshold: Option<u16>,
+    /// Skip rename detection for commits pairing more candidates than this squared
+    pub rename_limit: Option<usize>,
+    /// Minimum length of abbreviated SHAs, instead of the repository's `core.abbrev`
+    pub sha_length: Option<usize>,
+    /// Replace emails, names or both with stable tokens in everything written out
+    pub redact: Option<RedactMode>,
+    /// Key of the hash behind redaction tokens; random per run when unset
+    pub redact_salt: Option<String>,
+    /// Write which identity each redaction token stands for to this file
+    pub redaction_map: Option<PathBuf>,
+    /// Wrap the commits as `{"meta", "commits"}` with a description of the run;
+    /// the server does by default
+    pub envelope: bool,
+    /// Walk from this `--rev` instead of HEAD; batch requests set it per repository
+    pub rev: Option<String>,
+    /// Walk this `--range` of `index` instead, e.g. `v1.0..HEAD`
+    pub range: Option<String>,
+    /// Only commits not reachable from this one; `watch` uses it to index just what
+    /// HEAD gained
+    pub after_commit: Option<git2::Oid>,
+    /// Resume the walk at this commit, the `next_cursor` of a truncated response
+    pub cursor: Option<git2::Oid>,
+    /// Stop once the commits take this many bytes of JSON; the server sets it from
+    /// `--max-response-bytes`
+    pub max_output_bytes: Option<u64>,
+}
+
+/// Default for `--word-diff-max-bytes`.
+const WORD_DIFF_MAX_BYTES: u64 = 64 * 1024;
+
+/// Default for `--rename-limit`, git's `diff.renameLimit`.
+const RENAME_LIMIT: usize = 1000;
+
+/// How the `diff` text of each file is rendered.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PatchFormat {
+    /// Lines without their `+`/`-`/space markers, as produced since the first release
+    #[default]
+    Legacy,
+    /// A regular unified diff that `git apply` and `patch` accept
+    Unified,
+}
+
+impl PatchFormat {
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "legacy" => Ok(PatchFormat::Legacy),
+            "unified" => Ok(PatchFormat::Unified),
+            _ => Err(format!(
+                "Invalid diff format '{}', expected unified or legacy",
+                raw
+            )),
+        }
+    }
+}
+
+/// What `index` writes: one JSON document, or with `--format ndjson` one commit per
+/// line as it is indexed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Json,
+    Ndjson,
+}
+
+impl OutputFormat {
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            _ => Err(format!("expected json or ndjson, got '{}'", raw)),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OutputFormat::Json => "json",
+            OutputFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+/// How commits at a graft boundary are indexed. Their diff lists the whole tree as
+/// added, which says nothing about the change they made.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BoundaryDiff {
+    /// Keep the whole-tree patch
+    Full,
+    /// Keep the commit with an empty `git_diff`
+    #[default]
+    Empty,
+    /// Leave the commit out
+    Omit,
+}
+
+impl BoundaryDiff {
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "full" => Ok(BoundaryDiff::Full),
+            "empty" => Ok(BoundaryDiff::Empty),
+            "omit" => Ok(BoundaryDiff::Omit),
+            _ => Err(format!("expected full, empty or omit, got '{}'", raw)),
+        }
+    }
+}
+
+impl IndexOptions {
+    /// Applies `flag` if it is an index option, pulling its value from `value`.
+    /// Returns `Ok(false)` for flags that belong to someone else. Errors describe just
+    /// the bad value, since it may come from a flag, the config file or the environment.
+    pub fn apply_flag(
+        &mut self,
+        flag: &str,
+        value: &mut dyn FnMut() -> Result<String, String>,
+    ) -> Result<bool, String> {
+        match flag {
+            "--since" => self.since = Some(parse_date(&value()?, false)?),
+            "--until" => self.until = Some(parse_date(&value()?, true)?),
+            "--path" => self.paths.push(value()?),
+            "--author" => self.author = Some(value()?),
+            "--recurse-submodules" => self.recurse_submodules = true,
+            "--include-generated" => self.include_generated = true,
+            "--context-lines" => {
+                let raw = value()?;
+                let lines = raw
+                    .parse()
+                    .map_err(|_| format!("expected a non-negative integer, got '{}'", raw))?;
+                self.context_lines = Some(lines);
+            }
+            "--ignore-whitespace" => self.ignore_whitespace = true,
+            "--ignore-whitespace-change" => self.ignore_whitespace_change = true,
+            "--diff-format" => self.diff_format = PatchFormat::parse(&value()?)?,
+            "--word-diff" => self.word_diff = true,
+            "--word-diff-max-bytes" => {
+                let raw = value()?;
+                let bytes = raw
+                    .parse()
+                    .map_err(|_| format!("expected a non-negative integer, got '{}'", raw))?;
+                self.word_diff_max_bytes = Some(bytes);
+            }
+            "--max-diff-bytes" => {
+                let raw = value()?;
+                let bytes = raw
+                    .parse()
+                    .map_err(|_| format!("expected a non-negative integer, got '{}'", raw))?;
+                self.max_diff_bytes = Some(bytes);
+            }
+            "--no-diff" => self.no_diff = true,
+            "--stats-only" => self.stats_only = true,
+            "--flag-large-additions" => {
+                let raw = value()?;
+                let bytes = raw
+                    .parse()
+                    .map_err(|_| format!("expected a non-negative integer, got '{}'", raw))?;
+                self.flag_large_additions = Some(bytes);
+            }
+            "--boundary-diff" => self.boundary_diff = BoundaryDiff::parse(&value()?)?,
+            "--rename-threshold" => self.rename_threshold = Some(parse_percent(&value()?)?),
+            "--copy-threshold" => self.copy_threshold = Some(parse_percent(&value()?)?),
+            "--rename-limit" => {
+                let raw = value()?;
+                self.rename_limit = Some(
+                    raw.parse()
+                        .map_err(|_| format!("expected a non-negative integer, got '{}'", raw))?,
+                );
+            }
+            "--sha-length" => self.sha_length = Some(parse_sha_length(&value()?)?),
+            "--redact" => self.redact = Some(RedactMode::parse(&value()?)?),
+            "--redact-salt" => self.redact_salt = Some(value()?),
+            "--redaction-map" => self.redaction_map = Some(PathBuf::from(value()?)),
+            "--envelope" => self.envelope = true,
+            "--identity-map" => self.identity_map = Some(PathBuf::from(value()?)),
+            "--all" => self.all = true,
+            "--rev" => self.rev = Some(value()?),
+            "--exclude" => self.exclude.push(value()?),
+            "--enrich" => self.enrich = Some(crate::enrich::parse_provider(&value()?)?),
+            "--github-token" => self.github_token = Some(value()?),
+            "--gitlab-url" => self.gitlab_url = Some(value()?),
+            "--gitlab-token" => self.gitlab_token = Some(value()?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// These options as the base of a server request: the `[index]` settings and
+    /// variables, less those that only make sense for a single run of `index`.
+    pub fn request_defaults(&self) -> IndexOptions {
+        IndexOptions {
+            redaction_map: None,
+            envelope: true,
+            rev: None,
+            range: None,
+            after_commit: None,
+            cursor: None,
+            max_output_bytes: None,
+            ..self.clone()
+        }
+    }
+
+    /// Reads `since`, `until`, `author`, `all` and `path` (comma separated) query
+    /// parameters, and the redaction ones, over `defaults`.
+    pub fn from_query(
+        params: &HashMap<String, String>,
+        defaults: &IndexOptions,
+    ) -> Result<Self, String> {
+        let mut options = IndexOptions::redaction_from_query(params, defaults)?;
+        if let Some(since) = params.get("since") {
+            options.since = Some(parse_date(since, false)?);
+        }
+        if let Some(until) = params.get("until") {
+            options.until = Some(parse_date(until, true)?);
+        }
+        if let Some(paths) = params.get("path") {
+            options.paths = paths.split(',').map(String::from).collect();
+        }
+        if let Some(author) = params.get("author") {
+            options.author = Some(author.clone());
+        }
+        if let Some(all) = params.get("all") {
+            options.all = all == "true";
+        }
+        Ok(options)
+    }
+
+    /// Reads the `redact` and `redact_salt` query parameters alone over `defaults`, for
+    /// the endpoints that take no filters.
+    pub fn redaction_from_query(
+        params: &HashMap<String, String>,
+        defaults: &IndexOptions,
+    ) -> Result<Self, String> {
+        let mut options = defaults.clone();
+        if let Some(mode) = params.get("redact") {
+            options.redact = Some(RedactMode::parse(mode).map_err(|e| format!("redact: {}", e))?);
+        }
+        if let Some(salt) = params.get("redact_salt") {
+            options.redact_salt = Some(salt.clone());
+        }
+        Ok(options)
+    }
+
+    /// Reads the filters and diff options from a JSON request body, under the same
+    /// names as the flags (`exclude_paths` for `--exclude`, `include_diffs: false` for
+    /// `--no-diff`). Fields the body leaves out keep their value in `defaults`.
+    pub fn from_json(body: &serde_json::Value, defaults: &IndexOptions) -> Result<Self, String> {
+        let mut options = defaults.clone();
+        if let Some(since) = body["since"].as_str() {
+            options.since = Some(parse_date(since, false)?);
+        }
+        if let Some(until) = body["until"].as_str() {
+            options.until = Some(parse_date(until, true)?);
+        }
+        if let Some(paths) = body["paths"].as_array() {
+            options.paths = paths
+                .iter()
+                .map(|p| p.as_str().map(String::from).ok_or("paths must be strings"))
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(author) = body["author"].as_str() {
+            options.author = Some(author.to_string());
+        }
+        let flag = |key: &str, default: bool| body[key].as_bool().unwrap_or(default);
+        options.recurse_submodules = flag("recurse_submodules", options.recurse_submodules);
+        options.include_generated = flag("include_generated", options.include_generated);
+        if !body["context_lines"].is_null() {
+            let lines = body["context_lines"]
+                .as_u64()
+                .and_then(|lines| u32::try_from(lines).ok())
+                .ok_or("context_lines must be a non-negative integer")?;
+            options.context_lines = Some(lines);
+        }
+        options.ignore_whitespace = flag("ignore_whitespace", options.ignore_whitespace);
+        options.ignore_whitespace_change =
+            flag("ignore_whitespace_change", options.ignore_whitespace_change);
+        if let Some(format) = body["diff_format"].as_str() {
+            options.diff_format = PatchFormat::parse(format)?;
+        }
+        options.word_diff = flag("word_diff", options.word_diff);
+        if !body["word_diff_max_bytes"].is_null() {
+            let bytes = body["word_diff_max_bytes"]
+                .as_u64()
+                .ok_or("word_diff_max_bytes must be a non-negative integer")?;
+            options.word_diff_max_bytes = Some(bytes);
+        }
+        if !body["max_diff_bytes"].is_null() {
+            let bytes = body["max_diff_bytes"]
+                .as_u64()
+                .ok_or("max_diff_bytes must be a non-negative integer")?;
+            options.max_diff_bytes = Some(bytes);
+        }
+        options.no_diff = !flag("include_diffs", !options.no_diff);
+        options.stats_only = flag("stats_only", options.stats_only);
+        if !body["flag_large_additions"].is_null() {
+            let bytes = body["flag_large_additions"]
+                .as_u64()
+                .ok_or("flag_large_additions must be a non-negative integer")?;
+            options.flag_large_additions = Some(bytes);
+        }
+        if let Some(mode) = body["boundary_diff"].as_str() {
+            options.boundary_diff = BoundaryDiff::parse(mode)?;
+        }
+        for (key, threshold) in [
+            ("rename_threshold", &mut options.rename_threshold),
+            ("copy_threshold", &mut options.copy_threshold),
+        ] {
+            if !body[key].is_null() {
+                *threshold = Some(
+                    body[key]
+                        .as_u64()
+                        .filter(|percent| *percent <= 100)
+                        .ok_or(format!("{} must be a percentage from 0 to 100", key))?
+                        as u16,
+                );
+            }
+        }
+        if !body["rename_limit"].is_null() {
+            let limit = body["rename_limit"]
+                .as_u64()
+                .ok_or("rename_limit must be a non-negative integer")?;
+            options.rename_limit = Some(limit as usize);
+        }
+        if !body["sha_length"].is_null() {
+            let length = body["sha_length"]
+                .as_u64()
+                .filter(|length| (4..=40).contains(length))
+                .ok_or("sha_length must be an integer from 4 to 40")?;
+            options.sha_length = Some(length as usize);
+        }
+        options.envelope = flag("envelope", true);
+        if let Some(cursor) = body["cursor"].as_str() {
+            options.cursor = Some(
+                git2::Oid::from_str(cursor)
+                    .ok()
+                    .filter(|_| cursor.len() == 40)
+                    .ok_or("cursor must be the full SHA of a next_cursor")?,
+            );
+        }
+        options.all = flag("all", options.all);
+        if let Some(patterns) = body["exclude_paths"].as_array() {
+            options.exclude = patterns
+                .iter()
+                .map(|p| {
+                    p.as_str()
+                        .map(String::from)
+                        .ok_or("exclude_paths must be strings")
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(provider) = body["enrich"].as_str() {
+            options.enrich = Some(crate::enrich::parse_provider(provider)?);
+        }
+        if let Some(mode) = body["redact"].as_str() {
+            options.redact = Some(RedactMode::parse(mode).map_err(|e| format!("redact: {}", e))?);
+        }
+        for (key, value) in [
+            ("redact_salt", &mut options.redact_salt),
+            ("github_token", &mut options.github_token),
+            ("gitlab_url", &mut options.gitlab_url),
+            ("gitlab_token", &mut options.gitlab_token),
+        ] {
+            if let Some(given) = body[key].as_str() {
+                *value = Some(given.to_string());
+            }
+        }
+        Ok(options)
+    }
+
+    /// Applies the filters that don't need a diff: dates and author. Date filters use
+    /// the committer date, like `git log --since/--until`.
+    pub fn accepts(&self, commit: &git2::Commit) -> bool {
+        let time = commit.time().seconds();
+        let in_range = self.since.is_none_or(|since| time >= since)
+            && self.until.is_none_or(|until| time <= until);
+        in_range
+            && self
+                .author
+                .as_deref()
+                .is_none_or(|pattern| matches_author(commit, pattern))
+    }
+
+    pub fn diff_options(&self) -> DiffOptions {
+        let mut diff_options = self.patch_options();
+        for path in &self.paths {
+            diff_options.pathspec(path);
+        }
+        diff_options
+    }
+
+    /// Diff options that only shape the patch text, without any path limits.
+    pub fn patch_options(&self) -> DiffOptions {
+        let mut diff_options = DiffOptions::new();
+        if let Some(lines) = self.context_lines {
+            diff_options.context_lines(lines);
+        }
+        diff_options
+            .ignore_whitespace(self.ignore_whitespace)
+            .ignore_whitespace_change(self.ignore_whitespace_change);
+        diff_options
+    }
+
+    pub fn ignores_whitespace(&self) -> bool {
+        self.ignore_whitespace || self.ignore_whitespace_change
+    }
+
+    /// Whether `git_diff` is filled in, which is what makes indexing expensive.
+    pub fn includes_patches(&self) -> bool {
+        !self.no_diff && !self.stats_only
+    }
+
+    /// Whether renames (and, with a `copy_threshold`, copies) are looked for.
+    pub fn finds_renames(&self) -> bool {
+        self.rename_threshold.is_some()
+            || self.copy_threshold.is_some()
+            || self.rename_limit.is_some()
+    }
+
+    pub fn rename_limit(&self) -> usize {
+        self.rename_limit.unwrap_or(RENAME_LIMIT)
+    }
+
+    /// `find_similar` options for rename detection, with libgit2's default of 50% for
+    /// thresholds that aren't set.
+    pub fn find_options(&self) -> DiffFindOptions {
+        let mut find = DiffFindOptions::new();
+        find.renames(true).rename_limit(self.rename_limit());
+        if let Some(threshold) = self.rename_threshold {
+            find.rename_threshold(threshold);
+        }
+        if let Some(threshold) = self.copy_threshold {
+            find.copies(true).copy_threshold(threshold);
+        }
+        find
+    }
+
+    pub fn word_diff_max_bytes(&self) -> u64 {
+        self.word_diff_max_bytes.unwrap_or(WORD_DIFF_MAX_BYTES)
+    }
+
+    /// Whether `diff_options` can leave out or alter parts of a commit's change.
+    pub fn narrows_diff(&self) -> bool {
+        !self.paths.is_empty()
+            || !self.exclude.is_empty()
+            || self.context_lines.is_some()
+            || self.ignores_whitespace()
+    }
+
+    /// Compiles the `exclude` patterns, in order, so later `!` patterns can re-include
+    /// what earlier ones excluded. `None` when nothing is excluded.
+    pub fn exclude_matcher(&self) -> Result<Option<Gitignore>, String> {
+        if self.exclude.is_empty() {
+            return Ok(None);
+        }
+        let mut builder = GitignoreBuilder::new("");
+        for pattern in &self.exclude {
+            builder
+                .add_line(None, pattern)
+                .map_err(|e| format!("Invalid exclude pattern '{}': {}", pattern, e))?;
+        }
+        let matcher = builder
+            .build()
+            .map_err(|e| format!("Invalid exclude patterns: {}", e))?;
+        Ok(Some(matcher))
+    }
+}
+
+fn matches_author(commit: &git2::Commit, pattern: &str) -> bool {
+    let author = commit.author();
+    let pattern = pattern.to_lowercase();
+    [author.name(), author.email()]
+        .iter()
+        .flatten()
+        .any(|field| field.to_lowercase().contains(&pattern))
+}
+
+fn parse_sha_length(raw: &str) -> Result<usize, String> {
+    match raw.parse::<usize>() {
+        Ok(length) if (4..=40).contains(&length) => Ok(length),
+        _ => Err(format!("expected an integer from 4 to 40, got '{}'", raw)),
+    }
+}
+
+fn parse_percent(raw: &str) -> Result<u16, String> {
+    match raw.trim_end_matches('%').parse::<u16>() {
+        Ok(percent) if percent <= 100 => Ok(percent),
+        _ => Err(format!(
+            "expected a percentage from 0 to 100, got '{}'",
+            raw
+        )),
+    }
+}
+
+/// Parses a subcommand's arguments. Positionals are returned in order, index options
+/// are applied to the returned `IndexOptions` and any other flag is offered to `extra`.
+pub fn parse_args<F>(args: &[String], mut extra: F) -> Result<(Vec<String>, IndexOptions), String>
+where
+    F: FnMut(&str, &mut dyn FnMut() -> Result<String, String>) -> Result<bool, String>,
+{
+    let mut positionals = Vec::new();
+    let mut options = IndexOptions::default();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positionals.push(arg.clone());
+            continue;
+        }
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| "missing value".to_string())
+        };
+        let known = options
+            .apply_flag(arg, &mut value)
+            .and_then(|known| Ok(known || extra(arg, &mut value)?))
+            .map_err(|e| format!("{}: {}", arg, e))?;
+        if !known {
+            return Err(format!("Unknown option: {}", arg));
+        }
+    }
+
+    Ok((positionals, options))
+}
+
+/// Accepts RFC 3339 timestamps or plain `YYYY-MM-DD` dates (UTC). A plain date used as
+/// an upper bound covers the whole day.
+pub fn parse_date(raw: &str, end_of_day: bool) -> Result<i64, String> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(raw) {
+        return Ok(date.timestamp());
+    }
+    let date = NaiveDate::parse_from_str(raw, "%Y-%m-%d")
+        .map_err(|_| format!("Invalid date '{}', expected YYYY-MM-DD or RFC 3339", raw))?;
+    let time = if end_of_day {
+        date.and_hms_opt(23, 59, 59)
+    } else {
+        date.and_hms_opt(0, 0, 0)
+    };
+    Ok(time.expect("valid time of day").and_utc().timestamp())
+}
@@ -0,0 +1,143 @@
+use crate::identities::Identities;
+use crate::options::IndexOptions;
+use crate::{for_each_commit, CustomError};
+use chrono::{Months, Utc};
+use git2::{Patch, Repository};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/// Default for `--depth`.
+pub const DEFAULT_DEPTH: usize = 2;
+/// Default for `--months`, the window used when `--since` isn't given.
+pub const DEFAULT_MONTHS: u32 = 12;
+/// Default for `--top`.
+pub const DEFAULT_TOP: usize = 5;
+
+#[derive(Serialize)]
+pub struct DirectoryOwnership {
+    /// Lines added plus lines removed in the directory
+    lines_changed: usize,
+    /// Fewest authors who together made at least half of the changes
+    bus_factor: usize,
+    top_contributors: Vec<Owner>,
+}
+
+#[derive(Serialize)]
+pub struct Owner {
+    name: String,
+    email: String,
+    lines_changed: usize,
+    /// Fraction of the directory's changed lines
+    share: f64,
+}
+
+/// Start of the window covering the last `months` months.
+pub fn window_start(months: u32) -> i64 {
+    let now = Utc::now();
+    now.checked_sub_months(Months::new(months))
+        .unwrap_or(now)
+        .timestamp()
+}
+
+/// Changed lines per author for every directory down to `depth` levels, `.` being the
+/// whole repository, over the filtered history. Authors are canonicalized through
+/// .mailmap and `--identity-map` and keyed by email.
+pub fn ownership(
+    repo_path: &Path,
+    options: &IndexOptions,
+    depth: usize,
+    top: usize,
+) -> Result<BTreeMap<String, DirectoryOwnership>, CustomError> {
+    let repo = Repository::open(repo_path)?;
+    let identities = Identities::load(&repo, options)?;
+
+    // Directory -> author email -> lines changed
+    let mut lines: HashMap<String, HashMap<String, usize>> = HashMap::new();
+    let mut names: HashMap<String, String> = HashMap::new();
+
+    for_each_commit(&repo, options, |commit, diff, _| {
+        let author = identities.author(commit)?;
+        let email = String::from_utf8_lossy(author.email_bytes()).to_lowercase();
+        names
+            .entry(email.clone())
+            .or_insert_with(|| String::from_utf8_lossy(author.name_bytes()).to_string());
+
+        for idx in 0..diff.deltas().len() {
+            let Some(patch) = Patch::from_diff(diff, idx)? else {
+                continue;
+            };
+            let (_, insertions, deletions) = patch.line_stats()?;
+            let delta = patch.delta();
+            let Some(path) = delta.new_file().path().or(delta.old_file().path()) else {
+                continue;
+            };
+            for directory in directories(path, depth) {
+                *lines
+                    .entry(directory)
+                    .or_default()
+                    .entry(email.clone())
+                    .or_default() += insertions + deletions;
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(lines
+        .into_iter()
+        .filter_map(|(directory, authors)| {
+            let total: usize = authors.values().sum();
+            if total == 0 {
+                return None;
+            }
+            let mut authors: Vec<(String, usize)> = authors.into_iter().collect();
+            authors.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+            let mut covered = 0;
+            let bus_factor = authors
+                .iter()
+                .take_while(|(_, lines)| {
+                    let below_half = covered * 2 < total;
+                    covered += lines;
+                    below_half
+                })
+                .count();
+            let top_contributors = authors
+                .into_iter()
+                .take(top)
+                .map(|(email, lines)| Owner {
+                    name: names.get(&email).cloned().unwrap_or_default(),
+                    email,
+                    lines_changed: lines,
+                    share: lines as f64 / total as f64,
+                })
+                .collect();
+
+            Some((
+                directory,
+                DirectoryOwnership {
+                    lines_changed: total,
+                    bus_factor,
+                    top_contributors,
+                },
+            ))
+        })
+        .collect())
+}
+
+/// `.` and the directories containing `path`, at most `depth` levels deep.
+fn directories(path: &Path, depth: usize) -> Vec<String> {
+    let mut directories = vec![".".to_string()];
+    let Some(parent) = path.parent() else {
+        return directories;
+    };
+    let mut prefix = String::new();
+    for component in parent.components().take(depth) {
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        prefix.push_str(&component.as_os_str().to_string_lossy());
+        directories.push(prefix.clone());
+    }
+    directories
+}
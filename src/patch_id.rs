@@ -0,0 +1,57 @@
+use crate::{CommitHistory, CustomError};
+use git2::Repository;
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// `git patch-id --stable` of a commit's full diff against its first parent. Merges
+/// have no single change to identify and empty commits have nothing to hash.
+pub fn patch_id(commit: &git2::Commit, diff: &git2::Diff) -> Result<Option<String>, CustomError> {
+    if commit.parent_count() > 1 || diff.deltas().len() == 0 {
+        return Ok(None);
+    }
+    Ok(Some(diff.patchid(None)?.to_string()))
+}
+
+/// Fills in `duplicate_of` for commits that repeat an earlier change.
+///
+/// A `(cherry picked from commit <sha>)` trailer wins when present. Otherwise commits
+/// are matched by patch-id, pointing at the oldest commit in the walk with the same
+/// one. `history` is in walk order, newest first.
+pub fn mark_duplicates(repo: &Repository, history: &mut [CommitHistory]) {
+    static TRAILER: OnceLock<Regex> = OnceLock::new();
+    let trailer = TRAILER
+        .get_or_init(|| Regex::new(r"\(cherry picked from commit ([0-9a-f]{7,40})\)").unwrap());
+    let mut originals: HashMap<String, String> = HashMap::new();
+
+    for entry in history.iter_mut().rev() {
+        let picked_from = trailer
+            .captures_iter(&entry.commit_message)
+            .last()
+            .map(|captures| captures[1].to_string());
+        // Expand abbreviated SHAs when the original is in this repository
+        let picked_from = picked_from.map(|sha| {
+            repo.revparse_single(&sha)
+                .map(|object| object.id().to_string())
+                .unwrap_or(sha)
+        });
+
+        let Some(patch_id) = &entry.patch_id else {
+            entry.duplicate_of = picked_from;
+            continue;
+        };
+        match (picked_from, originals.get(patch_id)) {
+            (Some(original), _) => {
+                // Later copies of this change point at the same original
+                originals
+                    .entry(patch_id.clone())
+                    .or_insert_with(|| original.clone());
+                entry.duplicate_of = Some(original);
+            }
+            (None, Some(original)) => entry.duplicate_of = Some(original.clone()),
+            (None, None) => {
+                originals.insert(patch_id.clone(), entry.commit_id.clone());
+            }
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use crate::CustomError;
+use git2::{ErrorCode, Oid, Repository, RevparseMode};
+use serde::{Serialize, Serializer};
+
+/// A `--range` resolved to commits, echoed in the output's `meta` so scripts can check
+/// what was indexed.
+#[derive(Clone, Debug, Serialize)]
+pub struct RevRange {
+    /// The range as given
+    pub spec: String,
+    /// Commits whose history is walked
+    #[serde(serialize_with = "hex_ids")]
+    pub include: Vec<Oid>,
+    /// Commits whose history is left out
+    #[serde(serialize_with = "hex_ids")]
+    pub exclude: Vec<Oid>,
+}
+
+/// Resolves a whitespace-separated list of `A..B` ranges, single refs and `^ref`
+/// exclusions, like `git log` takes them. A list made only of exclusions walks from
+/// HEAD. Three-dot ranges are refused.
+pub fn resolve(repo: &Repository, spec: &str) -> Result<RevRange, CustomError> {
+    let mut include = Vec::new();
+    let mut exclude = Vec::new();
+    for token in spec.split_whitespace() {
+        if token.contains("...") {
+            return Err(CustomError::InvalidRequest(format!(
+                "'{}': three-dot ranges are not supported yet",
+                token
+            )));
+        }
+        if let Some(excluded) = token.strip_prefix('^') {
+            exclude.push(commit_id(repo, excluded, token)?);
+        } else if token.contains("..") {
+            let (from, to) = token.split_once("..").expect("token contains ..");
+            // An empty side means HEAD, as in git
+            let from = if from.is_empty() { "HEAD" } else { from };
+            let to = if to.is_empty() { "HEAD" } else { to };
+            exclude.push(commit_id(repo, from, token)?);
+            include.push(commit_id(repo, to, token)?);
+        } else {
+            include.push(commit_id(repo, token, token)?);
+        }
+    }
+    if include.is_empty() && exclude.is_empty() {
+        return Err(CustomError::InvalidRequest(
+            "the range is empty".to_string(),
+        ));
+    }
+    if include.is_empty() {
+        include.push(commit_id(repo, "HEAD", spec)?);
+    }
+    Ok(RevRange {
+        spec: spec.to_string(),
+        include,
+        exclude,
+    })
+}
+
+/// The commit `rev` names. Errors quote `token`, the part of the range it came from.
+fn commit_id(repo: &Repository, rev: &str, token: &str) -> Result<Oid, CustomError> {
+    let resolved = repo.revparse(rev).and_then(|revspec| {
+        if !revspec.mode().contains(RevparseMode::SINGLE) {
+            return Err(git2::Error::from_str("not a single revision"));
+        }
+        revspec
+            .from()
+            .expect("single revspecs have a from object")
+            .peel_to_commit()
+    });
+    resolved
+        .map(|commit| commit.id())
+        .map_err(|e| match e.code() {
+            ErrorCode::NotFound | ErrorCode::Ambiguous => {
+                CustomError::RefNotFound(format!("'{}' in range '{}'", rev, token))
+            }
+            _ => CustomError::InvalidRequest(format!("invalid range '{}': {}", token, e.message())),
+        })
+}
+
+fn hex_ids<S: Serializer>(ids: &[Oid], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(ids.iter().map(Oid::to_string))
+}
@@ -0,0 +1,267 @@
+use crate::enrich::{Issue, PullRequest};
+use crate::options::IndexOptions;
+use crate::{CommitDiff, CommitHistory, CustomError};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use regex::{Captures, Regex};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+/// Hex digits of the salted hash kept in a token.
+const TOKEN_LENGTH: usize = 12;
+
+/// Domain of redacted emails, reserved so a token can never reach anyone.
+const EMAIL_DOMAIN: &str = "redacted.invalid";
+
+/// Any `local@host`: commits made on unconfigured machines carry addresses such as
+/// `root@localhost` or `dev@buildhost`, without a dotted domain.
+const EMAIL_PATTERN: &str = r"\b[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\b";
+
+/// The tokens handed out so far and what they stand for, with the file they go to.
+/// Only kept when `--redaction-map` was given, and shared by every `Redactor` of the
+/// run, so one map covers all the repositories and subcommands that produced output.
+static ISSUED: Mutex<Option<(PathBuf, BTreeMap<String, String>)>> = Mutex::new(None);
+
+/// What `--redact` hides.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RedactMode {
+    /// Emails, wherever they appear
+    Emails,
+    /// Names of authors, committers, taggers and trailer identities
+    Authors,
+    Both,
+}
+
+impl RedactMode {
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        match raw {
+            "emails" => Ok(RedactMode::Emails),
+            "authors" => Ok(RedactMode::Authors),
+            "both" => Ok(RedactMode::Both),
+            _ => Err(format!("expected emails, authors or both, got '{}'", raw)),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RedactMode::Emails => "emails",
+            RedactMode::Authors => "authors",
+            RedactMode::Both => "both",
+        }
+    }
+}
+
+/// Replaces contributor identities with tokens: an email with
+/// `<hash>@redacted.invalid` and a name with `Contributor-<hash>`, the hash being an
+/// HMAC-SHA256 of the lowercased value keyed with `--redact-salt`. The same identity
+/// gets the same token everywhere, and in every run with the same salt. Without
+/// `--redact` every method leaves its input alone.
+pub struct Redactor {
+    mode: Option<RedactMode>,
+    salt: String,
+    record: bool,
+}
+
+impl Redactor {
+    pub fn new(options: &IndexOptions) -> Self {
+        if let Some(path) = &options.redaction_map {
+            ISSUED
+                .lock()
+                .unwrap()
+                .get_or_insert_with(|| (path.clone(), BTreeMap::new()));
+        }
+        Redactor {
+            mode: options.redact,
+            salt: options
+                .redact_salt
+                .clone()
+                .unwrap_or_else(|| random_salt().to_string()),
+            record: options.redaction_map.is_some(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.mode.is_some()
+    }
+
+    fn redacts_emails(&self) -> bool {
+        matches!(self.mode, Some(RedactMode::Emails | RedactMode::Both))
+    }
+
+    fn redacts_names(&self) -> bool {
+        matches!(self.mode, Some(RedactMode::Authors | RedactMode::Both))
+    }
+
+    /// Replaces `email`, a field holding nothing but an email.
+    pub fn email(&self, email: &mut String) {
+        if self.redacts_emails() && !email.trim().is_empty() {
+            *email = self.email_token(email);
+        }
+    }
+
+    /// Replaces `name`, a field holding nothing but a person's name or login.
+    pub fn name(&self, name: &mut String) {
+        if self.redacts_names() && !name.trim().is_empty() {
+            *name = self.issue_token(format!("Contributor-{}", self.hash(name)), name);
+        }
+    }
+
+    /// Replaces the identities of trailers such as `Co-authored-by: Name <email>` and
+    /// anything shaped like an email address in free text: messages, diffs, file
+    /// contents. Names mentioned in running text can't be told apart and stay.
+    pub fn text(&self, text: &mut String) {
+        let trailer = trailer();
+        if self.redacts_names() && trailer.is_match(text) {
+            *text = trailer
+                .replace_all(text, |caps: &Captures| {
+                    let mut name = caps[2].to_string();
+                    self.name(&mut name);
+                    format!("{}{}{}", &caps[1], name, &caps[3])
+                })
+                .into_owned();
+        }
+        let email = email();
+        if self.redacts_emails() && email.is_match(text) {
+            *text = email
+                .replace_all(text, |caps: &Captures| self.email_token(&caps[0]))
+                .into_owned();
+        }
+    }
+
+    /// Redacts everything in `commit` that names or quotes a contributor.
+    pub fn commit(&self, commit: &mut CommitHistory) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.name(&mut commit.author);
+        self.text(&mut commit.commit_message);
+        for file in &mut commit.git_diff {
+            self.diff(file);
+        }
+        if let Some(pull_request) = &mut commit.pull_request {
+            self.pull_request(pull_request);
+        }
+        if let Some(issue) = &mut commit.issue {
+            self.issue(issue);
+        }
+    }
+
+    pub fn diff(&self, file: &mut CommitDiff) {
+        self.text(&mut file.diff);
+        if let Some(word_diff) = &mut file.word_diff {
+            self.text(word_diff);
+        }
+        for subject in file.submodule_commits.iter_mut().flatten() {
+            self.text(subject);
+        }
+    }
+
+    /// Replaces the emails in base64-encoded content, such as a binary file's.
+    pub fn base64(&self, content: &mut String) {
+        if !self.redacts_emails() {
+            return;
+        }
+        let Ok(bytes) = BASE64.decode(content.as_bytes()) else {
+            return;
+        };
+        static EMAIL: OnceLock<regex::bytes::Regex> = OnceLock::new();
+        let email = EMAIL.get_or_init(|| regex::bytes::Regex::new(EMAIL_PATTERN).unwrap());
+        if email.is_match(&bytes) {
+            let redacted = email.replace_all(&bytes, |caps: &regex::bytes::Captures| {
+                self.email_token(&String::from_utf8_lossy(&caps[0]))
+                    .into_bytes()
+            });
+            *content = BASE64.encode(redacted);
+        }
+    }
+
+    pub fn pull_request(&self, pull_request: &mut PullRequest) {
+        if let Some(author) = &mut pull_request.author {
+            self.name(author);
+        }
+        self.text(&mut pull_request.title);
+        if let Some(body) = &mut pull_request.body {
+            self.text(body);
+        }
+    }
+
+    pub fn issue(&self, issue: &mut Issue) {
+        if let Some(author) = &mut issue.author {
+            self.name(author);
+        }
+        self.text(&mut issue.title);
+        if let Some(body) = &mut issue.body {
+            self.text(body);
+        }
+    }
+
+    fn email_token(&self, email: &str) -> String {
+        self.issue_token(format!("{}@{}", self.hash(email), EMAIL_DOMAIN), email)
+    }
+
+    /// Notes `token` for the redaction map, keeping the first value seen for it.
+    fn issue_token(&self, token: String, value: &str) -> String {
+        if self.record {
+            if let Some((_, issued)) = ISSUED.lock().unwrap().as_mut() {
+                issued
+                    .entry(token.clone())
+                    .or_insert_with(|| value.trim().to_string());
+            }
+        }
+        token
+    }
+
+    /// The salted hash of `value`, ignoring case and surrounding whitespace.
+    fn hash(&self, value: &str) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(self.salt.as_bytes()).expect("HMAC takes any key size");
+        mac.update(value.trim().to_lowercase().as_bytes());
+        mac.finalize().into_bytes()[..TOKEN_LENGTH / 2]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+/// Writes the tokens handed out by this run and the identities they stand for to the
+/// `--redaction-map` file, as a JSON object. Does nothing without one.
+pub fn save_map() -> Result<(), CustomError> {
+    let guard = ISSUED.lock().unwrap();
+    let Some((path, issued)) = guard.as_ref() else {
+        return Ok(());
+    };
+    std::fs::write(path, serde_json::to_string_pretty(issued)? + "\n")?;
+    Ok(())
+}
+
+fn email() -> &'static Regex {
+    static EMAIL: OnceLock<Regex> = OnceLock::new();
+    EMAIL.get_or_init(|| Regex::new(EMAIL_PATTERN).unwrap())
+}
+
+/// `Signed-off-by: Name <email>` and the like, whose name is the second group.
+fn trailer() -> &'static Regex {
+    static TRAILER: OnceLock<Regex> = OnceLock::new();
+    TRAILER.get_or_init(|| {
+        Regex::new(
+            r"(?mi)^((?:[a-z-]+-by|cc|author|committer):[ \t]*)([^<>\n]*[^<>\s])([ \t]*<[^<>\n]*>)",
+        )
+        .unwrap()
+    })
+}
+
+/// Salt for runs without `--redact-salt`: random, so the tokens can't be linked to
+/// those of other runs, but the same for the whole process.
+fn random_salt() -> &'static str {
+    static SALT: OnceLock<String> = OnceLock::new();
+    SALT.get_or_init(|| {
+        use std::collections::hash_map::RandomState;
+        use std::hash::{BuildHasher, Hasher};
+        (0..4)
+            .map(|_| format!("{:016x}", RandomState::new().build_hasher().finish()))
+            .collect()
+    })
+}
@@ -0,0 +1,163 @@
+use crate::redact::Redactor;
+use crate::CustomError;
+use git2::{BranchType, ObjectType, Repository};
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Serialize)]
+pub struct Refs {
+    branches: Vec<Branch>,
+    tags: Vec<Tag>,
+}
+
+#[derive(Serialize)]
+pub struct Branch {
+    name: String,
+    target_sha: String,
+    is_head: bool,
+}
+
+#[derive(Serialize)]
+pub struct Tag {
+    name: String,
+    /// The commit the tag points at, with annotated tags peeled through the tag object
+    target_sha: String,
+    annotated: bool,
+    tagger: Option<Tagger>,
+    message: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Tagger {
+    name: String,
+    email: String,
+}
+
+impl Refs {
+    /// Redacts the taggers and tag messages; branch and tag names are left as they are.
+    pub fn redact(&mut self, redactor: &Redactor) {
+        for tag in &mut self.tags {
+            if let Some(tagger) = &mut tag.tagger {
+                redactor.name(&mut tagger.name);
+                redactor.email(&mut tagger.email);
+            }
+            if let Some(message) = &mut tag.message {
+                redactor.text(message);
+            }
+        }
+    }
+}
+
+/// Local branches and all tags, each sorted by name.
+pub fn list_refs(repo_path: &Path) -> Result<Refs, CustomError> {
+    let repo = Repository::open(repo_path)?;
+
+    let mut branches = Vec::new();
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        let Some(name) = branch.name()? else {
+            continue;
+        };
+        branches.push(Branch {
+            name: name.to_string(),
+            target_sha: branch.get().peel(ObjectType::Commit)?.id().to_string(),
+            is_head: branch.is_head(),
+        });
+    }
+    branches.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut tags = Vec::new();
+    for name in repo.tag_names(None)?.iter().flatten() {
+        let reference = repo.find_reference(&format!("refs/tags/{}", name))?;
+        // Tags may point at trees or blobs, in which case there is no commit to peel to
+        let target = reference
+            .peel(ObjectType::Commit)
+            .or_else(|_| reference.peel(ObjectType::Any))?;
+        let tag_object = reference.peel(ObjectType::Tag).ok();
+        let annotated = tag_object.as_ref().and_then(|object| object.as_tag());
+
+        tags.push(Tag {
+            name: name.to_string(),
+            target_sha: target.id().to_string(),
+            annotated: annotated.is_some(),
+            tagger: annotated.and_then(|tag| tag.tagger()).map(|tagger| Tagger {
+                name: String::from_utf8_lossy(tagger.name_bytes()).to_string(),
+                email: String::from_utf8_lossy(tagger.email_bytes()).to_string(),
+            }),
+            message: annotated.and_then(|tag| tag.message()).map(String::from),
+        });
+    }
+    tags.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(Refs { branches, tags })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_repo::TestRepo;
+
+    /// Branches and both kinds of tag, created out of name order.
+    fn tagged() -> (TestRepo, String, String) {
+        let repo = TestRepo::new();
+        let first = repo.write("a.txt", "a\n").commit("First");
+        repo.git(["tag", "-a", "v10", "-m", "Release 10\n\nNotes."]);
+        let second = repo.write("a.txt", "b\n").commit("Second");
+        repo.git(["tag", "v2"]);
+        repo.git(["tag", "-a", "a-tag", "-m", "Early", &first]);
+        repo.git(["branch", "zeta", &first]);
+        repo.git(["branch", "alpha"]);
+        (repo, first, second)
+    }
+
+    #[test]
+    fn lists_branches_sorted_by_name() {
+        let (repo, first, second) = tagged();
+        let refs = list_refs(repo.path()).unwrap();
+        let branches: Vec<_> = refs
+            .branches
+            .iter()
+            .map(|b| (b.name.as_str(), b.target_sha.as_str(), b.is_head))
+            .collect();
+        assert_eq!(
+            branches,
+            [
+                ("alpha", second.as_str(), false),
+                ("main", second.as_str(), true),
+                ("zeta", first.as_str(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn lists_both_kinds_of_tag_sorted_by_name() {
+        let (repo, first, second) = tagged();
+        let refs = list_refs(repo.path()).unwrap();
+        let names: Vec<_> = refs.tags.iter().map(|tag| tag.name.as_str()).collect();
+        assert_eq!(names, ["a-tag", "v10", "v2"]);
+
+        // Annotated tags are peeled to their commit, not the tag object
+        let v10 = &refs.tags[1];
+        assert!(v10.annotated);
+        assert_eq!(v10.target_sha, first);
+        assert_eq!(v10.message.as_deref(), Some("Release 10\n\nNotes.\n"));
+        let tagger = v10.tagger.as_ref().unwrap();
+        assert_eq!(
+            (tagger.name.as_str(), tagger.email.as_str()),
+            ("Jane Doe", "jane@example.com")
+        );
+
+        let v2 = &refs.tags[2];
+        assert!(!v2.annotated);
+        assert_eq!(v2.target_sha, second);
+        assert!(v2.tagger.is_none() && v2.message.is_none());
+    }
+
+    #[test]
+    fn output_is_stable() {
+        let (repo, _, _) = tagged();
+        let first = serde_json::to_string(&list_refs(repo.path()).unwrap()).unwrap();
+        let second = serde_json::to_string(&list_refs(repo.path()).unwrap()).unwrap();
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,104 @@
+use crate::options::IndexOptions;
+use crate::redact::Redactor;
+use crate::{
+    commit_message, commit_summary, commit_tree_diff, diff_entries, history_revwalk, CommitHistory,
+    CustomError,
+};
+use git2::Repository;
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Clone, Debug, Default)]
+pub struct SearchOptions {
+    /// Treat the query as a regular expression instead of a plain substring
+    pub regex: bool,
+    pub include_diffs: bool,
+    pub max_results: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResults {
+    query: String,
+    /// Commits examined; equals the size of the history unless `truncated` is set
+    commits_scanned: usize,
+    /// Whether the walk stopped early because `max_results` was reached
+    truncated: bool,
+    matches: Vec<CommitHistory>,
+}
+
+enum Matcher {
+    Substring(String),
+    Pattern(Regex),
+}
+
+impl Matcher {
+    fn is_match(&self, message: &str) -> bool {
+        match self {
+            Matcher::Substring(needle) => message.to_lowercase().contains(needle),
+            Matcher::Pattern(regex) => regex.is_match(message),
+        }
+    }
+}
+
+/// Commits whose message matches `query` case-insensitively, newest first.
+pub fn search(
+    repo_path: &Path,
+    query: &str,
+    options: &IndexOptions,
+    search: &SearchOptions,
+) -> Result<SearchResults, CustomError> {
+    let matcher = if search.regex {
+        let regex = RegexBuilder::new(query)
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| {
+                CustomError::InvalidRequest(format!("invalid regex '{}': {}", query, e))
+            })?;
+        Matcher::Pattern(regex)
+    } else {
+        Matcher::Substring(query.to_lowercase())
+    };
+
+    let repo = Repository::open(repo_path)?;
+    let redactor = Redactor::new(options);
+    let mut commits_scanned = 0;
+    let mut matches = Vec::new();
+    let mut truncated = false;
+
+    for commit_id in history_revwalk(&repo, options)? {
+        if search.max_results.is_some_and(|max| matches.len() >= max) {
+            truncated = true;
+            break;
+        }
+        let commit = repo.find_commit(commit_id?)?;
+        commits_scanned += 1;
+
+        // Cheap checks first: the diff is only computed when it is actually needed
+        if !options.accepts(&commit) || !matcher.is_match(&commit_message(&commit).text) {
+            continue;
+        }
+        let mut entry = commit_summary(&repo, &commit, options.sha_length)?;
+        if search.include_diffs || !options.paths.is_empty() {
+            let diff = commit_tree_diff(&repo, &commit, &mut options.diff_options())?;
+            if !options.paths.is_empty() && diff.deltas().len() == 0 {
+                continue;
+            }
+            if search.include_diffs {
+                entry.git_diff = diff_entries(&diff, options.diff_format)?;
+            }
+        }
+        redactor.commit(&mut entry);
+        matches.push(entry);
+    }
+
+    // The query may well be the email being looked for
+    let mut query = query.to_string();
+    redactor.text(&mut query);
+    Ok(SearchResults {
+        query,
+        commits_scanned,
+        truncated,
+        matches,
+    })
+}
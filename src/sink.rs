@@ -0,0 +1,183 @@
+use crate::envelope::Meta;
+use crate::options::IndexOptions;
+use crate::range::RevRange;
+use crate::{CommitHistory, CustomError};
+use serde::Serialize;
+use std::io::{self, Write};
+use tokio::sync::mpsc;
+
+/// What an index run is about to walk, given to `CommitSink::on_start`.
+#[derive(Clone, Debug, Serialize)]
+pub struct RunStart {
+    /// The ref the history is walked from, `HEAD` when detached, null for `--range`
+    pub git_ref: Option<String>,
+    /// The commit that ref points at; null for an empty repository
+    pub tip: Option<String>,
+    /// What `--range` resolved to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<RevRange>,
+    pub empty_repository: bool,
+    pub shallow: bool,
+}
+
+/// How an index run ended, given to `CommitSink::on_finish`.
+#[derive(Clone, Debug, Serialize)]
+pub struct RunEnd {
+    pub commit_count: usize,
+    /// Where the rest of a history cut short by `max_output_bytes` starts
+    pub next_cursor: Option<String>,
+    /// Size of the commits as pretty-printed JSON
+    pub output_bytes: u64,
+}
+
+/// Receives the commits of an index run as they are produced, newest first, so they
+/// can be written out without holding the whole history in memory.
+///
+/// Commits arrive before anything that needs the whole history: `duplicate_of` is
+/// only set by `VecSink` users, which mark duplicates afterwards, and `--enrich` is
+/// applied to collected histories only. An error from any method stops the walk and
+/// is returned by the indexing function.
+pub trait CommitSink {
+    fn on_start(&mut self, _run: &RunStart) -> Result<(), CustomError> {
+        Ok(())
+    }
+
+    fn on_commit(&mut self, commit: &CommitHistory) -> Result<(), CustomError>;
+
+    /// Called once the walk is done, not when it failed.
+    fn on_finish(&mut self, _run: &RunEnd) -> Result<(), CustomError> {
+        Ok(())
+    }
+}
+
+/// Collects the history in memory, for callers that post-process it as a whole.
+#[derive(Default)]
+pub struct VecSink {
+    pub commits: Vec<CommitHistory>,
+}
+
+impl CommitSink for VecSink {
+    fn on_commit(&mut self, commit: &CommitHistory) -> Result<(), CustomError> {
+        self.commits.push(commit.clone());
+        Ok(())
+    }
+}
+
+/// Writes the commits as a pretty-printed JSON array, byte for byte what
+/// `serde_json::to_writer_pretty` makes of the whole history, once `finish` closes it.
+pub struct JsonSink<W: Write> {
+    writer: W,
+    count: usize,
+}
+
+impl<W: Write> JsonSink<W> {
+    pub fn new(writer: W) -> Self {
+        JsonSink { writer, count: 0 }
+    }
+
+    /// Closes the array and returns the writer.
+    pub fn finish(mut self) -> Result<W, CustomError> {
+        let end: &[u8] = if self.count == 0 { b"[]" } else { b"\n]" };
+        self.writer.write_all(end)?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> CommitSink for JsonSink<W> {
+    fn on_commit(&mut self, commit: &CommitHistory) -> Result<(), CustomError> {
+        let mut out = String::from(if self.count == 0 { "[\n  " } else { ",\n  " });
+        // Strings escape their line breaks, so indenting every line nests the object
+        out.push_str(&serde_json::to_string_pretty(commit)?.replace('\n', "\n  "));
+        self.writer.write_all(out.as_bytes())?;
+        self.count += 1;
+        Ok(())
+    }
+}
+
+/// Writes one compact JSON commit per line, after a `{"meta": {...}}` line with
+/// `--envelope`.
+pub struct NdjsonSink<W: Write> {
+    writer: W,
+    /// The repository and options the meta line describes, with `--envelope`
+    meta: Option<(String, IndexOptions)>,
+}
+
+impl<W: Write> NdjsonSink<W> {
+    pub fn new(writer: W) -> Self {
+        NdjsonSink { writer, meta: None }
+    }
+
+    /// Starts the output with the envelope's `meta` for `repo`, as far as it is known
+    /// before the first commit: without `commit_count`, `truncated` and `next_cursor`.
+    pub fn with_meta(writer: W, repo: &str, options: &IndexOptions) -> Self {
+        NdjsonSink {
+            writer,
+            meta: Some((repo.to_string(), options.clone())),
+        }
+    }
+}
+
+impl<W: Write> CommitSink for NdjsonSink<W> {
+    fn on_start(&mut self, run: &RunStart) -> Result<(), CustomError> {
+        if let Some((repo, options)) = &self.meta {
+            let meta = Meta::at_start(repo, run, options);
+            serde_json::to_writer(&mut self.writer, &serde_json::json!({ "meta": meta }))?;
+            self.writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    fn on_commit(&mut self, commit: &CommitHistory) -> Result<(), CustomError> {
+        serde_json::to_writer(&mut self.writer, commit)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn on_finish(&mut self, _run: &RunEnd) -> Result<(), CustomError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// One step of an index run, as `ChannelSink` passes it on.
+pub enum Event {
+    Start(RunStart),
+    Commit(Box<CommitHistory>),
+    Finish(RunEnd),
+}
+
+/// Feeds a tokio channel from the blocking indexing thread, for async consumers such
+/// as a streamed HTTP response. A full channel makes indexing wait, and a dropped
+/// receiver stops it.
+pub struct ChannelSink {
+    sender: mpsc::Sender<Event>,
+}
+
+impl ChannelSink {
+    pub fn new(sender: mpsc::Sender<Event>) -> Self {
+        ChannelSink { sender }
+    }
+
+    fn send(&self, event: Event) -> Result<(), CustomError> {
+        self.sender.blocking_send(event).map_err(|_| {
+            CustomError::IoError(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the receiver of the commits went away",
+            ))
+        })
+    }
+}
+
+impl CommitSink for ChannelSink {
+    fn on_start(&mut self, run: &RunStart) -> Result<(), CustomError> {
+        self.send(Event::Start(run.clone()))
+    }
+
+    fn on_commit(&mut self, commit: &CommitHistory) -> Result<(), CustomError> {
+        self.send(Event::Commit(Box::new(commit.clone())))
+    }
+
+    fn on_finish(&mut self, run: &RunEnd) -> Result<(), CustomError> {
+        self.send(Event::Finish(run.clone()))
+    }
+}
@@ -0,0 +1,136 @@
+use std::cell::Cell;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+/// Commit time of the first fixture commit; every further commit is a minute later,
+/// so fixtures (and their commit ids) are the same in every run.
+const FIRST_COMMIT_TIME: i64 = 1_700_000_000;
+
+/// A throwaway git repository for the tests, built with the git command line so the
+/// fixtures are exactly what users' repositories look like.
+pub struct TestRepo {
+    dir: TempDir,
+    clock: Cell<i64>,
+}
+
+impl TestRepo {
+    /// An empty repository whose unborn branch is `main`.
+    pub fn new() -> Self {
+        let repo = TestRepo {
+            dir: TempDir::new().unwrap(),
+            clock: Cell::new(FIRST_COMMIT_TIME),
+        };
+        repo.git(["init", "-q", "-b", "main"]);
+        repo
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn open(&self) -> git2::Repository {
+        git2::Repository::open(self.path()).unwrap()
+    }
+
+    /// Writes `contents` to `path`, creating its parent directories.
+    pub fn write(&self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> &Self {
+        let path = self.path().join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+        self
+    }
+
+    pub fn remove(&self, path: impl AsRef<Path>) -> &Self {
+        std::fs::remove_file(self.path().join(path)).unwrap();
+        self
+    }
+
+    /// Moves `from` to `to`, creating the parent directories of `to`.
+    pub fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> &Self {
+        let to = self.path().join(to);
+        std::fs::create_dir_all(to.parent().unwrap()).unwrap();
+        std::fs::rename(self.path().join(from), to).unwrap();
+        self
+    }
+
+    /// Commits everything in the working tree as Jane Doe and returns the commit id.
+    pub fn commit(&self, message: &str) -> String {
+        self.commit_as("Jane Doe", "jane@example.com", message)
+    }
+
+    pub fn commit_as(&self, name: &str, email: &str, message: &str) -> String {
+        self.git(["add", "-A"]);
+        self.git_as(
+            name,
+            email,
+            ["commit", "-q", "--allow-empty", "-m", message],
+        );
+        self.head()
+    }
+
+    pub fn head(&self) -> String {
+        self.git(["rev-parse", "HEAD"]).trim().to_string()
+    }
+
+    /// Runs git in the repository as Jane Doe and returns its stdout, panicking with
+    /// its stderr if it fails.
+    pub fn git<I, S>(&self, args: I) -> String
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.git_as("Jane Doe", "jane@example.com", args)
+    }
+
+    pub fn git_as<I, S>(&self, name: &str, email: &str, args: I) -> String
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let output = self.command(name, email).args(args).output().unwrap();
+        assert!(
+            output.status.success(),
+            "git failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+
+    /// Git in the repository with a fixed identity, date and configuration, for
+    /// commands whose output or exit status the test looks at itself.
+    pub fn command(&self, name: &str, email: &str) -> Command {
+        let time = self.clock.get();
+        self.clock.set(time + 60);
+        let date = format!("{} +0000", time);
+        let mut command = Command::new("git");
+        command
+            .current_dir(self.path())
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("GIT_CONFIG_GLOBAL", "/dev/null")
+            .env("GIT_AUTHOR_NAME", name)
+            .env("GIT_AUTHOR_EMAIL", email)
+            .env("GIT_AUTHOR_DATE", &date)
+            .env("GIT_COMMITTER_NAME", name)
+            .env("GIT_COMMITTER_EMAIL", email)
+            .env("GIT_COMMITTER_DATE", &date);
+        command
+    }
+
+    /// A `--git-config` entry that makes the server's clones of `https://fixture/<name>`
+    /// clone this repository instead.
+    pub fn clone_config(&self) -> String {
+        format!(
+            "url.{}.insteadOf=https://{}",
+            self.path().display(),
+            self.url()
+        )
+    }
+
+    /// The `repo_url` the server clones this repository from, see `clone_config`.
+    pub fn url(&self) -> String {
+        let name = self.path().file_name().unwrap().to_string_lossy();
+        format!("fixture/{}", name)
+    }
+}
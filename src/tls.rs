@@ -0,0 +1,110 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// TLS acceptor built from a PEM certificate chain and private key. The pair can be
+/// re-read at runtime so certificates rotate without a restart.
+pub struct TlsSettings {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+impl TlsSettings {
+    /// Loads and validates the certificate and key, so a bad pair fails at startup
+    /// rather than on the first connection.
+    pub fn load(cert_path: &Path, key_path: &Path) -> io::Result<Self> {
+        let acceptor = build_acceptor(cert_path, key_path)?;
+        Ok(TlsSettings {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            acceptor: RwLock::new(acceptor),
+        })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    /// Swaps in a freshly loaded certificate; the old one stays active on failure.
+    pub fn reload(&self) -> io::Result<()> {
+        let acceptor = build_acceptor(&self.cert_path, &self.key_path)?;
+        *self.acceptor.write().unwrap() = acceptor;
+        Ok(())
+    }
+}
+
+fn build_acceptor(cert_path: &Path, key_path: &Path) -> io::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(cert_path, &format!("unreadable certificate PEM: {}", e)))?;
+    if certs.is_empty() {
+        return Err(invalid(cert_path, "no certificates found"));
+    }
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(open(key_path)?))
+        .map_err(|e| invalid(key_path, &format!("unreadable private key PEM: {}", e)))?
+        .ok_or_else(|| invalid(key_path, "no private key found"))?;
+
+    // with_single_cert also checks that the key belongs to the certificate
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "TLS certificate {} and key {} cannot be used together: {}",
+                    cert_path.display(),
+                    key_path.display(),
+                    e
+                ),
+            )
+        })?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    File::open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn invalid(path: &Path, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), reason),
+    )
+}
+
+/// Reloads the certificate and key whenever the process receives SIGHUP.
+#[cfg(unix)]
+pub fn reload_on_sighup(tls: Arc<TlsSettings>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match tls.reload() {
+                Ok(()) => println!("Reloaded TLS certificate"),
+                Err(e) => eprintln!(
+                    "Failed to reload TLS certificate, keeping previous one: {}",
+                    e
+                ),
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn reload_on_sighup(_tls: Arc<TlsSettings>) {}
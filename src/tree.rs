@@ -0,0 +1,96 @@
+use crate::{resolve_commit, CustomError};
+use git2::{ObjectType, Odb, Repository, TreeWalkMode, TreeWalkResult};
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Serialize)]
+pub struct TreeEntry {
+    name: String,
+    path: String,
+    kind: &'static str,
+    /// Git file mode in octal, e.g. `100644`
+    mode: String,
+    size: Option<usize>,
+}
+
+/// Entries of the tree at `rev`, optionally below `subdir`, in git's tree order.
+/// Recursive listings are pre-order: a directory comes right before its contents.
+pub fn list_tree(
+    repo_path: &Path,
+    rev: &str,
+    subdir: Option<&str>,
+    recursive: bool,
+) -> Result<Vec<TreeEntry>, CustomError> {
+    let repo = Repository::open(repo_path)?;
+    let root = resolve_commit(&repo, rev)?.tree()?;
+
+    let (tree, prefix) = match subdir
+        .map(|dir| dir.trim_matches('/'))
+        .filter(|d| !d.is_empty())
+    {
+        Some(dir) => {
+            let entry = root.get_path(Path::new(dir)).map_err(|_| {
+                CustomError::PathNotFound(format!("{} does not exist at {}", dir, rev))
+            })?;
+            if entry.kind() != Some(ObjectType::Tree) {
+                return Err(CustomError::PathNotFound(format!(
+                    "{} is not a directory at {}",
+                    dir, rev
+                )));
+            }
+            (repo.find_tree(entry.id())?, format!("{}/", dir))
+        }
+        None => (root, String::new()),
+    };
+
+    let odb = repo.odb()?;
+    let mut entries = Vec::new();
+    if recursive {
+        let mut failure = None;
+        let walked = tree.walk(TreeWalkMode::PreOrder, |parent, entry| {
+            match tree_entry(&odb, &format!("{}{}", prefix, parent), entry) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => {
+                    failure = Some(e);
+                    return TreeWalkResult::Abort;
+                }
+            }
+            TreeWalkResult::Ok
+        });
+        // An aborted walk reports a generic error; the callback's own error is the real one
+        if let Some(e) = failure {
+            return Err(e.into());
+        }
+        walked?;
+    } else {
+        for entry in tree.iter() {
+            entries.push(tree_entry(&odb, &prefix, &entry)?);
+        }
+    }
+
+    Ok(entries)
+}
+
+fn tree_entry(odb: &Odb, dir: &str, entry: &git2::TreeEntry) -> Result<TreeEntry, git2::Error> {
+    let name = String::from_utf8_lossy(entry.name_bytes()).to_string();
+    let mode = entry.filemode();
+    let kind = match mode & 0o170000 {
+        0o040000 => "tree",
+        0o160000 => "submodule",
+        0o120000 => "symlink",
+        _ => "blob",
+    };
+    // Reading just the object header avoids loading file contents
+    let size = match kind {
+        "blob" => Some(odb.read_header(entry.id())?.0),
+        _ => None,
+    };
+
+    Ok(TreeEntry {
+        path: format!("{}{}", dir, name),
+        name,
+        kind,
+        mode: format!("{:06o}", mode),
+        size,
+    })
+}
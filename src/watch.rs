@@ -0,0 +1,182 @@
+use crate::options::IndexOptions;
+use crate::sink::{CommitSink, JsonSink};
+use crate::{enrich, head_is_unborn, index_repo, patch_id, CommitHistory, CustomError};
+use git2::{Oid, Repository};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+/// Default for `--interval`.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Indexes `repo_path` into `output`, then checks HEAD every `interval` and updates the
+/// file whenever it moves, until Ctrl-C. Commits HEAD gained are indexed on their own
+/// and added in front; a HEAD that no longer contains the previous tip (force-push,
+/// rebase, reset) triggers a full rebuild. The file is replaced atomically, so it is
+/// always complete JSON.
+pub async fn watch(
+    repo_path: &Path,
+    output: &Path,
+    interval: Duration,
+    options: &IndexOptions,
+) -> Result<(), CustomError> {
+    if options.all || options.rev.is_some() || options.range.is_some() {
+        return Err(CustomError::InvalidRequest(
+            "watch follows HEAD and can't be combined with --all, --rev or --range".to_string(),
+        ));
+    }
+    if options.envelope {
+        return Err(CustomError::InvalidRequest(
+            "watch writes a bare array of commits and can't be combined with --envelope"
+                .to_string(),
+        ));
+    }
+
+    let mut tip = head_tip(repo_path)?;
+    let mut history = index(repo_path, options).await?;
+    write_history(output, &history)?;
+    println!(
+        "Indexed {} commits into {}, watching for changes (Ctrl-C to stop)",
+        history.len(),
+        output.display()
+    );
+
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        tokio::select! {
+            _ = &mut ctrl_c => break,
+            _ = tokio::time::sleep(interval) => {}
+        }
+
+        let new_tip = match head_tip(repo_path) {
+            Ok(new_tip) if new_tip == tip => continue,
+            Ok(new_tip) => new_tip,
+            Err(e) => {
+                eprintln!("Warning: could not read HEAD: {}", e);
+                continue;
+            }
+        };
+        match update(repo_path, options, tip, new_tip, &mut history).await {
+            Ok(added) => {
+                write_history(output, &history)?;
+                println!("HEAD moved to {}, {}", describe(new_tip), added);
+                tip = new_tip;
+            }
+            // Leave the file as it was and try again on the next change
+            Err(e) => eprintln!("Warning: failed to re-index: {}", e),
+        }
+    }
+
+    println!("Stopped watching, {} is complete", output.display());
+    Ok(())
+}
+
+/// Brings `history` from `old_tip` to `new_tip`, and says what changed.
+async fn update(
+    repo_path: &Path,
+    options: &IndexOptions,
+    old_tip: Option<Oid>,
+    new_tip: Option<Oid>,
+    history: &mut Vec<CommitHistory>,
+) -> Result<String, CustomError> {
+    let repo = Repository::open(repo_path)?;
+    let fast_forward = match (old_tip, new_tip) {
+        (Some(old), Some(new)) => repo.graph_descendant_of(new, old).unwrap_or(false),
+        (None, Some(_)) => true,
+        (_, None) => false,
+    };
+
+    if !fast_forward {
+        eprintln!(
+            "Warning: HEAD no longer contains {} (force-push or rebase?), rebuilding the whole history",
+            describe(old_tip)
+        );
+        *history = index(repo_path, options).await?;
+        return Ok(format!("rebuilt {} commits", history.len()));
+    }
+
+    let mut incremental = options.clone();
+    incremental.after_commit = old_tip;
+    let mut added = index(repo_path, &incremental).await?;
+    let count = added.len();
+    added.append(history);
+    *history = added;
+    // A new commit may repeat the change of an older one
+    patch_id::mark_duplicates(&repo, history);
+    Ok(format!("added {} commits", count))
+}
+
+async fn index(
+    repo_path: &Path,
+    options: &IndexOptions,
+) -> Result<Vec<CommitHistory>, CustomError> {
+    let mut indexed = index_repo(repo_path, options)?;
+    enrich::enrich(&mut indexed.commits, indexed.remote_url.as_deref(), options).await;
+    Ok(indexed.commits)
+}
+
+/// The commit HEAD points at, or `None` before the first commit.
+fn head_tip(repo_path: &Path) -> Result<Option<Oid>, CustomError> {
+    let repo = Repository::open(repo_path)?;
+    if head_is_unborn(&repo) {
+        return Ok(None);
+    }
+    let tip = repo.head()?.peel_to_commit()?.id();
+    Ok(Some(tip))
+}
+
+fn describe(tip: Option<Oid>) -> String {
+    tip.map_or("an unborn branch".to_string(), |oid| {
+        oid.to_string()[..7].to_string()
+    })
+}
+
+/// Writes to a temporary file next to `output` and renames it into place.
+fn write_history(output: &Path, history: &[CommitHistory]) -> Result<(), CustomError> {
+    let dir = output
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut json = JsonSink::new(NamedTempFile::new_in(dir)?);
+    for commit in history {
+        json.on_commit(commit)?;
+    }
+    let mut file = json.finish()?;
+    file.flush()?;
+    file.persist(output)
+        .map_err(|e| CustomError::IoError(e.error))?;
+    // Watching only ends when the process is killed, so the map follows every write
+    crate::redact::save_map()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_repo::TestRepo;
+
+    #[tokio::test]
+    async fn options_that_change_the_file_shape_are_refused() {
+        let repo = TestRepo::new();
+        repo.write("README.md", "hello\n").commit("Add a readme");
+        let output = repo.path().join("history.json");
+
+        for options in [
+            IndexOptions {
+                envelope: true,
+                ..IndexOptions::default()
+            },
+            IndexOptions {
+                range: Some("HEAD~1..HEAD".to_string()),
+                ..IndexOptions::default()
+            },
+        ] {
+            let err = watch(repo.path(), &output, DEFAULT_INTERVAL, &options)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, CustomError::InvalidRequest(_)), "{}", err);
+        }
+        assert!(!output.exists());
+    }
+}
@@ -0,0 +1,278 @@
+use crate::conditional::Tip;
+use hmac::{Hmac, Mac};
+use hyper::HeaderMap;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a re-indexed history is served, in case the push that should have
+/// replaced it never arrived.
+const CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Largest push payload read, GitHub's own limit. Bodies are read before their
+/// signature can be checked, so this is all an unauthenticated sender can make the
+/// server hold.
+pub const MAX_PAYLOAD_BYTES: usize = 25 * 1024 * 1024;
+
+/// Push notifications that keep the history of their repositories indexed ahead of
+/// time. Disabled unless the server has a `--webhook-secret`.
+pub struct Webhooks {
+    secret: Option<String>,
+    /// Latest default-options history of each repository kept warm, by `cache_key`
+    cache: Mutex<HashMap<String, Cached>>,
+    /// Repositories being re-indexed, and whether another push arrived meanwhile
+    in_flight: Mutex<HashMap<String, bool>>,
+}
+
+/// A history indexed ahead of time.
+#[derive(Clone)]
+pub struct Cached {
+    pub history: String,
+    /// The commit it was indexed from, for the response's validators
+    pub tip: Option<Tip>,
+    stored: Instant,
+}
+
+/// The repository a push event is about.
+pub struct Push {
+    /// Repository URL without its scheme, as `/git_history` takes it
+    pub repo_url: String,
+    pub git_ref: Option<String>,
+}
+
+impl Webhooks {
+    pub fn new(secret: Option<String>) -> Self {
+        Webhooks {
+            secret,
+            cache: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    /// Checks the `X-Hub-Signature-256: sha256=<hex>` HMAC of `body`, in constant time.
+    pub fn verify(&self, headers: &HeaderMap, body: &[u8]) -> bool {
+        let Some(secret) = &self.secret else {
+            return false;
+        };
+        let Some(signature) = headers
+            .get("X-Hub-Signature-256")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("sha256="))
+            .and_then(decode_hex)
+        else {
+            return false;
+        };
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes any key size");
+        mac.update(body);
+        mac.verify_slice(&signature).is_ok()
+    }
+
+    /// The history of `repo_url`, unless it is older than `CACHE_TTL`.
+    pub fn cached(&self, repo_url: &str) -> Option<Cached> {
+        let mut cache = self.cache.lock().unwrap();
+        let key = cache_key(repo_url);
+        let cached = cache.get(&key)?;
+        if cached.stored.elapsed() >= CACHE_TTL {
+            cache.remove(&key);
+            return None;
+        }
+        Some(cached.clone())
+    }
+
+    /// Keeps `history` for `repo_url`, unless another push arrived while it was
+    /// indexed and made it stale already.
+    pub fn store(&self, repo_url: &str, history: String, tip: Option<Tip>) {
+        let key = cache_key(repo_url);
+        if self.in_flight.lock().unwrap().get(&key) == Some(&true) {
+            return;
+        }
+        let cached = Cached {
+            history,
+            tip,
+            stored: Instant::now(),
+        };
+        let mut cache = self.cache.lock().unwrap();
+        // Histories nobody asked for again would otherwise stay until the next push
+        cache.retain(|_, cached| cached.stored.elapsed() < CACHE_TTL);
+        cache.insert(key, cached);
+    }
+
+    /// Records a push, dropping the history it makes stale. Returns true if a
+    /// re-index should start, or false if one is already running, in which case it
+    /// will run once more when it finishes.
+    pub fn begin(&self, repo_url: &str) -> bool {
+        self.cache.lock().unwrap().remove(&cache_key(repo_url));
+        let mut in_flight = self.in_flight.lock().unwrap();
+        match in_flight.get_mut(&cache_key(repo_url)) {
+            Some(pending) => {
+                *pending = true;
+                false
+            }
+            None => {
+                in_flight.insert(cache_key(repo_url), false);
+                true
+            }
+        }
+    }
+
+    /// Records a finished re-index. Returns true if pushes arrived while it ran, so
+    /// the repository should be indexed again.
+    pub fn finish(&self, repo_url: &str) -> bool {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let key = cache_key(repo_url);
+        if in_flight.get(&key) == Some(&true) {
+            in_flight.insert(key, false);
+            true
+        } else {
+            in_flight.remove(&key);
+            false
+        }
+    }
+}
+
+/// Reads a GitHub webhook delivery, JSON or form encoded. Returns `Ok(None)` for
+/// events that don't change the indexed history: pings, other event types and pushes
+/// to branches other than the default one.
+pub fn github_push(headers: &HeaderMap, body: &[u8]) -> Result<Option<Push>, String> {
+    let event = headers
+        .get("X-GitHub-Event")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    if event != "push" {
+        return Ok(None);
+    }
+
+    let form = headers
+        .get("Content-Type")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+    let payload: serde_json::Value = if form {
+        let payload = form_urlencoded::parse(body)
+            .find(|(key, _)| key == "payload")
+            .ok_or("Missing payload field")?
+            .1;
+        serde_json::from_str(&payload)
+    } else {
+        serde_json::from_slice(body)
+    }
+    .map_err(|e| format!("Invalid JSON: {}", e))?;
+
+    let repository = &payload["repository"];
+    let clone_url = repository["clone_url"]
+        .as_str()
+        .ok_or("Missing field: repository.clone_url")?;
+    let git_ref = payload["ref"].as_str();
+    if let (Some(git_ref), Some(default_branch)) = (git_ref, repository["default_branch"].as_str())
+    {
+        if git_ref != format!("refs/heads/{}", default_branch) {
+            return Ok(None);
+        }
+    }
+    Ok(Some(Push {
+        repo_url: cache_key(clone_url),
+        git_ref: git_ref.map(String::from),
+    }))
+}
+
+/// Reads a `{"repo_url": "...", "ref": "..."}` delivery from any other source.
+pub fn generic_push(body: &[u8]) -> Result<Push, String> {
+    let payload: serde_json::Value =
+        serde_json::from_slice(body).map_err(|e| format!("Invalid JSON: {}", e))?;
+    let repo_url = payload["repo_url"]
+        .as_str()
+        .ok_or("Missing field: repo_url")?;
+    Ok(Push {
+        repo_url: cache_key(repo_url),
+        git_ref: payload["ref"].as_str().map(String::from),
+    })
+}
+
+/// One spelling per repository: no scheme, trailing slash or `.git` suffix.
+pub fn cache_key(repo_url: &str) -> String {
+    let url = repo_url
+        .split_once("://")
+        .map_or(repo_url, |(_, rest)| rest)
+        .trim_end_matches('/');
+    url.strip_suffix(".git").unwrap_or(url).to_string()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tip() -> Option<Tip> {
+        Some(Tip { id: "a".repeat(40) })
+    }
+
+    #[test]
+    fn pushes_evict_the_cached_history() {
+        let webhooks = Webhooks::new(Some("secret".to_string()));
+        webhooks.store("github.com/o/r", "[]".to_string(), tip());
+        let cached = webhooks.cached("https://github.com/o/r.git").unwrap();
+        assert_eq!(cached.history, "[]");
+        assert_eq!(cached.tip.unwrap().id, "a".repeat(40));
+
+        assert!(webhooks.begin("github.com/o/r"));
+        assert!(webhooks.cached("github.com/o/r").is_none());
+        // A push during the re-index makes its result stale before it is stored
+        assert!(!webhooks.begin("github.com/o/r"));
+        webhooks.store("github.com/o/r", "[1]".to_string(), tip());
+        assert!(webhooks.cached("github.com/o/r").is_none());
+        assert!(webhooks.finish("github.com/o/r"));
+        webhooks.store("github.com/o/r", "[2]".to_string(), tip());
+        assert!(!webhooks.finish("github.com/o/r"));
+        assert_eq!(webhooks.cached("github.com/o/r").unwrap().history, "[2]");
+    }
+
+    #[test]
+    fn cached_histories_expire() {
+        let webhooks = Webhooks::new(Some("secret".to_string()));
+        webhooks.store("github.com/o/r", "[]".to_string(), tip());
+        let stored = Instant::now().checked_sub(CACHE_TTL).unwrap();
+        webhooks
+            .cache
+            .lock()
+            .unwrap()
+            .get_mut("github.com/o/r")
+            .unwrap()
+            .stored = stored;
+        assert!(webhooks.cached("github.com/o/r").is_none());
+        assert!(webhooks.cache.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn storing_sweeps_expired_histories() {
+        let webhooks = Webhooks::new(Some("secret".to_string()));
+        webhooks.store("github.com/o/old", "[]".to_string(), tip());
+        webhooks.store("github.com/o/recent", "[]".to_string(), tip());
+        let stored = Instant::now().checked_sub(CACHE_TTL).unwrap();
+        webhooks
+            .cache
+            .lock()
+            .unwrap()
+            .get_mut("github.com/o/old")
+            .unwrap()
+            .stored = stored;
+
+        webhooks.store("github.com/o/new", "[]".to_string(), tip());
+        let mut keys: Vec<String> = webhooks.cache.lock().unwrap().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, ["github.com/o/new", "github.com/o/recent"]);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::CustomError;
+use git2::Patch;
+
+/// Largest token table the LCS may allocate for one block of changed lines. Bigger
+/// blocks are rendered as whole removed and added lines instead.
+const MAX_LCS_CELLS: usize = 4_000_000;
+
+/// Renders a file's patch like `git diff --word-diff=plain`: context as is, and each
+/// run of removed lines followed by added lines merged into one text where removed
+/// words show as `[-old-]` and added ones as `{+new+}`.
+///
+/// `None` for binary files and files whose two sides together exceed `max_bytes`,
+/// which keep only their line diff.
+pub fn render(patch: &Patch, max_bytes: u64) -> Result<Option<String>, CustomError> {
+    let delta = patch.delta();
+    if delta.flags().is_binary() || delta.old_file().size() + delta.new_file().size() > max_bytes {
+        return Ok(None);
+    }
+
+    let mut out = String::new();
+    for hunk_idx in 0..patch.num_hunks() {
+        let (hunk, line_count) = patch.hunk(hunk_idx)?;
+        out.push_str(&String::from_utf8_lossy(hunk.header()));
+
+        let mut removed = String::new();
+        let mut added = String::new();
+        for line_idx in 0..line_count {
+            let line = patch.line_in_hunk(hunk_idx, line_idx)?;
+            let content = String::from_utf8_lossy(line.content());
+            match line.origin() {
+                '-' => removed.push_str(&content),
+                '+' => added.push_str(&content),
+                ' ' => {
+                    flush(&mut out, &mut removed, &mut added);
+                    out.push_str(&content);
+                }
+                // End-of-file newline markers carry no words
+                _ => {}
+            }
+        }
+        flush(&mut out, &mut removed, &mut added);
+    }
+    Ok(Some(out))
+}
+
+fn flush(out: &mut String, removed: &mut String, added: &mut String) {
+    if removed.is_empty() && added.is_empty() {
+        return;
+    }
+    let old = tokens(removed);
+    let new = tokens(added);
+    if old.len() * new.len() > MAX_LCS_CELLS {
+        push_change(out, "[-", removed, "-]");
+        push_change(out, "{+", added, "+}");
+    } else {
+        render_tokens(out, &old, &new);
+    }
+    removed.clear();
+    added.clear();
+}
+
+/// Splits text into alternating runs of whitespace and non-whitespace.
+fn tokens(text: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut in_space = None;
+    for (idx, ch) in text.char_indices() {
+        let space = ch.is_whitespace();
+        if in_space.is_some_and(|in_space| in_space != space) {
+            tokens.push(&text[start..idx]);
+            start = idx;
+        }
+        in_space = Some(space);
+    }
+    if start < text.len() {
+        tokens.push(&text[start..]);
+    }
+    tokens
+}
+
+/// Longest common subsequence over tokens, walked front to back so removed and added
+/// tokens can be grouped into as few markers as possible.
+fn render_tokens(out: &mut String, old: &[&str], new: &[&str]) {
+    let width = new.len() + 1;
+    // lcs[i * width + j] is the LCS length of old[i..] and new[j..]
+    let mut lcs = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i * width + j] = if old[i] == new[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut removed = String::new();
+    let mut added = String::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            push_change(out, "[-", &removed, "-]");
+            push_change(out, "{+", &added, "+}");
+            removed.clear();
+            added.clear();
+            out.push_str(old[i]);
+            i += 1;
+            j += 1;
+        } else if j < new.len()
+            && (i == old.len() || lcs[i * width + j + 1] >= lcs[(i + 1) * width + j])
+        {
+            added.push_str(new[j]);
+            j += 1;
+        } else {
+            removed.push_str(old[i]);
+            i += 1;
+        }
+    }
+    push_change(out, "[-", &removed, "-]");
+    push_change(out, "{+", &added, "+}");
+}
+
+fn push_change(out: &mut String, open: &str, text: &str, close: &str) {
+    if !text.is_empty() {
+        out.push_str(open);
+        out.push_str(text);
+        out.push_str(close);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word_diff(old: &str, new: &str, max_bytes: u64) -> Option<String> {
+        let patch = Patch::from_buffers(old.as_bytes(), None, new.as_bytes(), None, None).unwrap();
+        render(&patch, max_bytes).unwrap()
+    }
+
+    #[test]
+    fn marks_words_changed_in_the_middle_of_long_lines() {
+        let words: Vec<String> = (0..200).map(|n| format!("word{}", n)).collect();
+        let old = words.join(" ") + "\n";
+        let mut edited = words.clone();
+        edited[100] = "changed".into();
+        edited.insert(150, "extra".into());
+        let new = edited.join(" ") + "\n";
+
+        let out = word_diff(&old, &new, 1 << 20).unwrap();
+        assert!(out.starts_with("@@ -1 +1 @@"));
+        assert!(out.contains(" word99 [-word100-]{+changed+} word101 "));
+        assert!(out.contains(" word149 {+extra +}word150 "));
+        assert!(out.contains(&words[..100].join(" ")));
+        assert_eq!(out.matches("[-").count(), 1);
+        assert_eq!(out.matches("{+").count(), 2);
+    }
+
+    #[test]
+    fn keeps_context_and_separates_blocks() {
+        let old = "alpha beta\nsame line\ngamma delta\n";
+        let new = "alpha BETA\nsame line\ngamma delta epsilon\n";
+        let out = word_diff(old, new, 1 << 20).unwrap();
+        assert_eq!(
+            out,
+            "@@ -1,3 +1,3 @@\nalpha [-beta-]{+BETA+}\nsame line\ngamma delta{+ epsilon+}\n"
+        );
+    }
+
+    #[test]
+    fn files_over_the_threshold_keep_only_their_line_diff() {
+        let old = "a fairly long line of prose\n";
+        let new = "a fairly short line of prose\n";
+        let size = (old.len() + new.len()) as u64;
+        assert!(word_diff(old, new, size).is_some());
+        assert_eq!(word_diff(old, new, size - 1), None);
+    }
+
+    #[test]
+    fn binary_files_have_no_word_diff() {
+        assert_eq!(word_diff("a\0b\n", "a\0c\n", 1 << 20), None);
+    }
+
+    #[test]
+    fn splits_on_whitespace_runs() {
+        assert_eq!(
+            tokens("  one\ttwo  three\n"),
+            ["  ", "one", "\t", "two", "  ", "three", "\n"]
+        );
+    }
+}
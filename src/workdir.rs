@@ -0,0 +1,283 @@
+use crate::CustomError;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
+
+/// Default for `--work-dir-ttl`: a day, far longer than any clone should take.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Start of the name of every clone directory, so the sweep leaves everything else in
+/// a shared temp directory alone.
+const PREFIX: &str = "git-history-clone-";
+
+/// How often a running clone's size is checked against `--max-clone-bytes`.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Where the server clones repositories, and how much disk it may take.
+#[derive(Clone, Debug)]
+pub struct WorkDir {
+    /// `--work-dir`; the system temp directory when unset
+    pub path: Option<PathBuf>,
+    /// Clone directories older than this are left over from a crash and removed at
+    /// startup
+    pub ttl: Duration,
+    /// Refuse to clone when the work directory's file system has less free space
+    pub min_free_bytes: Option<u64>,
+    /// Abort a clone once its directory grows larger than this
+    pub max_clone_bytes: Option<u64>,
+}
+
+impl Default for WorkDir {
+    fn default() -> Self {
+        WorkDir {
+            path: None,
+            ttl: DEFAULT_TTL,
+            min_free_bytes: None,
+            max_clone_bytes: None,
+        }
+    }
+}
+
+impl WorkDir {
+    pub fn root(&self) -> PathBuf {
+        self.path.clone().unwrap_or_else(std::env::temp_dir)
+    }
+
+    /// A new, empty clone directory, removed when the returned `TempDir` is dropped.
+    /// Fails with `InsufficientStorage` when less than `--min-free-bytes` is free.
+    pub fn create(&self) -> Result<TempDir, CustomError> {
+        let root = self.root();
+        fs::create_dir_all(&root)?;
+        if let (Some(min), Some(free)) = (self.min_free_bytes, free_bytes(&root)) {
+            if free < min {
+                return Err(CustomError::InsufficientStorage(format!(
+                    "{} has {} bytes free, less than the {} required by --min-free-bytes",
+                    root.display(),
+                    free,
+                    min
+                )));
+            }
+        }
+        Ok(tempfile::Builder::new().prefix(PREFIX).tempdir_in(&root)?)
+    }
+
+    /// Removes the clone directories older than the TTL that a killed server left
+    /// behind, returning how many there were. Other entries are never touched.
+    pub fn sweep(&self) -> io::Result<usize> {
+        let root = self.root();
+        if !root.is_dir() {
+            return Ok(0);
+        }
+        let now = SystemTime::now();
+        let mut removed = 0;
+        for entry in fs::read_dir(&root)? {
+            let entry = entry?;
+            if !entry.file_name().to_string_lossy().starts_with(PREFIX) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            let age = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .unwrap_or_default();
+            if !metadata.is_dir() || age < self.ttl {
+                continue;
+            }
+            match fs::remove_dir_all(entry.path()) {
+                Ok(()) => removed += 1,
+                Err(e) => eprintln!(
+                    "Failed to remove stale clone directory {}: {}",
+                    entry.path().display(),
+                    e
+                ),
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Resolves with the size of `dir` once it exceeds `--max-clone-bytes`, and never
+    /// without a limit, so it can be raced against a running clone.
+    pub async fn exceeded(&self, dir: &Path) -> u64 {
+        let Some(limit) = self.max_clone_bytes else {
+            return std::future::pending().await;
+        };
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let path = dir.to_path_buf();
+            let size = tokio::task::spawn_blocking(move || dir_size(&path))
+                .await
+                .unwrap_or(0);
+            if size > limit {
+                return size;
+            }
+        }
+    }
+}
+
+/// Bytes taken by the files below `path`, not following symlinks. Entries that vanish
+/// while walking are skipped.
+pub fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(kind) if kind.is_dir() => dir_size(&entry.path()),
+            Ok(_) => entry.metadata().map(|m| m.len()).unwrap_or(0),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// Bytes available to unprivileged users on the file system holding `path`, or `None`
+/// where that can't be asked, which skips the check.
+#[cfg(unix)]
+pub fn free_bytes(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is a valid C string and `stat` is only read after statvfs filled it
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stat.assume_init()
+    };
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn free_bytes(_path: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn age(path: &Path, age: Duration) {
+        let file = fs::File::open(path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    fn work_dir(root: &Path) -> WorkDir {
+        WorkDir {
+            path: Some(root.to_path_buf()),
+            ..WorkDir::default()
+        }
+    }
+
+    #[test]
+    fn sweep_removes_only_stale_clone_directories() {
+        let root = TempDir::new().unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let stale = root.path().join(format!("{}stale", PREFIX));
+        fs::create_dir_all(stale.join("repo/.git/objects")).unwrap();
+        fs::write(stale.join("repo/.git/objects/pack"), "pack").unwrap();
+        age(&stale, 2 * day);
+        let fresh = root.path().join(format!("{}fresh", PREFIX));
+        fs::create_dir(&fresh).unwrap();
+        let unrelated = root.path().join("someone-elses");
+        fs::create_dir(&unrelated).unwrap();
+        age(&unrelated, 2 * day);
+        let file = root.path().join(format!("{}file", PREFIX));
+        fs::write(&file, "not a directory").unwrap();
+        age(&file, 2 * day);
+
+        assert_eq!(work_dir(root.path()).sweep().unwrap(), 1);
+        assert!(!stale.exists());
+        assert!(fresh.exists());
+        assert!(unrelated.exists());
+        assert!(file.exists());
+
+        // A shorter TTL catches the fresh one too once it has aged
+        age(&fresh, Duration::from_secs(120));
+        let short = WorkDir {
+            ttl: Duration::from_secs(60),
+            ..work_dir(root.path())
+        };
+        assert_eq!(short.sweep().unwrap(), 1);
+        assert!(!fresh.exists());
+
+        let missing = work_dir(&root.path().join("missing"));
+        assert_eq!(missing.sweep().unwrap(), 0);
+    }
+
+    #[test]
+    fn counts_the_bytes_below_a_directory() {
+        let root = TempDir::new().unwrap();
+        fs::create_dir_all(root.path().join("a/b")).unwrap();
+        fs::write(root.path().join("top"), [0; 10]).unwrap();
+        fs::write(root.path().join("a/b/deep"), [0; 20]).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("/usr", root.path().join("a/link")).unwrap();
+        let size = dir_size(root.path());
+        // The symlink counts as itself, not as what it points to
+        assert!((30..40).contains(&size), "{}", size);
+        assert_eq!(dir_size(&root.path().join("missing")), 0);
+    }
+
+    #[test]
+    fn refuses_to_create_below_the_free_space_minimum() {
+        let root = TempDir::new().unwrap();
+        let nested = root.path().join("work");
+        let full = WorkDir {
+            min_free_bytes: Some(u64::MAX),
+            ..work_dir(&nested)
+        };
+        let Err(CustomError::InsufficientStorage(message)) = full.create() else {
+            panic!("expected InsufficientStorage");
+        };
+        assert!(message.contains("--min-free-bytes"), "{}", message);
+        assert_eq!(fs::read_dir(&nested).unwrap().count(), 0);
+
+        let roomy = WorkDir {
+            min_free_bytes: Some(1),
+            ..work_dir(&nested)
+        };
+        let dir = roomy.create().unwrap();
+        let name = dir
+            .path()
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        assert!(name.starts_with(PREFIX), "{}", name);
+        drop(dir);
+        assert_eq!(fs::read_dir(&nested).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn exceeded_fires_only_over_the_cap() {
+        let root = TempDir::new().unwrap();
+        fs::write(root.path().join("blob"), [0; 100]).unwrap();
+        let capped = WorkDir {
+            max_clone_bytes: Some(10),
+            ..WorkDir::default()
+        };
+        let size = tokio::time::timeout(Duration::from_secs(2), capped.exceeded(root.path()))
+            .await
+            .unwrap();
+        assert_eq!(size, 100);
+
+        let roomy = WorkDir {
+            max_clone_bytes: Some(1000),
+            ..WorkDir::default()
+        };
+        let wait = Duration::from_millis(600);
+        assert!(tokio::time::timeout(wait, roomy.exceeded(root.path()))
+            .await
+            .is_err());
+        assert!(
+            tokio::time::timeout(wait, WorkDir::default().exceeded(root.path()))
+                .await
+                .is_err()
+        );
+    }
+}
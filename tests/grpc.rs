@@ -0,0 +1,270 @@
+// Drives the gRPC server of a running `git-history server --grpc-bind` end to end.
+#![cfg(feature = "grpc")]
+
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tonic::codec::ProstCodec;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::transport::Channel;
+use tonic::Code;
+
+#[allow(dead_code)]
+mod proto {
+    tonic::include_proto!("git_history.v1");
+}
+
+/// The server process, killed when the test is done with it.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn git(repo: &Path, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .current_dir(repo)
+        .args(args)
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_CONFIG_GLOBAL", "/dev/null")
+        .env("GIT_AUTHOR_NAME", "Jane Doe")
+        .env("GIT_AUTHOR_EMAIL", "jane@example.com")
+        .env("GIT_AUTHOR_DATE", "1700000000 +0000")
+        .env("GIT_COMMITTER_NAME", "Jane Doe")
+        .env("GIT_COMMITTER_EMAIL", "jane@example.com")
+        .env("GIT_COMMITTER_DATE", "1700000000 +0000")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "git failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// A repository with three commits, the last one touching two files.
+fn fixture() -> TempDir {
+    let dir = TempDir::new().unwrap();
+    let repo = dir.path();
+    git(repo, &["init", "-q", "-b", "main"]);
+    std::fs::write(repo.join("README.md"), "# Fixture\n").unwrap();
+    git(repo, &["add", "-A"]);
+    git(repo, &["commit", "-q", "-m", "Add the readme"]);
+    std::fs::write(repo.join("src.rs"), "fn main() {}\n").unwrap();
+    git(repo, &["add", "-A"]);
+    git(repo, &["commit", "-q", "-m", "Add the code"]);
+    std::fs::write(repo.join("README.md"), "# Fixture\n\nNow with docs.\n").unwrap();
+    std::fs::write(repo.join("src.rs"), "fn main() {\n    run();\n}\n").unwrap();
+    git(repo, &["add", "-A"]);
+    git(repo, &["commit", "-q", "-m", "Run something"]);
+    dir
+}
+
+/// Starts the server with repositories below `root` open to `repo_path` requests and
+/// connects to its gRPC port once it listens.
+async fn start(root: &Path) -> (Server, tonic::client::Grpc<Channel>) {
+    start_with(root, &[]).await
+}
+
+async fn start_with(root: &Path, args: &[&str]) -> (Server, tonic::client::Grpc<Channel>) {
+    // An empty working directory, so no git-history.toml is picked up
+    let work_dir = TempDir::new().unwrap();
+    let grpc_addr = format!("127.0.0.1:{}", free_port());
+    let child = Command::new(env!("CARGO_BIN_EXE_git-history"))
+        .current_dir(work_dir.path())
+        .args(["server", "--bind", &format!("127.0.0.1:{}", free_port())])
+        .args(["--grpc-bind", &grpc_addr])
+        .arg("--allow-local-paths")
+        .arg(root)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let server = Server(child);
+
+    let deadline = Instant::now() + Duration::from_secs(20);
+    let channel = loop {
+        match Channel::from_shared(format!("http://{}", grpc_addr))
+            .unwrap()
+            .connect()
+            .await
+        {
+            Ok(channel) => break channel,
+            Err(e) if Instant::now() > deadline => panic!("server never listened: {}", e),
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+    (server, tonic::client::Grpc::new(channel))
+}
+
+async fn index(
+    client: &mut tonic::client::Grpc<Channel>,
+    request: proto::IndexRequest,
+) -> Result<Vec<proto::Commit>, tonic::Status> {
+    Ok(index_page(client, request).await?.0)
+}
+
+/// The commits of an `Index` call and the `x-next-cursor` trailer it ended with.
+async fn index_page(
+    client: &mut tonic::client::Grpc<Channel>,
+    request: proto::IndexRequest,
+) -> Result<(Vec<proto::Commit>, Option<String>), tonic::Status> {
+    client.ready().await.unwrap();
+    let path = PathAndQuery::from_static("/git_history.v1.GitHistory/Index");
+    let codec: ProstCodec<proto::IndexRequest, proto::Commit> = ProstCodec::default();
+    let mut stream = client
+        .server_streaming(tonic::Request::new(request), path, codec)
+        .await?
+        .into_inner();
+    let mut commits = Vec::new();
+    while let Some(commit) = stream.message().await? {
+        commits.push(commit);
+    }
+    let cursor = stream
+        .trailers()
+        .await?
+        .and_then(|trailers| trailers.get("x-next-cursor").cloned())
+        .map(|cursor| cursor.to_str().unwrap().to_string());
+    Ok((commits, cursor))
+}
+
+#[tokio::test]
+async fn index_streams_the_history_of_a_fixture() {
+    let repo = fixture();
+    let (_server, mut client) = start(repo.path()).await;
+
+    let request = proto::IndexRequest {
+        repo_path: Some(repo.path().display().to_string()),
+        diff_format: Some("unified".to_string()),
+        ..Default::default()
+    };
+    let commits = index(&mut client, request).await.unwrap();
+
+    let ids: Vec<String> = git(repo.path(), &["rev-list", "HEAD"])
+        .lines()
+        .map(String::from)
+        .collect();
+    let streamed: Vec<&str> = commits.iter().map(|c| c.commit_id.as_str()).collect();
+    assert_eq!(streamed, ids);
+    assert_eq!(commits[0].commit_message.trim(), "Run something");
+    assert_eq!(commits[0].author, "Jane Doe");
+    assert_eq!(commits[2].parent_ids, Vec::<String>::new());
+
+    let files: Vec<(&str, Option<u64>, Option<u64>)> = commits[0]
+        .git_diff
+        .iter()
+        .map(|file| (file.file.as_str(), file.additions, file.deletions))
+        .collect();
+    assert_eq!(
+        files,
+        [
+            ("README.md", Some(2), Some(0)),
+            ("src.rs", Some(3), Some(1))
+        ]
+    );
+    assert!(commits[0].git_diff[1].diff.contains("+    run();\n"));
+
+    // Filters reach the indexer like in a POST /git_history body
+    let request = proto::IndexRequest {
+        repo_path: Some(repo.path().display().to_string()),
+        paths: vec!["src.rs".to_string()],
+        stats_only: true,
+        ..Default::default()
+    };
+    let commits = index(&mut client, request).await.unwrap();
+    assert_eq!(commits.len(), 2);
+    assert!(commits.iter().all(|commit| commit.git_diff.is_empty()));
+    assert_eq!(commits[0].stats.as_ref().unwrap().files_changed, 1);
+
+    let request = proto::IndexRequest {
+        repo_path: Some(repo.path().display().to_string()),
+        redact: Some("authors".to_string()),
+        redact_salt: Some("pepper".to_string()),
+        ..Default::default()
+    };
+    let commits = index(&mut client, request).await.unwrap();
+    assert!(commits
+        .iter()
+        .all(|commit| commit.author.starts_with("Contributor-")));
+    assert_eq!(commits[0].author, commits[2].author);
+}
+
+#[tokio::test]
+async fn index_rejects_paths_outside_the_allowed_roots() {
+    let repo = fixture();
+    let other = fixture();
+    let (_server, mut client) = start(repo.path()).await;
+
+    let request = proto::IndexRequest {
+        repo_path: Some(other.path().display().to_string()),
+        ..Default::default()
+    };
+    let status = index(&mut client, request).await.unwrap_err();
+    assert_eq!(status.code(), Code::PermissionDenied);
+
+    let status = index(&mut client, proto::IndexRequest::default())
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn index_pages_through_a_history_cut_short() {
+    let repo = fixture();
+    // Every page holds the one commit the budget always admits
+    let (_server, mut client) = start_with(repo.path(), &["--max-response-bytes", "1"]).await;
+
+    let ids: Vec<String> = git(repo.path(), &["rev-list", "HEAD"])
+        .lines()
+        .map(String::from)
+        .collect();
+    let mut streamed = Vec::new();
+    let mut cursor = None;
+    loop {
+        let request = proto::IndexRequest {
+            repo_path: Some(repo.path().display().to_string()),
+            cursor: cursor.clone(),
+            ..Default::default()
+        };
+        let (commits, next_cursor) = index_page(&mut client, request).await.unwrap();
+        assert_eq!(commits.len(), 1);
+        streamed.extend(commits.into_iter().map(|commit| commit.commit_id));
+        // The trailer names the first commit left out
+        assert_eq!(next_cursor.as_ref(), ids.get(streamed.len()));
+        cursor = next_cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(streamed, ids);
+}
+
+#[tokio::test]
+async fn index_rejects_enrich() {
+    let repo = fixture();
+    let (_server, mut client) = start(repo.path()).await;
+
+    let request = proto::IndexRequest {
+        repo_path: Some(repo.path().display().to_string()),
+        enrich: Some("github".to_string()),
+        ..Default::default()
+    };
+    let status = index(&mut client, request).await.unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    assert!(status.message().contains("can't be streamed"));
+}